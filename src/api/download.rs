@@ -0,0 +1,145 @@
+use std::io::SeekFrom;
+use std::path::Path;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Redirect, Responder, Response};
+use rocket::Responder;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// The raw `Range` header, if the client sent one
+pub struct RangeHeader(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeader {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RangeHeader(
+            request.headers().get_one("Range").map(str::to_string),
+        ))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// Inclusive start and end
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range against a file of `len` bytes. Anything we
+/// don't understand (multiple ranges, other units) is ignored and the whole
+/// file is sent, which the spec allows.
+pub fn parse_range(header: &str, len: u64) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return Some(ByteRange::Unsatisfiable);
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = end.parse().ok()?;
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    if len == 0 || start >= len {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Satisfiable(start, end))
+}
+
+/// A file on disk served whole or as a single byte range
+pub struct RangedFile {
+    file: File,
+    total: u64,
+    range: Option<(u64, u64)>,
+    content_type: ContentType,
+    disposition: Option<String>,
+}
+
+impl RangedFile {
+    pub async fn open(
+        path: &Path,
+        range: &RangeHeader,
+        content_type: ContentType,
+        filename: Option<&str>,
+    ) -> Result<Self, Status> {
+        let mut file = File::open(path).await.map_err(|_| Status::NotFound)?;
+        let total = file
+            .metadata()
+            .await
+            .map_err(|_| Status::InternalServerError)?
+            .len();
+
+        let range = match range.0.as_deref().and_then(|header| parse_range(header, total)) {
+            Some(ByteRange::Satisfiable(start, end)) => {
+                file.seek(SeekFrom::Start(start))
+                    .await
+                    .map_err(|_| Status::InternalServerError)?;
+                Some((start, end))
+            }
+            Some(ByteRange::Unsatisfiable) => return Err(Status::RangeNotSatisfiable),
+            None => None,
+        };
+
+        Ok(Self {
+            file,
+            total,
+            range,
+            content_type,
+            disposition: filename.map(|name| format!("inline; filename=\"{name}\"")),
+        })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for RangedFile {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .header(self.content_type)
+            .header(Header::new("Accept-Ranges", "bytes"));
+        if let Some(disposition) = self.disposition {
+            response.header(Header::new("Content-Disposition", disposition));
+        }
+
+        match self.range {
+            Some((start, end)) => {
+                let len = end - start + 1;
+                response
+                    .status(Status::PartialContent)
+                    .header(Header::new(
+                        "Content-Range",
+                        format!("bytes {start}-{end}/{}", self.total),
+                    ))
+                    .header(Header::new("Content-Length", len.to_string()))
+                    .streamed_body(self.file.take(len));
+            }
+            None => {
+                response
+                    .header(Header::new("Content-Length", self.total.to_string()))
+                    .streamed_body(self.file);
+            }
+        }
+        response.ok()
+    }
+}
+
+#[derive(Responder)]
+pub enum Download {
+    File(RangedFile),
+    Redirect(Redirect),
+}
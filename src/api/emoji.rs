@@ -0,0 +1,123 @@
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+
+use image::{ImageFormat, ImageReader};
+use rocket::fs::TempFile;
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+pub const MAX_EMOJI_SIZE: u64 = 256 * 1024;
+pub const MAX_EMOJI_DIMENSION: u32 = 256;
+pub const MAX_EMOJI_PER_SERVER: i64 = 100;
+
+/// An emoji as it appears in a reaction route, either a plain Unicode
+/// sequence (`👍`, `👩‍👩‍👧`) or a server emoji written as `name:id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReactionEmoji {
+    Unicode(String),
+    Custom { name: String, id: Uuid },
+}
+
+impl ReactionEmoji {
+    /// The value stored in `reactions.emoji`. Custom emoji are keyed by id
+    /// alone so renaming one doesn't orphan its reactions.
+    pub fn key(&self) -> String {
+        match self {
+            ReactionEmoji::Unicode(emoji) => emoji.clone(),
+            ReactionEmoji::Custom { id, .. } => id.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ReactionEmoji {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReactionEmoji::Unicode(emoji) => write!(f, "{emoji}"),
+            ReactionEmoji::Custom { name, id } => write!(f, "{name}:{id}"),
+        }
+    }
+}
+
+impl FromStr for ReactionEmoji {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((name, id)) = s.split_once(':') {
+            if !is_valid_emoji_name(name) {
+                return Err(format!("Invalid custom emoji name: {name}"));
+            }
+            let id = Uuid::parse_str(id).map_err(|_| format!("Invalid custom emoji id: {id}"))?;
+            return Ok(ReactionEmoji::Custom {
+                name: name.to_string(),
+                id,
+            });
+        }
+
+        // emojis::get handles ZWJ sequences and skin tone modifiers
+        match emojis::get(s) {
+            Some(_) => Ok(ReactionEmoji::Unicode(s.to_string())),
+            None => Err(format!("Not a valid emoji: {s}")),
+        }
+    }
+}
+
+pub fn is_valid_emoji_name(name: &str) -> bool {
+    (2..=32).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// What we learned about an uploaded emoji image while validating it
+#[derive(Debug)]
+pub struct EmojiImage {
+    pub animated: bool,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Checks an emoji upload is a small PNG, JPEG, GIF or WebP image
+pub async fn inspect_emoji_image(file: &TempFile<'_>) -> Result<EmojiImage, String> {
+    if file.len() > MAX_EMOJI_SIZE {
+        return Err(format!(
+            "Emoji images must be at most {} KiB",
+            MAX_EMOJI_SIZE / 1024
+        ));
+    }
+
+    // Bounded by MAX_EMOJI_SIZE above, so buffering is fine here
+    let mut bytes = Vec::with_capacity(file.len() as usize);
+    let stream = file
+        .open()
+        .await
+        .map_err(|e| format!("Failed to read emoji image: {e}"))?;
+    tokio::pin!(stream);
+    stream
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|e| format!("Failed to read emoji image: {e}"))?;
+
+    let reader = ImageReader::new(Cursor::new(&bytes))
+        .with_guessed_format()
+        .map_err(|e| format!("Failed to read emoji image: {e}"))?;
+    let animated = match reader.format() {
+        Some(ImageFormat::Gif) => true,
+        Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP) => false,
+        _ => return Err("Emoji images must be PNG, JPEG, GIF or WebP".to_string()),
+    };
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| format!("Failed to decode emoji image: {e}"))?;
+    if width > MAX_EMOJI_DIMENSION || height > MAX_EMOJI_DIMENSION {
+        return Err(format!(
+            "Emoji images must be at most {MAX_EMOJI_DIMENSION}x{MAX_EMOJI_DIMENSION}, got {width}x{height}"
+        ));
+    }
+
+    Ok(EmojiImage {
+        animated,
+        width,
+        height,
+    })
+}
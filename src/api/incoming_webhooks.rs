@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+use uuid::Uuid;
+
+use super::{Embed, EmbedMedia, EmbedType};
+
+pub const MAX_WEBHOOK_NAME_LENGTH: usize = 80;
+pub const MAX_WEBHOOK_EMBEDS: usize = 10;
+const MAX_EMBED_TITLE_LENGTH: usize = 256;
+const MAX_EMBED_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_URL_LENGTH: usize = 2048;
+/// Executions allowed per webhook within RATE_LIMIT_WINDOW
+const RATE_LIMIT_CALLS: u32 = 30;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// A fresh execution token, base64url. Only its hash is stored.
+pub fn generate_token() -> String {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Names are shown where a username would be, so they follow similar rules
+pub fn is_valid_webhook_name(name: &str) -> bool {
+    let length = name.chars().count();
+    (1..=MAX_WEBHOOK_NAME_LENGTH).contains(&length) && !name.chars().any(char::is_control)
+}
+
+/// Avatars and embed links must be plain http(s) urls
+pub fn is_http_url(url: &str) -> bool {
+    url.len() <= MAX_URL_LENGTH && Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+}
+
+/// A rich embed as webhooks post it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEmbed {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub url: Option<String>,
+    /// `#rrggbb`
+    pub color: Option<String>,
+    pub image_url: Option<String>,
+}
+
+impl WebhookEmbed {
+    pub fn into_embed(self) -> Result<Embed, String> {
+        let title = self.title.map(|title| title.trim().to_string()).filter(|title| !title.is_empty());
+        let description = self
+            .description
+            .map(|description| description.trim().to_string())
+            .filter(|description| !description.is_empty());
+        if title.is_none() && description.is_none() && self.image_url.is_none() {
+            return Err("An embed needs a title, description or image".to_string());
+        }
+        if title.as_ref().is_some_and(|title| title.chars().count() > MAX_EMBED_TITLE_LENGTH) {
+            return Err(format!("Embed titles can be at most {MAX_EMBED_TITLE_LENGTH} characters"));
+        }
+        if description
+            .as_ref()
+            .is_some_and(|description| description.chars().count() > MAX_EMBED_DESCRIPTION_LENGTH)
+        {
+            return Err(format!("Embed descriptions can be at most {MAX_EMBED_DESCRIPTION_LENGTH} characters"));
+        }
+        for url in [&self.url, &self.image_url].into_iter().flatten() {
+            if !is_http_url(url) {
+                return Err(format!("Invalid embed url: {url}"));
+            }
+        }
+        if let Some(color) = &self.color {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(format!("Invalid embed color {color}, expected #rrggbb"));
+            }
+        }
+
+        Ok(Embed {
+            embed_type: EmbedType::Rich,
+            url: self.url.unwrap_or_default(),
+            title,
+            description,
+            site_name: None,
+            color: self.color,
+            image: self.image_url.map(|url| EmbedMedia {
+                url,
+                width: None,
+                height: None,
+            }),
+            video: None,
+        })
+    }
+}
+
+struct Window {
+    started: Instant,
+    calls: u32,
+}
+
+/// Caps how fast each webhook can post, independent of any user's limits
+#[derive(Default)]
+pub struct WebhookRateLimiter {
+    windows: Mutex<HashMap<Uuid, Window>>,
+}
+
+impl WebhookRateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a call, or says how long until the next one is allowed
+    pub fn check(&self, webhook_id: Uuid) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("webhook rate limits poisoned");
+        windows.retain(|_, window| now.duration_since(window.started) < RATE_LIMIT_WINDOW);
+
+        let window = windows.entry(webhook_id).or_insert(Window { started: now, calls: 0 });
+        if window.calls >= RATE_LIMIT_CALLS {
+            return Err(RATE_LIMIT_WINDOW - now.duration_since(window.started));
+        }
+        window.calls += 1;
+        Ok(())
+    }
+}
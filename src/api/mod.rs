@@ -1,38 +1,151 @@
 use anyhow::{anyhow, Result};
-use log::{error, info};
-use rocket::{delete, get, patch, post, put, routes, FromFormField, State};
+use log::{error, info, warn};
+use sqlx::{MySql, Pool};
+use rocket::{delete, get, head, options, patch, post, put, routes, Data, FromFormField, State};
 use rocket::serde::json::Json;
-use rocket::http::Status;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::Redirect;
 use rocket::form::{Form, FromForm};
 use rocket::fs::TempFile;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::data::{ByteUnit, Limits};
+use rocket::Shutdown;
 use serde::{Serialize, Deserialize};
 use url::Url;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr};
 use std::path::PathBuf;
 use std::str::FromStr;
 
+use crate::db::queries::{self, JoinOutcome, NewAttachment, ProfileUpdate};
+use crate::gateway::presence::{publish_presence, run_presence_sweeper, PresenceTracker, PresenceUpdate};
+use crate::gateway::typing::{TypingOutcome, TypingTracker, TYPING_TIMEOUT};
+use crate::gateway::{Gateway, GatewayEvent};
+use crate::media::{run_thumbnail_worker, ThumbnailQueue};
+use crate::storage::gc::run_storage_gc;
+use crate::mail::Mailer;
+use crate::push::{run_push_worker, PushQueue, Pusher};
+use crate::webhooks::{generate_secret, run_webhook_worker, WebhookEvent, WebhookQueue, WebhookSender};
+use crate::interactions::{
+    resolve_options, run_interaction_pruner, validate_command, CommandOption, CommandOptionType, InteractionOption, InteractionResponse,
+    InteractionResponseType, InteractionSender, RESPONSE_DEADLINE,
+};
+use crate::search::{build_search, Search, SearchFilters, SearchQuery};
+use crate::unfurl::{run_unfurler, UnfurlQueue, Unfurler};
+use crate::user::auth::{self, LockoutKind, LoginLocks, LoginOutcome, LoginRefusal};
+use crate::storage::{build_storage, is_valid_key, store_bytes, store_upload, ObjectLocation, Storage, StoredObject, UploadSource};
+use download::{Download, RangeHeader, RangedFile};
+use upload::{inspect_upload, InspectedUpload};
+use incoming_webhooks::{
+    generate_token, hash_token, is_http_url, is_valid_webhook_name, WebhookEmbed, WebhookRateLimiter, MAX_WEBHOOK_EMBEDS,
+};
+use emoji::{inspect_emoji_image, is_valid_emoji_name, ReactionEmoji, MAX_EMOJI_PER_SERVER, MAX_EMOJI_SIZE};
+use notifications::{ChannelNotificationOverride, MessageMentions, NotificationLevel, ServerNotificationSettings};
+use permissions::Permissions;
+use resumable::{
+    open_partial_upload, partial_upload_path, remove_partial_upload, TusRequest, TusResponse, UploadLocks,
+    TUS_EXTENSIONS, TUS_VERSION, UPLOAD_SESSION_HOURS,
+};
+use ratelimit::{ClientIp, RateLimitDecision, RateLimiter};
+use signing::{SignatureError, UrlSigner};
+
+pub mod download;
+pub mod emoji;
+pub mod incoming_webhooks;
+pub mod notifications;
+pub mod permissions;
+pub mod ratelimit;
+pub mod resumable;
+pub mod signing;
+pub mod upload;
+
+/// Distinct emoji allowed on a single message
+const MAX_REACTIONS_PER_MESSAGE: i64 = 20;
+const MAX_REACTION_USERS_PAGE: i64 = 100;
+const MAX_CUSTOM_STATUS_LENGTH: usize = 128;
+const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+const MAX_BIO_LENGTH: usize = 190;
+const MAX_PRONOUNS_LENGTH: usize = 40;
+const USERNAME_CHANGES_PER_HOUR: i64 = 2;
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// Hashing is slow on purpose, so passwords past this aren't worth hashing
+const MAX_PASSWORD_LENGTH: usize = 128;
+const SESSION_LIFETIME_DAYS: i64 = 30;
+const MAX_ATTACHMENTS_PER_UPLOAD: usize = 10;
+const MAX_MESSAGE_LENGTH: usize = 4000;
+const MAX_SEARCH_PAGE: i64 = 25;
+const MAX_PUSH_ENDPOINT_LENGTH: usize = 768;
+const MAX_WEBHOOKS_PER_SERVER: usize = 10;
+const MAX_WEBHOOK_URL_LENGTH: usize = 2048;
+const MIN_WEBHOOK_SECRET_LENGTH: usize = 16;
+const MAX_WEBHOOK_SECRET_LENGTH: usize = 255;
+const MAX_WEBHOOK_DELIVERIES_PAGE: i64 = 100;
+const MAX_BAN_REASON_LENGTH: usize = 512;
+const MAX_WEBHOOKS_PER_CHANNEL: i64 = 10;
+const MAX_APPLICATIONS_PER_USER: usize = 25;
+const MAX_APPLICATION_NAME_LENGTH: usize = 80;
+const MAX_APPLICATION_DESCRIPTION_LENGTH: usize = 1000;
+/// Per application, counted separately for global commands and each server
+const MAX_COMMANDS_PER_SCOPE: i64 = 100;
+const MAX_COMMAND_SUGGESTIONS: i64 = 25;
+/// Deep pages get slow on FULLTEXT, narrow the search instead
+const MAX_SEARCH_OFFSET: i64 = 5000;
+const MAX_INVITE_USES: i32 = 100;
+const MAX_INVITE_STATS_DAYS: i64 = 90;
+const MAX_MEMBERS_PAGE: i64 = 1000;
+/// A week. Invites that should outlive that are made without an expiry.
+const MAX_INVITE_LIFETIME_HOURS: i32 = 24 * 7;
+const MIN_VANITY_CODE_LENGTH: usize = 3;
+const MAX_VANITY_CODE_LENGTH: usize = 32;
+/// Avatars and banners, independent of the attachment limits
+const MAX_PROFILE_IMAGE_SIZE: u64 = 8 * 1024 * 1024;
+
 // Models
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
     pub display_name: Option<String>,
-    pub email: String,
+    /// None for bots, which can't log in
+    pub email: Option<String>,
     pub avatar: Option<String>,
+    pub banner: Option<String>,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    pub bot: bool,
     pub status: UserStatus,
     pub custom_status: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromFormField)]
+/// The profile other users get to see. Never includes the email or any other
+/// account detail, use this for anything that isn't the user's own account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicUser {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+    pub banner: Option<String>,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    pub bot: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
 #[serde(rename_all = "lowercase")]
 pub enum UserStatus {
     Online,
     Idle,
     Dnd,
+    /// Connected but shown to everyone else as offline
+    Invisible,
     Offline,
 }
 
@@ -70,7 +183,7 @@ pub enum ChannelType {
     Announcement,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub id: Uuid,
     pub content: String,
@@ -81,11 +194,54 @@ pub struct Message {
     pub created_at: DateTime<Utc>,
     pub edited_at: Option<DateTime<Utc>>,
     pub attachments: Vec<Attachment>,
-    pub mentions: Vec<User>,
+    /// Link previews, filled in shortly after the message is sent
+    pub embeds: Vec<Embed>,
+    pub mentions: Vec<PublicUser>,
     pub reactions: Vec<Reaction>,
+    /// Set on messages posted through an incoming webhook, whose `author_id`
+    /// is the webhook's id rather than a user's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<WebhookAuthor>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookAuthor {
+    pub id: Uuid,
+    pub name: String,
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Embed {
+    #[serde(rename = "type")]
+    pub embed_type: EmbedType,
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    /// The page's theme-color, as the page wrote it
+    pub color: Option<String>,
+    pub image: Option<EmbedMedia>,
+    pub video: Option<EmbedMedia>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbedType {
+    Link,
+    Image,
+    Video,
+    Rich,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbedMedia {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Attachment {
     pub id: Uuid,
     #[serde(rename = "type")]
@@ -97,9 +253,21 @@ pub struct Attachment {
     pub width: Option<i32>,
     pub height: Option<i32>,
     pub duration: Option<i32>,
+    /// Placeholder clients can paint while the image loads
+    pub blurhash: Option<String>,
+    /// Smallest first. Empty until the background job has made them.
+    pub thumbnails: Vec<Thumbnail>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub url: String,
+    pub width: i32,
+    pub height: i32,
+    pub mime_type: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum AttachmentType {
     Image,
@@ -107,7 +275,7 @@ pub enum AttachmentType {
     File,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Reaction {
     pub emoji: String,
     pub count: i32,
@@ -115,12 +283,58 @@ pub struct Reaction {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+pub struct ServerEmoji {
+    pub id: Uuid,
+    pub server_id: Uuid,
+    pub name: String,
+    pub image: String,
+    pub animated: bool,
+    pub uploader_id: Option<Uuid>,
+    /// Roles allowed to use the emoji, empty means everyone
+    pub roles: Vec<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Error {
     pub code: String,
     pub message: String,
     pub details: Option<serde_json::Value>,
 }
 
+impl Error {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+/// Error half of route results that need to explain themselves to the client
+pub type ApiError = (Status, Json<Error>);
+
+/// Errors from executing a webhook. Rate limited calls also carry a
+/// `Retry-After` header, like the ones refused by the global limiter.
+#[derive(rocket::Responder)]
+pub enum WebhookExecuteError {
+    Api(ApiError),
+    #[response(status = 429)]
+    RateLimited(Json<Error>, Header<'static>),
+}
+
+impl From<ApiError> for WebhookExecuteError {
+    fn from(error: ApiError) -> Self {
+        WebhookExecuteError::Api(error)
+    }
+}
+
 // Request/Response structs
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
@@ -129,6 +343,133 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+/// A developer's registration for a bot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Application {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub bot: PublicUser,
+    /// Where interactions are posted. Without one they go to the bot's gateway connection.
+    pub interactions_url: Option<String>,
+    // Only shown along with a new bot token
+    #[serde(skip_serializing)]
+    pub interactions_secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Only returned when the token is made, it can't be looked up later
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApplicationWithToken {
+    #[serde(flatten)]
+    pub application: Application,
+    /// Sent as `Authorization: Bot <token>`
+    pub token: String,
+    /// Signs interactions posted to the application's interactions url
+    pub interactions_secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApplicationRequest {
+    pub name: String,
+    pub description: Option<String>,
+    pub bot_username: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateApplicationRequest {
+    pub name: Option<String>,
+    /// An empty string removes the description
+    pub description: Option<String>,
+    /// An empty string goes back to delivering interactions over the gateway
+    pub interactions_url: Option<String>,
+}
+
+/// What a user is asked to approve before adding a bot to a server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BotAuthorizationPreview {
+    pub application: Application,
+    pub permissions: Permissions,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthorizeBotRequest {
+    pub client_id: Uuid,
+    pub server_id: Uuid,
+    #[serde(default)]
+    pub permissions: Permissions,
+}
+
+/// A slash command registered by an application, globally or for one server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApplicationCommand {
+    pub id: Uuid,
+    pub application_id: Uuid,
+    pub server_id: Option<Uuid>,
+    pub name: String,
+    pub description: String,
+    pub options: Vec<CommandOption>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Registering a name that already exists in the same scope replaces that command
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApplicationCommandRequest {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub options: Vec<CommandOption>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InvokeCommandRequest {
+    pub command_id: Uuid,
+    #[serde(default)]
+    pub options: Vec<InteractionOption>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InteractionCommand {
+    pub id: Uuid,
+    pub name: String,
+    pub options: Vec<InteractionOption>,
+}
+
+/// What a bot receives when one of its commands is invoked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub id: Uuid,
+    pub application_id: Uuid,
+    /// Authorizes the response, until `expires_at`
+    pub token: String,
+    pub server_id: Uuid,
+    pub channel_id: Uuid,
+    pub user: PublicUser,
+    pub command: InteractionCommand,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Returned to the invoking user, the bot's answer arrives over the gateway
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InteractionReceipt {
+    pub id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A bot's reply only the invoking user sees
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EphemeralMessage {
+    pub interaction_id: Uuid,
+    pub channel_id: Uuid,
+    pub author: PublicUser,
+    pub content: String,
+    pub embeds: Vec<Embed>,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginResponse {
     pub token: String,
@@ -177,6 +518,9 @@ pub struct CreateMessageForm<'r> {
     pub content: String,
     pub reply_to_id: Option<String>,
     pub attachments: Vec<TempFile<'r>>,
+    /// Attachments uploaded ahead of time, through `/channels/<id>/attachments`
+    /// or a finalized resumable upload
+    pub attachment_ids: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -184,12 +528,187 @@ pub struct UpdateMessageRequest {
     pub content: String,
 }
 
+/// Empty text fields clear the value they refer to
 #[derive(Debug, FromForm)]
 pub struct UpdateUserForm<'r> {
     pub username: Option<String>,
+    pub display_name: Option<String>,
     pub avatar: Option<TempFile<'r>>,
+    pub banner: Option<TempFile<'r>>,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
     pub status: Option<UserStatus>,
     pub custom_status: Option<String>,
+    /// RFC 3339. Left out, an existing expiry is kept
+    pub custom_status_expires_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateNicknameRequest {
+    pub nickname: Option<String>,
+}
+
+#[derive(Debug, FromForm)]
+pub struct CreateEmojiForm<'r> {
+    pub name: String,
+    pub image: TempFile<'r>,
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateEmojiRequest {
+    pub name: Option<String>,
+    pub roles: Option<Vec<Uuid>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdatePresenceRequest {
+    pub status: UserStatus,
+    pub custom_status: Option<String>,
+    pub custom_status_expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FriendStatus {
+    Friends,
+    /// They asked, the user can accept
+    Incoming,
+    /// The user asked and is waiting on them
+    Outgoing,
+}
+
+/// An entry on the user's friends list
+#[derive(Debug, Serialize)]
+pub struct Friend {
+    pub user: PublicUser,
+    pub status: FriendStatus,
+    /// Only shared between accepted friends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence: Option<PresenceUpdate>,
+    pub since: DateTime<Utc>,
+}
+
+/// Lets tools without an account post into a channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub server_id: Uuid,
+    pub channel_id: Uuid,
+    pub name: String,
+    pub avatar: Option<String>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Only returned when the token is made, it can't be looked up later
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookWithToken {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub token: String,
+    /// Where to post messages, relative to the api root
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub name: String,
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateWebhookRequest {
+    pub name: Option<String>,
+    /// An empty string removes the avatar
+    pub avatar: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExecuteWebhookRequest {
+    pub content: Option<String>,
+    /// Overrides the webhook's name for this message
+    pub username: Option<String>,
+    /// Overrides the webhook's avatar for this message
+    pub avatar_url: Option<String>,
+    pub embeds: Vec<WebhookEmbed>,
+}
+
+/// Multipart form for webhook messages with files, `payload_json` holds
+/// an `ExecuteWebhookRequest`
+#[derive(Debug, FromForm)]
+pub struct ExecuteWebhookForm<'r> {
+    pub payload_json: Option<String>,
+    pub files: Vec<TempFile<'r>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BanRequest {
+    pub reason: Option<String>,
+}
+
+/// Payload of `member.ban` webhook events
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ban {
+    pub user_id: Uuid,
+    pub moderator_id: Uuid,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Posts server events to an external url
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingWebhook {
+    pub id: Uuid,
+    pub server_id: Uuid,
+    pub url: String,
+    // Only shown once, when the webhook is created
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    pub enabled: bool,
+    pub consecutive_failures: i32,
+    /// Why the server turned the webhook off, if it did
+    pub disabled_reason: Option<String>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedOutgoingWebhook {
+    #[serde(flatten)]
+    pub webhook: OutgoingWebhook,
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateOutgoingWebhookRequest {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    /// Generated when left out
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateOutgoingWebhookRequest {
+    pub url: Option<String>,
+    pub events: Option<Vec<WebhookEvent>>,
+    pub enabled: Option<bool>,
+}
+
+/// One attempt at delivering an event. Retries share the `delivery_id`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub delivery_id: Uuid,
+    pub event: WebhookEvent,
+    pub attempt: i32,
+    pub success: bool,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i32,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -206,8 +725,145 @@ pub struct CreateInviteRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InviteResponse {
     pub code: String,
+    pub server_id: Uuid,
+    pub inviter_id: Uuid,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What anyone holding a code can see about the server before joining
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InvitePreview {
+    pub code: String,
+    pub server_id: Uuid,
+    pub server_name: String,
+    pub server_icon: Option<String>,
+    pub server_description: Option<String>,
+    pub member_count: i64,
     pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Member {
+    pub user: PublicUser,
+    pub nickname: Option<String>,
+    pub joined_at: DateTime<Utc>,
+    /// The invite or vanity code the member joined with. Only shown to
+    /// members who can manage the server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invite_code: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteStats {
+    pub code: String,
+    /// None for vanity codes and revoked invites
+    pub inviter_id: Option<Uuid>,
+    /// Every join, including members who have left since
+    pub uses: i32,
     pub max_uses: Option<i32>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub members_remaining: i64,
+    /// Joins per day within the requested window, days without any are left out
+    pub joins: Vec<InviteJoinDay>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteJoinDay {
+    pub date: chrono::NaiveDate,
+    pub joins: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadState {
+    pub channel_id: Uuid,
+    /// The last message the user acknowledged, if any
+    pub last_message_id: Option<Uuid>,
+    pub unread: bool,
+    /// Messages from others since the ack
+    pub unread_count: i64,
+    pub mention_count: i64,
+    /// Muted by the user's notification settings, never shown as unread
+    pub muted: bool,
+}
+
+/// A read state with what's needed to check the user may still see it
+#[derive(Debug)]
+pub struct ChannelReadState {
+    pub server_id: Uuid,
+    pub is_private: bool,
+    pub state: ReadState,
+}
+
+/// Fields left out keep their current value
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateServerNotificationSettings {
+    pub level: Option<NotificationLevel>,
+    pub muted: Option<bool>,
+    /// Only read when muting, leave it out to mute until unmuted
+    pub muted_until: Option<DateTime<Utc>>,
+    pub suppress_everyone: Option<bool>,
+    pub suppress_roles: Option<bool>,
+}
+
+/// Fields left out keep their current value. `DELETE` the override to go
+/// back to the server's settings.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateChannelNotificationSettings {
+    pub level: Option<NotificationLevel>,
+    pub muted: Option<bool>,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushSubscription {
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    pub endpoint: String,
+    // Encryption keys only ever go to the push service
+    #[serde(skip_serializing)]
+    pub p256dh: String,
+    #[serde(skip_serializing)]
+    pub auth: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The shape of a browser's `PushSubscription.toJSON()`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatePushSubscriptionRequest {
+    pub endpoint: String,
+    pub keys: PushSubscriptionKeys,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PushSubscriptionKeys {
+    pub p256dh: String,
+    pub auth: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VapidKeyResponse {
+    pub public_key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchResponse {
+    /// Matches across all pages
+    pub total_results: i64,
+    pub messages: Vec<Message>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateVanityCodeRequest {
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VanityCodeResponse {
+    pub code: Option<String>,
     pub uses: i32,
 }
 
@@ -219,6 +875,7 @@ impl FromStr for UserStatus {
             "online" => Ok(UserStatus::Online),
             "idle" => Ok(UserStatus::Idle),
             "dnd" => Ok(UserStatus::Dnd),
+            "invisible" => Ok(UserStatus::Invisible),
             "offline" => Ok(UserStatus::Offline),
             _ => Err(format!("Invalid user status: {}", s)),
         }
@@ -231,11 +888,25 @@ impl std::fmt::Display for UserStatus {
             UserStatus::Online => write!(f, "online"),
             UserStatus::Idle => write!(f, "idle"),
             UserStatus::Dnd => write!(f, "dnd"),
+            UserStatus::Invisible => write!(f, "invisible"),
             UserStatus::Offline => write!(f, "offline"),
         }
     }
 }
 
+impl FromStr for ChannelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(ChannelType::Text),
+            "voice" => Ok(ChannelType::Voice),
+            "announcement" => Ok(ChannelType::Announcement),
+            _ => Err(format!("Invalid channel type: {}", s)),
+        }
+    }
+}
+
 impl std::fmt::Display for ChannelType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -246,6 +917,28 @@ impl std::fmt::Display for ChannelType {
     }
 }
 
+impl FromStr for AttachmentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "image" => Ok(AttachmentType::Image),
+            "video" => Ok(AttachmentType::Video),
+            "file" => Ok(AttachmentType::File),
+            _ => Err(format!("Invalid attachment type: {}", s)),
+        }
+    }
+}
+
+/// Where clients download an attachment from
+pub fn attachment_url(attachment_id: Uuid, filename: &str) -> String {
+    format!("/attachments/{attachment_id}/{filename}")
+}
+
+pub fn thumbnail_url(attachment_id: Uuid, width: i32) -> String {
+    format!("/attachments/{attachment_id}/thumbnails/{width}")
+}
+
 impl std::fmt::Display for AttachmentType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -259,16 +952,120 @@ impl std::fmt::Display for AttachmentType {
 
 
 // Auth Routes
+/// Failed attempts slow down and eventually lock out the account and the ip
+/// they came from, on top of the auth rate limit
 #[post("/auth/login", format = "json", data = "<login>")]
-async fn login(login: Json<LoginRequest>) -> Result<Json<LoginResponse>, Status> {
+async fn login(
+    db: &State<Pool<MySql>>,
+    config: &State<ServerConfig>,
+    mailer: &State<Mailer>,
+    login_locks: &State<LoginLocks>,
+    client: ClientIp,
+    login: Json<LoginRequest>,
+) -> Result<Json<LoginResponse>, ApiError> {
     info!("Login attempt for user: {:?}", login.username.as_ref().or(login.email.as_ref()));
-    Err(Status::NotImplemented)
+    let login = login.into_inner();
+    let identifier = login
+        .username
+        .or(login.email)
+        .map(|identifier| identifier.trim().to_string())
+        .filter(|identifier| !identifier.is_empty())
+        .ok_or_else(|| api_error(Status::BadRequest, "INVALID_LOGIN", "A username or email is required"))?;
+    let protection = &config.login_protection;
+    let account = queries::get_login_account(db, &identifier).await.map_err(db_error)?;
+    let user_id = account.as_ref().map(|account| account.id);
+    let ip = client.0.to_string();
+
+    let _lock = login_locks.acquire(user_id, client.0).await;
+    if let Some(refusal) = auth::check_login(db, protection, user_id, client.0).await.map_err(db_error)? {
+        let outcome = match refusal {
+            LoginRefusal::Throttled { .. } => LoginOutcome::Throttled,
+            LoginRefusal::Locked { .. } => LoginOutcome::Locked,
+        };
+        queries::record_login_attempt(db, user_id, Some(&identifier), Some(&ip), outcome)
+            .await
+            .map_err(db_error)?;
+        return Err(login_refused(refusal));
+    }
+
+    let password_hash = account.as_ref().and_then(|account| account.password_hash.clone());
+    let verified = tokio::task::spawn_blocking(move || auth::verify_password(password_hash.as_deref(), &login.password))
+        .await
+        .unwrap_or(false);
+    if !verified {
+        let locked_until = auth::record_failed_login(db, protection, user_id, &identifier, client.0)
+            .await
+            .map_err(db_error)?;
+        if let (Some(until), Some(email)) = (locked_until, account.as_ref().and_then(|account| account.email.as_deref())) {
+            warn!("Locked account {} after repeated failed logins from {ip}", user_id.unwrap_or_default());
+            mailer.send(email, "Your account was locked", lockout_email(until, &ip));
+        }
+        return Err(api_error(Status::Unauthorized, "INVALID_CREDENTIALS", "Invalid username or password"));
+    }
+
+    // Only a known account's password verifies
+    let user_id = user_id.unwrap_or_default();
+    queries::record_login_attempt(db, Some(user_id), Some(&identifier), Some(&ip), LoginOutcome::Success)
+        .await
+        .map_err(db_error)?;
+    let token = generate_token();
+    let expires_at = Utc::now() + chrono::Duration::days(SESSION_LIFETIME_DAYS);
+    queries::create_session(db, user_id, &hash_token(&token), expires_at)
+        .await
+        .map_err(db_error)?;
+    let user = queries::get_user(db, user_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))?;
+    Ok(Json(LoginResponse { token, user }))
 }
 
 #[post("/auth/register", format = "json", data = "<registration>")]
-async fn register(registration: Json<RegisterRequest>) -> Result<Json<User>, Status> {
+async fn register(db: &State<Pool<MySql>>, registration: Json<RegisterRequest>) -> Result<Json<User>, ApiError> {
     info!("Registration attempt for user: {}", registration.username);
-    Err(Status::NotImplemented)
+    let registration = registration.into_inner();
+    let username = registration.username.trim();
+    if !is_valid_username(username) {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_USERNAME",
+            "Usernames must be 2-32 characters of letters, numbers, underscores and periods",
+        ));
+    }
+    let email = registration.email.trim();
+    if email.len() > 255 || !email.split_once('@').is_some_and(|(name, domain)| !name.is_empty() && !domain.is_empty()) {
+        return Err(api_error(Status::BadRequest, "INVALID_EMAIL", "That doesn't look like an email address"));
+    }
+    let length = registration.password.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_PASSWORD",
+            format!("Passwords must be {MIN_PASSWORD_LENGTH}-{MAX_PASSWORD_LENGTH} characters"),
+        ));
+    }
+
+    let password = registration.password;
+    let password_hash = match tokio::task::spawn_blocking(move || auth::hash_password(&password)).await {
+        Ok(Ok(hash)) => hash,
+        failed => {
+            error!("Failed to hash a password: {failed:?}");
+            return Err(api_error(Status::InternalServerError, "INTERNAL_SERVER_ERROR", "An internal server error occurred"));
+        }
+    };
+    let user_id = queries::register_user(db, username, email, &password_hash)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db_err) if db_err.is_unique_violation() => {
+                api_error(Status::Conflict, "ACCOUNT_EXISTS", "That username or email is already registered")
+            }
+            _ => db_error(e),
+        })?;
+    let user = queries::get_user(db, user_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))?;
+    Ok(Json(user))
 }
 
 // Server Routes
@@ -345,38 +1142,520 @@ async fn get_messages(
     Err(Status::NotImplemented)
 }
 
-#[post("/servers/<server_id>/channels/<channel_id>/messages", data = "<form>")]
-async fn create_message(user: AuthenticatedUser, channel_id: String, server_id: String, form: Form<CreateMessageForm<'_>>) -> Result<Status, String> {
+#[post("/channels/<channel_id>/messages", data = "<form>")]
+async fn create_message(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    config: &State<ServerConfig>,
+    gateway: &State<Gateway>,
+    typing: &State<TypingTracker>,
+    thumbnails: &State<ThumbnailQueue>,
+    signer: &State<UrlSigner>,
+    unfurl: &State<UnfurlQueue>,
+    search: &State<Search>,
+    push: &State<PushQueue>,
+    webhooks: &State<WebhookQueue>,
+    channel_id: String,
+    form: Form<CreateMessageForm<'_>>,
+) -> Result<Json<Message>, ApiError> {
     info!("Creating message in channel: {}", channel_id);
-    info!("Message contents: {}", form.content);
-    user.user_id;
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let form = form.into_inner();
 
+    let content = message_content(&form.content)?;
+    if form.attachments.len() + form.attachment_ids.len() > MAX_ATTACHMENTS_PER_UPLOAD {
+        return Err(api_error(
+            Status::BadRequest,
+            "TOO_MANY_ATTACHMENTS",
+            format!("A message can have at most {MAX_ATTACHMENTS_PER_UPLOAD} attachments"),
+        ));
+    }
+    if content.is_empty() && form.attachments.is_empty() && form.attachment_ids.is_empty() {
+        return Err(api_error(Status::BadRequest, "EMPTY_MESSAGE", "A message needs content or attachments"));
+    }
+    let reply_to_id = match form.reply_to_id.as_deref().filter(|id| !id.is_empty()) {
+        Some(id) => Some(require_message_in_channel(db, id, channel.id).await?),
+        None => None,
+    };
 
+    // Attachments uploaded ahead of time must be the sender's own, for this
+    // channel, and not already on another message
+    let mut attachment_ids = Vec::with_capacity(form.attachment_ids.len() + form.attachments.len());
+    for id in &form.attachment_ids {
+        let attachment_id = parse_id(id, "attachment")?;
+        let usable = queries::get_attachment(db, attachment_id)
+            .await
+            .map_err(db_error)?
+            .is_some_and(|stored| {
+                stored.uploader_id == Some(user.user_id) && stored.channel_id == channel.id && stored.message_id.is_none()
+            });
+        if !usable {
+            return Err(api_error(
+                Status::BadRequest,
+                "INVALID_ATTACHMENT_ID",
+                format!("Attachment {attachment_id} can't be used in this message"),
+            ));
+        }
+        attachment_ids.push(attachment_id);
+    }
+    if !form.attachments.is_empty() {
+        let uploaded =
+            store_attachments(db, storage, config, thumbnails, signer, &channel, Some(user.user_id), &form.attachments).await?;
+        attachment_ids.extend(uploaded.iter().map(|attachment| attachment.id));
+    }
 
+    let message_id = match queries::create_message(db, channel.id, user.user_id, &content, reply_to_id, &attachment_ids).await {
+        Ok(id) => id,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(api_error(
+                Status::BadRequest,
+                "INVALID_ATTACHMENT_ID",
+                "An attachment was used by another message in the meantime",
+            ))
+        }
+        Err(e) => return Err(db_error(e)),
+    };
+    // Your own message never makes a channel unread
+    queries::ack_message(db, user.user_id, channel.id, message_id)
+        .await
+        .map_err(db_error)?;
+    // Sending a message ends the sender's typing indicator
+    typing.clear(channel.id, user.user_id);
+    let message = after_message_created(db, gateway, signer, unfurl, search, push, webhooks, &channel, message_id).await?;
+    Ok(Json(message))
+}
 
-    Err(format!("{}",form.content))
-#[post("/server/<server_id>/channels/<channel_id>/messages", data = "<form>")]
-async fn create_message(channel_id: String, form: Form<CreateMessageForm<'_>>) -> Result<Json<Message>, Status> {
-    form.unwrap().reply_to_id;
-    info!("Creating message in channel: {}", channel_id);
-    
-    Err(Status::NotImplemented)
+/// Where clients sent messages before channels were addressed on their own.
+/// Kept so they keep working; the channel has to be in that server.
+#[post("/servers/<server_id>/channels/<channel_id>/messages", data = "<form>")]
+async fn create_server_channel_message(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    config: &State<ServerConfig>,
+    gateway: &State<Gateway>,
+    typing: &State<TypingTracker>,
+    thumbnails: &State<ThumbnailQueue>,
+    signer: &State<UrlSigner>,
+    unfurl: &State<UnfurlQueue>,
+    search: &State<Search>,
+    push: &State<PushQueue>,
+    webhooks: &State<WebhookQueue>,
+    server_id: String,
+    channel_id: String,
+    form: Form<CreateMessageForm<'_>>,
+) -> Result<Json<Message>, ApiError> {
+    let server_id = parse_id(&server_id, "server")?;
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    if channel.server_id != server_id {
+        return Err(api_error(Status::NotFound, "UNKNOWN_CHANNEL", "Channel not found"));
+    }
+    create_message(
+        user, db, storage, config, gateway, typing, thumbnails, signer, unfurl, search, push, webhooks, channel_id, form,
+    )
+    .await
 }
 
 #[patch("/channels/<channel_id>/messages/<message_id>", format = "json", data = "<message>")]
 async fn update_message(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    signer: &State<UrlSigner>,
+    unfurl: &State<UnfurlQueue>,
+    search: &State<Search>,
+    webhooks: &State<WebhookQueue>,
     channel_id: String,
     message_id: String,
     message: Json<UpdateMessageRequest>,
-) -> Result<Json<Message>, Status> {
+) -> Result<Json<Message>, ApiError> {
     info!("Updating message {} in channel {}", message_id, channel_id);
-    Err(Status::NotImplemented)
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let message_id = require_message_in_channel(db, &message_id, channel.id).await?;
+    let existing = queries::get_message(db, message_id, None)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_MESSAGE", "Message not found"))?;
+    if existing.author_id != user.user_id {
+        return Err(api_error(Status::Forbidden, "NOT_MESSAGE_AUTHOR", "Only the author can edit a message"));
+    }
+
+    let content = message_content(&message.content)?;
+    if content.is_empty() && existing.attachments.is_empty() {
+        return Err(api_error(Status::BadRequest, "EMPTY_MESSAGE", "A message needs content or attachments"));
+    }
+    queries::edit_message(db, message_id, &content)
+        .await
+        .map_err(db_error)?;
+    queries::set_message_mentions(db, message_id, &MessageMentions::parse(&content))
+        .await
+        .map_err(db_error)?;
+
+    let mut updated = queries::get_message(db, message_id, None)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_MESSAGE", "Message not found"))?;
+    if let Err(e) = search.index_message(&updated).await {
+        warn!("Failed to index message {}: {e:#}", updated.id);
+    }
+    signer.sign_message(&mut updated);
+    let recipients = channel_audience(db, gateway, &channel).await?;
+    gateway.dispatch(recipients, GatewayEvent::MessageUpdate(updated.clone()));
+    unfurl.enqueue(updated.id, &updated.content);
+    webhooks.emit(channel.server_id, WebhookEvent::MessageUpdate, channel.is_private, &updated);
+    Ok(Json(updated))
 }
 
 #[delete("/channels/<channel_id>/messages/<message_id>")]
-async fn delete_message(channel_id: String, message_id: String) -> Status {
+async fn delete_message(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    search: &State<Search>,
+    webhooks: &State<WebhookQueue>,
+    channel_id: String,
+    message_id: String,
+) -> Result<Status, ApiError> {
     info!("Deleting message {} from channel {}", message_id, channel_id);
-    Status::NoContent
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let message_id = require_message_in_channel(db, &message_id, channel.id).await?;
+    let message = queries::get_message(db, message_id, None)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_MESSAGE", "Message not found"))?;
+    // Authors can always delete their own messages
+    if message.author_id != user.user_id {
+        require_permission(db, user.user_id, channel.server_id, Permissions::MANAGE_MESSAGES).await?;
+    }
+
+    queries::delete_message(db, message_id).await.map_err(db_error)?;
+    if let Err(e) = search.remove_message(message_id).await {
+        warn!("Failed to remove message {} from the search index: {e:#}", message_id);
+    }
+    let recipients = channel_audience(db, gateway, &channel).await?;
+    gateway.dispatch(recipients, GatewayEvent::MessageDelete { id: message_id, channel_id: channel.id });
+    webhooks.emit(
+        channel.server_id,
+        WebhookEvent::MessageDelete,
+        channel.is_private,
+        &serde_json::json!({ "id": message_id, "channel_id": channel.id }),
+    );
+    Ok(Status::NoContent)
+}
+
+#[get("/servers/<server_id>/messages/search?<q>&<offset>&<limit>")]
+async fn search_messages(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    search: &State<Search>,
+    signer: &State<UrlSigner>,
+    server_id: String,
+    q: String,
+    offset: Option<i64>,
+    limit: Option<i64>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    info!("Searching messages in server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    let invalid_query = |message: String| api_error(Status::BadRequest, "INVALID_SEARCH_QUERY", message);
+    let query = SearchQuery::parse(&q).map_err(invalid_query)?;
+    if query.is_empty() {
+        return Err(invalid_query("Search for some text or add a filter".to_string()));
+    }
+
+    // The search is scoped to what the user can read, nothing else is ever looked at
+    let permissions = queries::get_member_permissions(db, server_id, user.user_id)
+        .await
+        .map_err(db_error)?
+        .unwrap_or_default();
+    let readable = queries::get_server_channel_names(db, server_id, permissions.contains(Permissions::VIEW_PRIVATE_CHANNELS))
+        .await
+        .map_err(db_error)?;
+    let mut channel_ids: Vec<Uuid> = readable.iter().map(|(id, _)| *id).collect();
+    if !query.channels.is_empty() {
+        let mut wanted = Vec::new();
+        for channel in &query.channels {
+            let found = readable
+                .iter()
+                .find(|(id, name)| id.to_string() == *channel || name.eq_ignore_ascii_case(channel))
+                .ok_or_else(|| invalid_query(format!("Unknown channel {channel}")))?;
+            wanted.push(found.0);
+        }
+        channel_ids = wanted;
+    }
+
+    let mut author_ids = Vec::with_capacity(query.from.len());
+    for name in &query.from {
+        author_ids.push(resolve_search_user(db, server_id, name).await?);
+    }
+    let mut mention_ids = Vec::with_capacity(query.mentions.len());
+    for name in &query.mentions {
+        mention_ids.push(resolve_search_user(db, server_id, name).await?);
+    }
+
+    let filters = SearchFilters {
+        text: query.text,
+        channel_ids,
+        author_ids,
+        mention_ids,
+        has: query.has,
+        before: query.before,
+        after: query.after,
+        pinned: query.pinned,
+        offset: offset.unwrap_or(0).clamp(0, MAX_SEARCH_OFFSET),
+        limit: limit.unwrap_or(25).clamp(1, MAX_SEARCH_PAGE),
+    };
+    let results = search.search(&filters).await.map_err(|e| {
+        error!("Search failed: {e:#}");
+        api_error(Status::InternalServerError, "INTERNAL_SERVER_ERROR", "An internal server error occurred")
+    })?;
+
+    let mut messages = Vec::with_capacity(results.message_ids.len());
+    for message_id in results.message_ids {
+        if let Some(mut message) = queries::get_message(db, message_id, Some(user.user_id)).await.map_err(db_error)? {
+            signer.sign_message(&mut message);
+            messages.push(message);
+        }
+    }
+    Ok(Json(SearchResponse {
+        total_results: results.total,
+        messages,
+    }))
+}
+
+#[post("/channels/<channel_id>/messages/<message_id>/ack")]
+async fn ack_message(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    channel_id: String,
+    message_id: String,
+) -> Result<Status, ApiError> {
+    info!("Acknowledging message {} in channel {}", message_id, channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let message_id = require_message_in_channel(db, &message_id, channel.id).await?;
+
+    queries::ack_message(db, user.user_id, channel.id, message_id)
+        .await
+        .map_err(db_error)?;
+    // Keeps the user's other sessions in sync
+    gateway.dispatch(vec![user.user_id], GatewayEvent::MessageAck { channel_id: channel.id, message_id });
+    Ok(Status::NoContent)
+}
+
+#[post("/servers/<server_id>/ack")]
+async fn ack_server(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    server_id: String,
+) -> Result<Status, ApiError> {
+    info!("Marking server {} as read", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    let permissions = queries::get_member_permissions(db, server_id, user.user_id)
+        .await
+        .map_err(db_error)?
+        .unwrap_or_default();
+
+    let acked = queries::ack_server(db, user.user_id, server_id, permissions.contains(Permissions::VIEW_PRIVATE_CHANNELS))
+        .await
+        .map_err(db_error)?;
+    for (channel_id, message_id) in acked {
+        gateway.dispatch(vec![user.user_id], GatewayEvent::MessageAck { channel_id, message_id });
+    }
+    Ok(Status::NoContent)
+}
+
+#[get("/users/@me/read-states")]
+async fn get_read_states(user: AuthenticatedUser, db: &State<Pool<MySql>>) -> Result<Json<Vec<ReadState>>, ApiError> {
+    info!("Fetching read states for user: {}", user.user_id);
+    Ok(Json(visible_read_states(db, user.user_id).await?))
+}
+
+// Notification Settings Routes
+#[get("/users/@me/notification-settings")]
+async fn get_notification_settings(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+) -> Result<Json<Vec<ServerNotificationSettings>>, ApiError> {
+    info!("Fetching notification settings for user: {}", user.user_id);
+    let settings = queries::get_notification_settings(db, user.user_id)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(settings))
+}
+
+#[patch("/users/@me/notification-settings/servers/<server_id>", format = "json", data = "<update>")]
+async fn update_server_notification_settings(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    server_id: String,
+    update: Json<UpdateServerNotificationSettings>,
+) -> Result<Json<ServerNotificationSettings>, ApiError> {
+    info!("Updating notification settings for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    let update = update.into_inner();
+    let mut settings = queries::get_server_notification_settings(db, user.user_id, server_id)
+        .await
+        .map_err(db_error)?;
+
+    if let Some(level) = update.level {
+        settings.level = level;
+    }
+    if let Some(muted) = update.muted {
+        (settings.muted, settings.muted_until) = mute_state(muted, update.muted_until)?;
+    }
+    if let Some(suppress) = update.suppress_everyone {
+        settings.suppress_everyone = suppress;
+    }
+    if let Some(suppress) = update.suppress_roles {
+        settings.suppress_roles = suppress;
+    }
+
+    queries::set_server_notification_settings(db, user.user_id, &settings)
+        .await
+        .map_err(db_error)?;
+    gateway.dispatch(vec![user.user_id], GatewayEvent::NotificationSettingsUpdate(settings.clone()));
+    Ok(Json(settings))
+}
+
+#[patch("/users/@me/notification-settings/channels/<channel_id>", format = "json", data = "<update>")]
+async fn update_channel_notification_settings(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    channel_id: String,
+    update: Json<UpdateChannelNotificationSettings>,
+) -> Result<Json<ServerNotificationSettings>, ApiError> {
+    info!("Updating notification settings for channel: {}", channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let update = update.into_inner();
+    let settings = queries::get_server_notification_settings(db, user.user_id, channel.server_id)
+        .await
+        .map_err(db_error)?;
+    let mut channel_override = settings
+        .channel_overrides
+        .iter()
+        .find(|o| o.channel_id == channel.id)
+        .cloned()
+        .unwrap_or(ChannelNotificationOverride {
+            channel_id: channel.id,
+            level: None,
+            muted: false,
+            muted_until: None,
+        });
+
+    if update.level.is_some() {
+        channel_override.level = update.level;
+    }
+    if let Some(muted) = update.muted {
+        (channel_override.muted, channel_override.muted_until) = mute_state(muted, update.muted_until)?;
+    }
+
+    queries::set_channel_notification_override(db, user.user_id, &channel_override)
+        .await
+        .map_err(db_error)?;
+    notification_settings_changed(db, gateway, user.user_id, channel.server_id).await
+}
+
+#[delete("/users/@me/notification-settings/channels/<channel_id>")]
+async fn reset_channel_notification_settings(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    channel_id: String,
+) -> Result<Json<ServerNotificationSettings>, ApiError> {
+    info!("Resetting notification settings for channel: {}", channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    queries::delete_channel_notification_override(db, user.user_id, channel.id)
+        .await
+        .map_err(db_error)?;
+    notification_settings_changed(db, gateway, user.user_id, channel.server_id).await
+}
+
+// Push Routes
+/// The key browsers need to subscribe, as `applicationServerKey`
+#[get("/push/vapid-public-key")]
+async fn get_vapid_public_key(
+    config: &State<ServerConfig>,
+    push: &State<PushQueue>,
+) -> Result<Json<VapidKeyResponse>, ApiError> {
+    info!("Fetching VAPID public key");
+    match (&config.push.vapid, push.pusher()) {
+        (Some(keys), Some(_)) => Ok(Json(VapidKeyResponse {
+            public_key: keys.public_key.clone(),
+        })),
+        _ => Err(push_disabled()),
+    }
+}
+
+#[post("/users/@me/push-subscriptions", format = "json", data = "<subscription>")]
+async fn create_push_subscription(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    push: &State<PushQueue>,
+    subscription: Json<CreatePushSubscriptionRequest>,
+) -> Result<Json<PushSubscription>, ApiError> {
+    info!("Registering push subscription for user: {}", user.user_id);
+    let pusher = push.pusher().ok_or_else(push_disabled)?;
+    let subscription = subscription.into_inner();
+    let invalid = |message: String| api_error(Status::BadRequest, "INVALID_PUSH_SUBSCRIPTION", message);
+    if subscription.endpoint.len() > MAX_PUSH_ENDPOINT_LENGTH {
+        return Err(invalid("Endpoint is too long".to_string()));
+    }
+    pusher.check_endpoint(&subscription.endpoint).map_err(invalid)?;
+    // Both are base64url: a P-256 point and a 16 byte secret
+    let keys = subscription.keys;
+    if !is_base64url(&keys.p256dh, 87) || !is_base64url(&keys.auth, 22) {
+        return Err(invalid("Malformed subscription keys".to_string()));
+    }
+
+    let id = queries::upsert_push_subscription(
+        db,
+        user.user_id,
+        &subscription.endpoint,
+        &keys.p256dh,
+        &keys.auth,
+    )
+    .await
+    .map_err(db_error)?;
+    queries::get_push_subscriptions(db, user.user_id)
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .find(|subscription| subscription.id == id)
+        .map(Json)
+        .ok_or_else(unknown_push_subscription)
+}
+
+#[get("/users/@me/push-subscriptions")]
+async fn get_push_subscriptions(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+) -> Result<Json<Vec<PushSubscription>>, ApiError> {
+    info!("Listing push subscriptions for user: {}", user.user_id);
+    let subscriptions = queries::get_push_subscriptions(db, user.user_id)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(subscriptions))
+}
+
+#[delete("/users/@me/push-subscriptions/<subscription_id>")]
+async fn delete_push_subscription(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    subscription_id: String,
+) -> Result<Status, ApiError> {
+    info!("Removing push subscription: {}", subscription_id);
+    let subscription_id = parse_id(&subscription_id, "push subscription")?;
+    if !queries::delete_push_subscription_for(db, subscription_id, user.user_id)
+        .await
+        .map_err(db_error)?
+    {
+        return Err(unknown_push_subscription());
+    }
+    Ok(Status::NoContent)
 }
 
 // Pin Routes
@@ -394,89 +1673,2274 @@ async fn unpin_message(channel_id: String, message_id: String) -> Status {
 
 // Typing Indicator Route
 #[post("/channels/<channel_id>/typing")]
-async fn send_typing_indicator(channel_id: String) -> Status {
+async fn send_typing_indicator(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    typing: &State<TypingTracker>,
+    channel_id: String,
+) -> Result<Status, ApiError> {
     info!("Sending typing indicator in channel: {}", channel_id);
-    Status::NoContent
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+
+    match typing.record(channel.id, user.user_id) {
+        TypingOutcome::Broadcast => {
+            let mut recipients = channel_audience(db, gateway, &channel).await?;
+            recipients.retain(|id| *id != user.user_id);
+            gateway.dispatch(
+                recipients,
+                GatewayEvent::TypingStart {
+                    channel_id: channel.id,
+                    user_id: user.user_id,
+                    timestamp: Utc::now(),
+                    expires_in: TYPING_TIMEOUT.as_secs(),
+                },
+            );
+            Ok(Status::NoContent)
+        }
+        TypingOutcome::Refreshed => Ok(Status::NoContent),
+        TypingOutcome::RateLimited { retry_after } => Err((
+            Status::TooManyRequests,
+            Json(
+                Error::new("RATE_LIMITED", "You are sending typing indicators too quickly")
+                    .with_details(serde_json::json!({ "retry_after": retry_after.as_secs_f64() })),
+            ),
+        )),
+    }
+}
+
+// Gateway Route
+#[get("/gateway")]
+async fn gateway_stream(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    presence: &State<PresenceTracker>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], ApiError> {
+    info!("Opening gateway stream for user: {}", user.user_id);
+    let (preference, custom_status) = queries::get_presence_settings(db, user.user_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))?;
+
+    // Sent first so the client can draw unread badges straight away
+    let ready = GatewayEvent::Ready {
+        user_id: user.user_id,
+        read_states: visible_read_states(db, user.user_id).await?,
+    };
+
+    let mut events = gateway.subscribe();
+    let connection = gateway.connect(user.user_id);
+    presence.connected(user.user_id, preference, custom_status);
+    if let Some(update) = presence.refresh(user.user_id, gateway) {
+        publish_presence(db, gateway, update).await;
+    }
+
+    Ok(EventStream! {
+        // Held for the life of the stream so the user counts as connected
+        let _connection = connection;
+        yield Event::json(&ready).event(ready.name());
+        loop {
+            let dispatch = select! {
+                dispatch = events.recv() => match dispatch {
+                    Ok(dispatch) => dispatch,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+            if dispatch.recipients.contains(&user.user_id) {
+                yield Event::json(&dispatch.event).event(dispatch.event.name());
+            }
+        }
+    })
 }
 
 // Reaction Routes
 #[put("/channels/<channel_id>/reactions/<message_id>/<emoji>")]
-async fn add_reaction(channel_id: String, message_id: String, emoji: String) -> Status {
+async fn add_reaction(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    channel_id: String,
+    message_id: String,
+    emoji: String,
+) -> Result<Status, ApiError> {
     info!("Adding reaction {} to message {} in channel {}", emoji, message_id, channel_id);
-    Status::NoContent
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let message_id = require_message_in_channel(db, &message_id, channel.id).await?;
+    let emoji = resolve_reaction_emoji(db, channel.server_id, user.user_id, &emoji).await?;
+
+    let added = queries::add_reaction(db, message_id, user.user_id, &emoji.key(), MAX_REACTIONS_PER_MESSAGE)
+        .await
+        .map_err(db_error)?;
+    if !added {
+        return Err(api_error(
+            Status::BadRequest,
+            "TOO_MANY_REACTIONS",
+            format!("Messages can have at most {MAX_REACTIONS_PER_MESSAGE} different reactions"),
+        ));
+    }
+    let recipients = channel_audience(db, gateway, &channel).await?;
+    gateway.dispatch(
+        recipients,
+        GatewayEvent::ReactionAdd {
+            channel_id: channel.id,
+            message_id,
+            user_id: user.user_id,
+            emoji: emoji.to_string(),
+        },
+    );
+    Ok(Status::NoContent)
 }
 
 #[delete("/channels/<channel_id>/reactions/<message_id>/<emoji>")]
-async fn remove_reaction(channel_id: String, message_id: String, emoji: String) -> Status {
+async fn remove_reaction(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    channel_id: String,
+    message_id: String,
+    emoji: String,
+) -> Result<Status, ApiError> {
     info!("Removing reaction {} from message {} in channel {}", emoji, message_id, channel_id);
-    Status::NoContent
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let message_id = require_message_in_channel(db, &message_id, channel.id).await?;
+    let emoji = parse_reaction_emoji(&emoji)?;
+
+    let removed = queries::remove_reaction(db, message_id, user.user_id, &emoji.key())
+        .await
+        .map_err(db_error)?;
+    if removed {
+        let recipients = channel_audience(db, gateway, &channel).await?;
+        gateway.dispatch(
+            recipients,
+            GatewayEvent::ReactionRemove {
+                channel_id: channel.id,
+                message_id,
+                user_id: user.user_id,
+                emoji: emoji.to_string(),
+            },
+        );
+    }
+    Ok(Status::NoContent)
 }
 
-// User Routes
-#[get("/users/@me")]
-async fn get_current_user() -> Result<Json<User>, Status> {
-    info!("Fetching current user profile");
-    Err(Status::NotImplemented)
+#[get("/channels/<channel_id>/reactions/<message_id>/<emoji>?<after>&<limit>")]
+async fn get_reaction_users(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    channel_id: String,
+    message_id: String,
+    emoji: String,
+    after: Option<String>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<PublicUser>>, ApiError> {
+    info!("Fetching users who reacted {} to message {} in channel {}", emoji, message_id, channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let message_id = require_message_in_channel(db, &message_id, channel.id).await?;
+    let emoji = parse_reaction_emoji(&emoji)?;
+    let after = after.as_deref().map(|id| parse_id(id, "user")).transpose()?;
+    let limit = limit.unwrap_or(25).clamp(1, MAX_REACTION_USERS_PAGE);
+
+    let users = queries::get_reaction_users(db, message_id, &emoji.key(), limit, after)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(users))
 }
 
-#[patch("/users/@me", data = "<form>")]
-async fn update_current_user(form: Form<UpdateUserForm<'_>>) -> Result<Json<User>, Status> {
-    info!("Updating current user profile");
-    Err(Status::NotImplemented)
+#[delete("/channels/<channel_id>/reactions/<message_id>")]
+async fn clear_reactions(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    channel_id: String,
+    message_id: String,
+) -> Result<Status, ApiError> {
+    info!("Clearing all reactions from message {} in channel {}", message_id, channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    require_permission(db, user.user_id, channel.server_id, Permissions::MANAGE_MESSAGES).await?;
+    let message_id = require_message_in_channel(db, &message_id, channel.id).await?;
+
+    if queries::clear_reactions(db, message_id).await.map_err(db_error)? > 0 {
+        let recipients = channel_audience(db, gateway, &channel).await?;
+        gateway.dispatch(
+            recipients,
+            GatewayEvent::ReactionClear {
+                channel_id: channel.id,
+                message_id,
+                emoji: None,
+            },
+        );
+    }
+    Ok(Status::NoContent)
 }
 
-#[get("/users/<user_id>")]
-async fn get_user(user_id: String) -> Result<Json<User>, Status> {
-    info!("Fetching user profile: {}", user_id);
-    Err(Status::NotImplemented)
+#[delete("/channels/<channel_id>/reactions/<message_id>/<emoji>/all")]
+async fn clear_reaction_emoji(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    channel_id: String,
+    message_id: String,
+    emoji: String,
+) -> Result<Status, ApiError> {
+    info!("Clearing reaction {} from message {} in channel {}", emoji, message_id, channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    require_permission(db, user.user_id, channel.server_id, Permissions::MANAGE_MESSAGES).await?;
+    let message_id = require_message_in_channel(db, &message_id, channel.id).await?;
+    let emoji = parse_reaction_emoji(&emoji)?;
+
+    let cleared = queries::clear_reaction_emoji(db, message_id, &emoji.key())
+        .await
+        .map_err(db_error)?;
+    if cleared > 0 {
+        let recipients = channel_audience(db, gateway, &channel).await?;
+        gateway.dispatch(
+            recipients,
+            GatewayEvent::ReactionClear {
+                channel_id: channel.id,
+                message_id,
+                emoji: Some(emoji.to_string()),
+            },
+        );
+    }
+    Ok(Status::NoContent)
 }
 
-// Server Join/Invite Routes
-#[post("/users/@me/servers", format = "json", data = "<join_request>")]
-async fn join_server(join_request: Json<JoinServerRequest>) -> Result<Json<Server>, Status> {
-    info!("Joining server with invite code: {}", join_request.invite_code);
-    Err(Status::NotImplemented)
+// Emoji Routes
+#[get("/servers/<server_id>/emojis")]
+async fn get_server_emojis(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+) -> Result<Json<Vec<ServerEmoji>>, ApiError> {
+    info!("Fetching emojis for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    let emoji = queries::list_server_emoji(db, server_id).await.map_err(db_error)?;
+    Ok(Json(emoji))
 }
 
-#[post("/servers/<server_id>/invites", format = "json", data = "<invite_request>")]
-async fn create_invite(
+#[post("/servers/<server_id>/emojis", data = "<form>")]
+async fn create_server_emoji(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
     server_id: String,
-    invite_request: Json<CreateInviteRequest>,
-) -> Result<Json<InviteResponse>, Status> {
-    info!("Creating invite for server: {}", server_id);
-    Err(Status::NotImplemented)
+    form: Form<CreateEmojiForm<'_>>,
+) -> Result<Json<ServerEmoji>, ApiError> {
+    info!("Creating emoji {} in server {}", form.name, server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_EMOJIS).await?;
+
+    let form = form.into_inner();
+    if !is_valid_emoji_name(&form.name) {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_EMOJI_NAME",
+            "Emoji names must be 2-32 characters of letters, numbers and underscores",
+        ));
+    }
+    let roles = form
+        .roles
+        .iter()
+        .map(|id| parse_id(id, "role"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let inspected = validate_file((&form.image).into(), MAX_EMOJI_SIZE)
+        .await
+        .map_err(|e| api_error(Status::BadRequest, "INVALID_EMOJI_IMAGE", e))?;
+    let image = inspect_emoji_image(&form.image)
+        .await
+        .map_err(|e| api_error(Status::BadRequest, "INVALID_EMOJI_IMAGE", e))?;
+    let url = save_file(storage, (&form.image).into(), &inspected, "emojis")
+        .await
+        .map(|stored| public_file_url(&stored.key))
+        .map_err(|status| api_error(status, "UPLOAD_FAILED", "Failed to store the emoji image"))?;
+
+    let emoji_id = queries::create_server_emoji(
+        db,
+        server_id,
+        &form.name,
+        &url,
+        image.animated,
+        user.user_id,
+        &roles,
+        MAX_EMOJI_PER_SERVER,
+    )
+    .await
+    .map_err(|e| emoji_name_conflict(e, &form.name))?
+    // The image stays behind unreferenced until garbage collection
+    .ok_or_else(|| {
+        api_error(
+            Status::BadRequest,
+            "TOO_MANY_EMOJIS",
+            format!("Servers can have at most {MAX_EMOJI_PER_SERVER} emojis"),
+        )
+    })?;
+
+    queries::get_server_emoji(db, server_id, emoji_id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(|| api_error(Status::InternalServerError, "INTERNAL_SERVER_ERROR", "Emoji vanished after creation"))
 }
 
-// Attachment Routes
-#[post("/channels/<channel_id>/attachments", data = "<form>")]
-async fn upload_attachments(
-    channel_id: String,
-    form: Form<Vec<TempFile<'_>>>,
-) -> Result<Json<Vec<Attachment>>, Status> {
-    info!("Uploading attachments to channel: {}", channel_id);
-    Err(Status::NotImplemented)
+#[patch("/servers/<server_id>/emojis/<emoji_id>", format = "json", data = "<update>")]
+async fn update_server_emoji(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    emoji_id: String,
+    update: Json<UpdateEmojiRequest>,
+) -> Result<Json<ServerEmoji>, ApiError> {
+    info!("Updating emoji {} in server {}", emoji_id, server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_EMOJIS).await?;
+    let emoji_id = parse_id(&emoji_id, "emoji")?;
+    let unknown = || api_error(Status::NotFound, "UNKNOWN_EMOJI", "Emoji not found");
+
+    queries::get_server_emoji(db, server_id, emoji_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown)?;
+
+    if let Some(name) = &update.name {
+        if !is_valid_emoji_name(name) {
+            return Err(api_error(
+                Status::BadRequest,
+                "INVALID_EMOJI_NAME",
+                "Emoji names must be 2-32 characters of letters, numbers and underscores",
+            ));
+        }
+    }
+
+    queries::update_server_emoji(db, server_id, emoji_id, update.name.as_deref(), update.roles.as_deref())
+        .await
+        .map_err(|e| emoji_name_conflict(e, update.name.as_deref().unwrap_or_default()))?;
+
+    queries::get_server_emoji(db, server_id, emoji_id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(unknown)
 }
 
-pub async fn start_listener(config: &ServerConfig) -> Result<()> {
-    let log_level = &config.log_level.as_str().to_lowercase();
-    println!("Starting occult server. Current log level: {log_level}");
-    log::set_max_level(config.log_level);
+#[delete("/servers/<server_id>/emojis/<emoji_id>")]
+async fn delete_server_emoji(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    server_id: String,
+    emoji_id: String,
+) -> Result<Status, ApiError> {
+    info!("Deleting emoji {} from server {}", emoji_id, server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_EMOJIS).await?;
+    let emoji_id = parse_id(&emoji_id, "emoji")?;
 
-    let _server = rocket::build()
-        .configure(rocket::Config {
-            port: config.http_port.get() as u16,
-            address: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
-            ..Default::default()
-        })
-        .mount("/", routes![
-            // Auth routes
-            login,
-            register,
-            // Server routes
-            get_servers,
-            create_server,
-            get_server,
-            update_server,
+    let image = queries::delete_server_emoji(db, server_id, emoji_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_EMOJI", "Emoji not found"))?;
+    remove_public_file(db, storage, &image).await;
+    Ok(Status::NoContent)
+}
+
+// Presence Routes
+#[put("/users/@me/presence", format = "json", data = "<update>")]
+async fn update_presence(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    presence: &State<PresenceTracker>,
+    update: Json<UpdatePresenceRequest>,
+) -> Result<Json<PresenceUpdate>, ApiError> {
+    info!("Updating presence for user {} to {}", user.user_id, update.status);
+    set_user_presence(
+        db,
+        gateway,
+        presence,
+        user.user_id,
+        update.status,
+        update.custom_status.as_deref(),
+        update.custom_status_expires_at,
+    )
+    .await?;
+    Ok(Json(presence.presence_of(user.user_id, gateway)))
+}
+
+// User Routes
+#[get("/users/@me")]
+async fn get_current_user(user: AuthenticatedUser, db: &State<Pool<MySql>>) -> Result<Json<User>, ApiError> {
+    info!("Fetching current user profile");
+    queries::get_user(db, user.user_id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))
+}
+
+#[patch("/users/@me", data = "<form>")]
+async fn update_current_user(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    presence: &State<PresenceTracker>,
+    storage: &State<Storage>,
+    form: Form<UpdateUserForm<'_>>,
+) -> Result<Json<User>, ApiError> {
+    info!("Updating current user profile");
+    let form = form.into_inner();
+
+    // Everything is checked before anything is stored, so a bad field
+    // doesn't leave the rest half applied
+    let mut update = ProfileUpdate {
+        display_name: optional_text(form.display_name, "display name", MAX_DISPLAY_NAME_LENGTH)?,
+        bio: optional_text(form.bio, "bio", MAX_BIO_LENGTH)?,
+        pronouns: optional_text(form.pronouns, "pronouns", MAX_PRONOUNS_LENGTH)?,
+        status: form.status,
+        ..Default::default()
+    };
+    if let Some(username) = form.username.as_deref().map(str::trim) {
+        update.username = check_username_change(db, user.user_id, username).await?;
+    }
+    let expires_at = form
+        .custom_status_expires_at
+        .as_deref()
+        .map(|at| {
+            DateTime::parse_from_rfc3339(at)
+                .map(|at| at.with_timezone(&Utc))
+                .map_err(|_| api_error(Status::BadRequest, "INVALID_EXPIRY", "Custom status expiry must be an RFC 3339 timestamp"))
+        })
+        .transpose()?;
+    let custom_status = validate_presence(
+        form.status.unwrap_or(UserStatus::Online),
+        form.custom_status.as_deref(),
+        expires_at,
+    )?;
+    if form.custom_status.is_some() {
+        update.custom_status = Some(custom_status.map(str::to_string));
+        // Clearing the status clears its expiry, otherwise the stored one stays
+        // unless a new one was sent
+        if custom_status.is_none() {
+            update.custom_status_expires_at = Some(None);
+        }
+    }
+    if expires_at.is_some() {
+        update.custom_status_expires_at = Some(expires_at);
+    }
+
+    if let Some(avatar) = form.avatar {
+        update.avatar = Some(Some(save_image_upload(storage, &avatar, "avatars").await?));
+    }
+    if let Some(banner) = form.banner {
+        update.banner = Some(Some(save_image_upload(storage, &banner, "banners").await?));
+    }
+
+    let username = update.username.clone();
+    let presence_changed = update.status.is_some() || update.custom_status.is_some();
+    queries::update_user_profile(db, user.user_id, update)
+        .await
+        .map_err(|e| match (e.as_database_error(), username) {
+            (Some(db_err), Some(username)) if db_err.is_unique_violation() => username_taken(&username),
+            _ => db_error(e),
+        })?;
+
+    if presence_changed {
+        let (status, custom_status) = queries::get_presence_settings(db, user.user_id)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))?;
+        apply_presence(db, gateway, presence, user.user_id, status, custom_status).await;
+    }
+
+    queries::get_user(db, user.user_id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))
+}
+
+#[get("/users/<user_id>")]
+async fn get_user(_user: AuthenticatedUser, db: &State<Pool<MySql>>, user_id: String) -> Result<Json<PublicUser>, ApiError> {
+    info!("Fetching user profile: {}", user_id);
+    let user_id = parse_id(&user_id, "user")?;
+    queries::get_public_user(db, user_id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))
+}
+
+#[patch("/servers/<server_id>/members/@me", format = "json", data = "<update>")]
+async fn update_own_nickname(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    update: Json<UpdateNicknameRequest>,
+) -> Result<Status, ApiError> {
+    info!("Updating nickname in server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    let nickname = optional_text(update.into_inner().nickname, "nickname", MAX_DISPLAY_NAME_LENGTH)?.flatten();
+
+    if !queries::set_server_nickname(db, server_id, user.user_id, nickname.as_deref())
+        .await
+        .map_err(db_error)?
+    {
+        return Err(api_error(
+            Status::BadRequest,
+            "NOT_A_MEMBER",
+            "Nicknames can only be set by members of the server",
+        ));
+    }
+    Ok(Status::NoContent)
+}
+
+#[get("/servers/<server_id>/members?<after>&<limit>")]
+async fn get_server_members(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    after: Option<String>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<Member>>, ApiError> {
+    info!("Listing members of server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    let after = after.as_deref().map(|id| parse_id(id, "user")).transpose()?;
+    let limit = limit.unwrap_or(100).clamp(1, MAX_MEMBERS_PAGE);
+
+    let mut members = queries::get_server_members(db, server_id, limit, after)
+        .await
+        .map_err(db_error)?;
+    // Join sources are for moderators only
+    if require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await.is_err() {
+        for member in &mut members {
+            member.invite_code = None;
+        }
+    }
+    Ok(Json(members))
+}
+
+// Friend Routes
+#[get("/users/@me/friends")]
+async fn get_friends(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    presence: &State<PresenceTracker>,
+) -> Result<Json<Vec<Friend>>, ApiError> {
+    info!("Fetching friends of user: {}", user.user_id);
+    let friends = queries::get_friends(db, user.user_id).await.map_err(db_error)?;
+    Ok(Json(
+        friends
+            .into_iter()
+            .map(|(friend, status, since)| Friend {
+                presence: (status == FriendStatus::Friends).then(|| presence.presence_of(friend.id, gateway)),
+                user: friend,
+                status,
+                since,
+            })
+            .collect(),
+    ))
+}
+
+/// Sends a friend request, or accepts one the other user sent
+#[put("/users/@me/friends/<user_id>")]
+async fn add_friend(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    presence: &State<PresenceTracker>,
+    user_id: String,
+) -> Result<Json<Friend>, ApiError> {
+    info!("Adding friend {} for user {}", user_id, user.user_id);
+    let other_id = parse_id(&user_id, "user")?;
+    if other_id == user.user_id {
+        return Err(api_error(Status::BadRequest, "INVALID_FRIEND", "You can't add yourself as a friend"));
+    }
+    let other = queries::get_public_user(db, other_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))?;
+    if user.is_bot || other.bot {
+        return Err(api_error(Status::BadRequest, "INVALID_FRIEND", "Bots can't have friends"));
+    }
+
+    let before = queries::get_friend_status(db, user.user_id, other_id)
+        .await
+        .map_err(db_error)?;
+    let status = queries::add_friend(db, user.user_id, other_id)
+        .await
+        .map_err(db_error)?;
+
+    // Newly accepted friends start seeing each other's presence straight away
+    if status == FriendStatus::Friends && before != Some(FriendStatus::Friends) {
+        gateway.dispatch(
+            vec![user.user_id],
+            GatewayEvent::PresenceUpdate(presence.presence_of(other_id, gateway)),
+        );
+        gateway.dispatch(
+            vec![other_id],
+            GatewayEvent::PresenceUpdate(presence.presence_of(user.user_id, gateway)),
+        );
+    }
+
+    Ok(Json(Friend {
+        presence: (status == FriendStatus::Friends).then(|| presence.presence_of(other_id, gateway)),
+        user: other,
+        status,
+        since: Utc::now(),
+    }))
+}
+
+/// Unfriends, or declines or cancels a pending request
+#[delete("/users/@me/friends/<user_id>")]
+async fn remove_friend(user: AuthenticatedUser, db: &State<Pool<MySql>>, user_id: String) -> Result<Status, ApiError> {
+    info!("Removing friend {} for user {}", user_id, user.user_id);
+    let other_id = parse_id(&user_id, "user")?;
+    if !queries::remove_friend(db, user.user_id, other_id)
+        .await
+        .map_err(db_error)?
+    {
+        return Err(api_error(Status::NotFound, "UNKNOWN_FRIEND", "Not a friend or pending request"));
+    }
+    Ok(Status::NoContent)
+}
+
+// Server Join/Invite Routes
+#[post("/users/@me/servers", format = "json", data = "<join_request>")]
+async fn join_server(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhooks: &State<WebhookQueue>,
+    join_request: Json<JoinServerRequest>,
+) -> Result<Json<Server>, ApiError> {
+    info!("Joining server with invite code: {}", join_request.invite_code);
+    if user.is_bot {
+        return Err(api_error(
+            Status::Forbidden,
+            "BOTS_CANNOT_USE_INVITES",
+            "Bots join servers when a member authorizes their application",
+        ));
+    }
+    let code = join_request.invite_code.trim();
+    let server_id = match queries::use_invite(db, code, user.user_id)
+        .await
+        .map_err(db_error)?
+    {
+        JoinOutcome::Joined(server_id) => {
+            if let Some(joined) = queries::get_public_user(db, user.user_id).await.map_err(db_error)? {
+                let member = Member {
+                    user: joined,
+                    nickname: None,
+                    joined_at: Utc::now(),
+                    invite_code: Some(code.to_string()),
+                };
+                webhooks.emit(server_id, WebhookEvent::MemberJoin, false, &member);
+            }
+            server_id
+        }
+        JoinOutcome::AlreadyMember(server_id) => server_id,
+        JoinOutcome::Banned => {
+            return Err(api_error(Status::Forbidden, "BANNED", "You are banned from this server"));
+        }
+        JoinOutcome::InvalidInvite => return Err(unknown_invite()),
+    };
+
+    queries::get_server(db, server_id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_SERVER", "Server not found"))
+}
+
+#[post("/servers/<server_id>/invites", format = "json", data = "<invite_request>")]
+async fn create_invite(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    invite_request: Json<CreateInviteRequest>,
+) -> Result<Json<InviteResponse>, ApiError> {
+    info!("Creating invite for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await?;
+    let CreateInviteRequest { max_uses, expires_in } = invite_request.into_inner();
+
+    // Zero means no limit, same as leaving the field out
+    let max_uses = match max_uses {
+        Some(uses) if !(0..=MAX_INVITE_USES).contains(&uses) => {
+            return Err(api_error(
+                Status::BadRequest,
+                "INVALID_MAX_USES",
+                format!("max_uses must be between 0 and {MAX_INVITE_USES}"),
+            ));
+        }
+        uses => uses.filter(|uses| *uses > 0),
+    };
+    let expires_in = match expires_in {
+        Some(hours) if !(0..=MAX_INVITE_LIFETIME_HOURS).contains(&hours) => {
+            return Err(api_error(
+                Status::BadRequest,
+                "INVALID_EXPIRES_IN",
+                format!("expires_in must be between 0 and {MAX_INVITE_LIFETIME_HOURS} hours"),
+            ));
+        }
+        hours => hours.filter(|hours| *hours > 0),
+    };
+
+    let code = queries::create_invite(db, server_id, user.user_id, max_uses, expires_in)
+        .await
+        .map_err(db_error)?;
+    queries::get_invite(db, &code)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(unknown_invite)
+}
+
+#[get("/servers/<server_id>/invites")]
+async fn get_server_invites(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+) -> Result<Json<Vec<InviteResponse>>, ApiError> {
+    info!("Listing invites for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await?;
+    let invites = queries::list_invites(db, server_id).await.map_err(db_error)?;
+    Ok(Json(invites))
+}
+
+/// Public, so invite links can be previewed before signing in
+#[get("/invites/<code>")]
+async fn get_invite(db: &State<Pool<MySql>>, code: String) -> Result<Json<InvitePreview>, ApiError> {
+    info!("Previewing invite: {}", code);
+    queries::get_invite_preview(db, &code)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(unknown_invite)
+}
+
+#[delete("/invites/<code>")]
+async fn delete_invite(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    code: String,
+) -> Result<Status, ApiError> {
+    info!("Revoking invite: {}", code);
+    let invite = queries::get_invite(db, &code)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_invite)?;
+    // Whoever made an invite can always take it back
+    if invite.inviter_id != user.user_id {
+        if !validate_server_access(db, user.user_id, invite.server_id).await? {
+            return Err(unknown_invite());
+        }
+        require_permission(db, user.user_id, invite.server_id, Permissions::MANAGE_SERVER).await?;
+    }
+
+    if !queries::delete_invite(db, &code).await.map_err(db_error)? {
+        return Err(unknown_invite());
+    }
+    Ok(Status::NoContent)
+}
+
+#[get("/servers/<server_id>/invites/<code>/stats?<days>")]
+async fn get_invite_stats(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    code: String,
+    days: Option<i64>,
+) -> Result<Json<InviteStats>, ApiError> {
+    info!("Getting stats for invite {} in server {}", code, server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await?;
+    let days = days.unwrap_or(30).clamp(1, MAX_INVITE_STATS_DAYS);
+    let since = Utc::now() - chrono::Duration::days(days);
+
+    queries::get_invite_stats(db, server_id, &code, since)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(unknown_invite)
+}
+
+#[get("/servers/<server_id>/vanity-code")]
+async fn get_vanity_code(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+) -> Result<Json<VanityCodeResponse>, ApiError> {
+    info!("Getting vanity code for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await?;
+    let (code, uses) = match queries::get_vanity_code(db, server_id).await.map_err(db_error)? {
+        Some((code, uses)) => (Some(code), uses),
+        None => (None, 0),
+    };
+    Ok(Json(VanityCodeResponse { code, uses }))
+}
+
+#[put("/servers/<server_id>/vanity-code", format = "json", data = "<update>")]
+async fn update_vanity_code(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    update: Json<UpdateVanityCodeRequest>,
+) -> Result<Json<VanityCodeResponse>, ApiError> {
+    info!("Updating vanity code for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await?;
+
+    let code = update.into_inner().code.map(|code| code.trim().to_ascii_lowercase()).filter(|code| !code.is_empty());
+    if let Some(code) = &code {
+        if !is_valid_vanity_code(code) {
+            return Err(api_error(
+                Status::BadRequest,
+                "INVALID_VANITY_CODE",
+                format!(
+                    "Vanity codes must be {MIN_VANITY_CODE_LENGTH}-{MAX_VANITY_CODE_LENGTH} characters of a-z, 0-9 and -"
+                ),
+            ));
+        }
+    }
+
+    if !queries::set_vanity_code(db, server_id, code.as_deref()).await.map_err(db_error)? {
+        return Err(api_error(
+            Status::Conflict,
+            "VANITY_CODE_TAKEN",
+            "That code is already in use",
+        ));
+    }
+    let uses = queries::get_vanity_code(db, server_id)
+        .await
+        .map_err(db_error)?
+        .map_or(0, |(_, uses)| uses);
+    Ok(Json(VanityCodeResponse { code, uses }))
+}
+
+// Application Routes
+#[post("/applications", format = "json", data = "<application>")]
+async fn create_application(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application: Json<CreateApplicationRequest>,
+) -> Result<Json<ApplicationWithToken>, ApiError> {
+    info!("Creating application for user: {}", user.user_id);
+    require_human(&user)?;
+    let application = application.into_inner();
+    let name = application_name(&application.name)?;
+    let description = application_description(application.description)?;
+    let username = application.bot_username.trim();
+    if !is_valid_username(username) {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_USERNAME",
+            "Usernames must be 2-32 characters of letters, numbers, underscores and periods",
+        ));
+    }
+
+    let owned = queries::get_owned_applications(db, user.user_id).await.map_err(db_error)?;
+    if owned.len() >= MAX_APPLICATIONS_PER_USER {
+        return Err(api_error(
+            Status::BadRequest,
+            "TOO_MANY_APPLICATIONS",
+            format!("You can own at most {MAX_APPLICATIONS_PER_USER} applications"),
+        ));
+    }
+    let taken = || api_error(Status::Conflict, "USERNAME_TAKEN", format!("The username {username} is taken"));
+    if queries::username_taken(db, username, Uuid::nil()).await.map_err(db_error)? {
+        return Err(taken());
+    }
+    let token = generate_token();
+    let secret = generate_secret();
+    let id = queries::create_application(db, user.user_id, &name, description.as_deref(), username, &hash_token(&token), &secret)
+        .await
+        .map_err(|e| match e.as_database_error() {
+            Some(db_err) if db_err.is_unique_violation() => taken(),
+            _ => db_error(e),
+        })?;
+    let application = queries::get_application(db, id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_application)?;
+    Ok(Json(ApplicationWithToken {
+        application,
+        token,
+        interactions_secret: secret,
+    }))
+}
+
+#[get("/applications")]
+async fn get_applications(user: AuthenticatedUser, db: &State<Pool<MySql>>) -> Result<Json<Vec<Application>>, ApiError> {
+    info!("Listing applications for user: {}", user.user_id);
+    let applications = queries::get_owned_applications(db, user.user_id).await.map_err(db_error)?;
+    Ok(Json(applications))
+}
+
+#[get("/applications/<application_id>")]
+async fn get_application(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application_id: String,
+) -> Result<Json<Application>, ApiError> {
+    info!("Fetching application: {}", application_id);
+    let application = require_owned_application(db, user.user_id, &application_id).await?;
+    Ok(Json(application))
+}
+
+#[patch("/applications/<application_id>", format = "json", data = "<update>")]
+async fn update_application(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    interactions: &State<InteractionSender>,
+    application_id: String,
+    update: Json<UpdateApplicationRequest>,
+) -> Result<Json<Application>, ApiError> {
+    info!("Updating application: {}", application_id);
+    let application = require_owned_application(db, user.user_id, &application_id).await?;
+    let update = update.into_inner();
+    let name = match &update.name {
+        Some(name) => application_name(name)?,
+        None => application.name,
+    };
+    let description = match update.description {
+        Some(description) => application_description(Some(description))?,
+        None => application.description,
+    };
+    let interactions_url = match update.interactions_url.as_deref().map(str::trim) {
+        Some("") => None,
+        Some(url) => {
+            check_interactions_url(interactions, url)?;
+            Some(url.to_string())
+        }
+        None => application.interactions_url,
+    };
+
+    queries::update_application(db, application.id, &name, description.as_deref(), interactions_url.as_deref())
+        .await
+        .map_err(db_error)?;
+    queries::get_application(db, application.id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(unknown_application)
+}
+
+/// Deletes the application and its bot, which leaves every server it was in
+#[delete("/applications/<application_id>")]
+async fn delete_application(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application_id: String,
+) -> Result<Status, ApiError> {
+    info!("Deleting application: {}", application_id);
+    let application = require_owned_application(db, user.user_id, &application_id).await?;
+    queries::delete_application(db, &application).await.map_err(db_error)?;
+    Ok(Status::NoContent)
+}
+
+/// Issues a new bot token, for when the old one leaked
+#[post("/applications/<application_id>/bot/token")]
+async fn regenerate_bot_token(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application_id: String,
+) -> Result<Json<ApplicationWithToken>, ApiError> {
+    info!("Regenerating bot token for application: {}", application_id);
+    let application = require_owned_application(db, user.user_id, &application_id).await?;
+    let token = generate_token();
+    queries::set_bot_token(db, application.id, &hash_token(&token))
+        .await
+        .map_err(db_error)?;
+    let interactions_secret = application.interactions_secret.clone();
+    Ok(Json(ApplicationWithToken {
+        application,
+        token,
+        interactions_secret,
+    }))
+}
+
+/// The consent screen for adding a bot, e.g. from a link the developer shares
+#[get("/oauth2/authorize?<client_id>&<permissions>")]
+async fn get_bot_authorization(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    client_id: String,
+    permissions: Option<u64>,
+) -> Result<Json<BotAuthorizationPreview>, ApiError> {
+    info!("Previewing bot authorization for application: {}", client_id);
+    require_human(&user)?;
+    let application_id = parse_id(&client_id, "application")?;
+    let application = queries::get_application(db, application_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_application)?;
+    Ok(Json(BotAuthorizationPreview {
+        application,
+        permissions: Permissions::from_bits(permissions.unwrap_or(0)),
+    }))
+}
+
+/// Adds the application's bot to a server the user manages. The bot can't
+/// be granted more than the user holds themselves.
+#[post("/oauth2/authorize", format = "json", data = "<authorization>")]
+async fn authorize_bot(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhooks: &State<WebhookQueue>,
+    authorization: Json<AuthorizeBotRequest>,
+) -> Result<Json<Server>, ApiError> {
+    info!("Authorizing application {} for server {}", authorization.client_id, authorization.server_id);
+    require_human(&user)?;
+    let authorization = authorization.into_inner();
+    let server_id = require_server_access(db, user.user_id, &authorization.server_id.to_string()).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await?;
+    let granted = queries::get_member_permissions(db, server_id, user.user_id)
+        .await
+        .map_err(db_error)?
+        .unwrap_or_default();
+    if !granted.contains(authorization.permissions) {
+        return Err(api_error(
+            Status::Forbidden,
+            "MISSING_PERMISSIONS",
+            "You can't grant a bot permissions you don't have",
+        ));
+    }
+    let application = queries::get_application(db, authorization.client_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_application)?;
+    if queries::is_banned(db, server_id, application.bot.id).await.map_err(db_error)? {
+        return Err(api_error(Status::Forbidden, "BANNED", "This bot is banned from the server"));
+    }
+
+    let added = queries::add_bot_to_server(db, server_id, &application, authorization.permissions)
+        .await
+        .map_err(db_error)?;
+    if !added {
+        return Err(api_error(Status::Conflict, "ALREADY_MEMBER", "The bot is already in this server"));
+    }
+    let member = Member {
+        user: application.bot.clone(),
+        nickname: None,
+        joined_at: Utc::now(),
+        invite_code: None,
+    };
+    webhooks.emit(server_id, WebhookEvent::MemberJoin, false, &member);
+
+    queries::get_server(db, server_id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_SERVER", "Server not found"))
+}
+
+// Application Command Routes
+#[post("/applications/<application_id>/commands", format = "json", data = "<command>")]
+async fn create_global_command(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application_id: String,
+    command: Json<CreateApplicationCommandRequest>,
+) -> Result<Json<ApplicationCommand>, ApiError> {
+    info!("Registering global command for application: {}", application_id);
+    let application = require_command_manager(db, user.user_id, &application_id).await?;
+    register_command(db, &application, None, command.into_inner()).await.map(Json)
+}
+
+#[get("/applications/<application_id>/commands")]
+async fn get_global_commands(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application_id: String,
+) -> Result<Json<Vec<ApplicationCommand>>, ApiError> {
+    info!("Listing global commands for application: {}", application_id);
+    let application = require_command_manager(db, user.user_id, &application_id).await?;
+    let commands = queries::get_application_commands(db, application.id, None)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(commands))
+}
+
+/// Commands only available in one server, which the bot has to be a member of
+#[post("/applications/<application_id>/servers/<server_id>/commands", format = "json", data = "<command>")]
+async fn create_server_command(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application_id: String,
+    server_id: String,
+    command: Json<CreateApplicationCommandRequest>,
+) -> Result<Json<ApplicationCommand>, ApiError> {
+    info!("Registering command for application {} in server {}", application_id, server_id);
+    let application = require_command_manager(db, user.user_id, &application_id).await?;
+    let server_id = require_bot_in_server(db, &application, &server_id).await?;
+    register_command(db, &application, Some(server_id), command.into_inner()).await.map(Json)
+}
+
+#[get("/applications/<application_id>/servers/<server_id>/commands")]
+async fn get_server_application_commands(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application_id: String,
+    server_id: String,
+) -> Result<Json<Vec<ApplicationCommand>>, ApiError> {
+    info!("Listing commands for application {} in server {}", application_id, server_id);
+    let application = require_command_manager(db, user.user_id, &application_id).await?;
+    let server_id = require_bot_in_server(db, &application, &server_id).await?;
+    let commands = queries::get_application_commands(db, application.id, Some(server_id))
+        .await
+        .map_err(db_error)?;
+    Ok(Json(commands))
+}
+
+#[delete("/applications/<application_id>/commands/<command_id>")]
+async fn delete_application_command(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    application_id: String,
+    command_id: String,
+) -> Result<Status, ApiError> {
+    info!("Deleting command {} of application {}", command_id, application_id);
+    let application = require_command_manager(db, user.user_id, &application_id).await?;
+    let command_id = parse_id(&command_id, "command")?;
+    let command = queries::get_application_command(db, command_id)
+        .await
+        .map_err(db_error)?
+        .filter(|command| command.application_id == application.id)
+        .ok_or_else(unknown_command)?;
+    queries::delete_application_command(db, command.id)
+        .await
+        .map_err(db_error)?;
+    Ok(Status::NoContent)
+}
+
+/// Commands the server's bots offer, for autocomplete while the user types `/`.
+/// A bot's server command hides its global one of the same name.
+#[get("/servers/<server_id>/commands?<query>&<limit>")]
+async fn search_server_commands(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    query: Option<String>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<ApplicationCommand>>, ApiError> {
+    info!("Searching commands in server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    let prefix = query.unwrap_or_default().trim().trim_start_matches('/').to_lowercase();
+    let limit = limit.unwrap_or(MAX_COMMAND_SUGGESTIONS).clamp(1, MAX_COMMAND_SUGGESTIONS);
+    let mut commands = queries::get_server_commands(db, server_id, &prefix, limit)
+        .await
+        .map_err(db_error)?;
+    let mut seen = HashSet::new();
+    commands.retain(|command| seen.insert((command.application_id, command.name.clone())));
+    Ok(Json(commands))
+}
+
+// Interaction Routes
+/// Invokes a command. The bot answers over the gateway within the deadline,
+/// with a message in the channel or an ephemeral one only the invoker sees.
+#[post("/channels/<channel_id>/interactions", format = "json", data = "<invocation>")]
+async fn invoke_command(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    signer: &State<UrlSigner>,
+    unfurl: &State<UnfurlQueue>,
+    search: &State<Search>,
+    push: &State<PushQueue>,
+    webhooks: &State<WebhookQueue>,
+    interactions: &State<InteractionSender>,
+    channel_id: String,
+    invocation: Json<InvokeCommandRequest>,
+) -> Result<Json<InteractionReceipt>, ApiError> {
+    info!("Invoking command in channel: {}", channel_id);
+    require_human(&user)?;
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let invocation = invocation.into_inner();
+    let command = queries::get_application_command(db, invocation.command_id)
+        .await
+        .map_err(db_error)?
+        .filter(|command| command.server_id.is_none() || command.server_id == Some(channel.server_id))
+        .ok_or_else(unknown_command)?;
+    let application = queries::get_application(db, command.application_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_command)?;
+    // Covers the bot not being in the server at all
+    if !validate_channel_access(db, application.bot.id, channel.id).await? {
+        return Err(api_error(
+            Status::Forbidden,
+            "APPLICATION_CANNOT_ACCESS_CHANNEL",
+            "The bot can't see this channel",
+        ));
+    }
+    let options = resolve_options(&command.options, invocation.options)
+        .map_err(|message| api_error(Status::BadRequest, "INVALID_COMMAND_OPTIONS", message))?;
+    check_option_targets(db, user.user_id, &channel, &command.options, &options).await?;
+    let invoker = queries::get_public_user(db, user.user_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))?;
+
+    let token = generate_token();
+    let created_at = Utc::now();
+    let interaction = Interaction {
+        id: Uuid::new_v4(),
+        application_id: application.id,
+        token: token.clone(),
+        server_id: channel.server_id,
+        channel_id: channel.id,
+        user: invoker,
+        command: InteractionCommand {
+            id: command.id,
+            name: command.name,
+            options,
+        },
+        created_at,
+        expires_at: created_at + chrono::Duration::from_std(RESPONSE_DEADLINE).unwrap_or_default(),
+    };
+    queries::create_interaction(db, &interaction, &hash_token(&token))
+        .await
+        .map_err(db_error)?;
+    tokio::spawn(expire_interaction_after_deadline(
+        db.inner().clone(),
+        gateway.inner().clone(),
+        interaction.id,
+        channel.id,
+        user.user_id,
+    ));
+
+    let unavailable = || {
+        api_error(
+            Status::ServiceUnavailable,
+            "APPLICATION_UNAVAILABLE",
+            "The application couldn't be reached",
+        )
+    };
+    match &application.interactions_url {
+        Some(url) => match interactions
+            .deliver(url, &application.interactions_secret, interaction.id, &interaction)
+            .await
+        {
+            Ok(Some(response)) => {
+                let responded = respond_to_interaction(
+                    db, gateway, signer, unfurl, search, push, webhooks, interaction.id, user.user_id, &channel,
+                    &application.bot, response,
+                )
+                .await;
+                // The bot's mistakes shouldn't look like the invoker's
+                if let Err((_, error)) = responded {
+                    warn!("Application {} answered interaction {} badly: {}", application.id, interaction.id, error.message);
+                    queries::expire_interaction(db, interaction.id).await.map_err(db_error)?;
+                    return Err(unavailable());
+                }
+            }
+            // The bot responds through the callback instead
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Failed to deliver interaction {} to {url}: {e}", interaction.id);
+                queries::expire_interaction(db, interaction.id).await.map_err(db_error)?;
+                return Err(unavailable());
+            }
+        },
+        None if gateway.is_connected(application.bot.id) => {
+            gateway.dispatch(vec![application.bot.id], GatewayEvent::InteractionCreate(interaction.clone()));
+        }
+        None => {
+            queries::expire_interaction(db, interaction.id).await.map_err(db_error)?;
+            return Err(unavailable());
+        }
+    }
+
+    Ok(Json(InteractionReceipt {
+        id: interaction.id,
+        expires_at: interaction.expires_at,
+    }))
+}
+
+/// Where a bot that got the interaction over the gateway, or acknowledged it
+/// over http, sends its response. The token stands in for authentication.
+#[post("/interactions/<interaction_id>/<token>/callback", format = "json", data = "<response>")]
+async fn interaction_callback(
+    db: &State<Pool<MySql>>,
+    gateway: &State<Gateway>,
+    signer: &State<UrlSigner>,
+    unfurl: &State<UnfurlQueue>,
+    search: &State<Search>,
+    push: &State<PushQueue>,
+    webhooks: &State<WebhookQueue>,
+    interaction_id: String,
+    token: String,
+    response: Json<InteractionResponse>,
+) -> Result<Status, ApiError> {
+    info!("Responding to interaction: {}", interaction_id);
+    let interaction_id = Uuid::parse_str(&interaction_id).map_err(|_| unknown_interaction())?;
+    let interaction = queries::get_interaction(db, interaction_id, &hash_token(&token))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_interaction)?;
+    if interaction.state == "responded" {
+        return Err(api_error(
+            Status::Conflict,
+            "INTERACTION_ALREADY_RESPONDED",
+            "This interaction was already responded to",
+        ));
+    }
+    if interaction.state == "expired" || interaction.expires_at <= Utc::now() {
+        return Err(interaction_expired());
+    }
+    let application = queries::get_application(db, interaction.application_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_interaction)?;
+    let channel = queries::get_channel(db, interaction.channel_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_interaction)?;
+
+    respond_to_interaction(
+        db, gateway, signer, unfurl, search, push, webhooks, interaction.id, interaction.user_id, &channel,
+        &application.bot, response.into_inner(),
+    )
+    .await?;
+    Ok(Status::NoContent)
+}
+
+// Incoming Webhook Routes
+#[post("/channels/<channel_id>/webhooks", format = "json", data = "<webhook>")]
+async fn create_webhook(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    channel_id: String,
+    webhook: Json<CreateWebhookRequest>,
+) -> Result<Json<WebhookWithToken>, ApiError> {
+    info!("Creating webhook in channel: {}", channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    require_permission(db, user.user_id, channel.server_id, Permissions::MANAGE_WEBHOOKS).await?;
+    let webhook = webhook.into_inner();
+    let name = webhook_name(&webhook.name)?;
+    let avatar = webhook_avatar(webhook.avatar)?;
+
+    let token = generate_token();
+    let id = queries::create_webhook(
+        db,
+        &channel,
+        &name,
+        avatar.as_deref(),
+        &hash_token(&token),
+        user.user_id,
+        MAX_WEBHOOKS_PER_CHANNEL,
+    )
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| {
+        api_error(
+            Status::BadRequest,
+            "TOO_MANY_WEBHOOKS",
+            format!("A channel can have at most {MAX_WEBHOOKS_PER_CHANNEL} webhooks"),
+        )
+    })?;
+    let webhook = queries::get_webhook(db, id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_webhook)?;
+    Ok(Json(with_token(webhook, token)))
+}
+
+#[get("/channels/<channel_id>/webhooks")]
+async fn get_channel_webhooks(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    channel_id: String,
+) -> Result<Json<Vec<Webhook>>, ApiError> {
+    info!("Listing webhooks for channel: {}", channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    require_permission(db, user.user_id, channel.server_id, Permissions::MANAGE_WEBHOOKS).await?;
+    let webhooks = queries::get_webhooks(db, channel.server_id, Some(channel.id))
+        .await
+        .map_err(db_error)?;
+    Ok(Json(webhooks))
+}
+
+#[get("/servers/<server_id>/webhooks")]
+async fn get_server_webhooks(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+) -> Result<Json<Vec<Webhook>>, ApiError> {
+    info!("Listing webhooks for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_WEBHOOKS).await?;
+    let webhooks = queries::get_webhooks(db, server_id, None).await.map_err(db_error)?;
+    Ok(Json(webhooks))
+}
+
+#[get("/webhooks/<webhook_id>")]
+async fn get_webhook(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhook_id: String,
+) -> Result<Json<Webhook>, ApiError> {
+    info!("Fetching webhook: {}", webhook_id);
+    let webhook = require_webhook(db, user.user_id, &webhook_id).await?;
+    Ok(Json(webhook))
+}
+
+#[patch("/webhooks/<webhook_id>", format = "json", data = "<update>")]
+async fn update_webhook(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhook_id: String,
+    update: Json<UpdateWebhookRequest>,
+) -> Result<Json<Webhook>, ApiError> {
+    info!("Updating webhook: {}", webhook_id);
+    let webhook = require_webhook(db, user.user_id, &webhook_id).await?;
+    let update = update.into_inner();
+    let name = match &update.name {
+        Some(name) => webhook_name(name)?,
+        None => webhook.name,
+    };
+    let avatar = match update.avatar {
+        Some(avatar) => webhook_avatar(Some(avatar))?,
+        None => webhook.avatar,
+    };
+
+    queries::update_webhook(db, webhook.id, &name, avatar.as_deref())
+        .await
+        .map_err(db_error)?;
+    queries::get_webhook(db, webhook.id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(unknown_webhook)
+}
+
+/// Issues a new token, for when the old url leaked
+#[post("/webhooks/<webhook_id>/token")]
+async fn regenerate_webhook_token(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhook_id: String,
+) -> Result<Json<WebhookWithToken>, ApiError> {
+    info!("Regenerating token for webhook: {}", webhook_id);
+    let webhook = require_webhook(db, user.user_id, &webhook_id).await?;
+    let token = generate_token();
+    queries::set_webhook_token(db, webhook.id, &hash_token(&token))
+        .await
+        .map_err(db_error)?;
+    Ok(Json(with_token(webhook, token)))
+}
+
+#[delete("/webhooks/<webhook_id>")]
+async fn delete_webhook(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhook_id: String,
+) -> Result<Status, ApiError> {
+    info!("Deleting webhook: {}", webhook_id);
+    let webhook = require_webhook(db, user.user_id, &webhook_id).await?;
+    queries::delete_webhook(db, webhook.id).await.map_err(db_error)?;
+    Ok(Status::NoContent)
+}
+
+/// Posts a message as the webhook. The token in the url is the only credential.
+#[post("/webhooks/<webhook_id>/<token>", format = "json", data = "<payload>", rank = 2)]
+async fn execute_webhook(
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    config: &State<ServerConfig>,
+    gateway: &State<Gateway>,
+    thumbnails: &State<ThumbnailQueue>,
+    signer: &State<UrlSigner>,
+    unfurl: &State<UnfurlQueue>,
+    search: &State<Search>,
+    push: &State<PushQueue>,
+    webhooks: &State<WebhookQueue>,
+    limits: &State<WebhookRateLimiter>,
+    webhook_id: String,
+    token: String,
+    payload: Json<ExecuteWebhookRequest>,
+) -> Result<Json<Message>, WebhookExecuteError> {
+    info!("Executing webhook: {}", webhook_id);
+    let webhook = require_webhook_token(db, limits, &webhook_id, &token).await?;
+    post_webhook_message(
+        db, storage, config, thumbnails, signer, gateway, unfurl, search, push, webhooks, webhook, payload.into_inner(), &[],
+    )
+        .await
+    .map_err(WebhookExecuteError::from)
+}
+
+/// Same as `execute_webhook`, with files. The rest of the message goes in
+/// the `payload_json` field.
+#[post("/webhooks/<webhook_id>/<token>", format = "multipart/form-data", data = "<form>", rank = 3)]
+async fn execute_webhook_with_files(
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    config: &State<ServerConfig>,
+    gateway: &State<Gateway>,
+    thumbnails: &State<ThumbnailQueue>,
+    signer: &State<UrlSigner>,
+    unfurl: &State<UnfurlQueue>,
+    search: &State<Search>,
+    push: &State<PushQueue>,
+    webhooks: &State<WebhookQueue>,
+    limits: &State<WebhookRateLimiter>,
+    webhook_id: String,
+    token: String,
+    form: Form<ExecuteWebhookForm<'_>>,
+) -> Result<Json<Message>, WebhookExecuteError> {
+    info!("Executing webhook with files: {}", webhook_id);
+    let webhook = require_webhook_token(db, limits, &webhook_id, &token).await?;
+    let form = form.into_inner();
+    let payload = match form.payload_json.as_deref().filter(|payload| !payload.trim().is_empty()) {
+        Some(payload) => serde_json::from_str(payload)
+            .map_err(|e| api_error(Status::BadRequest, "INVALID_PAYLOAD", format!("Invalid payload_json: {e}")))?,
+        None => ExecuteWebhookRequest::default(),
+    };
+    post_webhook_message(
+        db, storage, config, thumbnails, signer, gateway, unfurl, search, push, webhooks, webhook, payload, &form.files,
+    )
+        .await
+    .map_err(WebhookExecuteError::from)
+}
+
+// Ban Routes
+#[put("/servers/<server_id>/bans/<user_id>", format = "json", data = "<ban>")]
+async fn ban_member(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhooks: &State<WebhookQueue>,
+    server_id: String,
+    user_id: String,
+    ban: Json<BanRequest>,
+) -> Result<Status, ApiError> {
+    info!("Banning user {} from server {}", user_id, server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::BAN_MEMBERS).await?;
+    let target = parse_id(&user_id, "user")?;
+    let server = queries::get_server(db, server_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_SERVER", "Server not found"))?;
+    if target == user.user_id || target == server.owner_id {
+        return Err(api_error(Status::Forbidden, "CANNOT_BAN", "You can't ban yourself or the server owner"));
+    }
+    let reason = ban.into_inner().reason.map(|reason| reason.trim().to_string()).filter(|reason| !reason.is_empty());
+    if reason.as_ref().is_some_and(|reason| reason.chars().count() > MAX_BAN_REASON_LENGTH) {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_BAN_REASON",
+            format!("Ban reasons can be at most {MAX_BAN_REASON_LENGTH} characters"),
+        ));
+    }
+    if queries::get_public_user(db, target).await.map_err(db_error)?.is_none() {
+        return Err(api_error(Status::NotFound, "UNKNOWN_USER", "User not found"));
+    }
+
+    if queries::ban_member(db, server_id, target, user.user_id, reason.as_deref())
+        .await
+        .map_err(db_error)?
+    {
+        let ban = Ban {
+            user_id: target,
+            moderator_id: user.user_id,
+            reason,
+            created_at: Utc::now(),
+        };
+        webhooks.emit(server_id, WebhookEvent::MemberBan, false, &ban);
+    }
+    Ok(Status::NoContent)
+}
+
+#[delete("/servers/<server_id>/bans/<user_id>")]
+async fn unban_member(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    user_id: String,
+) -> Result<Status, ApiError> {
+    info!("Unbanning user {} from server {}", user_id, server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::BAN_MEMBERS).await?;
+    let target = parse_id(&user_id, "user")?;
+    if !queries::unban_member(db, server_id, target).await.map_err(db_error)? {
+        return Err(api_error(Status::NotFound, "UNKNOWN_BAN", "User is not banned"));
+    }
+    Ok(Status::NoContent)
+}
+
+// Outgoing Webhook Routes
+#[post("/servers/<server_id>/outgoing-webhooks", format = "json", data = "<webhook>")]
+async fn create_outgoing_webhook(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhooks: &State<WebhookQueue>,
+    server_id: String,
+    webhook: Json<CreateOutgoingWebhookRequest>,
+) -> Result<Json<CreatedOutgoingWebhook>, ApiError> {
+    info!("Creating outgoing webhook for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await?;
+    let sender = webhooks.sender().ok_or_else(webhooks_disabled)?;
+    let webhook = webhook.into_inner();
+    check_webhook_url(sender, &webhook.url)?;
+    let events = webhook_events(webhook.events)?;
+    let secret = match webhook.secret {
+        Some(secret) if (MIN_WEBHOOK_SECRET_LENGTH..=MAX_WEBHOOK_SECRET_LENGTH).contains(&secret.len()) => secret,
+        Some(_) => {
+            return Err(api_error(
+                Status::BadRequest,
+                "INVALID_WEBHOOK_SECRET",
+                format!("Secrets must be {MIN_WEBHOOK_SECRET_LENGTH} to {MAX_WEBHOOK_SECRET_LENGTH} bytes long"),
+            ))
+        }
+        None => generate_secret(),
+    };
+
+    let existing = queries::get_outgoing_webhooks(db, server_id).await.map_err(db_error)?;
+    if existing.len() >= MAX_WEBHOOKS_PER_SERVER {
+        return Err(api_error(
+            Status::BadRequest,
+            "TOO_MANY_WEBHOOKS",
+            format!("A server can have at most {MAX_WEBHOOKS_PER_SERVER} outgoing webhooks"),
+        ));
+    }
+    let id = queries::create_outgoing_webhook(db, server_id, user.user_id, &webhook.url, &secret, &events)
+        .await
+        .map_err(db_error)?;
+    let webhook = queries::get_outgoing_webhook(db, id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_webhook)?;
+    Ok(Json(CreatedOutgoingWebhook { webhook, secret }))
+}
+
+#[get("/servers/<server_id>/outgoing-webhooks")]
+async fn get_outgoing_webhooks(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+) -> Result<Json<Vec<OutgoingWebhook>>, ApiError> {
+    info!("Listing outgoing webhooks for server: {}", server_id);
+    let server_id = require_server_access(db, user.user_id, &server_id).await?;
+    require_permission(db, user.user_id, server_id, Permissions::MANAGE_SERVER).await?;
+    let webhooks = queries::get_outgoing_webhooks(db, server_id).await.map_err(db_error)?;
+    Ok(Json(webhooks))
+}
+
+#[get("/servers/<server_id>/outgoing-webhooks/<webhook_id>")]
+async fn get_outgoing_webhook(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    webhook_id: String,
+) -> Result<Json<OutgoingWebhook>, ApiError> {
+    info!("Fetching outgoing webhook: {}", webhook_id);
+    let webhook = require_outgoing_webhook(db, user.user_id, &server_id, &webhook_id).await?;
+    Ok(Json(webhook))
+}
+
+#[patch("/servers/<server_id>/outgoing-webhooks/<webhook_id>", format = "json", data = "<update>")]
+async fn update_outgoing_webhook(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    webhooks: &State<WebhookQueue>,
+    server_id: String,
+    webhook_id: String,
+    update: Json<UpdateOutgoingWebhookRequest>,
+) -> Result<Json<OutgoingWebhook>, ApiError> {
+    info!("Updating outgoing webhook: {}", webhook_id);
+    let webhook = require_outgoing_webhook(db, user.user_id, &server_id, &webhook_id).await?;
+    let sender = webhooks.sender().ok_or_else(webhooks_disabled)?;
+    let update = update.into_inner();
+    let url = update.url.unwrap_or(webhook.url);
+    check_webhook_url(sender, &url)?;
+    let events = match update.events {
+        Some(events) => webhook_events(events)?,
+        None => webhook.events,
+    };
+
+    queries::update_outgoing_webhook(db, webhook.id, &url, &events, update.enabled.unwrap_or(webhook.enabled))
+        .await
+        .map_err(db_error)?;
+    queries::get_outgoing_webhook(db, webhook.id)
+        .await
+        .map_err(db_error)?
+        .map(Json)
+        .ok_or_else(unknown_webhook)
+}
+
+#[delete("/servers/<server_id>/outgoing-webhooks/<webhook_id>")]
+async fn delete_outgoing_webhook(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    webhook_id: String,
+) -> Result<Status, ApiError> {
+    info!("Deleting outgoing webhook: {}", webhook_id);
+    let webhook = require_outgoing_webhook(db, user.user_id, &server_id, &webhook_id).await?;
+    queries::delete_outgoing_webhook(db, webhook.id).await.map_err(db_error)?;
+    Ok(Status::NoContent)
+}
+
+#[get("/servers/<server_id>/outgoing-webhooks/<webhook_id>/deliveries?<before>&<limit>")]
+async fn get_webhook_deliveries(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    server_id: String,
+    webhook_id: String,
+    before: Option<String>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<WebhookDelivery>>, ApiError> {
+    info!("Listing deliveries for outgoing webhook: {}", webhook_id);
+    let webhook = require_outgoing_webhook(db, user.user_id, &server_id, &webhook_id).await?;
+    let before = before
+        .as_deref()
+        .map(|before| {
+            DateTime::parse_from_rfc3339(before)
+                .map(|before| before.with_timezone(&Utc))
+                .map_err(|_| api_error(Status::BadRequest, "INVALID_TIMESTAMP", "before must be an RFC 3339 timestamp"))
+        })
+        .transpose()?;
+    let limit = limit.unwrap_or(50).clamp(1, MAX_WEBHOOK_DELIVERIES_PAGE);
+    let deliveries = queries::get_webhook_deliveries(db, webhook.id, before, limit)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(deliveries))
+}
+
+// Attachment Routes
+#[post("/channels/<channel_id>/attachments", data = "<form>")]
+async fn upload_attachments(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    config: &State<ServerConfig>,
+    thumbnails: &State<ThumbnailQueue>,
+    signer: &State<UrlSigner>,
+    channel_id: String,
+    form: Form<Vec<TempFile<'_>>>,
+) -> Result<Json<Vec<Attachment>>, ApiError> {
+    info!("Uploading attachments to channel: {}", channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+    let attachments = store_attachments(db, storage, config, thumbnails, signer, &channel, Some(user.user_id), &form).await?;
+    Ok(Json(attachments))
+}
+
+/// Validates and stores a batch of multipart uploads as pending attachments
+async fn store_attachments(
+    db: &Pool<MySql>,
+    storage: &Storage,
+    config: &ServerConfig,
+    thumbnails: &ThumbnailQueue,
+    signer: &UrlSigner,
+    channel: &Channel,
+    uploader_id: Option<Uuid>,
+    files: &[TempFile<'_>],
+) -> Result<Vec<Attachment>, ApiError> {
+    if files.len() > MAX_ATTACHMENTS_PER_UPLOAD {
+        return Err(api_error(
+            Status::BadRequest,
+            "TOO_MANY_ATTACHMENTS",
+            format!("At most {MAX_ATTACHMENTS_PER_UPLOAD} files can be uploaded at once"),
+        ));
+    }
+    let max_size = upload_limit(db, config, channel.server_id).await?;
+
+    // Check every file before storing any, so the client hears about all the
+    // problems at once and nothing is left half uploaded
+    let mut inspected = Vec::with_capacity(files.len());
+    let mut rejected = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        let filename = sanitize_filename(
+            file.raw_name()
+                .map(|name| name.dangerous_unsafe_unsanitized_raw().as_str())
+                .unwrap_or("file"),
+        );
+        match validate_file(file.into(), max_size).await {
+            Ok(upload) => inspected.push((filename, upload)),
+            Err(reason) => rejected.push(serde_json::json!({
+                "index": index,
+                "filename": filename,
+                "reason": reason,
+            })),
+        }
+    }
+    if !rejected.is_empty() {
+        return Err((
+            Status::BadRequest,
+            Json(
+                Error::new(
+                    "INVALID_ATTACHMENTS",
+                    format!("{} of {} files were rejected", rejected.len(), files.len()),
+                )
+                .with_details(serde_json::json!({ "files": rejected })),
+            ),
+        ));
+    }
+
+    let mut attachments = Vec::with_capacity(files.len());
+    for (file, (filename, upload)) in files.iter().zip(inspected) {
+        let stored = save_file(storage, file.into(), &upload, "attachments")
+            .await
+            .map_err(|status| api_error(status, "UPLOAD_FAILED", format!("Failed to store {filename}")))?;
+        attachments.push(record_attachment(db, thumbnails, signer, channel.id, uploader_id, &filename, &upload, &stored).await?);
+    }
+    Ok(attachments)
+}
+
+/// Saves the attachment row for a stored upload, queues its thumbnails and
+/// returns it with signed urls
+async fn record_attachment(
+    db: &Pool<MySql>,
+    thumbnails: &ThumbnailQueue,
+    signer: &UrlSigner,
+    channel_id: Uuid,
+    uploader_id: Option<Uuid>,
+    filename: &str,
+    upload: &InspectedUpload,
+    stored: &StoredObject,
+) -> Result<Attachment, ApiError> {
+    let attachment_id = queries::add_attachment(
+        db,
+        NewAttachment {
+            channel_id,
+            uploader_id,
+            message_id: None,
+            attachment_type: upload.attachment_type.clone(),
+            storage_key: &stored.key,
+            filename,
+            size: stored.size as i64,
+            mime_type: &stored.content_type,
+            width: upload.width,
+            height: upload.height,
+            duration: upload.duration,
+        },
+    )
+    .await
+    .map_err(db_error)?;
+    if matches!(upload.attachment_type, AttachmentType::Image) {
+        // Thumbnails are made in the background, the response doesn't wait for them
+        queries::enqueue_thumbnail_job(db, attachment_id)
+            .await
+            .map_err(db_error)?;
+        thumbnails.wake();
+    }
+    let mut attachment = queries::get_attachment(db, attachment_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::InternalServerError, "INTERNAL_SERVER_ERROR", "Attachment vanished after upload"))?
+        .attachment;
+    signer.sign_attachment(&mut attachment);
+    Ok(attachment)
+}
+
+// Resumable Upload Routes, following the tus 1.0 protocol (https://tus.io)
+// with a finalize step that turns the finished upload into an attachment
+#[options("/uploads")]
+fn upload_options(config: &State<ServerConfig>) -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", TUS_EXTENSIONS)
+        .header("Tus-Max-Size", config.max_upload_size)
+}
+
+#[post("/channels/<channel_id>/uploads")]
+async fn create_upload(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    config: &State<ServerConfig>,
+    tus: TusRequest,
+    channel_id: String,
+) -> Result<TusResponse, ApiError> {
+    info!("Creating resumable upload in channel: {}", channel_id);
+    let channel = require_channel_access(db, user.user_id, &channel_id).await?;
+
+    // Upload-Defer-Length isn't supported, the size is needed to check the limit
+    let length = tus.upload_length.ok_or_else(|| {
+        api_error(Status::BadRequest, "MISSING_UPLOAD_LENGTH", "The Upload-Length header is required")
+    })?;
+    if length == 0 {
+        return Err(api_error(Status::BadRequest, "EMPTY_UPLOAD", "File is empty"));
+    }
+    let max_size = upload_limit(db, config, channel.server_id).await?;
+    if length > max_size {
+        return Err(api_error(
+            Status::PayloadTooLarge,
+            "UPLOAD_TOO_LARGE",
+            format!(
+                "File is {} but the upload limit is {}",
+                upload::format_size(length),
+                upload::format_size(max_size)
+            ),
+        ));
+    }
+
+    let filename = sanitize_filename(tus.metadata.get("filename").map(String::as_str).unwrap_or("file"));
+    let expires_at = Utc::now() + chrono::Duration::hours(UPLOAD_SESSION_HOURS);
+    let upload_id = queries::create_upload_session(db, channel.id, user.user_id, &filename, length as i64, expires_at)
+        .await
+        .map_err(db_error)?;
+
+    Ok(TusResponse::new(Status::Created)
+        .header("Location", format!("/uploads/{upload_id}"))
+        .header("Upload-Expires", http_date(expires_at)))
+}
+
+#[head("/uploads/<upload_id>")]
+async fn get_upload_progress(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    _tus: TusRequest,
+    upload_id: String,
+) -> Result<TusResponse, ApiError> {
+    info!("Fetching progress of upload: {}", upload_id);
+    let upload_id = parse_id(&upload_id, "upload")?;
+    let session = require_upload_session(db, user.user_id, upload_id).await?;
+
+    Ok(TusResponse::new(Status::Ok)
+        .header("Upload-Offset", session.upload_offset)
+        .header("Upload-Length", session.upload_length)
+        .header("Upload-Expires", http_date(session.expires_at))
+        .header("Cache-Control", "no-store"))
+}
+
+#[patch("/uploads/<upload_id>", data = "<chunk>")]
+async fn upload_chunk(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    locks: &State<UploadLocks>,
+    tus: TusRequest,
+    upload_id: String,
+    chunk: Data<'_>,
+) -> Result<TusResponse, ApiError> {
+    info!("Receiving chunk for upload: {}", upload_id);
+    let upload_id = parse_id(&upload_id, "upload")?;
+    if tus.content_type.as_deref() != Some("application/offset+octet-stream") {
+        return Err(api_error(
+            Status::UnsupportedMediaType,
+            "INVALID_CONTENT_TYPE",
+            "Chunks must be sent as application/offset+octet-stream",
+        ));
+    }
+    let offset = tus.upload_offset.ok_or_else(|| {
+        api_error(Status::BadRequest, "MISSING_UPLOAD_OFFSET", "The Upload-Offset header is required")
+    })?;
+
+    // Taken before reading the session so the offset can't move under us
+    let _lock = locks.acquire(upload_id).ok_or_else(upload_locked)?;
+    let session = require_upload_session(db, user.user_id, upload_id).await?;
+    if offset != session.upload_offset as u64 {
+        return Err((
+            Status::Conflict,
+            Json(
+                Error::new("OFFSET_MISMATCH", "Upload-Offset doesn't match what the server has received")
+                    .with_details(serde_json::json!({ "upload_offset": session.upload_offset })),
+            ),
+        ));
+    }
+
+    let remaining = (session.upload_length - session.upload_offset) as u64;
+    let mut file = open_partial_upload(session.id, offset).await.map_err(upload_error)?;
+    let written = chunk.open(ByteUnit::from(remaining)).stream_to(&mut file).await;
+
+    // Whatever made it to disk counts, even if the connection dropped part way
+    file.sync_all().await.map_err(|e| upload_error(e.into()))?;
+    let received = file
+        .metadata()
+        .await
+        .map_err(|e| upload_error(e.into()))?
+        .len()
+        .min(session.upload_length as u64);
+    queries::set_upload_offset(db, session.id, received as i64)
+        .await
+        .map_err(db_error)?;
+
+    match written {
+        Ok(written) if !written.complete => Err(api_error(
+            Status::PayloadTooLarge,
+            "CHUNK_TOO_LARGE",
+            "The chunk runs past the Upload-Length given when the upload was created",
+        )),
+        Ok(_) => Ok(TusResponse::new(Status::NoContent)
+            .header("Upload-Offset", received)
+            .header("Upload-Expires", http_date(session.expires_at))),
+        Err(e) => {
+            warn!("Chunk for upload {} was cut short: {e}", session.id);
+            Err(api_error(Status::BadRequest, "CHUNK_INTERRUPTED", "The chunk was cut short, resume from Upload-Offset"))
+        }
+    }
+}
+
+#[delete("/uploads/<upload_id>")]
+async fn cancel_upload(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    locks: &State<UploadLocks>,
+    _tus: TusRequest,
+    upload_id: String,
+) -> Result<TusResponse, ApiError> {
+    info!("Cancelling upload: {}", upload_id);
+    let upload_id = parse_id(&upload_id, "upload")?;
+    let _lock = locks.acquire(upload_id).ok_or_else(upload_locked)?;
+    let session = require_upload_session(db, user.user_id, upload_id).await?;
+
+    queries::delete_upload_session(db, session.id)
+        .await
+        .map_err(db_error)?;
+    remove_partial_upload(session.id).await.map_err(upload_error)?;
+    Ok(TusResponse::new(Status::NoContent))
+}
+
+#[post("/uploads/<upload_id>/finalize")]
+async fn finalize_upload(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    config: &State<ServerConfig>,
+    thumbnails: &State<ThumbnailQueue>,
+    signer: &State<UrlSigner>,
+    locks: &State<UploadLocks>,
+    upload_id: String,
+) -> Result<Json<Attachment>, ApiError> {
+    info!("Finalizing upload: {}", upload_id);
+    let upload_id = parse_id(&upload_id, "upload")?;
+    let _lock = locks.acquire(upload_id).ok_or_else(upload_locked)?;
+    let session = require_upload_session(db, user.user_id, upload_id).await?;
+    if session.upload_offset < session.upload_length {
+        return Err((
+            Status::Conflict,
+            Json(
+                Error::new("UPLOAD_INCOMPLETE", "Not all of the file has been uploaded yet")
+                    .with_details(serde_json::json!({
+                        "upload_offset": session.upload_offset,
+                        "upload_length": session.upload_length,
+                    })),
+            ),
+        ));
+    }
+
+    // Access could have been lost, or the limit lowered, since the upload began
+    let channel = require_channel_access(db, user.user_id, &session.channel_id.to_string()).await?;
+    let max_size = upload_limit(db, config, channel.server_id).await?;
+    let path = partial_upload_path(session.id).map_err(upload_error)?;
+    let source = UploadSource::Path {
+        path: &path,
+        len: session.upload_length as u64,
+    };
+    let upload = validate_file(source, max_size)
+        .await
+        .map_err(|reason| api_error(Status::BadRequest, "INVALID_ATTACHMENT", reason))?;
+    let stored = save_file(storage, source, &upload, "attachments")
+        .await
+        .map_err(|status| api_error(status, "UPLOAD_FAILED", format!("Failed to store {}", session.filename)))?;
+    let attachment = record_attachment(db, thumbnails, signer, channel.id, Some(user.user_id), &session.filename, &upload, &stored).await?;
+
+    queries::delete_upload_session(db, session.id)
+        .await
+        .map_err(db_error)?;
+    if let Err(e) = remove_partial_upload(session.id).await {
+        warn!("Failed to clean up finished upload {}: {e:#}", session.id);
+    }
+    Ok(Json(attachment))
+}
+
+/// Hands out fresh signed urls, for clients holding links that have expired
+#[get("/attachments/<attachment_id>")]
+async fn get_attachment(
+    user: AuthenticatedUser,
+    db: &State<Pool<MySql>>,
+    signer: &State<UrlSigner>,
+    attachment_id: String,
+) -> Result<Json<Attachment>, ApiError> {
+    info!("Fetching attachment: {}", attachment_id);
+    let attachment_id = parse_id(&attachment_id, "attachment")?;
+    let not_found = || api_error(Status::NotFound, "UNKNOWN_ATTACHMENT", "Attachment not found");
+    let stored = queries::get_attachment(db, attachment_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(not_found)?;
+    if !validate_channel_access(db, user.user_id, stored.channel_id).await? {
+        return Err(not_found());
+    }
+
+    let mut attachment = stored.attachment;
+    signer.sign_attachment(&mut attachment);
+    Ok(Json(attachment))
+}
+
+// Downloads are authorised by the url's signature alone, so they work from
+// <img> tags and media players that can't send an Authorization header
+#[get("/attachments/<attachment_id>/<_filename>?<expires>&<signature>")]
+async fn download_attachment(
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    signer: &State<UrlSigner>,
+    range: RangeHeader,
+    attachment_id: String,
+    _filename: String,
+    expires: i64,
+    signature: String,
+) -> Result<Download, ApiError> {
+    info!("Downloading attachment: {}", attachment_id);
+    let attachment_id = parse_id(&attachment_id, "attachment")?;
+    signer
+        .verify_attachment(attachment_id, expires, &signature)
+        .map_err(signature_error)?;
+    let stored = queries::get_attachment(db, attachment_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_ATTACHMENT", "Attachment not found"))?;
+
+    let content_type = ContentType::parse_flexible(&stored.attachment.mime_type).unwrap_or(ContentType::Binary);
+    serve_object(storage, &stored.storage_key, &range, content_type, Some(&stored.attachment.filename))
+        .await
+        .map_err(|status| api_error(status, "DOWNLOAD_FAILED", "Failed to read the attachment"))
+}
+
+#[get("/attachments/<attachment_id>/thumbnails/<width>?<expires>&<signature>")]
+async fn download_thumbnail(
+    db: &State<Pool<MySql>>,
+    storage: &State<Storage>,
+    signer: &State<UrlSigner>,
+    range: RangeHeader,
+    attachment_id: String,
+    width: i32,
+    expires: i64,
+    signature: String,
+) -> Result<Download, ApiError> {
+    info!("Downloading {}px thumbnail of attachment: {}", width, attachment_id);
+    let attachment_id = parse_id(&attachment_id, "attachment")?;
+    signer
+        .verify_thumbnail(attachment_id, width, expires, &signature)
+        .map_err(signature_error)?;
+    let not_found = || api_error(Status::NotFound, "UNKNOWN_THUMBNAIL", "Thumbnail not found");
+
+    let thumbnail = queries::get_thumbnails(db, attachment_id)
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .find(|thumbnail| thumbnail.thumbnail.width == width)
+        .ok_or_else(not_found)?;
+    let content_type = ContentType::parse_flexible(&thumbnail.thumbnail.mime_type).unwrap_or(ContentType::Binary);
+    serve_object(storage, &thumbnail.storage_key, &range, content_type, None)
+        .await
+        .map_err(|status| api_error(status, "DOWNLOAD_FAILED", "Failed to read the thumbnail"))
+}
+
+/// Where the rate limiter sends refused requests, answered by the 429 catcher
+#[get("/rate-limited")]
+fn rate_limited() -> Status {
+    Status::TooManyRequests
+}
+
+// Public uploads such as emoji and avatars
+#[get("/files/<key..>")]
+async fn get_file(storage: &State<Storage>, range: RangeHeader, key: PathBuf) -> Result<Download, Status> {
+    let key = key.to_string_lossy().replace('\\', "/");
+    if !is_valid_key(&key) {
+        return Err(Status::NotFound);
+    }
+    let content_type = key
+        .rsplit_once('.')
+        .and_then(|(_, ext)| ContentType::from_extension(ext))
+        .unwrap_or(ContentType::Binary);
+    serve_object(storage, &key, &range, content_type, None).await
+}
+
+pub async fn start_listener(config: &ServerConfig, pool: Pool<MySql>) -> Result<()> {
+    let log_level = &config.log_level.as_str().to_lowercase();
+    println!("Starting occult server. Current log level: {log_level}");
+    log::set_max_level(config.log_level);
+
+    let storage = build_storage(&config.storage)?;
+    let gateway = Gateway::new();
+    let presence = PresenceTracker::new();
+    let thumbnails = ThumbnailQueue::new();
+    tokio::spawn(run_presence_sweeper(pool.clone(), gateway.clone(), presence.clone()));
+    tokio::spawn(run_thumbnail_worker(pool.clone(), storage.clone(), thumbnails.clone()));
+    tokio::spawn(run_storage_gc(pool.clone(), storage.clone()));
+    let signer = UrlSigner::new(&config.url_signing_key, config.attachment_url_ttl);
+    let search = build_search(&config.search, pool.clone())?;
+    let push = match (config.push.enabled, &config.push.vapid) {
+        (true, Some(_)) => {
+            let pusher = Pusher::new(&config.push)?;
+            let (queue, receiver) = PushQueue::new(pusher.clone());
+            tokio::spawn(run_push_worker(pool.clone(), gateway.clone(), pusher, receiver));
+            queue
+        }
+        (true, None) => {
+            warn!("Push notifications are off because the config has no push.vapid keys");
+            PushQueue::disabled()
+        }
+        (false, _) => PushQueue::disabled(),
+    };
+    let webhooks = if config.webhooks.enabled {
+        let sender = WebhookSender::new(&config.webhooks)?;
+        let (queue, receiver) = WebhookQueue::new(sender.clone());
+        tokio::spawn(run_webhook_worker(pool.clone(), sender, receiver));
+        queue
+    } else {
+        WebhookQueue::disabled()
+    };
+    let interactions = InteractionSender::new(&config.webhooks)?;
+    tokio::spawn(run_interaction_pruner(pool.clone()));
+    let unfurl = if config.unfurl.enabled {
+        let (queue, receiver) = UnfurlQueue::new();
+        let unfurler = Unfurler::new(&config.unfurl)?;
+        tokio::spawn(run_unfurler(pool.clone(), gateway.clone(), signer.clone(), search.clone(), unfurler, receiver));
+        queue
+    } else {
+        UnfurlQueue::disabled()
+    };
+
+    let _server = rocket::build()
+        .configure(rocket::Config {
+            port: config.http_port.get() as u16,
+            address: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+            // Rocket's defaults (1 MiB per file) are far below our own upload limits,
+            // which are enforced per server in validate_file
+            limits: Limits::default()
+                .limit("file", ByteUnit::from(config.max_upload_size))
+                .limit(
+                    "data-form",
+                    ByteUnit::from(config.max_upload_size * MAX_ATTACHMENTS_PER_UPLOAD as u64),
+                ),
+            ..Default::default()
+        })
+        .manage(pool)
+        .manage(gateway)
+        .manage(storage)
+        .manage(thumbnails)
+        .manage(UploadLocks::new())
+        .manage(signer)
+        .manage(unfurl)
+        .manage(search)
+        .manage(push)
+        .manage(webhooks)
+        .manage(WebhookRateLimiter::new())
+        .manage(LoginLocks::new())
+        .manage(interactions)
+        .manage(Mailer::new(&config.mail)?)
+        .manage(config.clone())
+        .manage(presence)
+        .manage(TypingTracker::new())
+        .attach(RateLimiter::new(&config.rate_limits))
+        .mount("/", routes![
+            // Auth routes
+            login,
+            register,
+            // Server routes
+            get_servers,
+            create_server,
+            get_server,
+            update_server,
             delete_server,
             // Channel routes
             get_channels,
@@ -487,26 +3951,118 @@ pub async fn start_listener(config: &ServerConfig) -> Result<()> {
             // Message routes
             get_messages,
             create_message,
+            create_server_channel_message,
             update_message,
             delete_message,
+            search_messages,
+            // Read state routes
+            ack_message,
+            ack_server,
+            get_read_states,
+            // Push routes
+            get_vapid_public_key,
+            create_push_subscription,
+            get_push_subscriptions,
+            delete_push_subscription,
+            // Notification settings routes
+            get_notification_settings,
+            update_server_notification_settings,
+            update_channel_notification_settings,
+            reset_channel_notification_settings,
             // Pin routes
             pin_message,
             unpin_message,
             // Typing indicator
             send_typing_indicator,
+            // Gateway
+            gateway_stream,
+            // Presence routes
+            update_presence,
             // Reaction routes
             add_reaction,
             remove_reaction,
+            get_reaction_users,
+            clear_reactions,
+            clear_reaction_emoji,
+            // Emoji routes
+            get_server_emojis,
+            create_server_emoji,
+            update_server_emoji,
+            delete_server_emoji,
             // User routes
             get_current_user,
             update_current_user,
             get_user,
+            update_own_nickname,
+            get_server_members,
+            // Friend routes
+            get_friends,
+            add_friend,
+            remove_friend,
             // Server join/invite routes
             join_server,
             create_invite,
+            get_server_invites,
+            get_invite_stats,
+            get_invite,
+            delete_invite,
+            get_vanity_code,
+            update_vanity_code,
+            // Application routes
+            create_application,
+            get_applications,
+            get_application,
+            update_application,
+            delete_application,
+            regenerate_bot_token,
+            get_bot_authorization,
+            authorize_bot,
+            // Application command routes
+            create_global_command,
+            get_global_commands,
+            create_server_command,
+            get_server_application_commands,
+            delete_application_command,
+            search_server_commands,
+            // Interaction routes
+            invoke_command,
+            interaction_callback,
+            // Incoming webhook routes
+            create_webhook,
+            get_channel_webhooks,
+            get_server_webhooks,
+            get_webhook,
+            update_webhook,
+            regenerate_webhook_token,
+            delete_webhook,
+            execute_webhook,
+            execute_webhook_with_files,
+            // Ban routes
+            ban_member,
+            unban_member,
+            // Outgoing webhook routes
+            create_outgoing_webhook,
+            get_outgoing_webhooks,
+            get_outgoing_webhook,
+            update_outgoing_webhook,
+            delete_outgoing_webhook,
+            get_webhook_deliveries,
             // Attachment routes
             upload_attachments,
+            get_attachment,
+            download_attachment,
+            // Resumable uploads
+            upload_options,
+            create_upload,
+            get_upload_progress,
+            upload_chunk,
+            cancel_upload,
+            finalize_upload,
+            download_thumbnail,
+            get_file,
+            rate_limited,
         ])
+        .register("/", rocket::catchers![not_found, unauthorized, forbidden, too_many_requests, internal_error])
         .launch()
         .await
         .map_err(|e| {
@@ -545,6 +4101,19 @@ fn forbidden() -> Json<Error> {
     })
 }
 
+#[rocket::catch(429)]
+fn too_many_requests(request: &Request) -> Json<Error> {
+    let error = Error::new("RATE_LIMITED", "You are being rate limited");
+    match request.local_cache(|| None::<RateLimitDecision>) {
+        Some(decision) => Json(error.with_details(serde_json::json!({
+            "retry_after": decision.retry_after.unwrap_or_default().as_secs_f64(),
+            "global": decision.global(),
+            "bucket": decision.bucket.as_str(),
+        }))),
+        None => Json(error),
+    }
+}
+
 #[rocket::catch(500)]
 fn internal_error() -> Json<Error> {
     Json(Error {
@@ -554,13 +4123,16 @@ fn internal_error() -> Json<Error> {
     })
 }
 
-// JWT Authentication Guard implementation
+// Authentication guard, for session and bot tokens
 use rocket::request::{FromRequest, Outcome, Request};
 
 use crate::workspace::{Port, ServerConfig};
 
+#[derive(Debug, Clone, Copy)]
 pub struct AuthenticatedUser {
     pub user_id: Uuid,
+    /// Signed in with a `Bot` token rather than as a person
+    pub is_bot: bool,
 }
 
 #[rocket::async_trait]
@@ -568,51 +4140,337 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
     type Error = Error;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        // Get the authorization header
-        let auth_header = request.headers().get_one("Authorization");
-        
-        match auth_header {
-            Some(header) => {
-                if !header.starts_with("Bearer ") {
-                    return Outcome::Error((
-                        Status::Unauthorized,
-                        Error {
-                            code: "INVALID_TOKEN".to_string(),
-                            message: "Invalid authentication token format".to_string(),
-                            details: None,
-                        },
-                    ));
-                }
+        // The rate limiter authenticates requests before they're routed, the
+        // guard reuses its result rather than looking the token up again
+        match request.local_cache_async(authenticate(request)).await {
+            Ok(user) => Outcome::Success(*user),
+            Err((status, error)) => Outcome::Error((*status, error.clone())),
+        }
+    }
+}
 
-                let token = header.replace("Bearer ", "");
-                
-                // TODO: Implement JWT validation
-                // For now, return a mock user
-                Outcome::Success(AuthenticatedUser {
-                    user_id: Uuid::new_v4(),
-                })
-            }
-            None => Outcome::Error((
+async fn authenticate(request: &Request<'_>) -> Result<AuthenticatedUser, (Status, Error)> {
+    let user = check_token(request).await?;
+    // Any authenticated request counts as activity for idle detection
+    if let Some(presence) = request.rocket().state::<PresenceTracker>() {
+        presence.touch(user.user_id);
+    }
+    Ok(user)
+}
+
+async fn check_token(request: &Request<'_>) -> Result<AuthenticatedUser, (Status, Error)> {
+    // Get the authorization header
+    let auth_header = request.headers().get_one("Authorization");
+    let (token, is_bot) = match auth_header {
+        Some(header) if header.starts_with("Bot ") => (header.trim_start_matches("Bot ").trim(), true),
+        Some(header) if header.starts_with("Bearer ") => (header.trim_start_matches("Bearer ").trim(), false),
+        Some(_) => {
+            return Err((
                 Status::Unauthorized,
-                Error {
-                    code: "MISSING_TOKEN".to_string(),
-                    message: "Authentication token is required".to_string(),
-                    details: None,
-                },
-            )),
+                Error::new("INVALID_TOKEN", "Invalid authentication token format"),
+            ))
+        }
+        None => {
+            return Err((
+                Status::Unauthorized,
+                Error::new("MISSING_TOKEN", "Authentication token is required"),
+            ))
+        }
+    };
+    let Some(pool) = request.rocket().state::<Pool<MySql>>() else {
+        return Err((
+            Status::InternalServerError,
+            Error::new("INTERNAL_SERVER_ERROR", "Database is not available"),
+        ));
+    };
+
+    // Bot and session tokens are only stored hashed
+    let token_hash = hash_token(token);
+    let user_id = if is_bot {
+        queries::get_bot_by_token(pool, &token_hash).await
+    } else {
+        queries::get_session_user(pool, &token_hash).await
+    };
+    match user_id {
+        Ok(Some(user_id)) => Ok(AuthenticatedUser { user_id, is_bot }),
+        Ok(None) if is_bot => Err((
+            Status::Unauthorized,
+            Error::new("INVALID_TOKEN", "Invalid bot token"),
+        )),
+        Ok(None) => Err((
+            Status::Unauthorized,
+            Error::new("INVALID_TOKEN", "Invalid or expired session token"),
+        )),
+        Err(e) => {
+            error!("Database error: {e}");
+            Err((
+                Status::InternalServerError,
+                Error::new("INTERNAL_SERVER_ERROR", "Failed to check the token"),
+            ))
         }
     }
 }
 
 // Helper functions for common operations
-async fn validate_server_access(user_id: Uuid, server_id: Uuid) -> Result<bool, Status> {
-    // TODO: Implement server access validation
-    Ok(true)
+fn api_error(status: Status, code: &str, message: impl Into<String>) -> ApiError {
+    (status, Json(Error::new(code, message)))
 }
 
-async fn validate_channel_access(user_id: Uuid, channel_id: Uuid) -> Result<bool, Status> {
-    // TODO: Implement channel access validation
-    Ok(true)
+fn db_error(e: sqlx::Error) -> ApiError {
+    error!("Database error: {e}");
+    api_error(
+        Status::InternalServerError,
+        "INTERNAL_SERVER_ERROR",
+        "An internal server error occurred",
+    )
+}
+
+fn emoji_name_conflict(e: sqlx::Error, name: &str) -> ApiError {
+    match e.as_database_error() {
+        Some(db_err) if db_err.is_unique_violation() => api_error(
+            Status::Conflict,
+            "EMOJI_NAME_TAKEN",
+            format!("An emoji named {name} already exists in this server"),
+        ),
+        _ => db_error(e),
+    }
+}
+
+fn push_disabled() -> ApiError {
+    api_error(Status::NotFound, "PUSH_DISABLED", "Push notifications are not enabled on this server")
+}
+
+fn unknown_push_subscription() -> ApiError {
+    api_error(Status::NotFound, "UNKNOWN_PUSH_SUBSCRIPTION", "Push subscription not found")
+}
+
+/// Unpadded base64url of exactly `len` characters
+fn is_base64url(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn login_refused(refusal: LoginRefusal) -> ApiError {
+    match refusal {
+        LoginRefusal::Throttled { retry_after } => (
+            Status::TooManyRequests,
+            Json(
+                Error::new("LOGIN_THROTTLED", "Too many failed logins, wait before trying again")
+                    .with_details(serde_json::json!({ "retry_after": retry_after.as_secs_f64() })),
+            ),
+        ),
+        LoginRefusal::Locked { kind, until } => (
+            Status::TooManyRequests,
+            Json(
+                Error::new(
+                    match kind {
+                        LockoutKind::Account => "ACCOUNT_LOCKED",
+                        LockoutKind::Ip => "IP_LOCKED",
+                    },
+                    "Too many failed logins, try again later",
+                )
+                .with_details(serde_json::json!({
+                    "retry_after": (until - Utc::now()).num_seconds().max(1),
+                    "locked_until": until,
+                })),
+            ),
+        ),
+    }
+}
+
+fn lockout_email(until: DateTime<Utc>, ip: &str) -> String {
+    format!(
+        "Someone failed to sign in to your account too many times, most recently from {ip}, \
+         so it has been locked until {} UTC.\n\n\
+         If this was you, wait until then and try again. If it wasn't, your password may be known \
+         to someone else and you should change it once you can sign in.\n",
+        until.format("%Y-%m-%d %H:%M")
+    )
+}
+
+fn unknown_application() -> ApiError {
+    api_error(Status::NotFound, "UNKNOWN_APPLICATION", "Application not found")
+}
+
+fn unknown_command() -> ApiError {
+    api_error(Status::NotFound, "UNKNOWN_COMMAND", "Command not found")
+}
+
+fn unknown_interaction() -> ApiError {
+    api_error(Status::NotFound, "UNKNOWN_INTERACTION", "Interaction not found")
+}
+
+fn interaction_expired() -> ApiError {
+    api_error(
+        Status::Gone,
+        "INTERACTION_EXPIRED",
+        format!("Interactions must be responded to within {} seconds", RESPONSE_DEADLINE.as_secs()),
+    )
+}
+
+/// For account management that a bot token must never be able to do
+fn require_human(user: &AuthenticatedUser) -> Result<(), ApiError> {
+    if user.is_bot {
+        return Err(api_error(Status::Forbidden, "BOTS_NOT_ALLOWED", "Bots can't use this endpoint"));
+    }
+    Ok(())
+}
+
+fn application_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    let length = name.chars().count();
+    if !(2..=MAX_APPLICATION_NAME_LENGTH).contains(&length) || name.chars().any(char::is_control) {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_APPLICATION_NAME",
+            format!("Application names must be 2 to {MAX_APPLICATION_NAME_LENGTH} characters"),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// An empty description means none
+fn application_description(description: Option<String>) -> Result<Option<String>, ApiError> {
+    let Some(description) = description.map(|d| d.trim().to_string()).filter(|d| !d.is_empty()) else {
+        return Ok(None);
+    };
+    if description.chars().count() > MAX_APPLICATION_DESCRIPTION_LENGTH {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_APPLICATION_DESCRIPTION",
+            format!("Descriptions can be at most {MAX_APPLICATION_DESCRIPTION_LENGTH} characters"),
+        ));
+    }
+    Ok(Some(description))
+}
+
+fn with_token(webhook: Webhook, token: String) -> WebhookWithToken {
+    WebhookWithToken {
+        url: format!("/webhooks/{}/{}", webhook.id, token),
+        webhook,
+        token,
+    }
+}
+
+fn webhook_name(name: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    if !is_valid_webhook_name(name) {
+        return Err(api_error(Status::BadRequest, "INVALID_WEBHOOK_NAME", "Webhook names must be 1 to 80 characters"));
+    }
+    Ok(name.to_string())
+}
+
+/// An empty avatar means none
+fn webhook_avatar(avatar: Option<String>) -> Result<Option<String>, ApiError> {
+    let Some(avatar) = avatar.map(|avatar| avatar.trim().to_string()).filter(|avatar| !avatar.is_empty()) else {
+        return Ok(None);
+    };
+    if !is_http_url(&avatar) {
+        return Err(api_error(Status::BadRequest, "INVALID_AVATAR_URL", "Avatars must be http(s) urls"));
+    }
+    Ok(Some(avatar))
+}
+
+fn webhooks_disabled() -> ApiError {
+    api_error(Status::NotFound, "WEBHOOKS_DISABLED", "Outgoing webhooks are not enabled on this server")
+}
+
+fn unknown_webhook() -> ApiError {
+    api_error(Status::NotFound, "UNKNOWN_WEBHOOK", "Webhook not found")
+}
+
+fn check_interactions_url(sender: &InteractionSender, url: &str) -> Result<(), ApiError> {
+    let invalid = |message: String| api_error(Status::BadRequest, "INVALID_INTERACTIONS_URL", message);
+    if url.len() > MAX_WEBHOOK_URL_LENGTH {
+        return Err(invalid("Interactions url is too long".to_string()));
+    }
+    sender.check_url(url).map_err(invalid)
+}
+
+fn check_webhook_url(sender: &WebhookSender, url: &str) -> Result<(), ApiError> {
+    let invalid = |message: String| api_error(Status::BadRequest, "INVALID_WEBHOOK_URL", message);
+    if url.len() > MAX_WEBHOOK_URL_LENGTH {
+        return Err(invalid("Webhook url is too long".to_string()));
+    }
+    sender.check_url(url).map_err(invalid)
+}
+
+/// Deduplicated, and at least one
+fn webhook_events(mut events: Vec<WebhookEvent>) -> Result<Vec<WebhookEvent>, ApiError> {
+    let mut seen = Vec::with_capacity(events.len());
+    events.retain(|event| {
+        let new = !seen.contains(event);
+        seen.push(*event);
+        new
+    });
+    if events.is_empty() {
+        return Err(api_error(Status::BadRequest, "NO_WEBHOOK_EVENTS", "Subscribe to at least one event"));
+    }
+    Ok(events)
+}
+
+fn unknown_invite() -> ApiError {
+    api_error(Status::NotFound, "UNKNOWN_INVITE", "Invite is invalid or has expired")
+}
+
+fn is_valid_vanity_code(code: &str) -> bool {
+    (MIN_VANITY_CODE_LENGTH..=MAX_VANITY_CODE_LENGTH).contains(&code.len())
+        && code.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !code.starts_with('-')
+        && !code.ends_with('-')
+}
+
+fn parse_id(id: &str, kind: &str) -> Result<Uuid, ApiError> {
+    Uuid::parse_str(id).map_err(|_| {
+        api_error(Status::BadRequest, "INVALID_ID", format!("Invalid {kind} id: {id}"))
+    })
+}
+
+fn parse_reaction_emoji(emoji: &str) -> Result<ReactionEmoji, ApiError> {
+    ReactionEmoji::from_str(emoji).map_err(|e| api_error(Status::BadRequest, "INVALID_EMOJI", e))
+}
+
+/// Parses an emoji and makes sure custom emoji belong to the channel's server
+/// and aren't restricted to roles the user lacks
+async fn resolve_reaction_emoji(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    user_id: Uuid,
+    emoji: &str,
+) -> Result<ReactionEmoji, ApiError> {
+    let emoji = parse_reaction_emoji(emoji)?;
+    if let ReactionEmoji::Custom { name, id } = &emoji {
+        let usable = queries::can_use_server_emoji(pool, server_id, *id, name, user_id)
+            .await
+            .map_err(db_error)?;
+        if !usable {
+            return Err(api_error(
+                Status::BadRequest,
+                "UNKNOWN_EMOJI",
+                format!("Unknown custom emoji: {name}"),
+            ));
+        }
+    }
+    Ok(emoji)
+}
+
+async fn validate_server_access(pool: &Pool<MySql>, user_id: Uuid, server_id: Uuid) -> Result<bool, ApiError> {
+    let permissions = queries::get_member_permissions(pool, server_id, user_id)
+        .await
+        .map_err(db_error)?;
+    Ok(permissions.is_some())
+}
+
+async fn validate_channel_access(pool: &Pool<MySql>, user_id: Uuid, channel_id: Uuid) -> Result<bool, ApiError> {
+    let Some(channel) = queries::get_channel(pool, channel_id).await.map_err(db_error)? else {
+        return Ok(false);
+    };
+    let permissions = queries::get_member_permissions(pool, channel.server_id, user_id)
+        .await
+        .map_err(db_error)?;
+    Ok(match permissions {
+        Some(permissions) => !channel.is_private || permissions.contains(Permissions::VIEW_PRIVATE_CHANNELS),
+        None => false,
+    })
 }
 
 async fn validate_message_ownership(user_id: Uuid, message_id: Uuid) -> Result<bool, Status> {
@@ -620,13 +4478,792 @@ async fn validate_message_ownership(user_id: Uuid, message_id: Uuid) -> Result<b
     Ok(true)
 }
 
-// File handling utilities
-async fn save_file(file: TempFile<'_>, path: &str) -> Result<String, Status> {
-    // TODO: Implement file saving logic
-    Ok("file_url".to_string())
+/// The user's read states for the channels they can currently see
+async fn visible_read_states(pool: &Pool<MySql>, user_id: Uuid) -> Result<Vec<ReadState>, ApiError> {
+    let states = queries::get_read_states(pool, user_id).await.map_err(db_error)?;
+    let mut can_view_private = HashMap::new();
+    let mut visible = Vec::with_capacity(states.len());
+    for channel in states {
+        if channel.is_private {
+            let allowed = match can_view_private.get(&channel.server_id) {
+                Some(allowed) => *allowed,
+                None => {
+                    let allowed = queries::get_member_permissions(pool, channel.server_id, user_id)
+                        .await
+                        .map_err(db_error)?
+                        .is_some_and(|permissions| permissions.contains(Permissions::VIEW_PRIVATE_CHANNELS));
+                    can_view_private.insert(channel.server_id, allowed);
+                    allowed
+                }
+            };
+            if !allowed {
+                continue;
+            }
+        }
+        visible.push(channel.state);
+    }
+    Ok(visible)
+}
+
+/// The stored form of a mute request. An expiry has to be in the future.
+fn mute_state(muted: bool, until: Option<DateTime<Utc>>) -> Result<(bool, Option<DateTime<Utc>>), ApiError> {
+    if !muted {
+        return Ok((false, None));
+    }
+    if until.is_some_and(|until| until <= Utc::now()) {
+        return Err(api_error(Status::BadRequest, "INVALID_MUTE_EXPIRY", "muted_until must be in the future"));
+    }
+    Ok((true, until))
+}
+
+/// Sends the server's settings as they are now to all of the user's sessions
+async fn notification_settings_changed(
+    pool: &Pool<MySql>,
+    gateway: &Gateway,
+    user_id: Uuid,
+    server_id: Uuid,
+) -> Result<Json<ServerNotificationSettings>, ApiError> {
+    let settings = queries::get_server_notification_settings(pool, user_id, server_id)
+        .await
+        .map_err(db_error)?;
+    gateway.dispatch(vec![user_id], GatewayEvent::NotificationSettingsUpdate(settings.clone()));
+    Ok(Json(settings))
+}
+
+/// A `from:` or `mentions:` value: a username, a user id or a `<@user_id>` mention
+async fn resolve_search_user(pool: &Pool<MySql>, server_id: Uuid, name: &str) -> Result<Uuid, ApiError> {
+    let name = name.trim_start_matches("<@").trim_end_matches('>');
+    if let Ok(id) = Uuid::parse_str(name) {
+        return Ok(id);
+    }
+    queries::find_server_member(pool, server_id, name.trim_start_matches('@'))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::BadRequest, "INVALID_SEARCH_QUERY", format!("Unknown member {name}")))
+}
+
+/// Trimmed message text, refused if it's too long
+fn message_content(content: &str) -> Result<String, ApiError> {
+    let content = content.trim();
+    if content.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(api_error(
+            Status::BadRequest,
+            "MESSAGE_TOO_LONG",
+            format!("Messages can be at most {MAX_MESSAGE_LENGTH} characters"),
+        ));
+    }
+    Ok(content.to_string())
+}
+
+/// Trims a text field from a profile form. `Some("")` clears the field.
+fn optional_text(value: Option<String>, field: &str, max_len: usize) -> Result<Option<Option<String>>, ApiError> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    if value.chars().count() > max_len {
+        return Err(api_error(
+            Status::BadRequest,
+            "FIELD_TOO_LONG",
+            format!("The {field} can be at most {max_len} characters"),
+        ));
+    }
+    Ok(Some((!value.is_empty()).then(|| value.to_string())))
+}
+
+fn is_valid_username(username: &str) -> bool {
+    (2..=32).contains(&username.chars().count())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Checks a new username is valid, free and not changed too often. None if
+/// it's the user's current one.
+async fn check_username_change(pool: &Pool<MySql>, user_id: Uuid, username: &str) -> Result<Option<String>, ApiError> {
+    if !is_valid_username(username) {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_USERNAME",
+            "Usernames must be 2-32 characters of letters, numbers, underscores and periods",
+        ));
+    }
+    let current = queries::get_user(pool, user_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_USER", "User not found"))?;
+    if current.username == username {
+        return Ok(None);
+    }
+
+    let since = Utc::now() - chrono::Duration::hours(1);
+    let recent = queries::count_username_changes_since(pool, user_id, since)
+        .await
+        .map_err(db_error)?;
+    if recent >= USERNAME_CHANGES_PER_HOUR {
+        return Err(api_error(
+            Status::TooManyRequests,
+            "USERNAME_RATE_LIMITED",
+            format!("Usernames can only be changed {USERNAME_CHANGES_PER_HOUR} times per hour"),
+        ));
+    }
+
+    if queries::username_taken(pool, username, user_id).await.map_err(db_error)? {
+        return Err(username_taken(username));
+    }
+    Ok(Some(username.to_string()))
+}
+
+fn username_taken(username: &str) -> ApiError {
+    api_error(Status::Conflict, "USERNAME_TAKEN", format!("The username {username} is taken"))
 }
 
-async fn validate_file(file: &TempFile<'_>) -> Result<(), Status> {
-    // TODO: Implement file validation logic
+/// Stores the user's chosen status and custom status and tells everyone who
+/// can see them if that changed what they look like
+async fn set_user_presence(
+    pool: &Pool<MySql>,
+    gateway: &Gateway,
+    presence: &PresenceTracker,
+    user_id: Uuid,
+    status: UserStatus,
+    custom_status: Option<&str>,
+    custom_status_expires_at: Option<DateTime<Utc>>,
+) -> Result<(), ApiError> {
+    let custom_status = validate_presence(status, custom_status, custom_status_expires_at)?;
+
+    queries::update_user_status(pool, user_id, status)
+        .await
+        .map_err(db_error)?;
+    queries::update_custom_status(pool, user_id, custom_status, custom_status_expires_at)
+        .await
+        .map_err(db_error)?;
+
+    apply_presence(pool, gateway, presence, user_id, status, custom_status.map(str::to_string)).await;
+    Ok(())
+}
+
+/// Checks a status change before anything is stored, returning the custom
+/// status trimmed, or None if it was blank
+fn validate_presence(
+    status: UserStatus,
+    custom_status: Option<&str>,
+    custom_status_expires_at: Option<DateTime<Utc>>,
+) -> Result<Option<&str>, ApiError> {
+    if status == UserStatus::Offline {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_STATUS",
+            "Offline is computed from your connections, use invisible instead",
+        ));
+    }
+    let custom_status = custom_status
+        .map(str::trim)
+        .filter(|status| !status.is_empty());
+    if custom_status.map_or(false, |status| status.chars().count() > MAX_CUSTOM_STATUS_LENGTH) {
+        return Err(api_error(
+            Status::BadRequest,
+            "CUSTOM_STATUS_TOO_LONG",
+            format!("Custom statuses can be at most {MAX_CUSTOM_STATUS_LENGTH} characters"),
+        ));
+    }
+    if custom_status_expires_at.map_or(false, |at| at <= Utc::now()) {
+        return Err(api_error(
+            Status::BadRequest,
+            "INVALID_EXPIRY",
+            "Custom status expiry must be in the future",
+        ));
+    }
+    Ok(custom_status)
+}
+
+/// Hands a stored status change to the tracker and tells everyone who can
+/// see the user if it changed what they look like
+async fn apply_presence(
+    pool: &Pool<MySql>,
+    gateway: &Gateway,
+    presence: &PresenceTracker,
+    user_id: Uuid,
+    status: UserStatus,
+    custom_status: Option<String>,
+) {
+    presence.set_preference(user_id, status, custom_status);
+    if let Some(changed) = presence.refresh(user_id, gateway) {
+        publish_presence(pool, gateway, changed).await;
+    }
+}
+
+/// Parses a server id and checks the user is a member of it
+async fn require_server_access(pool: &Pool<MySql>, user_id: Uuid, server_id: &str) -> Result<Uuid, ApiError> {
+    let server_id = parse_id(server_id, "server")?;
+    if validate_server_access(pool, user_id, server_id).await? {
+        Ok(server_id)
+    } else {
+        Err(api_error(Status::NotFound, "UNKNOWN_SERVER", "Server not found"))
+    }
+}
+
+/// Looks up a channel the user is allowed to read. Channels the user can't
+/// see are reported as missing so their existence isn't leaked.
+async fn require_channel_access(pool: &Pool<MySql>, user_id: Uuid, channel_id: &str) -> Result<Channel, ApiError> {
+    let channel_id = parse_id(channel_id, "channel")?;
+    let not_found = || api_error(Status::NotFound, "UNKNOWN_CHANNEL", "Channel not found");
+    if !validate_channel_access(pool, user_id, channel_id).await? {
+        return Err(not_found());
+    }
+    queries::get_channel(pool, channel_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(not_found)
+}
+
+/// Everything that follows storing a new message, however it was sent:
+/// recording its mentions, indexing it, sending it to the channel, and
+/// queueing link previews, push notifications and outgoing webhooks
+#[allow(clippy::too_many_arguments)]
+async fn after_message_created(
+    db: &Pool<MySql>,
+    gateway: &Gateway,
+    signer: &UrlSigner,
+    unfurl: &UnfurlQueue,
+    search: &Search,
+    push: &PushQueue,
+    webhooks: &WebhookQueue,
+    channel: &Channel,
+    message_id: Uuid,
+) -> Result<Message, ApiError> {
+    let mut message = queries::get_message(db, message_id, None)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| api_error(Status::InternalServerError, "INTERNAL_SERVER_ERROR", "Message vanished after it was sent"))?;
+    // Push notifications go to the mentioned, so these have to be in first
+    queries::set_message_mentions(db, message_id, &MessageMentions::parse(&message.content))
+        .await
+        .map_err(db_error)?;
+    if let Err(e) = search.index_message(&message).await {
+        warn!("Failed to index message {}: {e:#}", message.id);
+    }
+    signer.sign_message(&mut message);
+
+    let recipients = channel_audience(db, gateway, channel).await?;
+    gateway.dispatch(recipients, GatewayEvent::MessageCreate(message.clone()));
+    // Bots and webhooks that send embeds of their own get no link previews
+    if message.embeds.is_empty() {
+        unfurl.enqueue(message.id, &message.content);
+    }
+    push.enqueue(message.id, channel.server_id);
+    webhooks.emit(channel.server_id, WebhookEvent::MessageCreate, channel.is_private, &message);
+    Ok(message)
+}
+
+/// Connected users who can currently read the channel
+pub(crate) async fn channel_audience(pool: &Pool<MySql>, gateway: &Gateway, channel: &Channel) -> Result<Vec<Uuid>, ApiError> {
+    if !channel.is_private {
+        let members = queries::get_server_member_ids(pool, channel.server_id)
+            .await
+            .map_err(db_error)?;
+        return Ok(members.into_iter().filter(|id| gateway.is_connected(*id)).collect());
+    }
+    let members = queries::get_server_member_permissions(pool, channel.server_id)
+        .await
+        .map_err(db_error)?;
+    Ok(members
+        .into_iter()
+        .filter(|(id, permissions)| {
+            gateway.is_connected(*id) && permissions.contains(Permissions::VIEW_PRIVATE_CHANNELS)
+        })
+        .map(|(id, _)| id)
+        .collect())
+}
+
+/// The application, if the user owns it
+async fn require_owned_application(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    application_id: &str,
+) -> Result<Application, ApiError> {
+    let application_id = parse_id(application_id, "application")?;
+    queries::get_application(pool, application_id)
+        .await
+        .map_err(db_error)?
+        .filter(|application| application.owner_id == user_id)
+        .ok_or_else(unknown_application)
+}
+
+/// The application, if the user owns it or is its bot
+async fn require_command_manager(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    application_id: &str,
+) -> Result<Application, ApiError> {
+    let application_id = parse_id(application_id, "application")?;
+    queries::get_application(pool, application_id)
+        .await
+        .map_err(db_error)?
+        .filter(|application| application.owner_id == user_id || application.bot.id == user_id)
+        .ok_or_else(unknown_application)
+}
+
+async fn require_bot_in_server(pool: &Pool<MySql>, application: &Application, server_id: &str) -> Result<Uuid, ApiError> {
+    let server_id = parse_id(server_id, "server")?;
+    if !validate_server_access(pool, application.bot.id, server_id).await? {
+        return Err(api_error(Status::NotFound, "UNKNOWN_SERVER", "The bot isn't in this server"));
+    }
+    Ok(server_id)
+}
+
+/// Validates a command and registers it, replacing any with the same name
+async fn register_command(
+    pool: &Pool<MySql>,
+    application: &Application,
+    server_id: Option<Uuid>,
+    command: CreateApplicationCommandRequest,
+) -> Result<ApplicationCommand, ApiError> {
+    let description = command.description.trim();
+    validate_command(&command.name, description, &command.options)
+        .map_err(|message| api_error(Status::BadRequest, "INVALID_COMMAND", message))?;
+    let id = queries::upsert_application_command(
+        pool,
+        application.id,
+        server_id,
+        &command.name,
+        description,
+        &command.options,
+        MAX_COMMANDS_PER_SCOPE,
+    )
+    .await
+    .map_err(db_error)?
+    .ok_or_else(|| {
+        api_error(
+            Status::BadRequest,
+            "TOO_MANY_COMMANDS",
+            format!("An application can register at most {MAX_COMMANDS_PER_SCOPE} commands here"),
+        )
+    })?;
+    queries::get_application_command(pool, id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_command)
+}
+
+/// User, channel and role options must point at something in the invoking
+/// server. Channels also have to be visible to the invoker.
+async fn check_option_targets(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    channel: &Channel,
+    definitions: &[CommandOption],
+    options: &[InteractionOption],
+) -> Result<(), ApiError> {
+    for option in options {
+        let Some(definition) = definitions.iter().find(|definition| definition.name == option.name) else {
+            continue;
+        };
+        // resolve_options already checked these parse
+        let Some(id) = option.value.as_str().and_then(|id| Uuid::parse_str(id).ok()) else {
+            continue;
+        };
+        let found = match definition.option_type {
+            CommandOptionType::User => validate_server_access(pool, id, channel.server_id).await?,
+            CommandOptionType::Channel => {
+                queries::get_channel(pool, id)
+                    .await
+                    .map_err(db_error)?
+                    .is_some_and(|target| target.server_id == channel.server_id)
+                    && validate_channel_access(pool, user_id, id).await?
+            }
+            CommandOptionType::Role => queries::is_server_role(pool, channel.server_id, id)
+                .await
+                .map_err(db_error)?,
+            _ => continue,
+        };
+        if !found {
+            return Err(api_error(
+                Status::BadRequest,
+                "INVALID_COMMAND_OPTIONS",
+                format!("Option {} doesn't refer to anything in this server", option.name),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Tells the invoker their command went unanswered, unless the bot responded
+async fn expire_interaction_after_deadline(
+    pool: Pool<MySql>,
+    gateway: Gateway,
+    interaction_id: Uuid,
+    channel_id: Uuid,
+    user_id: Uuid,
+) {
+    tokio::time::sleep(RESPONSE_DEADLINE).await;
+    match queries::expire_interaction(&pool, interaction_id).await {
+        Ok(true) => gateway.dispatch(
+            vec![user_id],
+            GatewayEvent::InteractionFailed {
+                interaction_id,
+                channel_id,
+                reason: "The application didn't respond in time".to_string(),
+            },
+        ),
+        Ok(false) => {}
+        Err(e) => error!("Failed to expire interaction {interaction_id}: {e}"),
+    }
+}
+
+/// The webhook, if the user may manage the server's webhooks
+async fn require_webhook(pool: &Pool<MySql>, user_id: Uuid, webhook_id: &str) -> Result<Webhook, ApiError> {
+    let webhook_id = parse_id(webhook_id, "webhook")?;
+    let webhook = queries::get_webhook(pool, webhook_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_webhook)?;
+    if !validate_server_access(pool, user_id, webhook.server_id).await? {
+        return Err(unknown_webhook());
+    }
+    require_permission(pool, user_id, webhook.server_id, Permissions::MANAGE_WEBHOOKS).await?;
+    Ok(webhook)
+}
+
+/// The webhook a token belongs to, counted against its rate limit. Wrong
+/// tokens look the same as unknown webhooks.
+async fn require_webhook_token(
+    pool: &Pool<MySql>,
+    limits: &WebhookRateLimiter,
+    webhook_id: &str,
+    token: &str,
+) -> Result<Webhook, WebhookExecuteError> {
+    let webhook_id = Uuid::parse_str(webhook_id).map_err(|_| unknown_webhook())?;
+    let webhook = queries::get_webhook_with_token(pool, webhook_id, &hash_token(token))
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_webhook)?;
+    if let Err(retry_after) = limits.check(webhook.id) {
+        let seconds = (retry_after.as_secs_f64().ceil() as u64).max(1);
+        return Err(WebhookExecuteError::RateLimited(
+            Json(
+                Error::new("RATE_LIMITED", "This webhook is posting too quickly")
+                    .with_details(serde_json::json!({ "retry_after": retry_after.as_secs_f64() })),
+            ),
+            Header::new("Retry-After", seconds.to_string()),
+        ));
+    }
+    Ok(webhook)
+}
+
+/// Validates a webhook's message and sends it like `create_message` would
+#[allow(clippy::too_many_arguments)]
+async fn post_webhook_message(
+    db: &Pool<MySql>,
+    storage: &Storage,
+    config: &ServerConfig,
+    thumbnails: &ThumbnailQueue,
+    signer: &UrlSigner,
+    gateway: &Gateway,
+    unfurl: &UnfurlQueue,
+    search: &Search,
+    push: &PushQueue,
+    webhooks: &WebhookQueue,
+    webhook: Webhook,
+    payload: ExecuteWebhookRequest,
+    files: &[TempFile<'_>],
+) -> Result<Json<Message>, ApiError> {
+    let channel = queries::get_channel(db, webhook.channel_id)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(unknown_webhook)?;
+    let content = message_content(payload.content.as_deref().unwrap_or_default())?;
+    let author = WebhookAuthor {
+        id: webhook.id,
+        name: match payload.username.as_deref().map(str::trim).filter(|name| !name.is_empty()) {
+            Some(name) => webhook_name(name)?,
+            None => webhook.name,
+        },
+        avatar: match webhook_avatar(payload.avatar_url)? {
+            Some(avatar) => Some(avatar),
+            None => webhook.avatar,
+        },
+    };
+    if payload.embeds.len() > MAX_WEBHOOK_EMBEDS {
+        return Err(api_error(
+            Status::BadRequest,
+            "TOO_MANY_EMBEDS",
+            format!("A message can have at most {MAX_WEBHOOK_EMBEDS} embeds"),
+        ));
+    }
+    let embeds = payload
+        .embeds
+        .into_iter()
+        .map(WebhookEmbed::into_embed)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|message| api_error(Status::BadRequest, "INVALID_EMBED", message))?;
+    if content.is_empty() && embeds.is_empty() && files.is_empty() {
+        return Err(api_error(Status::BadRequest, "EMPTY_MESSAGE", "A message needs content, embeds or files"));
+    }
+
+    let attachment_ids = if files.is_empty() {
+        Vec::new()
+    } else {
+        store_attachments(db, storage, config, thumbnails, signer, &channel, None, files)
+            .await?
+            .iter()
+            .map(|attachment| attachment.id)
+            .collect()
+    };
+    let message_id = queries::create_webhook_message(db, channel.id, &author, &content, &embeds, &attachment_ids)
+        .await
+        .map_err(db_error)?;
+    let message = after_message_created(db, gateway, signer, unfurl, search, push, webhooks, &channel, message_id).await?;
+    Ok(Json(message))
+}
+
+/// Validates a bot's response and delivers it, as a message in the channel
+/// or only to the user who invoked the command. Each interaction takes one.
+#[allow(clippy::too_many_arguments)]
+async fn respond_to_interaction(
+    db: &Pool<MySql>,
+    gateway: &Gateway,
+    signer: &UrlSigner,
+    unfurl: &UnfurlQueue,
+    search: &Search,
+    push: &PushQueue,
+    webhooks: &WebhookQueue,
+    interaction_id: Uuid,
+    user_id: Uuid,
+    channel: &Channel,
+    bot: &PublicUser,
+    response: InteractionResponse,
+) -> Result<(), ApiError> {
+    let content = message_content(response.content.as_deref().unwrap_or_default())?;
+    if response.embeds.len() > MAX_WEBHOOK_EMBEDS {
+        return Err(api_error(
+            Status::BadRequest,
+            "TOO_MANY_EMBEDS",
+            format!("A message can have at most {MAX_WEBHOOK_EMBEDS} embeds"),
+        ));
+    }
+    let embeds = response
+        .embeds
+        .into_iter()
+        .map(WebhookEmbed::into_embed)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|message| api_error(Status::BadRequest, "INVALID_EMBED", message))?;
+    if content.is_empty() && embeds.is_empty() {
+        return Err(api_error(Status::BadRequest, "EMPTY_MESSAGE", "A response needs content or embeds"));
+    }
+    if !queries::mark_interaction_responded(db, interaction_id).await.map_err(db_error)? {
+        return Err(interaction_expired());
+    }
+
+    if response.response_type == InteractionResponseType::Ephemeral {
+        gateway.dispatch(
+            vec![user_id],
+            GatewayEvent::EphemeralMessageCreate(EphemeralMessage {
+                interaction_id,
+                channel_id: channel.id,
+                author: bot.clone(),
+                content,
+                embeds,
+                created_at: Utc::now(),
+            }),
+        );
+        return Ok(());
+    }
+
+    let message_id = queries::create_message(db, channel.id, bot.id, &content, None, &[])
+        .await
+        .map_err(db_error)?;
+    if !embeds.is_empty() {
+        queries::set_message_embeds(db, message_id, &embeds)
+            .await
+            .map_err(db_error)?;
+    }
+    after_message_created(db, gateway, signer, unfurl, search, push, webhooks, channel, message_id).await?;
     Ok(())
+}
+
+/// The webhook, if it belongs to the server and the user may manage the server
+async fn require_outgoing_webhook(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    server_id: &str,
+    webhook_id: &str,
+) -> Result<OutgoingWebhook, ApiError> {
+    let server_id = require_server_access(pool, user_id, server_id).await?;
+    require_permission(pool, user_id, server_id, Permissions::MANAGE_SERVER).await?;
+    let webhook_id = parse_id(webhook_id, "webhook")?;
+    queries::get_outgoing_webhook(pool, webhook_id)
+        .await
+        .map_err(db_error)?
+        .filter(|webhook| webhook.server_id == server_id)
+        .ok_or_else(unknown_webhook)
+}
+
+async fn require_message_in_channel(pool: &Pool<MySql>, message_id: &str, channel_id: Uuid) -> Result<Uuid, ApiError> {
+    let message_id = parse_id(message_id, "message")?;
+    match queries::get_message_channel_id(pool, message_id).await.map_err(db_error)? {
+        Some(id) if id == channel_id => Ok(message_id),
+        _ => Err(api_error(Status::NotFound, "UNKNOWN_MESSAGE", "Message not found")),
+    }
+}
+
+async fn require_permission(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    server_id: Uuid,
+    required: Permissions,
+) -> Result<(), ApiError> {
+    let permissions = queries::get_member_permissions(pool, server_id, user_id)
+        .await
+        .map_err(db_error)?
+        .unwrap_or_default();
+    if permissions.contains(required) {
+        Ok(())
+    } else {
+        Err(api_error(
+            Status::Forbidden,
+            "MISSING_PERMISSIONS",
+            "You don't have permission to do that",
+        ))
+    }
+}
+
+// File handling utilities
+/// Runs an image upload (avatar, banner, icon) through validation and storage
+async fn save_image_upload(storage: &Storage, file: &TempFile<'_>, category: &str) -> Result<String, ApiError> {
+    let inspected = validate_file(file.into(), MAX_PROFILE_IMAGE_SIZE)
+        .await
+        .map_err(|e| api_error(Status::BadRequest, "INVALID_IMAGE", e))?;
+    if !matches!(inspected.attachment_type, AttachmentType::Image) {
+        return Err(api_error(Status::BadRequest, "INVALID_IMAGE", "Uploads here must be images"));
+    }
+    save_file(storage, file.into(), &inspected, category)
+        .await
+        .map(|stored| public_file_url(&stored.key))
+        .map_err(|status| api_error(status, "UPLOAD_FAILED", "Failed to store the image"))
+}
+
+/// Stores a validated upload, preferring the metadata-stripped copy when
+/// validation produced one
+async fn save_file(
+    storage: &Storage,
+    file: UploadSource<'_>,
+    inspected: &InspectedUpload,
+    category: &str,
+) -> Result<StoredObject, Status> {
+    let stored = match &inspected.sanitized {
+        Some(bytes) => store_bytes(storage, bytes, &inspected.mime_type, category).await,
+        None => store_upload(storage, file, &inspected.mime_type, category).await,
+    };
+    stored.map_err(|e| {
+        error!("Failed to store upload: {e:#}");
+        Status::InternalServerError
+    })
+}
+
+/// Url of a public upload (emoji, avatars, ...) served by `get_file`
+fn public_file_url(key: &str) -> String {
+    format!("/files/{key}")
+}
+
+/// Deletes the object behind a public file url once nothing points at it.
+/// Objects are content addressed, so another row may share it.
+async fn remove_public_file(db: &Pool<MySql>, storage: &Storage, url: &str) {
+    let Some(key) = url.strip_prefix("/files/") else {
+        return;
+    };
+    match queries::is_storage_key_referenced(db, key).await {
+        Ok(false) => {
+            if let Err(e) = storage.delete(key).await {
+                warn!("Failed to delete unreferenced object {key}: {e:#}");
+            }
+        }
+        Ok(true) => {}
+        Err(e) => warn!("Failed to check references to {key}: {e}"),
+    }
+}
+
+/// Keeps the last path component of a client supplied filename and replaces
+/// anything that could upset a url or a Content-Disposition header
+fn sanitize_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .take(255)
+        .collect();
+    match sanitized.trim_matches('.') {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Serves a stored object off disk, or sends the client to the backend
+async fn serve_object(
+    storage: &Storage,
+    key: &str,
+    range: &RangeHeader,
+    content_type: ContentType,
+    filename: Option<&str>,
+) -> Result<Download, Status> {
+    let location = storage.locate(key).await.map_err(|e| {
+        error!("Failed to locate object {key}: {e:#}");
+        Status::InternalServerError
+    })?;
+    match location {
+        ObjectLocation::File(path) => Ok(Download::File(
+            RangedFile::open(&path, range, content_type, filename).await?,
+        )),
+        ObjectLocation::Url(url) => Ok(Download::Redirect(Redirect::temporary(url))),
+    }
+}
+
+/// Sessions belong to whoever created them, anyone else gets a 404
+async fn require_upload_session(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    upload_id: Uuid,
+) -> Result<queries::UploadSession, ApiError> {
+    queries::get_upload_session(pool, upload_id)
+        .await
+        .map_err(db_error)?
+        .filter(|session| session.uploader_id == user_id)
+        .ok_or_else(|| api_error(Status::NotFound, "UNKNOWN_UPLOAD", "Upload not found"))
+}
+
+fn upload_locked() -> ApiError {
+    api_error(Status::Locked, "UPLOAD_LOCKED", "Another request is already writing to this upload")
+}
+
+fn upload_error(e: anyhow::Error) -> ApiError {
+    error!("Resumable upload failed: {e:#}");
+    api_error(Status::InternalServerError, "UPLOAD_FAILED", "Failed to write the upload")
+}
+
+/// The IMF-fixdate format HTTP headers use
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn signature_error(error: SignatureError) -> ApiError {
+    match error {
+        SignatureError::Expired => api_error(Status::Forbidden, "URL_EXPIRED", "This link has expired, request a new one"),
+        SignatureError::Invalid => api_error(Status::Forbidden, "INVALID_SIGNATURE", "This link is not valid"),
+    }
+}
+
+/// Checks an upload against a size limit and works out what it really is.
+/// The error is a reason fit to show the uploader.
+async fn validate_file(file: UploadSource<'_>, max_size: u64) -> Result<InspectedUpload, String> {
+    inspect_upload(file, max_size).await
+}
+
+/// The smaller of the global upload limit and the server's own
+async fn upload_limit(pool: &Pool<MySql>, config: &ServerConfig, server_id: Uuid) -> Result<u64, ApiError> {
+    let server_limit = queries::get_server_upload_limit(pool, server_id)
+        .await
+        .map_err(db_error)?;
+    Ok(match server_limit {
+        Some(limit) if limit > 0 => config.max_upload_size.min(limit as u64),
+        _ => config.max_upload_size,
+    })
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationLevel {
+    #[default]
+    All,
+    Mentions,
+    Nothing,
+}
+
+impl NotificationLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationLevel::All => "all",
+            NotificationLevel::Mentions => "mentions",
+            NotificationLevel::Nothing => "nothing",
+        }
+    }
+}
+
+impl FromStr for NotificationLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotificationLevel::All),
+            "mentions" => Ok(NotificationLevel::Mentions),
+            "nothing" => Ok(NotificationLevel::Nothing),
+            _ => Err(format!("Invalid notification level: {s}")),
+        }
+    }
+}
+
+/// A user's settings for one server. Servers the user never touched use the
+/// default: every message notifies, nothing is muted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerNotificationSettings {
+    pub server_id: Uuid,
+    pub level: NotificationLevel,
+    pub muted: bool,
+    /// When a mute ends by itself, None for muted until unmuted
+    pub muted_until: Option<DateTime<Utc>>,
+    pub suppress_everyone: bool,
+    pub suppress_roles: bool,
+    pub channel_overrides: Vec<ChannelNotificationOverride>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelNotificationOverride {
+    pub channel_id: Uuid,
+    /// None inherits the server's level
+    pub level: Option<NotificationLevel>,
+    pub muted: bool,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+impl ServerNotificationSettings {
+    pub fn new(server_id: Uuid) -> Self {
+        Self {
+            server_id,
+            level: NotificationLevel::default(),
+            muted: false,
+            muted_until: None,
+            suppress_everyone: false,
+            suppress_roles: false,
+            channel_overrides: Vec::new(),
+        }
+    }
+
+    /// What applies in one channel right now. A channel inherits the server's
+    /// level unless it overrides it, and is muted if either of them is.
+    pub fn effective(&self, channel_id: Uuid, now: DateTime<Utc>) -> EffectiveNotificationSettings {
+        let channel = self.channel_overrides.iter().find(|o| o.channel_id == channel_id);
+        EffectiveNotificationSettings {
+            level: channel.and_then(|o| o.level).unwrap_or(self.level),
+            muted: is_muted(self.muted, self.muted_until, now)
+                || channel.is_some_and(|o| is_muted(o.muted, o.muted_until, now)),
+            suppress_everyone: self.suppress_everyone,
+            suppress_roles: self.suppress_roles,
+        }
+    }
+}
+
+fn is_muted(muted: bool, until: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+    muted && until.map_or(true, |until| until > now)
+}
+
+/// How a message reached the user, if it mentioned them at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MentionKind {
+    Direct,
+    Role,
+    Everyone,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EffectiveNotificationSettings {
+    pub level: NotificationLevel,
+    pub muted: bool,
+    pub suppress_everyone: bool,
+    pub suppress_roles: bool,
+}
+
+impl EffectiveNotificationSettings {
+    /// Whether a mention shows up in the channel's mention badge
+    pub fn counts_mention(&self, kind: MentionKind) -> bool {
+        self.level != NotificationLevel::Nothing
+            && match kind {
+                MentionKind::Direct => true,
+                MentionKind::Role => !self.suppress_roles,
+                MentionKind::Everyone => !self.suppress_everyone,
+            }
+    }
+
+    /// Whether a message should be pushed to the user's devices. Muting
+    /// silences pushes and the unread marker, mention badges stay.
+    pub fn should_notify(&self, mention: Option<MentionKind>) -> bool {
+        if self.muted {
+            return false;
+        }
+        match self.level {
+            NotificationLevel::All => true,
+            NotificationLevel::Mentions => mention.is_some_and(|kind| self.counts_mention(kind)),
+            NotificationLevel::Nothing => false,
+        }
+    }
+}
+
+/// Who a message mentions: `<@user_id>`, `<@&role_id>` and `@everyone`
+#[derive(Debug, Default)]
+pub struct MessageMentions {
+    pub user_ids: Vec<Uuid>,
+    pub role_ids: Vec<Uuid>,
+    pub everyone: bool,
+}
+
+impl MessageMentions {
+    pub fn parse(content: &str) -> Self {
+        Self {
+            user_ids: mentioned_user_ids(content),
+            role_ids: mention_ids(content, "<@&"),
+            everyone: content.contains("@everyone"),
+        }
+    }
+}
+
+/// Users mentioned as `<@user_id>`, without duplicates
+pub fn mentioned_user_ids(content: &str) -> Vec<Uuid> {
+    mention_ids(content, "<@")
+}
+
+fn mention_ids(content: &str, prefix: &str) -> Vec<Uuid> {
+    let mut ids = Vec::new();
+    for (start, _) in content.match_indices(prefix) {
+        let rest = &content[start + prefix.len()..];
+        // Role mentions start with "<@" too, their "&" fails to parse as a uuid
+        let Some(id) = rest.split_once('>').and_then(|(id, _)| Uuid::parse_str(id).ok()) else {
+            continue;
+        };
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
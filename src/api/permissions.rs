@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+/// Server permission bits. Stored as a `BIGINT UNSIGNED` on `roles` and OR'd
+/// together across every role a member holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Permissions(u64);
+
+impl Permissions {
+    /// Grants every other permission
+    pub const ADMINISTRATOR: Permissions = Permissions(1 << 0);
+    pub const MANAGE_SERVER: Permissions = Permissions(1 << 1);
+    pub const MANAGE_CHANNELS: Permissions = Permissions(1 << 2);
+    pub const MANAGE_MESSAGES: Permissions = Permissions(1 << 3);
+    /// Allows reading channels flagged `is_private`
+    pub const VIEW_PRIVATE_CHANNELS: Permissions = Permissions(1 << 4);
+    pub const MANAGE_EMOJIS: Permissions = Permissions(1 << 5);
+    pub const BAN_MEMBERS: Permissions = Permissions(1 << 6);
+    pub const MANAGE_WEBHOOKS: Permissions = Permissions(1 << 7);
+
+    pub const fn empty() -> Self {
+        Permissions(0)
+    }
+
+    pub const fn all() -> Self {
+        Permissions(u64::MAX)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Permissions(bits)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Permissions) -> bool {
+        self.0 & Self::ADMINISTRATOR.0 != 0 || self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Permissions {
+    type Output = Permissions;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Permissions(self.0 | rhs.0)
+    }
+}
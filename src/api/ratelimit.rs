@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Data, Request, Response};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::AuthenticatedUser;
+use crate::workspace::ServerConfig;
+
+/// Refused requests are rerouted here, so their handlers never run. Must
+/// match the `rate_limited` route.
+pub const RATE_LIMITED_PATH: &str = "/rate-limited";
+/// Buckets that have refilled completely are dropped this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Past this many buckets a sweep runs straight away, and if that doesn't
+/// free enough the longest idle buckets are dropped too
+const MAX_BUCKETS: usize = 100_000;
+
+/// `requests` per `per_seconds`, which may all be spent at once
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct BucketConfig {
+    pub requests: u32,
+    pub per_seconds: u64,
+}
+
+impl BucketConfig {
+    const fn new(requests: u32, per_seconds: u64) -> Self {
+        Self { requests, per_seconds }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.requests.max(1) as f64
+    }
+
+    /// Tokens regained per second
+    fn rate(&self) -> f64 {
+        self.capacity() / self.per_seconds.max(1) as f64
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    // Uses the X-Real-IP header for anonymous clients instead of the peer
+    // address. Only turn it on behind a reverse proxy that sets it.
+    pub trust_ip_header: bool,
+    // Counts every request a client makes, on top of its route's bucket
+    pub global: BucketConfig,
+    pub auth: BucketConfig,
+    // Sending, editing and deleting messages
+    pub messages: BucketConfig,
+    // Starting uploads, not the chunks of a resumable one
+    pub uploads: BucketConfig,
+    // Every other route
+    pub default: BucketConfig,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            trust_ip_header: false,
+            global: BucketConfig::new(50, 1),
+            auth: BucketConfig::new(5, 60),
+            messages: BucketConfig::new(10, 10),
+            uploads: BucketConfig::new(10, 60),
+            default: BucketConfig::new(60, 10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    Global,
+    Auth,
+    Messages,
+    Uploads,
+    Default,
+}
+
+impl RouteGroup {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteGroup::Global => "global",
+            RouteGroup::Auth => "auth",
+            RouteGroup::Messages => "messages",
+            RouteGroup::Uploads => "uploads",
+            RouteGroup::Default => "default",
+        }
+    }
+
+    /// Which bucket a request draws from. Requests are limited before
+    /// routing, so this goes by method and path.
+    fn classify(method: Method, path: &str) -> Self {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (_, ["auth", ..]) => RouteGroup::Auth,
+            (Method::Post, ["channels", _, "messages"] | ["servers", _, "channels", _, "messages"]) => {
+                RouteGroup::Messages
+            }
+            (Method::Patch | Method::Delete, ["channels", _, "messages", _]) => RouteGroup::Messages,
+            (Method::Post, ["channels", _, "attachments" | "uploads"]) => RouteGroup::Uploads,
+            _ => RouteGroup::Default,
+        }
+    }
+
+    fn bucket(&self, config: &RateLimitConfig) -> BucketConfig {
+        match self {
+            RouteGroup::Global => config.global,
+            RouteGroup::Auth => config.auth,
+            RouteGroup::Messages => config.messages,
+            RouteGroup::Uploads => config.uploads,
+            RouteGroup::Default => config.default,
+        }
+    }
+}
+
+/// Who a request counts against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ClientKey {
+    User(Uuid),
+    Ip(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.rate()).min(config.capacity());
+        self.updated = now;
+    }
+
+    /// Until the bucket is full again
+    fn reset_after(&self, config: &BucketConfig) -> Duration {
+        Duration::from_secs_f64(((config.capacity() - self.tokens) / config.rate()).max(0.0))
+    }
+
+    /// Until the next token
+    fn retry_after(&self, config: &BucketConfig) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / config.rate()).max(0.0))
+    }
+}
+
+/// What the limiter decided for one request, kept for the response headers
+/// and the 429 catcher
+#[derive(Debug, Clone)]
+pub struct RateLimitDecision {
+    pub bucket: RouteGroup,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset_after: Duration,
+    /// Set when the request was refused
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitDecision {
+    /// Whether the client hit the limit on all its requests rather than the route's
+    pub fn global(&self) -> bool {
+        self.bucket == RouteGroup::Global
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<(ClientKey, RouteGroup), Bucket>,
+    last_sweep: Instant,
+}
+
+impl Buckets {
+    fn sweep(&mut self, config: &RateLimitConfig, now: Instant) {
+        self.buckets.retain(|(_, group), bucket| {
+            let bucket_config = group.bucket(config);
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * bucket_config.rate()
+                < bucket_config.capacity()
+        });
+        if self.buckets.len() >= MAX_BUCKETS {
+            let mut updated: Vec<Instant> = self.buckets.values().map(|bucket| bucket.updated).collect();
+            let keep = MAX_BUCKETS / 2;
+            let (_, cutoff, _) = updated.select_nth_unstable_by(keep, |a, b| b.cmp(a));
+            let cutoff = *cutoff;
+            self.buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+        self.last_sweep = now;
+    }
+}
+
+/// Token bucket limits per user, or per ip for anonymous requests
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: config.clone(),
+            state: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes a token from both the client's global bucket and the route's,
+    /// or from neither if either is empty
+    fn check(&self, key: ClientKey, group: RouteGroup) -> RateLimitDecision {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("rate limit buckets poisoned");
+        if now.duration_since(state.last_sweep) >= SWEEP_INTERVAL || state.buckets.len() >= MAX_BUCKETS {
+            state.sweep(&self.config, now);
+        }
+
+        let (global_config, route_config) = (self.config.global, group.bucket(&self.config));
+        for (group, config) in [(RouteGroup::Global, global_config), (group, route_config)] {
+            state
+                .buckets
+                .entry((key, group))
+                .or_insert(Bucket {
+                    tokens: config.capacity(),
+                    updated: now,
+                })
+                .refill(&config, now);
+        }
+
+        let global = &state.buckets[&(key, RouteGroup::Global)];
+        if global.tokens < 1.0 {
+            return RateLimitDecision {
+                bucket: RouteGroup::Global,
+                limit: global_config.requests,
+                remaining: 0,
+                reset_after: global.reset_after(&global_config),
+                retry_after: Some(global.retry_after(&global_config)),
+            };
+        }
+        let route = &state.buckets[&(key, group)];
+        if route.tokens < 1.0 {
+            return RateLimitDecision {
+                bucket: group,
+                limit: route_config.requests,
+                remaining: 0,
+                reset_after: route.reset_after(&route_config),
+                retry_after: Some(route.retry_after(&route_config)),
+            };
+        }
+
+        for group in [RouteGroup::Global, group] {
+            if let Some(bucket) = state.buckets.get_mut(&(key, group)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        let route = &state.buckets[&(key, group)];
+        RateLimitDecision {
+            bucket: group,
+            limit: route_config.requests,
+            remaining: route.tokens.floor() as u32,
+            reset_after: route.reset_after(&route_config),
+            retry_after: None,
+        }
+    }
+
+    /// Auth routes always go by ip, so logging in as many accounts doesn't
+    /// buy more guesses. Elsewhere users with a valid token are keyed by id.
+    async fn client_key(&self, request: &Request<'_>, group: RouteGroup) -> Option<ClientKey> {
+        if group != RouteGroup::Auth && request.headers().contains("Authorization") {
+            if let Outcome::Success(user) = request.guard::<AuthenticatedUser>().await {
+                return Some(ClientKey::User(user.user_id));
+            }
+        }
+        client_ip(request, self.config.trust_ip_header).map(ClientKey::Ip)
+    }
+}
+
+fn client_ip(request: &Request<'_>, trust_ip_header: bool) -> Option<IpAddr> {
+    if trust_ip_header {
+        request.client_ip()
+    } else {
+        request.remote().map(|remote| remote.ip())
+    }
+}
+
+/// The client's address, the same one rate limits are keyed by
+pub struct ClientIp(pub IpAddr);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let trust_ip_header = request
+            .rocket()
+            .state::<ServerConfig>()
+            .is_some_and(|config| config.rate_limits.trust_ip_header);
+        match client_ip(request, trust_ip_header) {
+            Some(ip) => Outcome::Success(ClientIp(ip)),
+            None => Outcome::Error((Status::BadRequest, ())),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiter",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if !self.config.enabled {
+            return;
+        }
+        let group = RouteGroup::classify(request.method(), request.uri().path().as_str());
+        let Some(key) = self.client_key(request, group).await else {
+            return;
+        };
+        let decision = self.check(key, group);
+        let refused = decision.retry_after.is_some();
+        request.local_cache(|| Some(decision));
+        if refused {
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(RATE_LIMITED_PATH).expect("valid rate limit path"));
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(decision) = request.local_cache(|| None::<RateLimitDecision>) else {
+            return;
+        };
+        let reset = Utc::now().timestamp() + decision.reset_after.as_secs_f64().ceil() as i64;
+        response.set_raw_header("X-RateLimit-Limit", decision.limit.to_string());
+        response.set_raw_header("X-RateLimit-Remaining", decision.remaining.to_string());
+        response.set_raw_header("X-RateLimit-Reset", reset.to_string());
+        response.set_raw_header("X-RateLimit-Reset-After", format!("{:.3}", decision.reset_after.as_secs_f64()));
+        response.set_raw_header("X-RateLimit-Bucket", decision.bucket.as_str());
+        if let Some(retry_after) = decision.retry_after {
+            response.set_raw_header("Retry-After", (retry_after.as_secs_f64().ceil() as u64).max(1).to_string());
+            if decision.global() {
+                response.set_raw_header("X-RateLimit-Global", "true");
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use uuid::Uuid;
+
+use crate::workspace::get_data_dir;
+
+/// The only version of the tus protocol we speak
+pub const TUS_VERSION: &str = "1.0.0";
+/// tus extensions we implement, advertised on OPTIONS
+pub const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+/// Unfinished uploads are thrown away after this long
+pub const UPLOAD_SESSION_HOURS: i64 = 24;
+
+/// The tus headers a request carried, parsed. Every field is optional here,
+/// each route checks for the ones it needs.
+pub struct TusRequest {
+    pub upload_length: Option<u64>,
+    pub upload_offset: Option<u64>,
+    /// Decoded `Upload-Metadata` pairs
+    pub metadata: HashMap<String, String>,
+    pub content_type: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusRequest {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        // Requests without the header are let through so plain HTTP clients
+        // can use the same routes
+        if let Some(version) = headers.get_one("Tus-Resumable") {
+            if version != TUS_VERSION {
+                return Outcome::Error((
+                    Status::PreconditionFailed,
+                    format!("Unsupported tus version {version}, expected {TUS_VERSION}"),
+                ));
+            }
+        }
+
+        let number = |name: &str| match headers.get_one(name) {
+            Some(value) => value
+                .trim()
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|_| format!("{name} must be a non-negative integer")),
+            None => Ok(None),
+        };
+        let (upload_length, upload_offset) = match (number("Upload-Length"), number("Upload-Offset")) {
+            (Ok(length), Ok(offset)) => (length, offset),
+            (Err(e), _) | (_, Err(e)) => return Outcome::Error((Status::BadRequest, e)),
+        };
+        let metadata = match headers.get_one("Upload-Metadata").map(parse_metadata).transpose() {
+            Ok(metadata) => metadata.unwrap_or_default(),
+            Err(e) => return Outcome::Error((Status::BadRequest, e)),
+        };
+
+        Outcome::Success(TusRequest {
+            upload_length,
+            upload_offset,
+            metadata,
+            content_type: headers.get_one("Content-Type").map(str::to_string),
+        })
+    }
+}
+
+/// `key base64value,key2 base64value2`. Values may be left out.
+pub fn parse_metadata(header: &str) -> Result<HashMap<String, String>, String> {
+    let mut metadata = HashMap::new();
+    for pair in header.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => {
+                let value = BASE64_STANDARD
+                    .decode(value.trim())
+                    .ok()
+                    .and_then(|value| String::from_utf8(value).ok())
+                    .ok_or_else(|| format!("Upload-Metadata value for {key} is not valid base64 UTF-8"))?;
+                (key, value)
+            }
+            None => (pair, String::new()),
+        };
+        metadata.insert(key.to_string(), value);
+    }
+    Ok(metadata)
+}
+
+/// A bodiless tus response carrying upload state in its headers
+pub struct TusResponse {
+    status: Status,
+    headers: Vec<Header<'static>>,
+}
+
+impl TusResponse {
+    pub fn new(status: Status) -> Self {
+        Self {
+            status,
+            headers: vec![Header::new("Tus-Resumable", TUS_VERSION)],
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push(Header::new(name, value.to_string()));
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.status(self.status);
+        for header in self.headers {
+            response.header(header);
+        }
+        response.ok()
+    }
+}
+
+/// Uploads that have a chunk being written right now. tus asks servers to
+/// refuse concurrent writes to one upload rather than interleave them.
+#[derive(Clone, Default)]
+pub struct UploadLocks {
+    active: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+impl UploadLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// None when another request already holds the upload
+    pub fn acquire(&self, upload_id: Uuid) -> Option<UploadLock> {
+        let mut active = self.active.lock().expect("upload locks poisoned");
+        active.insert(upload_id).then(|| UploadLock {
+            locks: self.clone(),
+            upload_id,
+        })
+    }
+}
+
+pub struct UploadLock {
+    locks: UploadLocks,
+    upload_id: Uuid,
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        self.locks.active.lock().expect("upload locks poisoned").remove(&self.upload_id);
+    }
+}
+
+/// Where chunks are assembled before the finished file goes to storage. This
+/// is always local disk, whatever the storage backend is.
+pub fn partial_upload_path(upload_id: Uuid) -> Result<PathBuf> {
+    let mut path = get_data_dir().context("Failed to obtain data dir")?;
+    path.push("uploads");
+    path.push(format!("{upload_id}.part"));
+    Ok(path)
+}
+
+/// Opens the partial file for appending at `offset`. Anything past it was
+/// written by a chunk that never got recorded (a dropped connection, a crash)
+/// and is cut off, so the database offset is always the truth.
+pub async fn open_partial_upload(upload_id: Uuid, offset: u64) -> Result<tokio::fs::File> {
+    let path = partial_upload_path(upload_id)?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_context(|| format!("Failed to create {parent:#?}"))?;
+    }
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .with_context(|| format!("Failed to open {path:#?}"))?;
+    file.set_len(offset)
+        .await
+        .with_context(|| format!("Failed to truncate {path:#?}"))?;
+    Ok(file)
+}
+
+pub async fn remove_partial_upload(upload_id: Uuid) -> Result<()> {
+    let path = partial_upload_path(upload_id)?;
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {path:#?}"))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,278 @@
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::path::PathBuf;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{ImageDecoder, ImageFormat, ImageReader};
+use img_parts::jpeg::{markers, Jpeg};
+use img_parts::png::Png;
+use img_parts::webp::WebP;
+use img_parts::{Bytes, ImageEXIF};
+use tokio::io::AsyncReadExt;
+
+use super::AttachmentType;
+use crate::storage::UploadSource;
+
+/// Bytes read from the start of a file to recognise its type
+const SNIFF_LEN: usize = 8 * 1024;
+/// Images claiming to be bigger than this are refused before decoding
+const MAX_IMAGE_DIMENSION: u32 = 16384;
+/// Quality JPEGs are re-encoded at when their orientation is baked in
+const JPEG_QUALITY: u8 = 90;
+
+/// What an upload turned out to be, as opposed to what the client said it was
+#[derive(Debug)]
+pub struct InspectedUpload {
+    pub mime_type: String,
+    pub attachment_type: AttachmentType,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// Whole seconds, only for videos we can parse
+    pub duration: Option<i32>,
+    /// The image with its EXIF/XMP metadata removed. When set this is what
+    /// gets stored rather than the original upload.
+    pub sanitized: Option<Vec<u8>>,
+}
+
+/// Sniffs an upload's real type from its magic bytes and pulls out the
+/// metadata clients need to lay it out before downloading it
+pub async fn inspect_upload(file: UploadSource<'_>, max_size: u64) -> Result<InspectedUpload, String> {
+    if file.len() == 0 {
+        return Err("File is empty".to_string());
+    }
+    if file.len() > max_size {
+        return Err(format!(
+            "File is {} but the upload limit is {}",
+            format_size(file.len()),
+            format_size(max_size)
+        ));
+    }
+
+    let head = read_head(file).await?;
+    let mime_type = match infer::get(&head) {
+        Some(kind) => kind.mime_type().to_string(),
+        None if std::str::from_utf8(&head).is_ok() => "text/plain".to_string(),
+        None => "application/octet-stream".to_string(),
+    };
+
+    match mime_type.split('/').next() {
+        Some("image") => inspect_image(file, mime_type).await,
+        Some("video") => inspect_video(file, mime_type).await,
+        _ => Ok(plain_file(mime_type)),
+    }
+}
+
+fn plain_file(mime_type: String) -> InspectedUpload {
+    InspectedUpload {
+        mime_type,
+        attachment_type: AttachmentType::File,
+        width: None,
+        height: None,
+        duration: None,
+        sanitized: None,
+    }
+}
+
+async fn read_head(file: UploadSource<'_>) -> Result<Vec<u8>, String> {
+    let stream = file.open().await.map_err(|e| format!("Failed to read file: {e}"))?;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    stream
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .await
+        .map_err(|e| format!("Failed to read file: {e}"))?;
+    Ok(head)
+}
+
+async fn read_all(file: UploadSource<'_>) -> Result<Vec<u8>, String> {
+    let mut stream = file.open().await.map_err(|e| format!("Failed to read file: {e}"))?;
+    let mut bytes = Vec::with_capacity(file.len() as usize);
+    stream
+        .read_to_end(&mut bytes)
+        .await
+        .map_err(|e| format!("Failed to read file: {e}"))?;
+    Ok(bytes)
+}
+
+/// Images are held in memory so their metadata can be stripped. They are
+/// already bounded by the upload limit.
+async fn inspect_image(file: UploadSource<'_>, mime_type: String) -> Result<InspectedUpload, String> {
+    let bytes = read_all(file).await?;
+    tokio::task::spawn_blocking(move || {
+        let reader = ImageReader::new(Cursor::new(&bytes))
+            .with_guessed_format()
+            .map_err(|e| format!("Failed to read image: {e}"))?;
+        let Ok((width, height)) = reader.into_dimensions() else {
+            // A format we can't parse (HEIC, ...), hand it out as a plain file
+            return Ok(plain_file(mime_type));
+        };
+        if width > MAX_IMAGE_DIMENSION || height > MAX_IMAGE_DIMENSION {
+            return Err(format!(
+                "Image is {width}x{height}, images can be at most {MAX_IMAGE_DIMENSION}x{MAX_IMAGE_DIMENSION}"
+            ));
+        }
+
+        // The orientation goes with the rest of the EXIF, so it's applied
+        // first and the size reported is the upright one
+        let orientation = read_orientation(&bytes);
+        let (width, height) = match orientation {
+            Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH => {
+                (height, width)
+            }
+            _ => (width, height),
+        };
+
+        let sanitized = strip_metadata(&mime_type, bytes, orientation)?;
+        Ok(InspectedUpload {
+            mime_type,
+            attachment_type: AttachmentType::Image,
+            width: Some(width as i32),
+            height: Some(height as i32),
+            duration: None,
+            sanitized,
+        })
+    })
+    .await
+    .map_err(|e| format!("Failed to inspect image: {e}"))?
+}
+
+/// The EXIF orientation, if the image has one we can read
+fn read_orientation(bytes: &[u8]) -> Orientation {
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.orientation().ok())
+        .unwrap_or(Orientation::NoTransforms)
+}
+
+/// Removes EXIF, XMP and text metadata (and with them GPS tags) from the
+/// formats that carry it. Returns None for formats that have nothing to strip.
+fn strip_metadata(mime_type: &str, bytes: Vec<u8>, orientation: Orientation) -> Result<Option<Vec<u8>>, String> {
+    let mut out = Vec::new();
+    if mime_type == "image/jpeg" && orientation != Orientation::NoTransforms {
+        // JPEGs are never animated, so the rotation is baked into the pixels.
+        // Re-encoding drops every metadata segment along the way.
+        let mut image = ImageReader::with_format(Cursor::new(&bytes), ImageFormat::Jpeg)
+            .decode()
+            .map_err(|e| format!("Invalid JPEG: {e}"))?;
+        image.apply_orientation(orientation);
+        image
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))
+            .map_err(|e| format!("Failed to strip image metadata: {e}"))?;
+        return Ok(Some(out));
+    }
+    // PNGs and WebPs may be animated, so rather than re-encoding them they
+    // keep an EXIF block holding nothing but the orientation
+    let exif = (orientation != Orientation::NoTransforms).then(|| orientation_exif(orientation));
+
+    let bytes = Bytes::from(bytes);
+    let written = match mime_type {
+        "image/jpeg" => {
+            let mut jpeg = Jpeg::from_bytes(bytes).map_err(|e| format!("Invalid JPEG: {e}"))?;
+            // APP1 carries both EXIF and XMP, either can hold a location
+            jpeg.segments_mut().retain(|segment| segment.marker() != markers::APP1);
+            jpeg.encoder().write_to(&mut out)
+        }
+        "image/png" => {
+            let mut png = Png::from_bytes(bytes).map_err(|e| format!("Invalid PNG: {e}"))?;
+            // XMP lives in an iTXt chunk, and any text chunk can hold a location
+            png.chunks_mut()
+                .retain(|chunk| !matches!(&chunk.kind(), b"tEXt" | b"zTXt" | b"iTXt" | b"tIME"));
+            png.set_exif(exif);
+            png.encoder().write_to(&mut out)
+        }
+        "image/webp" => {
+            let mut webp = WebP::from_bytes(bytes).map_err(|e| format!("Invalid WebP: {e}"))?;
+            webp.chunks_mut().retain(|chunk| &chunk.id() != b"XMP ");
+            webp.set_exif(exif);
+            webp.encoder().write_to(&mut out)
+        }
+        _ => return Ok(None),
+    };
+    written.map_err(|e| format!("Failed to strip image metadata: {e}"))?;
+    Ok(Some(out))
+}
+
+/// A big endian TIFF block with a single IFD entry, the orientation tag
+fn orientation_exif(orientation: Orientation) -> Bytes {
+    let mut tiff = Vec::with_capacity(26);
+    tiff.extend_from_slice(b"MM\0\x2a");
+    tiff.extend_from_slice(&8u32.to_be_bytes()); // first IFD offset
+    tiff.extend_from_slice(&1u16.to_be_bytes()); // entry count
+    tiff.extend_from_slice(&0x0112u16.to_be_bytes()); // Orientation
+    tiff.extend_from_slice(&3u16.to_be_bytes()); // SHORT
+    tiff.extend_from_slice(&1u32.to_be_bytes()); // value count
+    tiff.extend_from_slice(&(orientation.to_exif() as u16).to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]); // pads the value to four bytes
+    tiff.extend_from_slice(&0u32.to_be_bytes()); // no next IFD
+    Bytes::from(tiff)
+}
+
+/// Reads dimensions and duration out of MP4/QuickTime containers. Other
+/// video formats are accepted without them.
+async fn inspect_video(file: UploadSource<'_>, mime_type: String) -> Result<InspectedUpload, String> {
+    let mut inspected = InspectedUpload {
+        attachment_type: AttachmentType::Video,
+        ..plain_file(mime_type)
+    };
+    if !matches!(inspected.mime_type.as_str(), "video/mp4" | "video/quicktime") {
+        return Ok(inspected);
+    }
+
+    let size = file.len();
+    let source = match file.path() {
+        Some(path) => VideoSource::Path(path.to_path_buf()),
+        None => VideoSource::Memory(read_all(file).await?),
+    };
+    let header = tokio::task::spawn_blocking(move || match source {
+        VideoSource::Path(path) => {
+            let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+            read_mp4_header(BufReader::new(file), size)
+        }
+        VideoSource::Memory(bytes) => read_mp4_header(Cursor::new(bytes), size),
+    })
+    .await
+    .map_err(|e| format!("Failed to inspect video: {e}"))?;
+
+    // A video we can't parse is still a video, it just won't have a size hint
+    if let Ok((width, height, duration)) = header {
+        inspected.width = width;
+        inspected.height = height;
+        inspected.duration = Some(duration);
+    }
+    Ok(inspected)
+}
+
+enum VideoSource {
+    Path(PathBuf),
+    Memory(Vec<u8>),
+}
+
+fn read_mp4_header<R: Read + Seek>(reader: R, size: u64) -> Result<(Option<i32>, Option<i32>, i32), String> {
+    let mp4 = mp4::Mp4Reader::read_header(reader, size).map_err(|e| e.to_string())?;
+    let video = mp4
+        .tracks()
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Video)));
+    Ok((
+        video.map(|track| track.width() as i32),
+        video.map(|track| track.height() as i32),
+        mp4.duration().as_secs().min(i32::MAX as u64) as i32,
+    ))
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
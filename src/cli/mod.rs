@@ -1,6 +1,9 @@
 use crate::{
     api::start_listener,
     db::start_db,
+    search::build_search,
+    storage::{build_storage, gc::collect_garbage},
+    user::auth,
     workspace::{get_config, is_initalized, ServerConfig},
 };
 use anyhow::{Context, Ok, Result};
@@ -37,6 +40,20 @@ enum Commands {
         /// Runs the server as a background process
         daemon: bool,
     },
+    /// Removes orphaned attachments, abandoned uploads and unreferenced files
+    Gc {
+        #[arg(long)]
+        /// Only report what would be removed
+        dry_run: bool,
+    },
+    /// Rebuilds the message search index from the database. Stop the server
+    /// first, the embedded index can't be written by two processes at once.
+    Reindex,
+    /// Lifts a login lockout and clears the failed attempts behind it
+    Unlock {
+        /// Username or email of the account, or an ip address
+        target: String,
+    },
 }
 
 pub async fn run_cli() -> Result<()> {
@@ -98,11 +115,36 @@ pub async fn run_cli() -> Result<()> {
                 }
             });
             debug!("server_config = {config:#?}");
-            let listener = start_listener(&config);
-            let db = start_db(&config);
-            tokio::join!(listener, db).0.unwrap_or_else(|e| loop {
-                
-            });
+            // The listener hands the pool to every route, so the db has to come up first
+            let db = start_db(&config).await;
+            start_listener(&config, db.pool).await?;
+            Ok(())
+        }
+        Some(Commands::Gc { dry_run }) => {
+            let config = get_config()?;
+            let db = start_db(&config).await;
+            let storage = build_storage(&config.storage)?;
+            let report = collect_garbage(&db.pool, &storage, dry_run).await?;
+            println!("{report}");
+            Ok(())
+        }
+        Some(Commands::Reindex) => {
+            let config = get_config()?;
+            let db = start_db(&config).await;
+            let search = build_search(&config.search, db.pool.clone())?;
+            let indexed = search.rebuild(&db.pool).await?;
+            println!("Indexed {indexed} messages");
+            Ok(())
+        }
+        Some(Commands::Unlock { target }) => {
+            let config = get_config()?;
+            let db = start_db(&config).await;
+            if auth::unlock(&db.pool, target.trim()).await? {
+                println!("Unlocked {target}");
+            } else {
+                eprintln!("No account found for {target}");
+                std::process::exit(1);
+            }
             Ok(())
         }
         None => {
@@ -1,6 +1,15 @@
 use std::{path::PathBuf, str::FromStr};
 
-use crate::workspace::{self, get_server_dir, Port, ServerConfig};
+use crate::api::signing::generate_signing_key;
+use crate::storage::StorageConfig;
+use crate::mail::MailConfig;
+use crate::push::PushConfig;
+use crate::search::SearchConfig;
+use crate::api::ratelimit::RateLimitConfig;
+use crate::user::auth::LoginProtectionConfig;
+use crate::webhooks::WebhookConfig;
+use crate::unfurl::UnfurlConfig;
+use crate::workspace::{self, get_server_dir, Port, ServerConfig, DEFAULT_ATTACHMENT_URL_TTL, DEFAULT_MAX_UPLOAD_SIZE};
 use anyhow::{Context, Result};
 use inquire::{Confirm, Password, Select, Text};
 
@@ -77,8 +86,17 @@ pub fn create_config_interactive() -> Result<ServerConfig> {
         db_user,
         db_pass,
         db_name,
-
-
+        storage: StorageConfig::default(),
+        max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+        url_signing_key: generate_signing_key(),
+        attachment_url_ttl: DEFAULT_ATTACHMENT_URL_TTL,
+        unfurl: UnfurlConfig::default(),
+        search: SearchConfig::default(),
+        push: PushConfig::generate(),
+        webhooks: WebhookConfig::default(),
+        rate_limits: RateLimitConfig::default(),
+        login_protection: LoginProtectionConfig::default(),
+        mail: MailConfig::default(),
     };
     let config_path = get_server_dir().context("Failed to obtain config path")?;
 
@@ -1,7 +1,8 @@
 mod tableconfig;
-mod queries;
+pub mod queries;
 use crate::cli::*;
 
+use crate::user::auth;
 use crate::workspace::ServerConfig;
 use anyhow::Result;
 use log::debug;
@@ -56,7 +57,8 @@ async fn db_setup(config: &ServerConfig) -> MySqlConnect {
                 "We were unable to commit the data to disk and are crashing, generally this indicates a connection issue, no data was not written to the disk so your data should be OK"
             );
         }
-        Err(_) => {
+        Err(e) => {
+            log::error!("Failed to set up the database tables: {e}");
             transaction.rollback().await.expect(
                 "We were unable to drop the transaction, and are crashing. Data was not written to disk so your data should be OK"
             )
@@ -65,14 +67,28 @@ async fn db_setup(config: &ServerConfig) -> MySqlConnect {
 
     // These are testing creds, dont even try it
     // Pass the pool reference directly, not a connection
-    queries::register_user(&db_connect.pool, "Caz", "admin@occult.chat", "oeisntjvlketnvdfhdlgnjflkjdhl")
-        .await
-        .expect("Failed to register user"); // Added error handling
+    // Already there on every start after the first, which is fine
+    let password = "oeisntjvlketnvdfhdlgnjflkjdhl";
+    match auth::hash_password(password) {
+        Ok(password_hash) => match queries::register_user(&db_connect.pool, "Caz", "admin@occult.chat", &password_hash).await {
+            Ok(_) => debug!("Seeded the test user"),
+            // Earlier versions stored the password as is, which never verifies
+            Err(error::Error::Database(e)) if e.is_unique_violation() => {
+                match queries::replace_password_hash(&db_connect.pool, "Caz", password, &password_hash).await {
+                    Ok(true) => debug!("Hashed the test user's password"),
+                    Ok(false) => debug!("Test user already seeded"),
+                    Err(e) => log::error!("Failed to hash the test user's password: {e}"),
+                }
+            }
+            Err(e) => log::error!("Failed to seed the test user: {e}"),
+        },
+        Err(e) => log::error!("Failed to hash the test user's password: {e}"),
+    }
 
     db_connect
 }
 
-pub async fn start_db(config: &ServerConfig) {
+pub async fn start_db(config: &ServerConfig) -> MySqlConnect {
     log::error!("Starting DB on port {:#?}", config.db_port);
-    db_setup(&config).await;
+    db_setup(&config).await
 }
\ No newline at end of file
@@ -1,9 +1,20 @@
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 
+use sqlx::types::Json;
 use sqlx::{MySql, Pool};
 use uuid::Uuid;
 use rand;
 use super::super::api::*;
+use super::super::api::permissions::Permissions;
+use super::super::api::notifications::{
+    ChannelNotificationOverride, MentionKind, MessageMentions, NotificationLevel,
+    ServerNotificationSettings,
+};
+use crate::search::{HasFilter, IndexedMessage};
+use crate::interactions::CommandOption;
+use crate::user::auth::{LockoutKind, LoginOutcome};
+use crate::webhooks::WebhookEvent;
 use chrono::{DateTime, Utc};
 
 // Auth & User Management
@@ -23,24 +34,27 @@ pub async fn register_user(
     Ok(id)
 }
 
+/// Swaps the password hash of the user by that name, if it's still `old_hash`
+pub async fn replace_password_hash(
+    pool: &Pool<MySql>,
+    username: &str,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE users SET password_hash = ? WHERE username = ? AND password_hash = ?",
+        new_hash, username, old_hash
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
 
 pub async fn login_user(
     pool: &Pool<MySql>,
     email: &str,
     password: &str,
 ) -> Result<Option<User>, sqlx::Error> {
-    #[derive(sqlx::FromRow)]
-    struct UserRow {
-        id: Vec<u8>,
-        username: String,
-        display_name: Option<String>,
-        email: String,
-        avatar: Option<String>,
-        status: String,  // MySQL ENUM comes as String
-        custom_status: Option<String>,
-        created_at: DateTime<Utc>,
-    }
-
     let user_row = sqlx::query_as!(
         UserRow,
         r#"SELECT 
@@ -49,9 +63,14 @@ pub async fn login_user(
             display_name,
             email,
             avatar,
+            banner,
+            bio,
+            pronouns,
+            is_bot as "is_bot: bool",
             status as "status: String",
             custom_status,
-            created_at
+            created_at,
+            updated_at
         FROM users 
         WHERE email = ? AND password_hash = ?"#,
         email, password
@@ -59,19 +78,200 @@ pub async fn login_user(
     .fetch_optional(pool)
     .await?;
 
-    Ok(user_row.map(|row| User {
-        id: Uuid::from_slice(&row.id).unwrap(),
-        username: row.username,
-        display_name: row.display_name,
-        email: row.email,
-        avatar: row.avatar,
-        status: UserStatus::from_str(&row.status).unwrap_or(UserStatus::Offline),
-        custom_status: row.custom_status,
-        created_at: row.created_at,
-        updated_at: Utc::now(),
+    Ok(user_row.and_then(UserRow::into_user))
+}
+
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    id: Vec<u8>,
+    username: String,
+    display_name: Option<String>,
+    email: Option<String>,
+    avatar: Option<String>,
+    banner: Option<String>,
+    bio: Option<String>,
+    pronouns: Option<String>,
+    is_bot: bool,
+    status: String,  // MySQL ENUM comes as String
+    custom_status: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl UserRow {
+    fn into_user(self) -> Option<User> {
+        Some(User {
+            id: Uuid::from_slice(&self.id).ok()?,
+            username: self.username,
+            display_name: self.display_name,
+            email: self.email,
+            avatar: self.avatar,
+            banner: self.banner,
+            bio: self.bio,
+            pronouns: self.pronouns,
+            bot: self.is_bot,
+            status: UserStatus::from_str(&self.status).unwrap_or(UserStatus::Offline),
+            custom_status: self.custom_status,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        })
+    }
+}
+
+/// The full account, including private fields. Only hand this to its owner.
+pub async fn get_user(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<Option<User>, sqlx::Error> {
+    let user_row = sqlx::query_as!(
+        UserRow,
+        r#"SELECT 
+            id,
+            username,
+            display_name,
+            email,
+            avatar,
+            banner,
+            bio,
+            pronouns,
+            is_bot as "is_bot: bool",
+            status as "status: String",
+            custom_status,
+            created_at,
+            updated_at
+        FROM users 
+        WHERE id = ?"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(user_row.and_then(UserRow::into_user))
+}
+
+pub async fn get_public_user(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<Option<PublicUser>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, username, display_name, avatar, banner, bio, pronouns, is_bot as "is_bot: bool", created_at
+         FROM users
+         WHERE id = ?"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(PublicUser {
+            id: Uuid::from_slice(&row.id).ok()?,
+            username: row.username,
+            display_name: row.display_name,
+            avatar: row.avatar,
+            banner: row.banner,
+            bio: row.bio,
+            pronouns: row.pronouns,
+            bot: row.is_bot,
+            created_at: row.created_at,
+        })
     }))
 }
 
+pub async fn username_taken(
+    pool: &Pool<MySql>,
+    username: &str,
+    except_user: Uuid,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM users WHERE username = ? AND id <> ?) as "taken!: bool""#,
+        username, except_user
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn count_username_changes_since(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!: i64" FROM username_history WHERE user_id = ? AND changed_at > ?"#,
+        user_id, since
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Profile fields to change. The outer Option is "leave as is", the inner
+/// one clears the field.
+#[derive(Debug, Default)]
+pub struct ProfileUpdate {
+    pub username: Option<String>,
+    pub display_name: Option<Option<String>>,
+    pub avatar: Option<Option<String>>,
+    pub banner: Option<Option<String>>,
+    pub bio: Option<Option<String>>,
+    pub pronouns: Option<Option<String>>,
+    pub status: Option<UserStatus>,
+    pub custom_status: Option<Option<String>>,
+    pub custom_status_expires_at: Option<Option<DateTime<Utc>>>,
+}
+
+/// Applies the whole update or none of it. Username changes are recorded in
+/// username_history.
+pub async fn update_user_profile(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    update: ProfileUpdate,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    if let Some(username) = &update.username {
+        sqlx::query!(
+            "INSERT INTO username_history (user_id, old_username)
+             SELECT id, username FROM users WHERE id = ?",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("UPDATE users SET username = ? WHERE id = ?", username, user_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let fields = [
+        ("display_name", update.display_name),
+        ("avatar", update.avatar),
+        ("banner", update.banner),
+        ("bio", update.bio),
+        ("pronouns", update.pronouns),
+        ("status", update.status.map(|status| Some(status.to_string()))),
+        ("custom_status", update.custom_status),
+    ];
+    let expires_at = update.custom_status_expires_at;
+    if fields.iter().any(|(_, value)| value.is_some()) || expires_at.is_some() {
+        let mut query = sqlx::QueryBuilder::<MySql>::new("UPDATE users SET ");
+        let mut set = query.separated(", ");
+        for (column, value) in fields {
+            if let Some(value) = value {
+                set.push(format!("{column} = "));
+                set.push_bind_unseparated(value);
+            }
+        }
+        if let Some(expires_at) = expires_at {
+            set.push("custom_status_expires_at = ");
+            set.push_bind_unseparated(expires_at);
+        }
+        query.push(" WHERE id = ");
+        query.push_bind(user_id);
+        query.build().execute(&mut *tx).await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
 pub async fn update_user_status(
     pool: &Pool<MySql>,
     user_id: Uuid,
@@ -87,6 +287,416 @@ pub async fn update_user_status(
     Ok(())
 }
 
+/// The status a user picked for themselves plus their custom status
+pub async fn get_presence_settings(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<Option<(UserStatus, Option<String>)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT status as "status: String", custom_status
+        FROM users
+        WHERE id = ?"#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| {
+        (
+            UserStatus::from_str(&row.status).unwrap_or(UserStatus::Online),
+            row.custom_status,
+        )
+    }))
+}
+
+/// Which of the users have chosen the status
+pub async fn get_users_with_status(
+    pool: &Pool<MySql>,
+    user_ids: &[Uuid],
+    status: UserStatus,
+) -> Result<HashSet<Uuid>, sqlx::Error> {
+    if user_ids.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let mut query = sqlx::QueryBuilder::<MySql>::new("SELECT id FROM users WHERE status = ");
+    query.push_bind(status.to_string());
+    query.push(" AND id IN (");
+    let mut ids = query.separated(", ");
+    for id in user_ids {
+        ids.push_bind(*id);
+    }
+    query.push(")");
+    let rows: Vec<Vec<u8>> = query.build_query_scalar().fetch_all(pool).await?;
+    Ok(rows.iter().filter_map(|id| Uuid::from_slice(id).ok()).collect())
+}
+
+pub async fn update_custom_status(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    custom_status: Option<&str>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE users SET custom_status = ?, custom_status_expires_at = ? WHERE id = ?",
+        custom_status, expires_at, user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Clears custom statuses past their expiry, returning whose were cleared
+pub async fn clear_expired_custom_statuses(
+    pool: &Pool<MySql>,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let rows = sqlx::query!(
+        "SELECT id FROM users
+         WHERE custom_status_expires_at IS NOT NULL AND custom_status_expires_at <= CURRENT_TIMESTAMP
+         FOR UPDATE"
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE users SET custom_status = NULL, custom_status_expires_at = NULL
+         WHERE custom_status_expires_at IS NOT NULL AND custom_status_expires_at <= CURRENT_TIMESTAMP"
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Uuid::from_slice(&row.id).ok())
+        .collect())
+}
+
+/// Everyone who shares at least one server with the user, and their friends
+pub async fn get_presence_audience(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"WITH memberships AS (
+            SELECT server_id, user_id FROM server_members
+            UNION
+            SELECT id, owner_id FROM servers
+        )
+        SELECT other.user_id as "user_id!: Vec<u8>"
+        FROM memberships me
+        JOIN memberships other ON other.server_id = me.server_id
+        WHERE me.user_id = ? AND other.user_id <> ?
+        UNION
+        SELECT friend_id FROM friendships WHERE user_id = ? AND accepted
+        UNION
+        SELECT user_id FROM friendships WHERE friend_id = ? AND accepted"#,
+        user_id, user_id, user_id, user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Uuid::from_slice(&row.user_id).ok())
+        .collect())
+}
+
+// Friends
+
+/// The user's friends and pending requests either way, newest first
+pub async fn get_friends(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<Vec<(PublicUser, FriendStatus, DateTime<Utc>)>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT u.id, u.username, u.display_name, u.avatar, u.banner, u.bio, u.pronouns,
+            u.is_bot as "is_bot: bool", u.created_at,
+            f.accepted as "accepted: bool", f.user_id = ? as "outgoing!: bool",
+            COALESCE(f.accepted_at, f.created_at) as "since!: DateTime<Utc>"
+        FROM friendships f
+        JOIN users u ON u.id = IF(f.user_id = ?, f.friend_id, f.user_id)
+        WHERE f.user_id = ? OR f.friend_id = ?
+        ORDER BY since DESC"#,
+        user_id, user_id, user_id, user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let status = match (row.accepted, row.outgoing) {
+                (true, _) => FriendStatus::Friends,
+                (false, true) => FriendStatus::Outgoing,
+                (false, false) => FriendStatus::Incoming,
+            };
+            let user = PublicUser {
+                id: Uuid::from_slice(&row.id).ok()?,
+                username: row.username,
+                display_name: row.display_name,
+                avatar: row.avatar,
+                banner: row.banner,
+                bio: row.bio,
+                pronouns: row.pronouns,
+                bot: row.is_bot,
+                created_at: row.created_at,
+            };
+            Some((user, status, row.since))
+        })
+        .collect())
+}
+
+/// Where the two users stand, from `user_id`'s side
+pub async fn get_friend_status(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    other_id: Uuid,
+) -> Result<Option<FriendStatus>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT accepted as "accepted: bool", user_id = ? as "outgoing!: bool"
+        FROM friendships
+        WHERE (user_id = ? AND friend_id = ?) OR (user_id = ? AND friend_id = ?)"#,
+        user_id, user_id, other_id, other_id, user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| match (row.accepted, row.outgoing) {
+        (true, _) => FriendStatus::Friends,
+        (false, true) => FriendStatus::Outgoing,
+        (false, false) => FriendStatus::Incoming,
+    }))
+}
+
+/// Sends a friend request, or accepts theirs if they already sent one.
+/// Returns where the two stand afterwards.
+pub async fn add_friend(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    other_id: Uuid,
+) -> Result<FriendStatus, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let accepted = sqlx::query!(
+        "UPDATE friendships SET accepted = true, accepted_at = CURRENT_TIMESTAMP
+         WHERE user_id = ? AND friend_id = ? AND NOT accepted",
+        other_id, user_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if !accepted {
+        sqlx::query!(
+            "INSERT IGNORE INTO friendships (user_id, friend_id)
+             SELECT ?, ? FROM DUAL
+             WHERE NOT EXISTS (SELECT 1 FROM friendships WHERE user_id = ? AND friend_id = ?)",
+            user_id, other_id, other_id, user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let status = sqlx::query_scalar!(
+        r#"SELECT accepted as "accepted: bool" FROM friendships
+        WHERE (user_id = ? AND friend_id = ?) OR (user_id = ? AND friend_id = ?)"#,
+        user_id, other_id, other_id, user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(if status { FriendStatus::Friends } else { FriendStatus::Outgoing })
+}
+
+/// Unfriends, declines or cancels, whichever applies. False if there was nothing between them.
+pub async fn remove_friend(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    other_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM friendships WHERE (user_id = ? AND friend_id = ?) OR (user_id = ? AND friend_id = ?)",
+        user_id, other_id, other_id, user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Sessions
+/// Starts a session, and clears out the user's expired ones while at it
+pub async fn create_session(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    token_hash: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM sessions WHERE user_id = ? AND expires_at <= CURRENT_TIMESTAMP",
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO sessions (id, user_id, token_hash, expires_at) VALUES (?, ?, ?, ?)",
+        Uuid::new_v4(), user_id, token_hash, expires_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Who the session token belongs to, if it hasn't expired
+pub async fn get_session_user(
+    pool: &Pool<MySql>,
+    token_hash: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM sessions WHERE token_hash = ? AND expires_at > CURRENT_TIMESTAMP",
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(user_id.and_then(|id| Uuid::from_slice(&id).ok()))
+}
+
+// Login Protection
+pub struct LoginAccount {
+    pub id: Uuid,
+    pub email: Option<String>,
+    pub password_hash: Option<String>,
+}
+
+/// The person signing in with this username or email. Bots can't sign in.
+pub async fn get_login_account(
+    pool: &Pool<MySql>,
+    identifier: &str,
+) -> Result<Option<LoginAccount>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id, email, password_hash FROM users WHERE (username = ? OR email = ?) AND NOT is_bot LIMIT 1",
+        identifier, identifier
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(LoginAccount {
+            id: Uuid::from_slice(&row.id).ok()?,
+            email: row.email,
+            password_hash: row.password_hash,
+        })
+    }))
+}
+
+pub async fn record_login_attempt(
+    pool: &Pool<MySql>,
+    user_id: Option<Uuid>,
+    identifier: Option<&str>,
+    ip: Option<&str>,
+    outcome: LoginOutcome,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO login_attempts (user_id, identifier, ip, outcome) VALUES (?, ?, ?, ?)",
+        user_id, identifier, ip, outcome.as_str()
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Wrong passwords for the account since `since`, not counting any before
+/// its last successful sign-in or unlock, and when the latest was
+pub async fn count_failed_logins_for_user(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<(i64, Option<DateTime<Utc>>), sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT COUNT(*) as "failures!: i64", MAX(created_at) as "last_failure: DateTime<Utc>"
+        FROM login_attempts
+        WHERE user_id = ? AND outcome = 'invalid_credentials' AND created_at > ?
+            AND id > COALESCE(
+                (SELECT MAX(id) FROM login_attempts WHERE user_id = ? AND outcome IN ('success', 'unlocked')),
+                0
+            )"#,
+        user_id, since, user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok((row.failures, row.last_failure))
+}
+
+/// Wrong passwords from the ip for any account since `since`, not counting
+/// any before it was last unlocked, and when the latest was
+pub async fn count_failed_logins_from_ip(
+    pool: &Pool<MySql>,
+    ip: &str,
+    since: DateTime<Utc>,
+) -> Result<(i64, Option<DateTime<Utc>>), sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT COUNT(*) as "failures!: i64", MAX(created_at) as "last_failure: DateTime<Utc>"
+        FROM login_attempts
+        WHERE ip = ? AND outcome = 'invalid_credentials' AND created_at > ?
+            AND id > COALESCE(
+                (SELECT MAX(id) FROM login_attempts WHERE ip = ? AND user_id IS NULL AND outcome = 'unlocked'),
+                0
+            )"#,
+        ip, since, ip
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok((row.failures, row.last_failure))
+}
+
+/// When the lockout ends, if there's one in effect
+pub async fn get_login_lockout(
+    pool: &Pool<MySql>,
+    kind: LockoutKind,
+    subject: &str,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT locked_until as "locked_until: DateTime<Utc>"
+        FROM login_lockouts
+        WHERE kind = ? AND subject = ? AND locked_until > ?"#,
+        kind.as_str(), subject, Utc::now()
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn lock_login(
+    pool: &Pool<MySql>,
+    kind: LockoutKind,
+    subject: &str,
+    until: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO login_lockouts (kind, subject, locked_until) VALUES (?, ?, ?)
+         ON DUPLICATE KEY UPDATE locked_until = VALUES(locked_until), created_at = CURRENT_TIMESTAMP",
+        kind.as_str(), subject, until
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn unlock_login(
+    pool: &Pool<MySql>,
+    kind: LockoutKind,
+    subject: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM login_lockouts WHERE kind = ? AND subject = ?",
+        kind.as_str(), subject
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
 // Server Management
 pub async fn create_server(
     pool: &Pool<MySql>,
@@ -107,6 +717,30 @@ pub async fn create_server(
     Ok(id)
 }
 
+pub async fn get_server(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Option<Server>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id, name, description, icon, owner_id, created_at, updated_at FROM servers WHERE id = ?",
+        server_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(Server {
+            id: Uuid::from_slice(&row.id).ok()?,
+            name: row.name,
+            description: row.description,
+            icon: row.icon,
+            owner_id: Uuid::from_slice(&row.owner_id).ok()?,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })
+    }))
+}
+
 pub async fn join_server(
     pool: &Pool<MySql>,
     server_id: Uuid,
@@ -121,20 +755,204 @@ pub async fn join_server(
     Ok(())
 }
 
-pub async fn leave_server(
+/// The server's own upload limit, if it set one
+pub async fn get_server_upload_limit(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Option<i64>, sqlx::Error> {
+    let row = sqlx::query!("SELECT max_upload_size FROM servers WHERE id = ?", server_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.and_then(|row| row.max_upload_size))
+}
+
+pub async fn get_server_member_ids(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    // The owner counts as a member even without a server_members row
+    let rows = sqlx::query!(
+        r#"SELECT user_id as "user_id!: Vec<u8>" FROM server_members WHERE server_id = ?
+        UNION
+        SELECT owner_id FROM servers WHERE id = ?"#,
+        server_id, server_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Uuid::from_slice(&row.user_id).ok())
+        .collect())
+}
+
+pub async fn set_server_nickname(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    user_id: Uuid,
+    nickname: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE server_members SET nickname = ? WHERE server_id = ? AND user_id = ?",
+        nickname, server_id, user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn leave_server(
     pool: &Pool<MySql>,
     server_id: Uuid,
     user_id: Uuid,
 ) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    delete_managed_roles(&mut tx, Some(server_id), user_id).await?;
     sqlx::query!(
         "DELETE FROM server_members WHERE server_id = ? AND user_id = ?",
         server_id, user_id
     )
-    .execute(pool)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// A managed role only ever belongs to the bot it was made for, so it goes
+/// when the bot leaves the server, or everywhere with None
+async fn delete_managed_roles(
+    tx: &mut sqlx::Transaction<'_, MySql>,
+    server_id: Option<Uuid>,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE r FROM roles r
+         JOIN member_roles mr ON mr.role_id = r.id
+         WHERE r.managed AND mr.user_id = ? AND (? IS NULL OR r.server_id = ?)",
+        user_id, server_id, server_id
+    )
+    .execute(&mut **tx)
     .await?;
     Ok(())
 }
 
+/// Effective permissions of a member, or None if the user is not in the server.
+/// The owner always has every permission.
+pub async fn get_member_permissions(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Permissions>, sqlx::Error> {
+    let server = sqlx::query!("SELECT owner_id FROM servers WHERE id = ?", server_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(server) = server else {
+        return Ok(None);
+    };
+    if server.owner_id == user_id.as_bytes() {
+        return Ok(Some(Permissions::all()));
+    }
+
+    let bits = sqlx::query_scalar!(
+        r#"SELECT CAST(COALESCE(BIT_OR(r.permissions), 0) AS UNSIGNED) as "permissions!: u64"
+        FROM server_members sm
+        LEFT JOIN member_roles mr ON mr.server_id = sm.server_id AND mr.user_id = sm.user_id
+        LEFT JOIN roles r ON r.id = mr.role_id
+        WHERE sm.server_id = ? AND sm.user_id = ?
+        GROUP BY sm.user_id"#,
+        server_id, user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(bits.map(Permissions::from_bits))
+}
+
+/// Every member's combined permissions in one go, the owner included
+pub async fn get_server_member_permissions(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Vec<(Uuid, Permissions)>, sqlx::Error> {
+    let owner_id = sqlx::query_scalar!("SELECT owner_id FROM servers WHERE id = ?", server_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(owner_id) = owner_id.and_then(|id| Uuid::from_slice(&id).ok()) else {
+        return Ok(Vec::new());
+    };
+
+    let rows = sqlx::query!(
+        r#"SELECT sm.user_id, CAST(COALESCE(BIT_OR(r.permissions), 0) AS UNSIGNED) as "permissions!: u64"
+        FROM server_members sm
+        LEFT JOIN member_roles mr ON mr.server_id = sm.server_id AND mr.user_id = sm.user_id
+        LEFT JOIN roles r ON r.id = mr.role_id
+        WHERE sm.server_id = ?
+        GROUP BY sm.user_id"#,
+        server_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut members = vec![(owner_id, Permissions::all())];
+    members.extend(rows.into_iter().filter_map(|row| {
+        let user_id = Uuid::from_slice(&row.user_id).ok()?;
+        (user_id != owner_id).then(|| (user_id, Permissions::from_bits(row.permissions)))
+    }));
+    Ok(members)
+}
+
+/// Ids and names of the server's channels, leaving out private ones unless
+/// `include_private`
+pub async fn get_server_channel_names(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    include_private: bool,
+) -> Result<Vec<(Uuid, String)>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT id, name FROM channels WHERE server_id = ? AND (is_private = false OR ?)",
+        server_id, include_private
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Some((Uuid::from_slice(&row.id).ok()?, row.name)))
+        .collect())
+}
+
+/// Resolves a username among the server's members, owner included
+pub async fn find_server_member(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    username: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let id = sqlx::query_scalar!(
+        "SELECT u.id FROM users u
+         JOIN servers s ON s.id = ?
+         WHERE u.username = ?
+         AND (u.id = s.owner_id OR EXISTS (
+             SELECT 1 FROM server_members m WHERE m.server_id = s.id AND m.user_id = u.id
+         ))",
+        server_id, username
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(id.and_then(|id| Uuid::from_slice(&id).ok()))
+}
+
+pub async fn is_server_role(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    role_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let found = sqlx::query_scalar!(
+        "SELECT 1 FROM roles WHERE id = ? AND server_id = ?",
+        role_id, server_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(found.is_some())
+}
+
 // Channel Management
 pub async fn create_channel(
     pool: &Pool<MySql>,
@@ -160,17 +978,23 @@ pub async fn get_channel_messages(
     channel_id: Uuid,
     limit: i32,
     before_id: Option<Uuid>,
+    viewer: Option<Uuid>,
 ) -> Result<Vec<Message>, sqlx::Error> {
     #[derive(sqlx::FromRow)]
     struct RawMessage {
         id: Vec<u8>,
         content: String,
         author_id: Vec<u8>,
+        webhook_id: Option<Vec<u8>>,
+        webhook_name: Option<String>,
+        webhook_avatar: Option<String>,
         channel_id: Vec<u8>,
         created_at: DateTime<Utc>,
         edited_at: Option<DateTime<Utc>>,
         reply_to_id: Option<Vec<u8>>,
         is_pinned: bool,
+        embeds: Option<Json<Vec<Embed>>>,
+        link_embeds: Option<Json<Vec<Embed>>>,
     }
 
     let channel_id_bytes = channel_id.as_bytes().to_vec();
@@ -180,7 +1004,10 @@ pub async fn get_channel_messages(
         sqlx::query_as!(
             RawMessage,
             r#"
-            SELECT id, is_pinned as "is_pinned: bool", reply_to_id, content, author_id, channel_id, created_at, edited_at
+            SELECT id, is_pinned as "is_pinned: bool", reply_to_id, content,
+                COALESCE(author_id, webhook_id) as "author_id!: Vec<u8>", webhook_id, webhook_name, webhook_avatar,
+                channel_id, created_at, edited_at, embeds as "embeds: Json<Vec<Embed>>",
+                link_embeds as "link_embeds: Json<Vec<Embed>>"
             FROM messages 
             WHERE channel_id = ? AND id < ?
             ORDER BY created_at DESC
@@ -196,7 +1023,10 @@ pub async fn get_channel_messages(
         sqlx::query_as!(
             RawMessage,
             r#"
-            SELECT id, is_pinned as "is_pinned: bool", reply_to_id, content, author_id, channel_id, created_at, edited_at
+            SELECT id, is_pinned as "is_pinned: bool", reply_to_id, content,
+                COALESCE(author_id, webhook_id) as "author_id!: Vec<u8>", webhook_id, webhook_name, webhook_avatar,
+                channel_id, created_at, edited_at, embeds as "embeds: Json<Vec<Embed>>",
+                link_embeds as "link_embeds: Json<Vec<Embed>>"
             FROM messages 
             WHERE channel_id = ?
             ORDER BY created_at DESC
@@ -209,6 +1039,9 @@ pub async fn get_channel_messages(
         .await?
     };
 
+    let message_ids: Vec<Uuid> = raw_messages.iter().filter_map(|raw| Uuid::from_slice(&raw.id).ok()).collect();
+    let mut reactions = get_message_reactions(pool, &message_ids, viewer).await?;
+
     // Convert raw messages to Message type
     let messages = raw_messages
         .into_iter()
@@ -229,123 +1062,2816 @@ pub async fn get_channel_messages(
                 created_at: raw.created_at,
                 edited_at: raw.edited_at,
                 attachments: vec![],
+                embeds: message_embeds(raw.embeds, raw.link_embeds),
                 mentions: vec![],
-                reactions: vec![],
+                reactions: reactions.remove(&id).unwrap_or_default(),
+                webhook: webhook_author(raw.webhook_id, raw.webhook_name, raw.webhook_avatar),
+            })
+        })
+        .collect();
+
+    Ok(messages)
+}
+
+pub async fn get_channel(
+    pool: &Pool<MySql>,
+    channel_id: Uuid,
+) -> Result<Option<Channel>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT
+            id,
+            name,
+            channel_type as "channel_type: String",
+            server_id,
+            topic,
+            slow_mode,
+            is_private as "is_private: bool",
+            last_message_id,
+            created_at,
+            updated_at
+        FROM channels
+        WHERE id = ?"#,
+        channel_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(Channel {
+            id: Uuid::from_slice(&row.id).ok()?,
+            name: row.name,
+            channel_type: ChannelType::from_str(&row.channel_type).unwrap_or(ChannelType::Text),
+            server_id: Uuid::from_slice(&row.server_id).ok()?,
+            topic: row.topic,
+            slow_mode: row.slow_mode,
+            is_private: row.is_private,
+            last_message_id: row.last_message_id.as_deref().map(Uuid::from_slice).transpose().ok().flatten(),
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })
+    }))
+}
+
+// Message Management
+pub async fn get_message_channel_id(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let row = sqlx::query!("SELECT channel_id FROM messages WHERE id = ?", message_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.and_then(|row| Uuid::from_slice(&row.channel_id).ok()))
+}
+
+/// Embeds the author supplied come first, link previews after them
+fn message_embeds(embeds: Option<Json<Vec<Embed>>>, link_embeds: Option<Json<Vec<Embed>>>) -> Vec<Embed> {
+    embeds.into_iter().chain(link_embeds).flat_map(|embeds| embeds.0).collect()
+}
+
+/// `viewer` decides which reactions are marked as their own
+pub async fn get_message(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    viewer: Option<Uuid>,
+) -> Result<Option<Message>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, is_pinned as "is_pinned: bool", reply_to_id, content,
+            COALESCE(author_id, webhook_id) as "author_id!: Vec<u8>", webhook_id, webhook_name, webhook_avatar,
+            channel_id, created_at, edited_at, embeds as "embeds: Json<Vec<Embed>>",
+                link_embeds as "link_embeds: Json<Vec<Embed>>"
+        FROM messages
+        WHERE id = ?"#,
+        message_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let attachments = get_message_attachments(pool, message_id).await?;
+    let reactions = get_message_reactions(pool, &[message_id], viewer)
+        .await?
+        .remove(&message_id)
+        .unwrap_or_default();
+    Ok((|| {
+        Some(Message {
+            id: Uuid::from_slice(&row.id).ok()?,
+            content: row.content,
+            author_id: Uuid::from_slice(&row.author_id).ok()?,
+            channel_id: Uuid::from_slice(&row.channel_id).ok()?,
+            is_pinned: row.is_pinned,
+            reply_to_id: row.reply_to_id.as_deref().map(Uuid::from_slice).transpose().ok().flatten(),
+            created_at: row.created_at,
+            edited_at: row.edited_at,
+            attachments,
+            embeds: message_embeds(row.embeds, row.link_embeds),
+            mentions: vec![],
+            reactions,
+            webhook: webhook_author(row.webhook_id, row.webhook_name, row.webhook_avatar),
+        })
+    })())
+}
+
+/// Webhook messages keep the name and avatar they were posted with
+fn webhook_author(id: Option<Vec<u8>>, name: Option<String>, avatar: Option<String>) -> Option<WebhookAuthor> {
+    Some(WebhookAuthor {
+        id: Uuid::from_slice(&id?).ok()?,
+        name: name.unwrap_or_default(),
+        avatar,
+    })
+}
+
+pub async fn get_message_attachments(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+) -> Result<Vec<Attachment>, sqlx::Error> {
+    let ids = sqlx::query_scalar!(
+        "SELECT id FROM attachments WHERE message_id = ? ORDER BY created_at, id",
+        message_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut attachments = Vec::with_capacity(ids.len());
+    for id in ids.iter().filter_map(|id| Uuid::from_slice(id).ok()) {
+        if let Some(stored) = get_attachment(pool, id).await? {
+            attachments.push(stored.attachment);
+        }
+    }
+    Ok(attachments)
+}
+
+/// Creates a message and claims the given pending attachments for it. Fails
+/// with `RowNotFound` if any of them was already used by another message.
+pub async fn create_message(
+    pool: &Pool<MySql>,
+    channel_id: Uuid,
+    author_id: Uuid,
+    content: &str,
+    reply_to_id: Option<Uuid>,
+    attachment_ids: &[Uuid],
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "INSERT INTO messages (id, channel_id, author_id, content, reply_to_id) 
+         VALUES (?, ?, ?, ?, ?)",
+        id, channel_id, author_id, content, reply_to_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    finish_message(tx, id, channel_id, attachment_ids).await?;
+    Ok(id)
+}
+
+/// Creates a message posted through an incoming webhook. It has no
+/// `author_id`, the webhook's name and avatar are stored with it instead.
+pub async fn create_webhook_message(
+    pool: &Pool<MySql>,
+    channel_id: Uuid,
+    webhook: &WebhookAuthor,
+    content: &str,
+    embeds: &[Embed],
+    attachment_ids: &[Uuid],
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+    let mut tx = pool.begin().await?;
+
+    let embeds = (!embeds.is_empty()).then_some(Json(embeds));
+    sqlx::query!(
+        "INSERT INTO messages (id, channel_id, webhook_id, webhook_name, webhook_avatar, content, embeds)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+        id, channel_id, webhook.id, webhook.name, webhook.avatar, content, embeds
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    finish_message(tx, id, channel_id, attachment_ids).await?;
+    Ok(id)
+}
+
+/// Claims the message's attachments and makes it the channel's latest
+async fn finish_message(
+    mut tx: sqlx::Transaction<'_, MySql>,
+    id: Uuid,
+    channel_id: Uuid,
+    attachment_ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    for attachment_id in attachment_ids {
+        let claimed = sqlx::query!(
+            "UPDATE attachments SET message_id = ? WHERE id = ? AND message_id IS NULL",
+            id, attachment_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if claimed == 0 {
+            // Dropping the transaction rolls the message back
+            return Err(sqlx::Error::RowNotFound);
+        }
+    }
+
+    // Update last_message_id in channel
+    sqlx::query!(
+        "UPDATE channels SET last_message_id = ? WHERE id = ?",
+        id, channel_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
+
+/// A page of messages for rebuilding the search index, ordered by id
+pub async fn get_messages_for_index(
+    pool: &Pool<MySql>,
+    after: Option<Uuid>,
+    limit: i64,
+) -> Result<Vec<IndexedMessage>, sqlx::Error> {
+    let after = after.unwrap_or(Uuid::nil());
+    let rows = sqlx::query!(
+        r#"SELECT m.id, m.channel_id, COALESCE(m.author_id, m.webhook_id) as "author_id!: Vec<u8>", m.content,
+            m.is_pinned as "is_pinned: bool", m.created_at,
+            COALESCE(JSON_LENGTH(m.embeds), 0) + COALESCE(JSON_LENGTH(m.link_embeds), 0) > 0 AS "has_embeds!: bool",
+            (SELECT GROUP_CONCAT(DISTINCT a.attachment_type) FROM attachments a WHERE a.message_id = m.id) AS attachment_types,
+            (SELECT GROUP_CONCAT(HEX(mn.user_id)) FROM mentions mn WHERE mn.message_id = m.id) AS mention_ids
+        FROM messages m
+        WHERE m.id > ?
+        ORDER BY m.id
+        LIMIT ?"#,
+        after, limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let has = row
+                .attachment_types
+                .as_deref()
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|kind| match kind {
+                    "image" => Some(HasFilter::Image),
+                    "video" => Some(HasFilter::Video),
+                    "file" => Some(HasFilter::File),
+                    _ => None,
+                })
+                .collect();
+            Some(IndexedMessage {
+                id: Uuid::from_slice(&row.id).ok()?,
+                channel_id: Uuid::from_slice(&row.channel_id).ok()?,
+                author_id: Uuid::from_slice(&row.author_id).ok()?,
+                mention_ids: row
+                    .mention_ids
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter_map(|id| Uuid::parse_str(id).ok())
+                    .collect(),
+                has: HasFilter::derived(has, &row.content, row.has_embeds),
+                content: row.content,
+                is_pinned: row.is_pinned,
+                created_at: row.created_at,
             })
         })
-        .collect();
+        .collect())
+}
+
+/// The users a message mentions directly
+pub async fn get_message_mention_ids(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let ids = sqlx::query_scalar!("SELECT user_id FROM mentions WHERE message_id = ?", message_id)
+        .fetch_all(pool)
+        .await?;
+    Ok(ids.iter().filter_map(|id| Uuid::from_slice(id).ok()).collect())
+}
+
+/// Replaces the users a message mentions. Ids that aren't users are ignored.
+pub async fn set_message_mentions(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    mentions: &MessageMentions,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM mentions WHERE message_id = ?", message_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM role_mentions WHERE message_id = ?", message_id)
+        .execute(&mut *tx)
+        .await?;
+    for user_id in &mentions.user_ids {
+        sqlx::query!(
+            "INSERT IGNORE INTO mentions (message_id, user_id) SELECT ?, id FROM users WHERE id = ?",
+            message_id, user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    // Only roles of the server the message was sent in
+    for role_id in &mentions.role_ids {
+        sqlx::query!(
+            "INSERT IGNORE INTO role_mentions (message_id, role_id)
+             SELECT m.id, r.id FROM messages m
+             JOIN channels c ON c.id = m.channel_id
+             JOIN roles r ON r.server_id = c.server_id
+             WHERE m.id = ? AND r.id = ?",
+            message_id, role_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query!(
+        "UPDATE messages SET mention_everyone = ? WHERE id = ?",
+        mentions.everyone, message_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn edit_message(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    new_content: &str,
+) -> Result<(), sqlx::Error> {
+    // Old previews no longer match the content, new ones are unfurled afresh.
+    // Embeds a bot or webhook supplied stay.
+    sqlx::query!(
+        "UPDATE messages SET content = ?, link_embeds = NULL, edited_at = CURRENT_TIMESTAMP 
+         WHERE id = ?",
+        new_content, message_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Stores embeds the author supplied, which survive edits
+pub async fn set_message_embeds(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    embeds: &[Embed],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE messages SET embeds = ? WHERE id = ?", Json(embeds), message_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Stores link previews, unless the message was edited (or deleted) since
+/// `content` was read. Returns whether they were stored.
+pub async fn set_link_embeds(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    content: &str,
+    embeds: &[Embed],
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE messages SET link_embeds = ? WHERE id = ? AND content = ?",
+        Json(embeds), message_id, content
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_message(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM messages WHERE id = ?", message_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Read States
+/// Read state of every channel in the user's servers, private ones included.
+/// Callers drop the channels the user can't see.
+pub async fn get_read_states(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<Vec<ChannelReadState>, sqlx::Error> {
+    // A channel is unread when its last message isn't the one acknowledged
+    let rows = sqlx::query!(
+        r#"SELECT c.id, c.server_id, c.is_private as "is_private: bool",
+            rs.last_message_id AS acked_message_id,
+            c.last_message_id IS NOT NULL AND NOT (c.last_message_id <=> rs.last_message_id) AS "unread!: bool"
+        FROM channels c
+        JOIN servers s ON s.id = c.server_id
+        LEFT JOIN server_members sm ON sm.server_id = s.id AND sm.user_id = ?
+        LEFT JOIN read_states rs ON rs.channel_id = c.id AND rs.user_id = ?
+        WHERE sm.user_id IS NOT NULL OR s.owner_id = ?"#,
+        user_id, user_id, user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let settings = get_notification_settings(pool, user_id).await?;
+    let counts = get_unread_counts(pool, user_id).await?;
+    let now = Utc::now();
+    let mut states = Vec::with_capacity(rows.len());
+    for row in rows {
+        let (Ok(channel_id), Ok(server_id)) = (Uuid::from_slice(&row.id), Uuid::from_slice(&row.server_id)) else {
+            continue;
+        };
+        let effective = settings
+            .iter()
+            .find(|s| s.server_id == server_id)
+            .map(|s| s.effective(channel_id, now))
+            .unwrap_or_else(|| ServerNotificationSettings::new(server_id).effective(channel_id, now));
+
+        let mut counted = 0;
+        for (kind, bit) in [
+            (MentionKind::Direct, MENTIONED_DIRECTLY),
+            (MentionKind::Everyone, MENTIONED_EVERYONE),
+            (MentionKind::Role, MENTIONED_BY_ROLE),
+        ] {
+            if effective.counts_mention(kind) {
+                counted |= bit;
+            }
+        }
+        let channel_counts = counts.get(&channel_id).map(Vec::as_slice).unwrap_or_default();
+        let unread_count: i64 = channel_counts.iter().map(|(_, count)| count).sum();
+        let mention_count = channel_counts
+            .iter()
+            .filter(|(kinds, _)| kinds & counted != 0)
+            .map(|(_, count)| count)
+            .sum();
+
+        // Muted channels never show as unread, but mentions in them still count
+        states.push(ChannelReadState {
+            server_id,
+            is_private: row.is_private,
+            state: ReadState {
+                channel_id,
+                last_message_id: row.acked_message_id.as_deref().and_then(|id| Uuid::from_slice(id).ok()),
+                unread: row.unread && !effective.muted,
+                unread_count: if effective.muted { 0 } else { unread_count },
+                mention_count,
+                muted: effective.muted,
+            },
+        });
+    }
+    Ok(states)
+}
+
+/// Bits of the `kinds` unread messages are grouped by
+const MENTIONED_DIRECTLY: i64 = 1;
+const MENTIONED_EVERYONE: i64 = 2;
+const MENTIONED_BY_ROLE: i64 = 4;
+
+/// Unread messages from others in every channel of the user's, grouped by
+/// how they mention the user. Past an ack that's everything later in the
+/// message sequence, without one it's everything since joining.
+async fn get_unread_counts(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<HashMap<Uuid, Vec<(i64, i64)>>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT channel_id, kinds AS "kinds!: i64", COUNT(*) AS "count!: i64"
+        FROM (
+            SELECT u.channel_id,
+                EXISTS(SELECT 1 FROM mentions mn WHERE mn.message_id = u.id AND mn.user_id = ?)
+                | (u.mention_everyone << 1)
+                | (EXISTS(
+                    SELECT 1 FROM role_mentions rm
+                    JOIN member_roles mr ON mr.role_id = rm.role_id
+                    WHERE rm.message_id = u.id AND mr.user_id = ?
+                ) << 2) AS kinds
+            FROM (
+                SELECT m.id, m.channel_id, m.mention_everyone
+                FROM read_states rs
+                JOIN channels c ON c.id = rs.channel_id
+                JOIN messages m ON m.channel_id = rs.channel_id AND m.seq > rs.last_read_seq
+                WHERE rs.user_id = ? AND NOT (c.last_message_id <=> rs.last_message_id)
+                    AND NOT m.author_id <=> ?
+                UNION ALL
+                SELECT m.id, m.channel_id, m.mention_everyone
+                FROM channels c
+                JOIN servers s ON s.id = c.server_id
+                LEFT JOIN server_members sm ON sm.server_id = s.id AND sm.user_id = ?
+                LEFT JOIN read_states rs ON rs.channel_id = c.id AND rs.user_id = ?
+                JOIN messages m ON m.channel_id = c.id AND m.created_at > COALESCE(sm.joined_at, s.created_at)
+                WHERE (sm.user_id IS NOT NULL OR s.owner_id = ?) AND rs.user_id IS NULL
+                    AND NOT m.author_id <=> ?
+            ) u
+        ) t
+        GROUP BY channel_id, kinds"#,
+        user_id, user_id,
+        user_id, user_id,
+        user_id, user_id, user_id, user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut counts: HashMap<Uuid, Vec<(i64, i64)>> = HashMap::new();
+    for row in rows {
+        if let Ok(channel_id) = Uuid::from_slice(&row.channel_id) {
+            counts.entry(channel_id).or_default().push((row.kinds, row.count));
+        }
+    }
+    Ok(counts)
+}
+
+// Push Subscriptions
+/// Registers a browser's subscription. A browser re-subscribing with the
+/// same endpoint replaces its keys, and the endpoint moves to this user.
+pub async fn upsert_push_subscription(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    endpoint: &str,
+    p256dh: &str,
+    auth: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO push_subscriptions (id, user_id, endpoint, p256dh, auth)
+         VALUES (?, ?, ?, ?, ?)
+         ON DUPLICATE KEY UPDATE
+            user_id = VALUES(user_id),
+            p256dh = VALUES(p256dh),
+            auth = VALUES(auth),
+            failures = 0",
+        id, user_id, endpoint, p256dh, auth
+    )
+    .execute(pool)
+    .await?;
+
+    let id = sqlx::query_scalar!("SELECT id FROM push_subscriptions WHERE endpoint = ?", endpoint)
+        .fetch_one(pool)
+        .await?;
+    Uuid::from_slice(&id).map_err(|_| sqlx::Error::Decode("Invalid UUID".into()))
+}
+
+pub async fn get_push_subscriptions(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<Vec<PushSubscription>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT id, user_id, endpoint, p256dh, auth, created_at
+         FROM push_subscriptions
+         WHERE user_id = ?
+         ORDER BY created_at",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(PushSubscription {
+                id: Uuid::from_slice(&row.id).ok()?,
+                user_id: Uuid::from_slice(&row.user_id).ok()?,
+                endpoint: row.endpoint,
+                p256dh: row.p256dh,
+                auth: row.auth,
+                created_at: row.created_at,
+            })
+        })
+        .collect())
+}
+
+/// Deletes the subscription only if it belongs to the user
+/// Every subscription of each of the users
+pub async fn get_users_push_subscriptions(
+    pool: &Pool<MySql>,
+    user_ids: &[Uuid],
+) -> Result<Vec<PushSubscription>, sqlx::Error> {
+    if user_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut query = sqlx::QueryBuilder::<MySql>::new(
+        "SELECT id, user_id, endpoint, p256dh, auth, created_at FROM push_subscriptions WHERE user_id IN (",
+    );
+    let mut ids = query.separated(", ");
+    for id in user_ids {
+        ids.push_bind(*id);
+    }
+    query.push(")");
+    let rows: Vec<(Vec<u8>, Vec<u8>, String, String, String, DateTime<Utc>)> =
+        query.build_query_as().fetch_all(pool).await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(id, user_id, endpoint, p256dh, auth, created_at)| {
+            Some(PushSubscription {
+                id: Uuid::from_slice(&id).ok()?,
+                user_id: Uuid::from_slice(&user_id).ok()?,
+                endpoint,
+                p256dh,
+                auth,
+                created_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn delete_push_subscription_for(
+    pool: &Pool<MySql>,
+    subscription_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM push_subscriptions WHERE id = ? AND user_id = ?",
+        subscription_id, user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn delete_push_subscription(
+    pool: &Pool<MySql>,
+    subscription_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM push_subscriptions WHERE id = ?", subscription_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn record_push_success(
+    pool: &Pool<MySql>,
+    subscription_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE push_subscriptions SET failures = 0, last_success_at = CURRENT_TIMESTAMP WHERE id = ?",
+        subscription_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Returns how many deliveries in a row have now failed
+pub async fn record_push_failure(
+    pool: &Pool<MySql>,
+    subscription_id: Uuid,
+) -> Result<i32, sqlx::Error> {
+    sqlx::query!(
+        "UPDATE push_subscriptions SET failures = failures + 1 WHERE id = ?",
+        subscription_id
+    )
+    .execute(pool)
+    .await?;
+    let failures = sqlx::query_scalar!("SELECT failures FROM push_subscriptions WHERE id = ?", subscription_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(failures.unwrap_or(0))
+}
+
+/// Everyone a message mentions and how. A user mentioned several ways gets
+/// the most direct one.
+pub async fn get_mention_recipients(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    server_id: Uuid,
+) -> Result<Vec<(Uuid, MentionKind)>, sqlx::Error> {
+    let mut recipients: Vec<(Uuid, MentionKind)> = Vec::new();
+    let mut add = |user_id: Uuid, kind: MentionKind| {
+        if !recipients.iter().any(|(id, _)| *id == user_id) {
+            recipients.push((user_id, kind));
+        }
+    };
+
+    let direct = sqlx::query_scalar!("SELECT user_id FROM mentions WHERE message_id = ?", message_id)
+        .fetch_all(pool)
+        .await?;
+    for id in direct.iter().filter_map(|id| Uuid::from_slice(id).ok()) {
+        add(id, MentionKind::Direct);
+    }
+
+    let by_role = sqlx::query_scalar!(
+        "SELECT DISTINCT mr.user_id FROM role_mentions rm
+         JOIN member_roles mr ON mr.role_id = rm.role_id
+         WHERE rm.message_id = ?",
+        message_id
+    )
+    .fetch_all(pool)
+    .await?;
+    for id in by_role.iter().filter_map(|id| Uuid::from_slice(id).ok()) {
+        add(id, MentionKind::Role);
+    }
+
+    let everyone = sqlx::query_scalar!(
+        r#"SELECT mention_everyone as "mention_everyone: bool" FROM messages WHERE id = ?"#,
+        message_id
+    )
+    .fetch_optional(pool)
+    .await?
+    .unwrap_or(false);
+    if everyone {
+        for id in get_server_member_ids(pool, server_id).await? {
+            add(id, MentionKind::Everyone);
+        }
+    }
+    Ok(recipients)
+}
+
+// Bans
+/// Bans the user and removes them from the server. Returns false if they
+/// were already banned.
+pub async fn ban_member(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    user_id: Uuid,
+    moderator_id: Uuid,
+    reason: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query!(
+        "INSERT IGNORE INTO server_bans (server_id, user_id, moderator_id, reason) VALUES (?, ?, ?, ?)",
+        server_id, user_id, moderator_id, reason
+    )
+    .execute(&mut *tx)
+    .await?;
+    delete_managed_roles(&mut tx, Some(server_id), user_id).await?;
+    sqlx::query!(
+        "DELETE FROM server_members WHERE server_id = ? AND user_id = ?",
+        server_id, user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn is_banned(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let banned = sqlx::query_scalar!(
+        "SELECT 1 FROM server_bans WHERE server_id = ? AND user_id = ?",
+        server_id, user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(banned.is_some())
+}
+
+pub async fn unban_member(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM server_bans WHERE server_id = ? AND user_id = ?",
+        server_id, user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Applications & Bots
+/// Creates the application along with its bot user
+pub async fn create_application(
+    pool: &Pool<MySql>,
+    owner_id: Uuid,
+    name: &str,
+    description: Option<&str>,
+    bot_username: &str,
+    token_hash: &str,
+    interactions_secret: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+    let bot_id = Uuid::new_v4();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "INSERT INTO users (id, username, is_bot) VALUES (?, ?, true)",
+        bot_id, bot_username
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO applications (id, owner_id, name, description, bot_id, bot_token_hash, interactions_secret)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+        id, owner_id, name, description, bot_id, token_hash, interactions_secret
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(id)
+}
+
+pub async fn get_application(
+    pool: &Pool<MySql>,
+    application_id: Uuid,
+) -> Result<Option<Application>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id, owner_id, name, description, bot_id, interactions_url, interactions_secret, created_at
+         FROM applications WHERE id = ?",
+        application_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let Ok(bot_id) = Uuid::from_slice(&row.bot_id) else {
+        return Ok(None);
+    };
+    let Some(bot) = get_public_user(pool, bot_id).await? else {
+        return Ok(None);
+    };
+
+    Ok((|| {
+        Some(Application {
+            id: Uuid::from_slice(&row.id).ok()?,
+            owner_id: Uuid::from_slice(&row.owner_id).ok()?,
+            name: row.name,
+            description: row.description,
+            bot,
+            interactions_url: row.interactions_url,
+            interactions_secret: row.interactions_secret,
+            created_at: row.created_at,
+        })
+    })())
+}
+
+pub async fn get_owned_applications(
+    pool: &Pool<MySql>,
+    owner_id: Uuid,
+) -> Result<Vec<Application>, sqlx::Error> {
+    let ids = sqlx::query_scalar!(
+        "SELECT id FROM applications WHERE owner_id = ? ORDER BY created_at",
+        owner_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut applications = Vec::with_capacity(ids.len());
+    for id in ids.iter().filter_map(|id| Uuid::from_slice(id).ok()) {
+        if let Some(application) = get_application(pool, id).await? {
+            applications.push(application);
+        }
+    }
+    Ok(applications)
+}
+
+pub async fn update_application(
+    pool: &Pool<MySql>,
+    application_id: Uuid,
+    name: &str,
+    description: Option<&str>,
+    interactions_url: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE applications SET name = ?, description = ?, interactions_url = ? WHERE id = ?",
+        name, description, interactions_url, application_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Deletes the bot user, which takes the application and its memberships
+/// with it, and the bot's managed roles
+pub async fn delete_application(
+    pool: &Pool<MySql>,
+    application: &Application,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    delete_managed_roles(&mut tx, None, application.bot.id).await?;
+    sqlx::query!("DELETE FROM users WHERE id = ? AND is_bot", application.bot.id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Replaces the bot's token, the old one stops working immediately
+pub async fn set_bot_token(
+    pool: &Pool<MySql>,
+    application_id: Uuid,
+    token_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE applications SET bot_token_hash = ? WHERE id = ?",
+        token_hash, application_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The bot user a token belongs to
+pub async fn get_bot_by_token(
+    pool: &Pool<MySql>,
+    token_hash: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let bot_id = sqlx::query_scalar!(
+        "SELECT bot_id FROM applications WHERE bot_token_hash = ?",
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(bot_id.and_then(|id| Uuid::from_slice(&id).ok()))
+}
+
+/// Adds the bot to the server with a role of its own holding the granted
+/// permissions. Returns false if the bot is already a member.
+pub async fn add_bot_to_server(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    application: &Application,
+    permissions: Permissions,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query!(
+        "INSERT IGNORE INTO server_members (user_id, server_id) VALUES (?, ?)",
+        application.bot.id, server_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    if permissions != Permissions::empty() {
+        let role_id = Uuid::new_v4();
+        sqlx::query!(
+            "INSERT INTO roles (id, server_id, name, permissions, managed) VALUES (?, ?, ?, ?, true)",
+            role_id, server_id, application.name, permissions.bits()
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO member_roles (server_id, user_id, role_id) VALUES (?, ?, ?)",
+            server_id, application.bot.id, role_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Application Commands
+/// Registers a command, replacing the one with the same name in the same
+/// scope. None if it's new and the scope already has `max_commands`.
+#[allow(clippy::too_many_arguments)]
+pub async fn upsert_application_command(
+    pool: &Pool<MySql>,
+    application_id: Uuid,
+    server_id: Option<Uuid>,
+    name: &str,
+    description: &str,
+    options: &[CommandOption],
+    max_commands: i64,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    // Lock the application row so two registrations can't both slip in under the cap
+    sqlx::query!("SELECT id FROM applications WHERE id = ? FOR UPDATE", application_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let existing = sqlx::query_scalar!(
+        "SELECT id FROM application_commands WHERE application_id = ? AND server_id <=> ? AND name = ? FOR UPDATE",
+        application_id, server_id, name
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .and_then(|id| Uuid::from_slice(&id).ok());
+
+    let id = match existing {
+        Some(id) => {
+            sqlx::query!(
+                "UPDATE application_commands SET description = ?, options = ? WHERE id = ?",
+                description, Json(options), id
+            )
+            .execute(&mut *tx)
+            .await?;
+            id
+        }
+        None => {
+            let count = sqlx::query_scalar!(
+                r#"SELECT COUNT(*) as "count!: i64" FROM application_commands WHERE application_id = ? AND server_id <=> ?"#,
+                application_id, server_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            if count >= max_commands {
+                tx.rollback().await?;
+                return Ok(None);
+            }
+            let id = Uuid::new_v4();
+            sqlx::query!(
+                "INSERT INTO application_commands (id, application_id, server_id, name, description, options)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+                id, application_id, server_id, name, description, Json(options)
+            )
+            .execute(&mut *tx)
+            .await?;
+            id
+        }
+    };
+
+    tx.commit().await?;
+    Ok(Some(id))
+}
+
+pub async fn get_application_command(
+    pool: &Pool<MySql>,
+    command_id: Uuid,
+) -> Result<Option<ApplicationCommand>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, application_id, server_id, name, description, options as "options: Json<Vec<CommandOption>>",
+            created_at, updated_at
+        FROM application_commands
+        WHERE id = ?"#,
+        command_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(ApplicationCommand {
+            id: Uuid::from_slice(&row.id).ok()?,
+            application_id: Uuid::from_slice(&row.application_id).ok()?,
+            server_id: row.server_id.and_then(|id| Uuid::from_slice(&id).ok()),
+            name: row.name,
+            description: row.description,
+            options: row.options.0,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })
+    }))
+}
+
+/// The application's global commands, or those it registered for one server
+pub async fn get_application_commands(
+    pool: &Pool<MySql>,
+    application_id: Uuid,
+    server_id: Option<Uuid>,
+) -> Result<Vec<ApplicationCommand>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT id, application_id, server_id, name, description, options as "options: Json<Vec<CommandOption>>",
+            created_at, updated_at
+        FROM application_commands
+        WHERE application_id = ? AND server_id <=> ?
+        ORDER BY name"#,
+        application_id, server_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(ApplicationCommand {
+                id: Uuid::from_slice(&row.id).ok()?,
+                application_id: Uuid::from_slice(&row.application_id).ok()?,
+                server_id: row.server_id.and_then(|id| Uuid::from_slice(&id).ok()),
+                name: row.name,
+                description: row.description,
+                options: row.options.0,
+                created_at: row.created_at,
+                updated_at: row.updated_at,
+            })
+        })
+        .collect())
+}
+
+/// Commands usable in the server, from every bot that's a member of it,
+/// whose names start with `prefix`
+pub async fn get_server_commands(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    prefix: &str,
+    limit: i64,
+) -> Result<Vec<ApplicationCommand>, sqlx::Error> {
+    let pattern = format!("{}%", prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+    let rows = sqlx::query!(
+        r#"SELECT c.id, c.application_id, c.server_id, c.name, c.description,
+            c.options as "options: Json<Vec<CommandOption>>", c.created_at, c.updated_at
+        FROM application_commands c
+        JOIN applications a ON a.id = c.application_id
+        JOIN server_members sm ON sm.user_id = a.bot_id AND sm.server_id = ?
+        WHERE (c.server_id IS NULL OR c.server_id = ?) AND c.name LIKE ?
+        ORDER BY c.name, c.server_id IS NULL
+        LIMIT ?"#,
+        server_id, server_id, pattern, limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(ApplicationCommand {
+                id: Uuid::from_slice(&row.id).ok()?,
+                application_id: Uuid::from_slice(&row.application_id).ok()?,
+                server_id: row.server_id.and_then(|id| Uuid::from_slice(&id).ok()),
+                name: row.name,
+                description: row.description,
+                options: row.options.0,
+                created_at: row.created_at,
+                updated_at: row.updated_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn delete_application_command(
+    pool: &Pool<MySql>,
+    command_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM application_commands WHERE id = ?", command_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Interactions
+pub struct StoredInteraction {
+    pub id: Uuid,
+    pub application_id: Uuid,
+    pub channel_id: Uuid,
+    pub user_id: Uuid,
+    pub state: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub async fn create_interaction(
+    pool: &Pool<MySql>,
+    interaction: &Interaction,
+    token_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO interactions (id, application_id, command_id, server_id, channel_id, user_id, token_hash, expires_at, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        interaction.id, interaction.application_id, interaction.command.id, interaction.server_id,
+        interaction.channel_id, interaction.user.id, token_hash, interaction.expires_at, interaction.created_at
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The interaction, if the token is the one it was issued with
+pub async fn get_interaction(
+    pool: &Pool<MySql>,
+    interaction_id: Uuid,
+    token_hash: &str,
+) -> Result<Option<StoredInteraction>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id, application_id, channel_id, user_id, state, expires_at
+        FROM interactions
+        WHERE id = ? AND token_hash = ?",
+        interaction_id, token_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(StoredInteraction {
+            id: Uuid::from_slice(&row.id).ok()?,
+            application_id: Uuid::from_slice(&row.application_id).ok()?,
+            channel_id: Uuid::from_slice(&row.channel_id).ok()?,
+            user_id: Uuid::from_slice(&row.user_id).ok()?,
+            state: row.state,
+            expires_at: row.expires_at,
+        })
+    }))
+}
+
+/// Claims the one response a pending interaction gets. False if it was
+/// already answered or its deadline passed.
+pub async fn mark_interaction_responded(
+    pool: &Pool<MySql>,
+    interaction_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE interactions SET state = 'responded' WHERE id = ? AND state = 'pending' AND expires_at > ?",
+        interaction_id, Utc::now()
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Gives up on a pending interaction. False if the bot responded in time.
+pub async fn expire_interaction(
+    pool: &Pool<MySql>,
+    interaction_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE interactions SET state = 'expired' WHERE id = ? AND state = 'pending'",
+        interaction_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn prune_interactions(
+    pool: &Pool<MySql>,
+    before: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM interactions WHERE created_at < ?", before)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// Incoming Webhooks
+/// None if the channel already has `max_webhooks`
+pub async fn create_webhook(
+    pool: &Pool<MySql>,
+    channel: &Channel,
+    name: &str,
+    avatar: Option<&str>,
+    token_hash: &str,
+    created_by: Uuid,
+    max_webhooks: i64,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let id = Uuid::new_v4();
+    let mut tx = pool.begin().await?;
+
+    // Lock the channel row so two creations can't both slip in under the cap
+    sqlx::query!("SELECT id FROM channels WHERE id = ? FOR UPDATE", channel.id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let count = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!: i64" FROM webhooks WHERE channel_id = ?"#,
+        channel.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if count >= max_webhooks {
+        tx.rollback().await?;
+        return Ok(None);
+    }
+
+    sqlx::query!(
+        "INSERT INTO webhooks (id, server_id, channel_id, name, avatar, token_hash, created_by)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+        id, channel.server_id, channel.id, name, avatar, token_hash, created_by
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Some(id))
+}
+
+pub async fn get_webhook(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+) -> Result<Option<Webhook>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT id, server_id, channel_id, name, avatar, created_by, created_at FROM webhooks WHERE id = ?",
+        webhook_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(Webhook {
+            id: Uuid::from_slice(&row.id).ok()?,
+            server_id: Uuid::from_slice(&row.server_id).ok()?,
+            channel_id: Uuid::from_slice(&row.channel_id).ok()?,
+            name: row.name,
+            avatar: row.avatar,
+            created_by: row.created_by.and_then(|id| Uuid::from_slice(&id).ok()),
+            created_at: row.created_at,
+        })
+    }))
+}
+
+/// The webhook, if the token hash matches
+pub async fn get_webhook_with_token(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+    token_hash: &str,
+) -> Result<Option<Webhook>, sqlx::Error> {
+    let matches = sqlx::query_scalar!(
+        "SELECT 1 FROM webhooks WHERE id = ? AND token_hash = ?",
+        webhook_id, token_hash
+    )
+    .fetch_optional(pool)
+    .await?
+    .is_some();
+    if !matches {
+        return Ok(None);
+    }
+    get_webhook(pool, webhook_id).await
+}
+
+/// A server's webhooks, or only one channel's
+pub async fn get_webhooks(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    channel_id: Option<Uuid>,
+) -> Result<Vec<Webhook>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT id, server_id, channel_id, name, avatar, created_by, created_at
+         FROM webhooks
+         WHERE server_id = ? AND (? IS NULL OR channel_id = ?)
+         ORDER BY created_at",
+        server_id, channel_id, channel_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(Webhook {
+                id: Uuid::from_slice(&row.id).ok()?,
+                server_id: Uuid::from_slice(&row.server_id).ok()?,
+                channel_id: Uuid::from_slice(&row.channel_id).ok()?,
+                name: row.name,
+                avatar: row.avatar,
+                created_by: row.created_by.and_then(|id| Uuid::from_slice(&id).ok()),
+                created_at: row.created_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn update_webhook(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+    name: &str,
+    avatar: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE webhooks SET name = ?, avatar = ? WHERE id = ?",
+        name, avatar, webhook_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Replaces the token, the old url stops working immediately
+pub async fn set_webhook_token(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+    token_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE webhooks SET token_hash = ? WHERE id = ?", token_hash, webhook_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_webhook(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM webhooks WHERE id = ?", webhook_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Outgoing Webhooks
+pub async fn create_outgoing_webhook(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    created_by: Uuid,
+    url: &str,
+    secret: &str,
+    events: &[WebhookEvent],
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        "INSERT INTO outgoing_webhooks (id, server_id, url, secret, events, created_by) VALUES (?, ?, ?, ?, ?, ?)",
+        id, server_id, url, secret, Json(events), created_by
+    )
+    .execute(pool)
+    .await?;
+    Ok(id)
+}
+
+pub async fn get_outgoing_webhook(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+) -> Result<Option<OutgoingWebhook>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, server_id, url, secret, events as "events: Json<Vec<WebhookEvent>>",
+            enabled as "enabled: bool", consecutive_failures, disabled_reason, created_by, created_at
+        FROM outgoing_webhooks
+        WHERE id = ?"#,
+        webhook_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(OutgoingWebhook {
+            id: Uuid::from_slice(&row.id).ok()?,
+            server_id: Uuid::from_slice(&row.server_id).ok()?,
+            url: row.url,
+            secret: row.secret,
+            events: row.events.0,
+            enabled: row.enabled,
+            consecutive_failures: row.consecutive_failures,
+            disabled_reason: row.disabled_reason,
+            created_by: row.created_by.and_then(|id| Uuid::from_slice(&id).ok()),
+            created_at: row.created_at,
+        })
+    }))
+}
+
+pub async fn get_outgoing_webhooks(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Vec<OutgoingWebhook>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT id, server_id, url, secret, events as "events: Json<Vec<WebhookEvent>>",
+            enabled as "enabled: bool", consecutive_failures, disabled_reason, created_by, created_at
+        FROM outgoing_webhooks
+        WHERE server_id = ?
+        ORDER BY created_at"#,
+        server_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(OutgoingWebhook {
+                id: Uuid::from_slice(&row.id).ok()?,
+                server_id: Uuid::from_slice(&row.server_id).ok()?,
+                url: row.url,
+                secret: row.secret,
+                events: row.events.0,
+                enabled: row.enabled,
+                consecutive_failures: row.consecutive_failures,
+                disabled_reason: row.disabled_reason,
+                created_by: row.created_by.and_then(|id| Uuid::from_slice(&id).ok()),
+                created_at: row.created_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn get_enabled_outgoing_webhooks(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Vec<OutgoingWebhook>, sqlx::Error> {
+    let mut webhooks = get_outgoing_webhooks(pool, server_id).await?;
+    webhooks.retain(|webhook| webhook.enabled);
+    Ok(webhooks)
+}
+
+/// Re-enabling a webhook forgets its past failures
+pub async fn update_outgoing_webhook(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+    url: &str,
+    events: &[WebhookEvent],
+    enabled: bool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE outgoing_webhooks
+         SET url = ?, events = ?,
+            consecutive_failures = IF(? AND NOT enabled, 0, consecutive_failures),
+            disabled_reason = IF(?, NULL, disabled_reason),
+            enabled = ?
+         WHERE id = ?",
+        url, Json(events), enabled, enabled, enabled, webhook_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_outgoing_webhook(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM outgoing_webhooks WHERE id = ?", webhook_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn record_outgoing_webhook_success(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE outgoing_webhooks SET consecutive_failures = 0 WHERE id = ?", webhook_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Returns how many deliveries in a row have now failed
+pub async fn record_outgoing_webhook_failure(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+) -> Result<i32, sqlx::Error> {
+    sqlx::query!(
+        "UPDATE outgoing_webhooks SET consecutive_failures = consecutive_failures + 1 WHERE id = ?",
+        webhook_id
+    )
+    .execute(pool)
+    .await?;
+    let failures = sqlx::query_scalar!("SELECT consecutive_failures FROM outgoing_webhooks WHERE id = ?", webhook_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(failures.unwrap_or(0))
+}
+
+pub async fn disable_outgoing_webhook(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE outgoing_webhooks SET enabled = FALSE, disabled_reason = ? WHERE id = ?",
+        reason, webhook_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn record_webhook_delivery(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+    delivery_id: Uuid,
+    event: WebhookEvent,
+    attempt: i32,
+    status_code: Option<u16>,
+    error: Option<&str>,
+    duration_ms: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO webhook_deliveries (id, webhook_id, delivery_id, event, attempt, status_code, error, duration_ms)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        Uuid::new_v4(), webhook_id, delivery_id, event.as_str(), attempt, status_code.map(i32::from), error, duration_ms
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Newest attempts first
+pub async fn get_webhook_deliveries(
+    pool: &Pool<MySql>,
+    webhook_id: Uuid,
+    before: Option<DateTime<Utc>>,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT id, delivery_id, event, attempt, status_code, error, duration_ms, created_at
+         FROM webhook_deliveries
+         WHERE webhook_id = ? AND (? IS NULL OR created_at < ?)
+         ORDER BY created_at DESC
+         LIMIT ?",
+        webhook_id, before, before, limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(WebhookDelivery {
+                id: Uuid::from_slice(&row.id).ok()?,
+                delivery_id: Uuid::from_slice(&row.delivery_id).ok()?,
+                event: WebhookEvent::from_str(&row.event).ok()?,
+                attempt: row.attempt,
+                success: row.status_code.is_some_and(|code| (200..300).contains(&code)),
+                status_code: row.status_code,
+                error: row.error,
+                duration_ms: row.duration_ms,
+                created_at: row.created_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn prune_webhook_deliveries(
+    pool: &Pool<MySql>,
+    older_than: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM webhook_deliveries WHERE created_at < ?", older_than)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+pub struct WebhookRetry {
+    pub delivery_id: Uuid,
+    pub webhook_id: Uuid,
+    pub event: WebhookEvent,
+    pub body: Vec<u8>,
+    /// Attempts already made
+    pub attempts: i32,
+}
+
+/// Stores (or pushes back) a delivery's next attempt. The body is kept as
+/// sent so every attempt carries the same envelope
+pub async fn schedule_webhook_retry(
+    pool: &Pool<MySql>,
+    delivery_id: Uuid,
+    webhook_id: Uuid,
+    event: WebhookEvent,
+    body: &[u8],
+    attempts: i32,
+    retry_in_secs: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO webhook_retries (delivery_id, webhook_id, event, body, attempts, next_attempt_at)
+         VALUES (?, ?, ?, ?, ?, CURRENT_TIMESTAMP + INTERVAL ? SECOND)
+         ON DUPLICATE KEY UPDATE attempts = VALUES(attempts), next_attempt_at = VALUES(next_attempt_at)",
+        delivery_id, webhook_id, event.as_str(), body, attempts, retry_in_secs
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Claims due retries the same way thumbnail jobs are claimed, so one that
+/// stalls is picked up again once its lease runs out
+pub async fn claim_webhook_retries(
+    pool: &Pool<MySql>,
+    limit: i64,
+) -> Result<Vec<WebhookRetry>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let rows = sqlx::query!(
+        "SELECT delivery_id, webhook_id, event, body, attempts FROM webhook_retries
+         WHERE next_attempt_at <= CURRENT_TIMESTAMP
+         ORDER BY next_attempt_at
+         LIMIT ?
+         FOR UPDATE SKIP LOCKED",
+        limit
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut retries = Vec::with_capacity(rows.len());
+    for row in rows {
+        sqlx::query!(
+            "UPDATE webhook_retries
+             SET next_attempt_at = CURRENT_TIMESTAMP + INTERVAL 10 MINUTE
+             WHERE delivery_id = ?",
+            row.delivery_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let (Ok(delivery_id), Ok(webhook_id), Ok(event)) = (
+            Uuid::from_slice(&row.delivery_id),
+            Uuid::from_slice(&row.webhook_id),
+            WebhookEvent::from_str(&row.event),
+        ) else {
+            continue;
+        };
+        retries.push(WebhookRetry {
+            delivery_id,
+            webhook_id,
+            event,
+            body: row.body,
+            attempts: row.attempts,
+        });
+    }
+
+    tx.commit().await?;
+    Ok(retries)
+}
+
+pub async fn delete_webhook_retry(
+    pool: &Pool<MySql>,
+    delivery_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM webhook_retries WHERE delivery_id = ?", delivery_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Notification Settings
+/// Every server the user changed settings for, with its channel overrides
+pub async fn get_notification_settings(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+) -> Result<Vec<ServerNotificationSettings>, sqlx::Error> {
+    let servers = sqlx::query!(
+        r#"SELECT server_id, level as "level: String", muted as "muted: bool",
+            muted_until as "muted_until: DateTime<Utc>",
+            suppress_everyone as "suppress_everyone: bool", suppress_roles as "suppress_roles: bool"
+        FROM server_notification_settings
+        WHERE user_id = ?"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let channels = sqlx::query!(
+        r#"SELECT cn.channel_id, c.server_id, cn.level as "level: String", cn.muted as "muted: bool",
+            cn.muted_until as "muted_until: DateTime<Utc>"
+        FROM channel_notification_settings cn
+        JOIN channels c ON c.id = cn.channel_id
+        WHERE cn.user_id = ?"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut settings: Vec<ServerNotificationSettings> = servers
+        .into_iter()
+        .filter_map(|row| {
+            Some(ServerNotificationSettings {
+                server_id: Uuid::from_slice(&row.server_id).ok()?,
+                level: NotificationLevel::from_str(&row.level).unwrap_or_default(),
+                muted: row.muted,
+                muted_until: row.muted_until,
+                suppress_everyone: row.suppress_everyone,
+                suppress_roles: row.suppress_roles,
+                channel_overrides: Vec::new(),
+            })
+        })
+        .collect();
+    for row in channels {
+        let (Ok(channel_id), Ok(server_id)) = (Uuid::from_slice(&row.channel_id), Uuid::from_slice(&row.server_id)) else {
+            continue;
+        };
+        let channel = ChannelNotificationOverride {
+            channel_id,
+            level: row.level.as_deref().and_then(|level| NotificationLevel::from_str(level).ok()),
+            muted: row.muted,
+            muted_until: row.muted_until,
+        };
+        match settings.iter_mut().find(|s| s.server_id == server_id) {
+            Some(server) => server.channel_overrides.push(channel),
+            None => {
+                let mut server = ServerNotificationSettings::new(server_id);
+                server.channel_overrides.push(channel);
+                settings.push(server);
+            }
+        }
+    }
+    Ok(settings)
+}
+
+pub async fn get_server_notification_settings(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    server_id: Uuid,
+) -> Result<ServerNotificationSettings, sqlx::Error> {
+    Ok(get_notification_settings(pool, user_id)
+        .await?
+        .into_iter()
+        .find(|s| s.server_id == server_id)
+        .unwrap_or_else(|| ServerNotificationSettings::new(server_id)))
+}
+
+/// Settings in one server for each of the users that changed them, with
+/// their channel overrides
+pub async fn get_users_notification_settings(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    user_ids: &[Uuid],
+) -> Result<HashMap<Uuid, ServerNotificationSettings>, sqlx::Error> {
+    let mut settings: HashMap<Uuid, ServerNotificationSettings> = HashMap::new();
+    if user_ids.is_empty() {
+        return Ok(settings);
+    }
+
+    let mut query = sqlx::QueryBuilder::<MySql>::new(
+        "SELECT user_id, level, muted, muted_until, suppress_everyone, suppress_roles
+         FROM server_notification_settings
+         WHERE server_id = ",
+    );
+    query.push_bind(server_id);
+    query.push(" AND user_id IN (");
+    let mut ids = query.separated(", ");
+    for id in user_ids {
+        ids.push_bind(*id);
+    }
+    query.push(")");
+    let servers: Vec<(Vec<u8>, String, bool, Option<DateTime<Utc>>, bool, bool)> =
+        query.build_query_as().fetch_all(pool).await?;
+    for (user_id, level, muted, muted_until, suppress_everyone, suppress_roles) in servers {
+        let Ok(user_id) = Uuid::from_slice(&user_id) else {
+            continue;
+        };
+        settings.insert(
+            user_id,
+            ServerNotificationSettings {
+                server_id,
+                level: NotificationLevel::from_str(&level).unwrap_or_default(),
+                muted,
+                muted_until,
+                suppress_everyone,
+                suppress_roles,
+                channel_overrides: Vec::new(),
+            },
+        );
+    }
+
+    let mut query = sqlx::QueryBuilder::<MySql>::new(
+        "SELECT cn.user_id, cn.channel_id, cn.level, cn.muted, cn.muted_until
+         FROM channel_notification_settings cn
+         JOIN channels c ON c.id = cn.channel_id
+         WHERE c.server_id = ",
+    );
+    query.push_bind(server_id);
+    query.push(" AND cn.user_id IN (");
+    let mut ids = query.separated(", ");
+    for id in user_ids {
+        ids.push_bind(*id);
+    }
+    query.push(")");
+    let channels: Vec<(Vec<u8>, Vec<u8>, Option<String>, bool, Option<DateTime<Utc>>)> =
+        query.build_query_as().fetch_all(pool).await?;
+    for (user_id, channel_id, level, muted, muted_until) in channels {
+        let (Ok(user_id), Ok(channel_id)) = (Uuid::from_slice(&user_id), Uuid::from_slice(&channel_id)) else {
+            continue;
+        };
+        settings
+            .entry(user_id)
+            .or_insert_with(|| ServerNotificationSettings::new(server_id))
+            .channel_overrides
+            .push(ChannelNotificationOverride {
+                channel_id,
+                level: level.as_deref().and_then(|level| NotificationLevel::from_str(level).ok()),
+                muted,
+                muted_until,
+            });
+    }
+    Ok(settings)
+}
+
+pub async fn set_server_notification_settings(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    settings: &ServerNotificationSettings,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO server_notification_settings
+            (user_id, server_id, level, muted, muted_until, suppress_everyone, suppress_roles)
+         VALUES (?, ?, ?, ?, ?, ?, ?)
+         ON DUPLICATE KEY UPDATE
+            level = VALUES(level),
+            muted = VALUES(muted),
+            muted_until = VALUES(muted_until),
+            suppress_everyone = VALUES(suppress_everyone),
+            suppress_roles = VALUES(suppress_roles)",
+        user_id, settings.server_id, settings.level.as_str(), settings.muted, settings.muted_until,
+        settings.suppress_everyone, settings.suppress_roles
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn set_channel_notification_override(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    channel: &ChannelNotificationOverride,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO channel_notification_settings (user_id, channel_id, level, muted, muted_until)
+         VALUES (?, ?, ?, ?, ?)
+         ON DUPLICATE KEY UPDATE
+            level = VALUES(level),
+            muted = VALUES(muted),
+            muted_until = VALUES(muted_until)",
+        user_id, channel.channel_id, channel.level.map(|level| level.as_str()), channel.muted, channel.muted_until
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_channel_notification_override(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    channel_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM channel_notification_settings WHERE user_id = ? AND channel_id = ?",
+        user_id, channel_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Marks the channel read up to the message. Acks never move backwards, so
+/// an older ack from another device is ignored.
+pub async fn ack_message(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    channel_id: Uuid,
+    message_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    // Assignments run left to right, last_message_id sees the old last_read_seq
+    sqlx::query!(
+        "INSERT INTO read_states (user_id, channel_id, last_message_id, last_read_seq)
+         SELECT ?, ?, id, seq FROM messages WHERE id = ?
+         ON DUPLICATE KEY UPDATE
+             last_message_id = IF(VALUES(last_read_seq) > last_read_seq, VALUES(last_message_id), last_message_id),
+             last_read_seq = GREATEST(last_read_seq, VALUES(last_read_seq))",
+        user_id, channel_id, message_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Acks the latest message of every channel in the server the user can see.
+/// Returns the channels that were marked, with the message they were marked at.
+pub async fn ack_server(
+    pool: &Pool<MySql>,
+    user_id: Uuid,
+    server_id: Uuid,
+    include_private: bool,
+) -> Result<Vec<(Uuid, Uuid)>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let rows = sqlx::query!(
+        r#"SELECT c.id, c.last_message_id as "last_message_id!: Vec<u8>"
+        FROM channels c
+        WHERE c.server_id = ? AND c.last_message_id IS NOT NULL AND (c.is_private = false OR ?)"#,
+        server_id, include_private
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut acked = Vec::with_capacity(rows.len());
+    for row in rows {
+        sqlx::query!(
+            "INSERT INTO read_states (user_id, channel_id, last_message_id, last_read_seq)
+             SELECT ?, channel_id, id, seq FROM messages WHERE id = ?
+             ON DUPLICATE KEY UPDATE
+                 last_message_id = IF(VALUES(last_read_seq) > last_read_seq, VALUES(last_message_id), last_message_id),
+                 last_read_seq = GREATEST(last_read_seq, VALUES(last_read_seq))",
+            user_id, row.last_message_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if let (Ok(channel_id), Ok(message_id)) = (Uuid::from_slice(&row.id), Uuid::from_slice(&row.last_message_id)) {
+            acked.push((channel_id, message_id));
+        }
+    }
+    tx.commit().await?;
+    Ok(acked)
+}
+
+// Reactions
+
+/// Adds a reaction unless it would push the message past `max_distinct`
+/// different emoji. Returns false when the cap was hit.
+pub async fn add_reaction(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    user_id: Uuid,
+    emoji: &str,
+    max_distinct: i64,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Lock the message row so two new emoji can't both slip in under the cap
+    sqlx::query!("SELECT id FROM messages WHERE id = ? FOR UPDATE", message_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let distinct = sqlx::query_scalar!(
+        r#"SELECT COUNT(DISTINCT emoji) as "count!: i64" FROM reactions WHERE message_id = ?"#,
+        message_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let known = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM reactions WHERE message_id = ? AND emoji = ?) as "known!: bool""#,
+        message_id, emoji
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !known && distinct >= max_distinct {
+        tx.rollback().await?;
+        return Ok(false);
+    }
+
+    sqlx::query!(
+        "INSERT IGNORE INTO reactions (message_id, user_id, emoji) VALUES (?, ?, ?)",
+        message_id, user_id, emoji
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn remove_reaction(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    user_id: Uuid,
+    emoji: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM reactions 
+         WHERE message_id = ? AND user_id = ? AND emoji = ?",
+        message_id, user_id, emoji
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn get_reaction_users(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    emoji: &str,
+    limit: i64,
+    after: Option<Uuid>,
+) -> Result<Vec<PublicUser>, sqlx::Error> {
+    // Paginate on user id, reactions have no meaningful order of their own
+    let after = after.unwrap_or(Uuid::nil());
+    let rows = sqlx::query!(
+        r#"SELECT u.id, u.username, u.display_name, u.avatar, u.banner, u.bio, u.pronouns,
+            u.is_bot as "is_bot: bool", u.created_at
+        FROM reactions r
+        JOIN users u ON u.id = r.user_id
+        WHERE r.message_id = ? AND r.emoji = ? AND r.user_id > ?
+        ORDER BY r.user_id
+        LIMIT ?"#,
+        message_id, emoji, after, limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(PublicUser {
+                id: Uuid::from_slice(&row.id).ok()?,
+                username: row.username,
+                display_name: row.display_name,
+                avatar: row.avatar,
+                banner: row.banner,
+                bio: row.bio,
+                pronouns: row.pronouns,
+                bot: row.is_bot,
+                created_at: row.created_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn clear_reactions(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM reactions WHERE message_id = ?", message_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn clear_reaction_emoji(
+    pool: &Pool<MySql>,
+    message_id: Uuid,
+    emoji: &str,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM reactions WHERE message_id = ? AND emoji = ?",
+        message_id, emoji
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Reactions on each of the messages, one entry per emoji with how many
+/// users picked it and whether `viewer` is one of them. Custom emoji come
+/// back as `name:id`, the way the reaction routes take them.
+pub async fn get_message_reactions(
+    pool: &Pool<MySql>,
+    message_ids: &[Uuid],
+    viewer: Option<Uuid>,
+) -> Result<HashMap<Uuid, Vec<Reaction>>, sqlx::Error> {
+    let mut reactions: HashMap<Uuid, Vec<Reaction>> = HashMap::new();
+    if message_ids.is_empty() {
+        return Ok(reactions);
+    }
+
+    let mut query = sqlx::QueryBuilder::<MySql>::new(
+        "SELECT message_id, emoji, COUNT(*), CAST(COALESCE(MAX(user_id = ",
+    );
+    query.push_bind(viewer);
+    query.push("), 0) AS SIGNED) FROM reactions WHERE message_id IN (");
+    let mut ids = query.separated(", ");
+    for id in message_ids {
+        ids.push_bind(*id);
+    }
+    query.push(") GROUP BY message_id, emoji ORDER BY COUNT(*) DESC, emoji");
+    let rows: Vec<(Vec<u8>, String, i64, i64)> = query.build_query_as().fetch_all(pool).await?;
+
+    // Custom emoji are stored by id alone
+    let custom: HashSet<Uuid> = rows.iter().filter_map(|(_, emoji, _, _)| Uuid::parse_str(emoji).ok()).collect();
+    let mut names = HashMap::new();
+    if !custom.is_empty() {
+        let mut query = sqlx::QueryBuilder::<MySql>::new("SELECT id, name FROM server_emoji WHERE id IN (");
+        let mut ids = query.separated(", ");
+        for id in &custom {
+            ids.push_bind(*id);
+        }
+        query.push(")");
+        let rows: Vec<(Vec<u8>, String)> = query.build_query_as().fetch_all(pool).await?;
+        names.extend(rows.into_iter().filter_map(|(id, name)| Some((Uuid::from_slice(&id).ok()?, name))));
+    }
+
+    for (message_id, emoji, count, reacted) in rows {
+        let Ok(message_id) = Uuid::from_slice(&message_id) else {
+            continue;
+        };
+        let emoji = match Uuid::parse_str(&emoji) {
+            Ok(id) => match names.get(&id) {
+                Some(name) => format!("{name}:{id}"),
+                // Deleted along with its reactions, just not yet
+                None => continue,
+            },
+            Err(_) => emoji,
+        };
+        reactions.entry(message_id).or_default().push(Reaction {
+            emoji,
+            count: count as i32,
+            has_reacted: reacted != 0,
+        });
+    }
+    Ok(reactions)
+}
+
+// Server Emoji
+
+/// True if the emoji exists in the server and the user holds one of its
+/// restricted roles (or it has none).
+pub async fn can_use_server_emoji(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    emoji_id: Uuid,
+    name: &str,
+    user_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS(
+            SELECT 1 FROM server_emoji e
+            WHERE e.id = ? AND e.server_id = ? AND e.name = ?
+            AND (
+                NOT EXISTS(SELECT 1 FROM server_emoji_roles er WHERE er.emoji_id = e.id)
+                OR EXISTS(
+                    SELECT 1 FROM server_emoji_roles er
+                    JOIN member_roles mr ON mr.role_id = er.role_id
+                    WHERE er.emoji_id = e.id AND mr.server_id = e.server_id AND mr.user_id = ?
+                )
+                OR EXISTS(SELECT 1 FROM servers s WHERE s.id = e.server_id AND s.owner_id = ?)
+            )
+        ) as "usable!: bool""#,
+        emoji_id, server_id, name, user_id, user_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn list_server_emoji(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Vec<ServerEmoji>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT id, name, image, animated as "animated: bool", uploader_id, created_at
+        FROM server_emoji
+        WHERE server_id = ?
+        ORDER BY created_at"#,
+        server_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let role_rows = sqlx::query!(
+        "SELECT er.emoji_id, er.role_id
+         FROM server_emoji_roles er
+         JOIN server_emoji e ON e.id = er.emoji_id
+         WHERE e.server_id = ?",
+        server_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let roles = role_rows
+                .iter()
+                .filter(|role| role.emoji_id == row.id)
+                .filter_map(|role| Uuid::from_slice(&role.role_id).ok())
+                .collect();
+            Some(ServerEmoji {
+                id: Uuid::from_slice(&row.id).ok()?,
+                server_id,
+                name: row.name,
+                image: row.image,
+                animated: row.animated,
+                uploader_id: row.uploader_id.as_deref().map(Uuid::from_slice).transpose().ok().flatten(),
+                roles,
+                created_at: row.created_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn get_server_emoji(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    emoji_id: Uuid,
+) -> Result<Option<ServerEmoji>, sqlx::Error> {
+    let emoji = list_server_emoji(pool, server_id).await?;
+    Ok(emoji.into_iter().find(|emoji| emoji.id == emoji_id))
+}
+
+/// Creates an emoji unless the server already has `max_emoji`. Returns None
+/// when the cap was hit.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_server_emoji(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    name: &str,
+    image: &str,
+    animated: bool,
+    uploader_id: Uuid,
+    roles: &[Uuid],
+    max_emoji: i64,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let id = Uuid::new_v4();
+    let mut tx = pool.begin().await?;
+
+    // Lock the server row so two uploads can't both slip in under the cap
+    sqlx::query!("SELECT id FROM servers WHERE id = ? FOR UPDATE", server_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let count = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!: i64" FROM server_emoji WHERE server_id = ?"#,
+        server_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if count >= max_emoji {
+        tx.rollback().await?;
+        return Ok(None);
+    }
+
+    sqlx::query!(
+        "INSERT INTO server_emoji (id, server_id, name, image, animated, uploader_id)
+         VALUES (?, ?, ?, ?, ?, ?)",
+        id, server_id, name, image, animated, uploader_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    for role_id in roles {
+        sqlx::query!(
+            "INSERT INTO server_emoji_roles (emoji_id, role_id)
+             SELECT ?, id FROM roles WHERE id = ? AND server_id = ?",
+            id, role_id, server_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(Some(id))
+}
+
+pub async fn update_server_emoji(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    emoji_id: Uuid,
+    name: Option<&str>,
+    roles: Option<&[Uuid]>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    if let Some(name) = name {
+        sqlx::query!(
+            "UPDATE server_emoji SET name = ? WHERE id = ? AND server_id = ?",
+            name, emoji_id, server_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if let Some(roles) = roles {
+        sqlx::query!("DELETE FROM server_emoji_roles WHERE emoji_id = ?", emoji_id)
+            .execute(&mut *tx)
+            .await?;
+        for role_id in roles {
+            sqlx::query!(
+                "INSERT INTO server_emoji_roles (emoji_id, role_id)
+                 SELECT ?, id FROM roles WHERE id = ? AND server_id = ?",
+                emoji_id, role_id, server_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Deletes an emoji along with every reaction that used it. Returns the
+/// emoji's image url, or None if there was no such emoji.
+pub async fn delete_server_emoji(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    emoji_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let image = sqlx::query_scalar!(
+        "SELECT image FROM server_emoji WHERE id = ? AND server_id = ? FOR UPDATE",
+        emoji_id, server_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(image) = image else {
+        tx.rollback().await?;
+        return Ok(None);
+    };
+
+    sqlx::query!("DELETE FROM server_emoji WHERE id = ?", emoji_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM reactions WHERE emoji = ?", emoji_id.to_string())
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(Some(image))
+}
+
+// Attachments
+#[derive(Debug)]
+pub struct NewAttachment<'a> {
+    pub channel_id: Uuid,
+    /// None for files posted through a webhook
+    pub uploader_id: Option<Uuid>,
+    pub message_id: Option<Uuid>,
+    pub attachment_type: AttachmentType,
+    pub storage_key: &'a str,
+    pub filename: &'a str,
+    pub size: i64,
+    pub mime_type: &'a str,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub duration: Option<i32>,
+}
+
+pub async fn add_attachment(
+    pool: &Pool<MySql>,
+    attachment: NewAttachment<'_>,
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+    let url = attachment_url(id, attachment.filename);
+    sqlx::query!(
+        "INSERT INTO attachments 
+         (id, message_id, channel_id, uploader_id, attachment_type, storage_key, url, filename, size, mime_type, width, height, duration) 
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        id,
+        attachment.message_id,
+        attachment.channel_id,
+        attachment.uploader_id,
+        attachment.attachment_type.to_string(),
+        attachment.storage_key,
+        url,
+        attachment.filename,
+        attachment.size,
+        attachment.mime_type,
+        attachment.width,
+        attachment.height,
+        attachment.duration
+    )
+    .execute(pool)
+    .await?;
+    Ok(id)
+}
+
+/// An attachment row along with where it lives and who it belongs to
+#[derive(Debug)]
+pub struct StoredAttachment {
+    pub attachment: Attachment,
+    pub channel_id: Uuid,
+    pub message_id: Option<Uuid>,
+    pub uploader_id: Option<Uuid>,
+    pub storage_key: String,
+}
+
+pub async fn get_attachment(
+    pool: &Pool<MySql>,
+    attachment_id: Uuid,
+) -> Result<Option<StoredAttachment>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, message_id, channel_id, uploader_id, attachment_type as "attachment_type: String",
+            storage_key, url, filename, size, mime_type, width, height, duration, blurhash
+        FROM attachments
+        WHERE id = ?"#,
+        attachment_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let thumbnails = get_thumbnails(pool, attachment_id)
+        .await?
+        .into_iter()
+        .map(|thumbnail| thumbnail.thumbnail)
+        .collect();
+
+    Ok((|| {
+        Some(StoredAttachment {
+            attachment: Attachment {
+                id: Uuid::from_slice(&row.id).ok()?,
+                attachment_type: AttachmentType::from_str(&row.attachment_type).unwrap_or(AttachmentType::File),
+                url: row.url,
+                filename: row.filename,
+                size: row.size,
+                mime_type: row.mime_type,
+                width: row.width,
+                height: row.height,
+                duration: row.duration,
+                blurhash: row.blurhash,
+                thumbnails,
+            },
+            channel_id: Uuid::from_slice(&row.channel_id).ok()?,
+            message_id: row.message_id.as_deref().map(Uuid::from_slice).transpose().ok().flatten(),
+            uploader_id: row.uploader_id.as_deref().map(Uuid::from_slice).transpose().ok().flatten(),
+            storage_key: row.storage_key,
+        })
+    })())
+}
 
-    Ok(messages)
+/// A thumbnail and the object backing it
+#[derive(Debug)]
+pub struct StoredThumbnail {
+    pub thumbnail: Thumbnail,
+    pub storage_key: String,
 }
 
-// Message Management
-pub async fn create_message(
+pub async fn get_thumbnails(
+    pool: &Pool<MySql>,
+    attachment_id: Uuid,
+) -> Result<Vec<StoredThumbnail>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT width, height, mime_type, storage_key
+         FROM attachment_thumbnails
+         WHERE attachment_id = ?
+         ORDER BY width",
+        attachment_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| StoredThumbnail {
+            thumbnail: Thumbnail {
+                url: thumbnail_url(attachment_id, row.width),
+                width: row.width,
+                height: row.height,
+                mime_type: row.mime_type,
+            },
+            storage_key: row.storage_key,
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+pub struct NewThumbnail {
+    pub width: i32,
+    pub height: i32,
+    pub mime_type: String,
+    pub storage_key: String,
+}
+
+/// Replaces an attachment's thumbnails, so a retried job can't leave duplicates
+pub async fn save_thumbnails(
+    pool: &Pool<MySql>,
+    attachment_id: Uuid,
+    blurhash: &str,
+    thumbnails: &[NewThumbnail],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM attachment_thumbnails WHERE attachment_id = ?", attachment_id)
+        .execute(&mut *tx)
+        .await?;
+    for thumbnail in thumbnails {
+        sqlx::query!(
+            "INSERT INTO attachment_thumbnails (attachment_id, width, height, mime_type, storage_key)
+             VALUES (?, ?, ?, ?, ?)",
+            attachment_id, thumbnail.width, thumbnail.height, thumbnail.mime_type, thumbnail.storage_key
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query!("UPDATE attachments SET blurhash = ? WHERE id = ?", blurhash, attachment_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+// Resumable uploads
+#[derive(Debug)]
+pub struct UploadSession {
+    pub id: Uuid,
+    pub channel_id: Uuid,
+    pub uploader_id: Uuid,
+    pub filename: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub async fn create_upload_session(
     pool: &Pool<MySql>,
     channel_id: Uuid,
-    author_id: Uuid,
-    content: &str,
-    reply_to_id: Option<Uuid>,
+    uploader_id: Uuid,
+    filename: &str,
+    upload_length: i64,
+    expires_at: DateTime<Utc>,
 ) -> Result<Uuid, sqlx::Error> {
     let id = Uuid::new_v4();
-    let mut tx = pool.begin().await?;
-
     sqlx::query!(
-        "INSERT INTO messages (id, channel_id, author_id, content, reply_to_id) 
-         VALUES (?, ?, ?, ?, ?)",
-        id, channel_id, author_id, content, reply_to_id
+        "INSERT INTO upload_sessions (id, channel_id, uploader_id, filename, upload_length, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?)",
+        id, channel_id, uploader_id, filename, upload_length, expires_at
     )
-    .execute(&mut *tx)
+    .execute(pool)
     .await?;
+    Ok(id)
+}
 
-    // Update last_message_id in channel
-    sqlx::query!(
-        "UPDATE channels SET last_message_id = ? WHERE id = ?",
-        id, channel_id
+/// Expired sessions are treated as gone even before they are cleaned up
+pub async fn get_upload_session(
+    pool: &Pool<MySql>,
+    upload_id: Uuid,
+) -> Result<Option<UploadSession>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, channel_id, uploader_id, filename, upload_length, upload_offset,
+            expires_at as "expires_at: DateTime<Utc>"
+        FROM upload_sessions
+        WHERE id = ? AND expires_at > CURRENT_TIMESTAMP"#,
+        upload_id
     )
-    .execute(&mut *tx)
+    .fetch_optional(pool)
     .await?;
 
-    tx.commit().await?;
-    Ok(id)
+    Ok(row.and_then(|row| {
+        Some(UploadSession {
+            id: Uuid::from_slice(&row.id).ok()?,
+            channel_id: Uuid::from_slice(&row.channel_id).ok()?,
+            uploader_id: Uuid::from_slice(&row.uploader_id).ok()?,
+            filename: row.filename,
+            upload_length: row.upload_length,
+            upload_offset: row.upload_offset,
+            expires_at: row.expires_at,
+        })
+    }))
 }
 
-pub async fn edit_message(
+pub async fn set_upload_offset(
     pool: &Pool<MySql>,
-    message_id: Uuid,
-    new_content: &str,
+    upload_id: Uuid,
+    upload_offset: i64,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        "UPDATE messages SET content = ?, edited_at = CURRENT_TIMESTAMP 
-         WHERE id = ?",
-        new_content, message_id
+        "UPDATE upload_sessions SET upload_offset = ? WHERE id = ?",
+        upload_offset, upload_id
     )
     .execute(pool)
     .await?;
     Ok(())
 }
 
-pub async fn delete_message(
+pub async fn delete_upload_session(
     pool: &Pool<MySql>,
-    message_id: Uuid,
+    upload_id: Uuid,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query!("DELETE FROM messages WHERE id = ?", message_id)
+    sqlx::query!("DELETE FROM upload_sessions WHERE id = ?", upload_id)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-// Reactions
-pub async fn add_reaction(
+pub async fn get_expired_upload_sessions(pool: &Pool<MySql>) -> Result<Vec<Uuid>, sqlx::Error> {
+    let rows = sqlx::query!("SELECT id FROM upload_sessions WHERE expires_at <= CURRENT_TIMESTAMP")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Uuid::from_slice(&row.id).ok())
+        .collect())
+}
+
+// Garbage collection
+/// Attachments uploaded before `cutoff` that never made it onto a message
+pub async fn get_orphaned_attachments(
     pool: &Pool<MySql>,
-    message_id: Uuid,
-    user_id: Uuid,
-    emoji: &str,
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT id FROM attachments WHERE message_id IS NULL AND created_at < ?",
+        cutoff
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Uuid::from_slice(&row.id).ok())
+        .collect())
+}
+
+/// Deletes attachment rows, their thumbnails and jobs go with them. The
+/// `message_id IS NULL` guard keeps one that got attached in the meantime.
+pub async fn delete_orphaned_attachments(
+    pool: &Pool<MySql>,
+    attachment_ids: &[Uuid],
+) -> Result<u64, sqlx::Error> {
+    if attachment_ids.is_empty() {
+        return Ok(0);
+    }
+    let mut query = sqlx::QueryBuilder::<MySql>::new("DELETE FROM attachments WHERE message_id IS NULL AND id IN (");
+    let mut ids = query.separated(", ");
+    for id in attachment_ids {
+        ids.push_bind(*id);
+    }
+    query.push(")");
+    Ok(query.build().execute(pool).await?.rows_affected())
+}
+
+/// Every storage key something still points at, leaving out attachments
+/// that would be collected as orphans at `cutoff`. Public files are stored
+/// as `/files/<key>` urls and come back as bare keys.
+pub async fn get_referenced_storage_keys(
+    pool: &Pool<MySql>,
+    cutoff: DateTime<Utc>,
+) -> Result<HashSet<String>, sqlx::Error> {
+    let keys = sqlx::query_scalar!(
+        r#"SELECT storage_key AS "storage_key!: String" FROM attachments
+            WHERE message_id IS NOT NULL OR created_at >= ?
+        UNION
+        SELECT t.storage_key FROM attachment_thumbnails t
+            JOIN attachments a ON a.id = t.attachment_id
+            WHERE a.message_id IS NOT NULL OR a.created_at >= ?
+        UNION SELECT avatar FROM users WHERE avatar IS NOT NULL
+        UNION SELECT banner FROM users WHERE banner IS NOT NULL
+        UNION SELECT icon FROM servers WHERE icon IS NOT NULL
+        UNION SELECT image FROM server_emoji"#,
+        cutoff,
+        cutoff
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(keys
+        .into_iter()
+        .map(|key| match key.strip_prefix("/files/") {
+            Some(key) => key.to_string(),
+            None => key,
+        })
+        .collect())
+}
+
+/// Whether any row still points at the object, however recent. Public
+/// files are matched by their `/files/<key>` url.
+pub async fn is_storage_key_referenced(
+    pool: &Pool<MySql>,
+    key: &str,
+) -> Result<bool, sqlx::Error> {
+    let url = format!("/files/{key}");
+    sqlx::query_scalar!(
+        r#"SELECT (
+            EXISTS(SELECT 1 FROM attachments WHERE storage_key = ?)
+            OR EXISTS(SELECT 1 FROM attachment_thumbnails WHERE storage_key = ?)
+            OR EXISTS(SELECT 1 FROM users WHERE avatar = ? OR banner = ?)
+            OR EXISTS(SELECT 1 FROM servers WHERE icon = ?)
+            OR EXISTS(SELECT 1 FROM server_emoji WHERE image = ?)
+        ) as "referenced!: bool""#,
+        key, key, url, url, url, url
+    )
+    .fetch_one(pool)
+    .await
+}
+
+// Thumbnail jobs
+pub async fn enqueue_thumbnail_job(
+    pool: &Pool<MySql>,
+    attachment_id: Uuid,
 ) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        "INSERT INTO reactions (message_id, user_id, emoji) VALUES (?, ?, ?)",
-        message_id, user_id, emoji
+        "INSERT IGNORE INTO thumbnail_jobs (attachment_id) VALUES (?)",
+        attachment_id
     )
     .execute(pool)
     .await?;
     Ok(())
 }
 
-pub async fn remove_reaction(
+#[derive(Debug)]
+pub struct ThumbnailJob {
+    pub attachment_id: Uuid,
+    /// Including the attempt that was just claimed
+    pub attempts: i32,
+}
+
+/// Claims due jobs by pushing their next attempt into the future, so another
+/// worker (or this one after a crash) only picks them up if they stall
+pub async fn claim_thumbnail_jobs(
     pool: &Pool<MySql>,
-    message_id: Uuid,
-    user_id: Uuid,
-    emoji: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        "DELETE FROM reactions 
-         WHERE message_id = ? AND user_id = ? AND emoji = ?",
-        message_id, user_id, emoji
+    limit: i64,
+) -> Result<Vec<ThumbnailJob>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let rows = sqlx::query!(
+        "SELECT attachment_id, attempts FROM thumbnail_jobs
+         WHERE next_attempt_at <= CURRENT_TIMESTAMP
+         ORDER BY next_attempt_at
+         LIMIT ?
+         FOR UPDATE SKIP LOCKED",
+        limit
     )
-    .execute(pool)
+    .fetch_all(&mut *tx)
     .await?;
+
+    let mut jobs = Vec::with_capacity(rows.len());
+    for row in rows {
+        sqlx::query!(
+            "UPDATE thumbnail_jobs
+             SET attempts = attempts + 1, next_attempt_at = CURRENT_TIMESTAMP + INTERVAL 10 MINUTE
+             WHERE attachment_id = ?",
+            row.attachment_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if let Ok(attachment_id) = Uuid::from_slice(&row.attachment_id) {
+            jobs.push(ThumbnailJob {
+                attachment_id,
+                attempts: row.attempts + 1,
+            });
+        }
+    }
+
+    tx.commit().await?;
+    Ok(jobs)
+}
+
+pub async fn complete_thumbnail_job(
+    pool: &Pool<MySql>,
+    attachment_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM thumbnail_jobs WHERE attachment_id = ?", attachment_id)
+        .execute(pool)
+        .await?;
     Ok(())
 }
 
-// Attachments
-pub async fn add_attachment(
+pub async fn fail_thumbnail_job(
     pool: &Pool<MySql>,
-    message_id: Uuid,
-    file_type: AttachmentType,
-    url: &str,
-    filename: &str,
-    size: i64,
-    mime_type: &str,
-) -> Result<Uuid, sqlx::Error> {
-    let id = Uuid::new_v4();
+    attachment_id: Uuid,
+    error: &str,
+    retry_in_secs: i64,
+) -> Result<(), sqlx::Error> {
     sqlx::query!(
-        "INSERT INTO attachments 
-         (id, message_id, attachment_type, url, filename, size, mime_type) 
-         VALUES (?, ?, ?, ?, ?, ?, ?)",
-        id, message_id, file_type.to_string(), url, filename, size, mime_type
+        "UPDATE thumbnail_jobs
+         SET last_error = ?, next_attempt_at = CURRENT_TIMESTAMP + INTERVAL ? SECOND
+         WHERE attachment_id = ?",
+        error, retry_in_secs, attachment_id
     )
     .execute(pool)
     .await?;
-    Ok(id)
+    Ok(())
 }
 
 // Invites
@@ -356,53 +3882,426 @@ pub async fn create_invite(
     max_uses: Option<i32>,
     expires_in: Option<i32>,
 ) -> Result<String, sqlx::Error> {
-    let code = generate_invite_code();
     let expires_at = expires_in.map(|hours| {
         chrono::Utc::now() + chrono::Duration::hours(hours as i64)
     });
 
-    sqlx::query!(
-        "INSERT INTO invites (code, server_id, inviter_id, max_uses, expires_at) 
-         VALUES (?, ?, ?, ?, ?)",
-        code, server_id, inviter_id, max_uses, expires_at
+    // Codes are random, on the rare collision just draw another
+    let mut attempts = 0;
+    loop {
+        let code = generate_invite_code();
+        let result = sqlx::query!(
+            "INSERT INTO invites (code, server_id, inviter_id, max_uses, expires_at) 
+             SELECT ?, ?, ?, ?, ?
+             WHERE NOT EXISTS (SELECT 1 FROM servers WHERE vanity_code = ?)",
+            code, server_id, inviter_id, max_uses, expires_at, code
+        )
+        .execute(pool)
+        .await;
+        attempts += 1;
+        match result {
+            Ok(done) if done.rows_affected() > 0 => return Ok(code),
+            Ok(_) if attempts < 5 => continue,
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() && attempts < 5 => continue,
+            Ok(_) => return Err(sqlx::Error::RowNotFound),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// An invite that can still be used: not expired and not used up
+pub async fn get_invite(
+    pool: &Pool<MySql>,
+    code: &str,
+) -> Result<Option<InviteResponse>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT code, server_id, inviter_id, max_uses, uses, expires_at as "expires_at: DateTime<Utc>", created_at
+        FROM invites
+        WHERE code = ?
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (max_uses IS NULL OR uses < max_uses)"#,
+        code
     )
-    .execute(pool)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|row| {
+        Some(InviteResponse {
+            code: row.code,
+            server_id: Uuid::from_slice(&row.server_id).ok()?,
+            inviter_id: Uuid::from_slice(&row.inviter_id).ok()?,
+            max_uses: row.max_uses,
+            uses: row.uses,
+            expires_at: row.expires_at,
+            created_at: row.created_at,
+        })
+    }))
+}
+
+/// The server's invites that are still usable, newest first
+pub async fn list_invites(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Vec<InviteResponse>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT code, server_id, inviter_id, max_uses, uses, expires_at as "expires_at: DateTime<Utc>", created_at
+        FROM invites
+        WHERE server_id = ?
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+        AND (max_uses IS NULL OR uses < max_uses)
+        ORDER BY created_at DESC"#,
+        server_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(InviteResponse {
+                code: row.code,
+                server_id: Uuid::from_slice(&row.server_id).ok()?,
+                inviter_id: Uuid::from_slice(&row.inviter_id).ok()?,
+                max_uses: row.max_uses,
+                uses: row.uses,
+                expires_at: row.expires_at,
+                created_at: row.created_at,
+            })
+        })
+        .collect())
+}
+
+pub async fn delete_invite(
+    pool: &Pool<MySql>,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM invites WHERE code = ?", code)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// What someone holding an invite or vanity code gets to see before joining
+pub async fn get_invite_preview(
+    pool: &Pool<MySql>,
+    code: &str,
+) -> Result<Option<InvitePreview>, sqlx::Error> {
+    let (server_id, expires_at) = match get_invite(pool, code).await? {
+        Some(invite) => (invite.server_id, invite.expires_at),
+        None => {
+            let vanity = sqlx::query_scalar!("SELECT id FROM servers WHERE vanity_code = ?", code)
+                .fetch_optional(pool)
+                .await?;
+            match vanity.and_then(|id| Uuid::from_slice(&id).ok()) {
+                Some(server_id) => (server_id, None),
+                None => return Ok(None),
+            }
+        }
+    };
+
+    let row = sqlx::query!(
+        r#"SELECT s.name, s.icon, s.description,
+            (SELECT COUNT(*) FROM server_members WHERE server_id = s.id)
+                + NOT EXISTS(SELECT 1 FROM server_members WHERE server_id = s.id AND user_id = s.owner_id)
+                AS "member_count!: i64"
+        FROM servers s
+        WHERE s.id = ?"#,
+        server_id
+    )
+    .fetch_optional(pool)
     .await?;
-    Ok(code)
+
+    Ok(row.map(|row| InvitePreview {
+        code: code.to_string(),
+        server_id,
+        server_name: row.name,
+        server_icon: row.icon,
+        server_description: row.description,
+        member_count: row.member_count,
+        expires_at,
+    }))
 }
 
+pub enum JoinOutcome {
+    Joined(Uuid),
+    AlreadyMember(Uuid),
+    Banned,
+    /// Unknown, expired or used up
+    InvalidInvite,
+}
+
+/// Redeems an invite or vanity code for `user_id`. The use count, ban check
+/// and membership all happen in one transaction, so a max-uses invite can't
+/// be overspent by concurrent joins.
 pub async fn use_invite(
     pool: &Pool<MySql>,
     code: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
+    user_id: Uuid,
+) -> Result<JoinOutcome, sqlx::Error> {
     let mut tx = pool.begin().await?;
 
     let invite = sqlx::query!(
-        "SELECT server_id, uses, max_uses, expires_at 
+        "SELECT server_id 
          FROM invites 
          WHERE code = ? 
          AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
-         AND (max_uses IS NULL OR uses < max_uses)",
+         AND (max_uses IS NULL OR uses < max_uses)
+         FOR UPDATE",
         code
     )
     .fetch_optional(&mut *tx)
     .await?;
+    let (server_id, is_vanity) = match invite {
+        Some(invite) => (invite.server_id, false),
+        None => {
+            let vanity = sqlx::query_scalar!("SELECT id FROM servers WHERE vanity_code = ? FOR UPDATE", code)
+                .fetch_optional(&mut *tx)
+                .await?;
+            match vanity {
+                Some(server_id) => (server_id, true),
+                None => {
+                    tx.rollback().await?;
+                    return Ok(JoinOutcome::InvalidInvite);
+                }
+            }
+        }
+    };
+    let server_uuid = Uuid::from_slice(&server_id).map_err(|_| sqlx::Error::Decode("Invalid UUID".into()))?;
 
-    if let Some(invite) = invite {
-        sqlx::query!(
-            "UPDATE invites SET uses = uses + 1 WHERE code = ?",
-            code
-        )
-        .execute(&mut *tx)
-        .await?;
+    let banned = sqlx::query_scalar!(
+        "SELECT 1 FROM server_bans WHERE server_id = ? AND user_id = ?",
+        server_id, user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .is_some();
+    if banned {
+        tx.rollback().await?;
+        return Ok(JoinOutcome::Banned);
+    }
+
+    let already_member = sqlx::query_scalar!(
+        "SELECT 1 FROM server_members WHERE server_id = ? AND user_id = ?
+         UNION
+         SELECT 1 FROM servers WHERE id = ? AND owner_id = ?",
+        server_id, user_id, server_id, user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .is_some();
+    if already_member {
+        // Rejoining doesn't spend a use
+        tx.rollback().await?;
+        return Ok(JoinOutcome::AlreadyMember(server_uuid));
+    }
 
-        tx.commit().await?;
-        Ok(Some(Uuid::from_slice(&invite.server_id).map_err(|_| sqlx::Error::Decode("Invalid UUID".into()))?))
+    sqlx::query!(
+        "INSERT INTO server_members (server_id, user_id, invite_code) VALUES (?, ?, ?)",
+        server_id, user_id, code
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO invite_joins (server_id, invite_code, user_id) VALUES (?, ?, ?)",
+        server_id, code, user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if is_vanity {
+        sqlx::query!("UPDATE servers SET vanity_uses = vanity_uses + 1 WHERE id = ?", server_id)
+            .execute(&mut *tx)
+            .await?;
     } else {
-        Ok(None)
+        sqlx::query!("UPDATE invites SET uses = uses + 1 WHERE code = ?", code)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    Ok(JoinOutcome::Joined(server_uuid))
+}
+
+/// A page of the server's members ordered by user id. The owner is listed
+/// even without a server_members row.
+pub async fn get_server_members(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    limit: i64,
+    after: Option<Uuid>,
+) -> Result<Vec<Member>, sqlx::Error> {
+    let after = after.unwrap_or(Uuid::nil());
+    let rows = sqlx::query!(
+        r#"SELECT u.id, u.username, u.display_name, u.avatar, u.banner, u.bio, u.pronouns,
+            u.is_bot as "is_bot: bool", u.created_at,
+            m.nickname, m.invite_code, m.joined_at AS "joined_at!: DateTime<Utc>"
+        FROM (
+            (SELECT user_id, nickname, invite_code, joined_at FROM server_members
+                WHERE server_id = ? AND user_id > ?
+                ORDER BY user_id
+                LIMIT ?)
+            UNION ALL
+            SELECT s.owner_id, NULL, NULL, s.created_at FROM servers s
+                WHERE s.id = ? AND s.owner_id > ?
+                AND NOT EXISTS(SELECT 1 FROM server_members WHERE server_id = s.id AND user_id = s.owner_id)
+        ) m
+        JOIN users u ON u.id = m.user_id
+        ORDER BY m.user_id
+        LIMIT ?"#,
+        server_id, after, limit, server_id, after, limit
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(Member {
+                user: PublicUser {
+                    id: Uuid::from_slice(&row.id).ok()?,
+                    username: row.username,
+                    display_name: row.display_name,
+                    avatar: row.avatar,
+                    banner: row.banner,
+                    bio: row.bio,
+                    pronouns: row.pronouns,
+                    bot: row.is_bot,
+                    created_at: row.created_at,
+                },
+                nickname: row.nickname,
+                joined_at: row.joined_at,
+                invite_code: row.invite_code,
+            })
+        })
+        .collect())
+}
+
+/// Joins through an invite or vanity code, whether or not it's still
+/// usable. None when the code never belonged to the server.
+pub async fn get_invite_stats(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    code: &str,
+    since: DateTime<Utc>,
+) -> Result<Option<InviteStats>, sqlx::Error> {
+    let invite = sqlx::query!(
+        r#"SELECT inviter_id, uses, max_uses, expires_at as "expires_at: DateTime<Utc>"
+        FROM invites WHERE code = ? AND server_id = ?"#,
+        code, server_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    let (inviter_id, uses, max_uses, expires_at) = match invite {
+        Some(invite) => (Uuid::from_slice(&invite.inviter_id).ok(), invite.uses, invite.max_uses, invite.expires_at),
+        None => {
+            let vanity_uses = sqlx::query_scalar!(
+                "SELECT vanity_uses FROM servers WHERE id = ? AND vanity_code = ?",
+                server_id, code
+            )
+            .fetch_optional(pool)
+            .await?;
+            let Some(uses) = vanity_uses else {
+                // Revoked invites keep their history
+                let joins = sqlx::query_scalar!(
+                    r#"SELECT COUNT(*) AS "count!: i64" FROM invite_joins WHERE server_id = ? AND invite_code = ?"#,
+                    server_id, code
+                )
+                .fetch_one(pool)
+                .await?;
+                if joins == 0 {
+                    return Ok(None);
+                }
+                return build_invite_stats(pool, server_id, code, since, None, joins as i32, None, None).await.map(Some);
+            };
+            (None, uses, None, None)
+        }
+    };
+    build_invite_stats(pool, server_id, code, since, inviter_id, uses, max_uses, expires_at)
+        .await
+        .map(Some)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn build_invite_stats(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    code: &str,
+    since: DateTime<Utc>,
+    inviter_id: Option<Uuid>,
+    uses: i32,
+    max_uses: Option<i32>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<InviteStats, sqlx::Error> {
+    let members_remaining = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!: i64" FROM server_members WHERE server_id = ? AND invite_code = ?"#,
+        server_id, code
+    )
+    .fetch_one(pool)
+    .await?;
+
+    let days = sqlx::query!(
+        r#"SELECT DATE(joined_at) AS "day!: chrono::NaiveDate", COUNT(*) AS "joins!: i64"
+        FROM invite_joins
+        WHERE server_id = ? AND invite_code = ? AND joined_at >= ?
+        GROUP BY DATE(joined_at)
+        ORDER BY DATE(joined_at)"#,
+        server_id, code, since
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(InviteStats {
+        code: code.to_string(),
+        inviter_id,
+        uses,
+        max_uses,
+        expires_at,
+        members_remaining,
+        joins: days
+            .into_iter()
+            .map(|row| InviteJoinDay {
+                date: row.day,
+                joins: row.joins,
+            })
+            .collect(),
+    })
+}
+
+/// Sets or clears the server's vanity code. Returns false when the code is
+/// already taken by another server or by a regular invite.
+pub async fn set_vanity_code(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+    code: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    if let Some(code) = code {
+        let taken_by_invite = sqlx::query_scalar!("SELECT 1 FROM invites WHERE code = ?", code)
+            .fetch_optional(pool)
+            .await?
+            .is_some();
+        if taken_by_invite {
+            return Ok(false);
+        }
+    }
+    match sqlx::query!("UPDATE servers SET vanity_code = ? WHERE id = ?", code, server_id)
+        .execute(pool)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(false),
+        Err(e) => Err(e),
     }
 }
 
+pub async fn get_vanity_code(
+    pool: &Pool<MySql>,
+    server_id: Uuid,
+) -> Result<Option<(String, i32)>, sqlx::Error> {
+    let row = sqlx::query!("SELECT vanity_code, vanity_uses FROM servers WHERE id = ?", server_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.and_then(|row| Some((row.vanity_code?, row.vanity_uses))))
+}
+
 // Helper function to generate random invite code
 fn generate_invite_code() -> String {
     use rand::Rng;
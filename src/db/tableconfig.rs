@@ -8,11 +8,16 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
             id BINARY(16) PRIMARY KEY,
             username VARCHAR(32) NOT NULL UNIQUE,
             display_name VARCHAR(32),
-            email VARCHAR(255) NOT NULL UNIQUE,
-            password_hash VARCHAR(255) NOT NULL,
+            email VARCHAR(255) UNIQUE,
+            password_hash VARCHAR(255),
+            is_bot BOOLEAN NOT NULL DEFAULT false,
             avatar TEXT,
-            status ENUM('online', 'idle', 'dnd', 'offline') NOT NULL DEFAULT 'offline',
+            banner TEXT,
+            bio TEXT,
+            pronouns VARCHAR(40),
+            status ENUM('online', 'idle', 'dnd', 'invisible', 'offline') NOT NULL DEFAULT 'online',
             custom_status TEXT,
+            custom_status_expires_at TIMESTAMP NULL,
             created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
             updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP
         )"
@@ -20,6 +25,34 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
     .execute(&mut **transaction)
     .await?;
 
+    // Signed in sessions. Only a hash of the token is kept, like bot tokens.
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS sessions (
+            id BINARY(16) PRIMARY KEY,
+            user_id BINARY(16) NOT NULL,
+            token_hash CHAR(64) NOT NULL UNIQUE,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            expires_at TIMESTAMP NOT NULL,
+            INDEX (user_id, expires_at),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create username_history table, used to rate limit username changes
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS username_history (
+            user_id BINARY(16) NOT NULL,
+            old_username VARCHAR(32) NOT NULL,
+            changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            INDEX (user_id, changed_at),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
     // Create servers table
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS servers (
@@ -28,6 +61,9 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
             description TEXT,
             icon TEXT,
             owner_id BINARY(16) NOT NULL,
+            max_upload_size BIGINT,
+            vanity_code VARCHAR(32) UNIQUE,
+            vanity_uses INT NOT NULL DEFAULT 0,
             created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
             updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
             FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE CASCADE
@@ -59,13 +95,24 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS messages (
             id BINARY(16) PRIMARY KEY,
+            seq BIGINT UNSIGNED NOT NULL AUTO_INCREMENT UNIQUE,
             content TEXT NOT NULL,
-            author_id BINARY(16) NOT NULL,
+            author_id BINARY(16),
+            webhook_id BINARY(16),
+            webhook_name VARCHAR(80),
+            webhook_avatar VARCHAR(2048),
             channel_id BINARY(16) NOT NULL,
             reply_to_id BINARY(16),
             is_pinned BOOLEAN NOT NULL DEFAULT false,
+            mention_everyone BOOLEAN NOT NULL DEFAULT false,
+            embeds JSON,
+            link_embeds JSON,
             created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
             edited_at TIMESTAMP NULL,
+            FULLTEXT INDEX idx_messages_content (content),
+            INDEX idx_messages_channel_created (channel_id, created_at),
+            INDEX idx_messages_channel_seq (channel_id, seq),
+            INDEX (webhook_id),
             FOREIGN KEY (author_id) REFERENCES users(id) ON DELETE CASCADE,
             FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE,
             FOREIGN KEY (reply_to_id) REFERENCES messages(id) ON DELETE SET NULL
@@ -84,12 +131,15 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
     .execute(&mut **transaction)
     .await?;
 
-    // Create attachments table
+    // Create attachments table, message_id stays NULL until a message claims the upload
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS attachments (
             id BINARY(16) PRIMARY KEY,
-            message_id BINARY(16) NOT NULL,
+            message_id BINARY(16),
+            channel_id BINARY(16) NOT NULL,
+            uploader_id BINARY(16),
             attachment_type ENUM('image', 'video', 'file') NOT NULL,
+            storage_key VARCHAR(255) NOT NULL,
             url TEXT NOT NULL,
             filename VARCHAR(255) NOT NULL,
             size BIGINT NOT NULL,
@@ -97,7 +147,61 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
             width INT,
             height INT,
             duration INT,
-            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+            blurhash VARCHAR(64),
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            INDEX (storage_key),
+            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+            FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE,
+            FOREIGN KEY (uploader_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create upload_sessions table for resumable uploads still in progress
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS upload_sessions (
+            id BINARY(16) PRIMARY KEY,
+            channel_id BINARY(16) NOT NULL,
+            uploader_id BINARY(16) NOT NULL,
+            filename VARCHAR(255) NOT NULL,
+            upload_length BIGINT NOT NULL,
+            upload_offset BIGINT NOT NULL DEFAULT 0,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            expires_at TIMESTAMP NOT NULL,
+            INDEX (expires_at),
+            FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE,
+            FOREIGN KEY (uploader_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create attachment_thumbnails table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS attachment_thumbnails (
+            attachment_id BINARY(16) NOT NULL,
+            width INT NOT NULL,
+            height INT NOT NULL,
+            mime_type VARCHAR(127) NOT NULL,
+            storage_key VARCHAR(255) NOT NULL,
+            PRIMARY KEY (attachment_id, width),
+            INDEX (storage_key),
+            FOREIGN KEY (attachment_id) REFERENCES attachments(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create thumbnail_jobs table, rows are removed once the job succeeds or gives up
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS thumbnail_jobs (
+            attachment_id BINARY(16) PRIMARY KEY,
+            attempts INT NOT NULL DEFAULT 0,
+            next_attempt_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            last_error TEXT,
+            INDEX (next_attempt_at),
+            FOREIGN KEY (attachment_id) REFERENCES attachments(id) ON DELETE CASCADE
         )"
     )
     .execute(&mut **transaction)
@@ -108,7 +212,7 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
         "CREATE TABLE IF NOT EXISTS reactions (
             message_id BINARY(16) NOT NULL,
             user_id BINARY(16) NOT NULL,
-            emoji VARCHAR(32) NOT NULL,
+            emoji VARCHAR(128) NOT NULL,
             PRIMARY KEY (message_id, user_id, emoji),
             FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
             FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
@@ -135,8 +239,11 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
         "CREATE TABLE IF NOT EXISTS server_members (
             user_id BINARY(16) NOT NULL,
             server_id BINARY(16) NOT NULL,
+            nickname VARCHAR(32),
+            invite_code VARCHAR(32),
             joined_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
             PRIMARY KEY (user_id, server_id),
+            INDEX idx_server_members_invite (server_id, invite_code),
             FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
             FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE
         )"
@@ -144,6 +251,57 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
     .execute(&mut **transaction)
     .await?;
 
+    // Create invite_joins table. Every join through an invite, including
+    // members who have since left, so invite stats survive churn.
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS invite_joins (
+            id BIGINT AUTO_INCREMENT PRIMARY KEY,
+            server_id BINARY(16) NOT NULL,
+            invite_code VARCHAR(32) NOT NULL,
+            user_id BINARY(16) NOT NULL,
+            joined_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            INDEX idx_invite_joins_code (server_id, invite_code, joined_at),
+            FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create read_states table. last_read_seq is the acknowledged message's
+    // place in messages.seq, unread counts are everything after it
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS read_states (
+            user_id BINARY(16) NOT NULL,
+            channel_id BINARY(16) NOT NULL,
+            last_message_id BINARY(16) NOT NULL,
+            last_read_seq BIGINT UNSIGNED NOT NULL,
+            updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+            PRIMARY KEY (user_id, channel_id),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+            FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create server_bans table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS server_bans (
+            server_id BINARY(16) NOT NULL,
+            user_id BINARY(16) NOT NULL,
+            moderator_id BINARY(16),
+            reason VARCHAR(512),
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (server_id, user_id),
+            FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+            FOREIGN KEY (moderator_id) REFERENCES users(id) ON DELETE SET NULL
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
     // Create invites table
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS invites (
@@ -161,5 +319,648 @@ pub async fn init_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(),
     .execute(&mut **transaction)
     .await?;
 
+    // Create roles table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS roles (
+            id BINARY(16) PRIMARY KEY,
+            server_id BINARY(16) NOT NULL,
+            name VARCHAR(100) NOT NULL,
+            permissions BIGINT UNSIGNED NOT NULL DEFAULT 0,
+            position INT NOT NULL DEFAULT 0,
+            managed BOOLEAN NOT NULL DEFAULT false,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create member_roles table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS member_roles (
+            server_id BINARY(16) NOT NULL,
+            user_id BINARY(16) NOT NULL,
+            role_id BINARY(16) NOT NULL,
+            PRIMARY KEY (server_id, user_id, role_id),
+            FOREIGN KEY (user_id, server_id) REFERENCES server_members(user_id, server_id) ON DELETE CASCADE,
+            FOREIGN KEY (role_id) REFERENCES roles(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create role_mentions table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS role_mentions (
+            message_id BINARY(16) NOT NULL,
+            role_id BINARY(16) NOT NULL,
+            PRIMARY KEY (message_id, role_id),
+            FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE,
+            FOREIGN KEY (role_id) REFERENCES roles(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create server_notification_settings table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS server_notification_settings (
+            user_id BINARY(16) NOT NULL,
+            server_id BINARY(16) NOT NULL,
+            level ENUM('all', 'mentions', 'nothing') NOT NULL DEFAULT 'all',
+            muted BOOLEAN NOT NULL DEFAULT false,
+            muted_until TIMESTAMP NULL,
+            suppress_everyone BOOLEAN NOT NULL DEFAULT false,
+            suppress_roles BOOLEAN NOT NULL DEFAULT false,
+            updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+            PRIMARY KEY (user_id, server_id),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+            FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create channel_notification_settings table. A NULL level inherits the server's
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS channel_notification_settings (
+            user_id BINARY(16) NOT NULL,
+            channel_id BINARY(16) NOT NULL,
+            level ENUM('all', 'mentions', 'nothing'),
+            muted BOOLEAN NOT NULL DEFAULT false,
+            muted_until TIMESTAMP NULL,
+            updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+            PRIMARY KEY (user_id, channel_id),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+            FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create push_subscriptions table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS push_subscriptions (
+            id BINARY(16) PRIMARY KEY,
+            user_id BINARY(16) NOT NULL,
+            endpoint VARCHAR(1024) NOT NULL,
+            p256dh VARCHAR(255) NOT NULL,
+            auth VARCHAR(255) NOT NULL,
+            failures INT NOT NULL DEFAULT 0,
+            last_success_at TIMESTAMP NULL,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (endpoint(768)),
+            INDEX (user_id),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create outgoing_webhooks table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS outgoing_webhooks (
+            id BINARY(16) PRIMARY KEY,
+            server_id BINARY(16) NOT NULL,
+            url VARCHAR(2048) NOT NULL,
+            secret VARCHAR(255) NOT NULL,
+            events JSON NOT NULL,
+            enabled BOOLEAN NOT NULL DEFAULT TRUE,
+            consecutive_failures INT NOT NULL DEFAULT 0,
+            disabled_reason VARCHAR(255),
+            created_by BINARY(16),
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+            INDEX (server_id),
+            FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE,
+            FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create webhook_deliveries table, one row per attempt
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            id BINARY(16) PRIMARY KEY,
+            webhook_id BINARY(16) NOT NULL,
+            delivery_id BINARY(16) NOT NULL,
+            event VARCHAR(32) NOT NULL,
+            attempt INT NOT NULL,
+            status_code INT,
+            error VARCHAR(512),
+            duration_ms INT NOT NULL,
+            created_at TIMESTAMP(3) NOT NULL DEFAULT CURRENT_TIMESTAMP(3),
+            INDEX idx_webhook_deliveries_webhook (webhook_id, created_at),
+            INDEX idx_webhook_deliveries_created (created_at),
+            FOREIGN KEY (webhook_id) REFERENCES outgoing_webhooks(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create webhook_retries table, deliveries waiting for their next attempt
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS webhook_retries (
+            delivery_id BINARY(16) PRIMARY KEY,
+            webhook_id BINARY(16) NOT NULL,
+            event VARCHAR(32) NOT NULL,
+            body MEDIUMBLOB NOT NULL,
+            attempts INT NOT NULL,
+            next_attempt_at TIMESTAMP NOT NULL,
+            INDEX (next_attempt_at),
+            FOREIGN KEY (webhook_id) REFERENCES outgoing_webhooks(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create webhooks table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS webhooks (
+            id BINARY(16) PRIMARY KEY,
+            server_id BINARY(16) NOT NULL,
+            channel_id BINARY(16) NOT NULL,
+            name VARCHAR(80) NOT NULL,
+            avatar VARCHAR(2048),
+            token_hash CHAR(64) NOT NULL,
+            created_by BINARY(16),
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            INDEX (server_id),
+            INDEX (channel_id),
+            FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE,
+            FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE,
+            FOREIGN KEY (created_by) REFERENCES users(id) ON DELETE SET NULL
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create applications table, each owns one bot user
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS applications (
+            id BINARY(16) PRIMARY KEY,
+            owner_id BINARY(16) NOT NULL,
+            name VARCHAR(80) NOT NULL,
+            description TEXT,
+            bot_id BINARY(16) NOT NULL UNIQUE,
+            bot_token_hash CHAR(64) NOT NULL UNIQUE,
+            interactions_url VARCHAR(2048),
+            interactions_secret CHAR(64) NOT NULL,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            INDEX (owner_id),
+            FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE RESTRICT,
+            FOREIGN KEY (bot_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create application_commands table, commands without a server_id are global
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS application_commands (
+            id BINARY(16) PRIMARY KEY,
+            application_id BINARY(16) NOT NULL,
+            server_id BINARY(16),
+            name VARCHAR(32) NOT NULL,
+            description VARCHAR(100) NOT NULL,
+            options JSON NOT NULL,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+            INDEX (application_id, server_id),
+            INDEX (server_id),
+            FOREIGN KEY (application_id) REFERENCES applications(id) ON DELETE CASCADE,
+            FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create interactions table, one row per command invocation
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS interactions (
+            id BINARY(16) PRIMARY KEY,
+            application_id BINARY(16) NOT NULL,
+            command_id BINARY(16),
+            server_id BINARY(16) NOT NULL,
+            channel_id BINARY(16) NOT NULL,
+            user_id BINARY(16) NOT NULL,
+            token_hash CHAR(64) NOT NULL,
+            state ENUM('pending', 'responded', 'expired') NOT NULL DEFAULT 'pending',
+            expires_at TIMESTAMP NOT NULL,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            INDEX (created_at),
+            FOREIGN KEY (application_id) REFERENCES applications(id) ON DELETE CASCADE,
+            FOREIGN KEY (command_id) REFERENCES application_commands(id) ON DELETE SET NULL,
+            FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create login_attempts table, the audit trail of sign-ins. Successes and
+    // unlocks reset the failure count of the account or ip they belong to
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS login_attempts (
+            id BIGINT AUTO_INCREMENT PRIMARY KEY,
+            user_id BINARY(16),
+            identifier VARCHAR(255),
+            ip VARCHAR(45),
+            outcome ENUM('success', 'invalid_credentials', 'throttled', 'locked', 'unlocked') NOT NULL,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            INDEX (user_id, created_at),
+            INDEX (ip, created_at),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create login_lockouts table, subject is a user id or an ip address
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS login_lockouts (
+            kind ENUM('account', 'ip') NOT NULL,
+            subject VARCHAR(45) NOT NULL,
+            locked_until TIMESTAMP NOT NULL,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (kind, subject)
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create friendships table. user_id sent the request, friend_id accepts it
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS friendships (
+            user_id BINARY(16) NOT NULL,
+            friend_id BINARY(16) NOT NULL,
+            accepted BOOLEAN NOT NULL DEFAULT false,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            accepted_at TIMESTAMP NULL,
+            PRIMARY KEY (user_id, friend_id),
+            INDEX (friend_id),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+            FOREIGN KEY (friend_id) REFERENCES users(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create server_emoji table
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS server_emoji (
+            id BINARY(16) PRIMARY KEY,
+            server_id BINARY(16) NOT NULL,
+            name VARCHAR(32) NOT NULL,
+            image TEXT NOT NULL,
+            animated BOOLEAN NOT NULL DEFAULT false,
+            uploader_id BINARY(16),
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (server_id, name),
+            FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE,
+            FOREIGN KEY (uploader_id) REFERENCES users(id) ON DELETE SET NULL
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // Create server_emoji_roles table, an emoji with no rows here is usable by everyone
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS server_emoji_roles (
+            emoji_id BINARY(16) NOT NULL,
+            role_id BINARY(16) NOT NULL,
+            PRIMARY KEY (emoji_id, role_id),
+            FOREIGN KEY (emoji_id) REFERENCES server_emoji(id) ON DELETE CASCADE,
+            FOREIGN KEY (role_id) REFERENCES roles(id) ON DELETE CASCADE
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // CREATE TABLE IF NOT EXISTS leaves tables from older versions alone,
+    // so bring their columns up to date
+    migrate_tables(transaction).await?;
+
+    Ok(())
+}
+
+/// Each step checks the live schema or schema_migrations first, so running
+/// them on every start is cheap
+async fn migrate_tables(transaction: &mut Transaction<'_, MySql>) -> Result<(), sqlx::Error> {
+    // Data migrations that only ever need to run once, by name
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            name VARCHAR(64) PRIMARY KEY,
+            applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )"
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    // reactions.emoji widened for ZWJ sequences and custom emoji keys
+    if column_type(transaction, "reactions", "emoji").await?.is_some_and(|(ty, _)| ty != "varchar(128)") {
+        alter(transaction, "ALTER TABLE reactions MODIFY emoji VARCHAR(128) NOT NULL").await?;
+    }
+    // Custom emoji reactions were first keyed as `name:id`, now by id alone.
+    // Unicode emoji never contain a colon. Rows that would collide with an
+    // already migrated one are the same reaction, so they're dropped.
+    run_once(
+        transaction,
+        "reaction_keys_by_emoji_id",
+        &[
+            "UPDATE IGNORE reactions SET emoji = SUBSTRING_INDEX(emoji, ':', -1) WHERE emoji LIKE '%:%'",
+            "DELETE FROM reactions WHERE emoji LIKE '%:%'",
+        ],
+    )
+    .await?;
+
+    // server_emoji images and uploaders
+    add_column(transaction, "server_emoji", "image", "TEXT NOT NULL").await?;
+    add_column(transaction, "server_emoji", "animated", "BOOLEAN NOT NULL DEFAULT false").await?;
+    add_column(
+        transaction,
+        "server_emoji",
+        "uploader_id",
+        "BINARY(16), ADD FOREIGN KEY (uploader_id) REFERENCES users(id) ON DELETE SET NULL",
+    )
+    .await?;
+
+    // users: invisible status and expiring custom statuses
+    if column_type(transaction, "users", "status").await?.is_some_and(|(ty, _)| !ty.contains("'invisible'")) {
+        alter(
+            transaction,
+            "ALTER TABLE users MODIFY status ENUM('online', 'idle', 'dnd', 'invisible', 'offline') NOT NULL DEFAULT 'online'",
+        )
+        .await?;
+        // 'offline' was the old default rather than anything users chose,
+        // and now means appearing offline
+        alter(transaction, "UPDATE users SET status = 'online' WHERE status = 'offline'").await?;
+    }
+    add_column(transaction, "users", "custom_status_expires_at", "TIMESTAMP NULL").await?;
+
+    // Profiles and per-server nicknames
+    add_column(transaction, "users", "banner", "TEXT").await?;
+    add_column(transaction, "users", "bio", "TEXT").await?;
+    add_column(transaction, "users", "pronouns", "VARCHAR(40)").await?;
+    add_column(transaction, "server_members", "nickname", "VARCHAR(32)").await?;
+
+    // attachments are uploaded before the message they go on, so they belong
+    // to a channel and an uploader until then
+    if column_type(transaction, "attachments", "message_id").await?.is_some_and(|(_, nullable)| !nullable) {
+        alter(transaction, "ALTER TABLE attachments MODIFY message_id BINARY(16) NULL").await?;
+    }
+    add_column(transaction, "attachments", "channel_id", "BINARY(16)").await?;
+    add_column(
+        transaction,
+        "attachments",
+        "uploader_id",
+        "BINARY(16), ADD FOREIGN KEY (uploader_id) REFERENCES users(id) ON DELETE CASCADE",
+    )
+    .await?;
+    add_column(transaction, "attachments", "storage_key", "VARCHAR(255) NOT NULL, ADD INDEX (storage_key)").await?;
+    add_column(transaction, "attachments", "created_at", "TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP").await?;
+    // Older attachments all have a message to take these from, and were
+    // served from their storage key under /files/
+    run_once(
+        transaction,
+        "attachment_channels_and_keys",
+        &[
+            "UPDATE attachments a JOIN messages m ON m.id = a.message_id
+             SET a.channel_id = m.channel_id, a.uploader_id = COALESCE(a.uploader_id, m.author_id)
+             WHERE a.channel_id IS NULL",
+            "UPDATE attachments SET storage_key = SUBSTRING(url, 8) WHERE storage_key = '' AND url LIKE '/files/%'",
+        ],
+    )
+    .await?;
+    if column_type(transaction, "attachments", "channel_id").await?.is_some_and(|(_, nullable)| nullable) {
+        alter(
+            transaction,
+            "ALTER TABLE attachments MODIFY channel_id BINARY(16) NOT NULL,
+             ADD FOREIGN KEY (channel_id) REFERENCES channels(id) ON DELETE CASCADE",
+        )
+        .await?;
+    }
+
+    // Per-server upload limits
+    add_column(transaction, "servers", "max_upload_size", "BIGINT").await?;
+
+    // Blurhash placeholders for image attachments
+    add_column(transaction, "attachments", "blurhash", "VARCHAR(64)").await?;
+
+    // Link previews
+    add_column(transaction, "messages", "embeds", "JSON").await?;
+
+    // Vanity invite codes
+    add_column(transaction, "servers", "vanity_code", "VARCHAR(32) UNIQUE").await?;
+    add_column(transaction, "servers", "vanity_uses", "INT NOT NULL DEFAULT 0").await?;
+
+    // Which invite each member joined through
+    add_column(
+        transaction,
+        "server_members",
+        "invite_code",
+        "VARCHAR(32), ADD INDEX idx_server_members_invite (server_id, invite_code)",
+    )
+    .await?;
+
+    // Message search runs on a FULLTEXT index
+    if !has_index(transaction, "messages", "idx_messages_content").await? {
+        alter(transaction, "ALTER TABLE messages ADD FULLTEXT INDEX idx_messages_content (content)").await?;
+    }
+
+    // Messages get a sequence that orders them strictly, which send times
+    // can't within a second. Existing messages are numbered by send time
+    // before the column starts counting by itself.
+    add_column(transaction, "messages", "seq", "BIGINT UNSIGNED NULL").await?;
+    run_once(
+        transaction,
+        "message_seq_by_created_at",
+        &["UPDATE messages m
+           JOIN (SELECT id, ROW_NUMBER() OVER (ORDER BY created_at, id) AS n FROM messages) o ON o.id = m.id
+           SET m.seq = o.n"],
+    )
+    .await?;
+    if column_type(transaction, "messages", "seq").await?.is_some_and(|(_, nullable)| nullable) {
+        alter(
+            transaction,
+            "ALTER TABLE messages MODIFY seq BIGINT UNSIGNED NOT NULL AUTO_INCREMENT UNIQUE,
+             ADD INDEX idx_messages_channel_seq (channel_id, seq)",
+        )
+        .await?;
+    }
+    if !has_index(transaction, "messages", "idx_messages_channel_created").await? {
+        alter(transaction, "ALTER TABLE messages ADD INDEX idx_messages_channel_created (channel_id, created_at)").await?;
+    }
+    // @everyone mentions
+    add_column(transaction, "messages", "mention_everyone", "BOOLEAN NOT NULL DEFAULT false").await?;
+
+    // Acks move from send times to the sequence. Acks of messages that are
+    // gone fall back to counting from when the user joined.
+    if column_type(transaction, "read_states", "last_read_at").await?.is_some() {
+        add_column(transaction, "read_states", "last_read_seq", "BIGINT UNSIGNED NOT NULL").await?;
+        alter(
+            transaction,
+            "UPDATE read_states rs JOIN messages m ON m.id = rs.last_message_id SET rs.last_read_seq = m.seq",
+        )
+        .await?;
+        alter(transaction, "DELETE FROM read_states WHERE last_read_seq = 0").await?;
+        alter(transaction, "ALTER TABLE read_states DROP COLUMN last_read_at").await?;
+    }
+
+    // Incoming webhooks post messages with no author, and upload files with
+    // no uploader, under their own name and avatar
+    if column_type(transaction, "messages", "author_id").await?.is_some_and(|(_, nullable)| !nullable) {
+        alter(transaction, "ALTER TABLE messages MODIFY author_id BINARY(16) NULL").await?;
+    }
+    if column_type(transaction, "attachments", "uploader_id").await?.is_some_and(|(_, nullable)| !nullable) {
+        alter(transaction, "ALTER TABLE attachments MODIFY uploader_id BINARY(16) NULL").await?;
+    }
+    add_column(transaction, "messages", "webhook_id", "BINARY(16), ADD INDEX (webhook_id)").await?;
+    add_column(transaction, "messages", "webhook_name", "VARCHAR(80)").await?;
+    add_column(transaction, "messages", "webhook_avatar", "VARCHAR(2048)").await?;
+
+    // Bot users have no email or password to log in with
+    add_column(transaction, "users", "is_bot", "BOOLEAN NOT NULL DEFAULT false").await?;
+    if column_type(transaction, "users", "email").await?.is_some_and(|(_, nullable)| !nullable) {
+        alter(transaction, "ALTER TABLE users MODIFY email VARCHAR(255) NULL").await?;
+    }
+    if column_type(transaction, "users", "password_hash").await?.is_some_and(|(_, nullable)| !nullable) {
+        alter(transaction, "ALTER TABLE users MODIFY password_hash VARCHAR(255) NULL").await?;
+    }
+    // Roles bots are added to servers with
+    add_column(transaction, "roles", "managed", "BOOLEAN NOT NULL DEFAULT false").await?;
+    // Cascading from the owner would leave the bot user behind, so owners
+    // have to delete their applications first
+    if let Some((name, rule)) = foreign_key(transaction, "applications", "owner_id").await? {
+        if rule != "restrict" {
+            alter(transaction, &format!("ALTER TABLE applications DROP FOREIGN KEY {name}")).await?;
+            alter(
+                transaction,
+                "ALTER TABLE applications ADD FOREIGN KEY (owner_id) REFERENCES users(id) ON DELETE RESTRICT",
+            )
+            .await?;
+        }
+    }
+
+    // Interactions over http. Existing applications get a signing secret
+    // like generate_secret() makes.
+    add_column(transaction, "applications", "interactions_url", "VARCHAR(2048)").await?;
+    add_column(transaction, "applications", "interactions_secret", "CHAR(64) NOT NULL").await?;
+    alter(
+        transaction,
+        "UPDATE applications SET interactions_secret = LOWER(HEX(RANDOM_BYTES(32))) WHERE interactions_secret = ''",
+    )
+    .await?;
+
+    // Link previews live apart from the embeds bots and webhooks supply, so
+    // edits only drop the previews. People's messages only ever had previews.
+    add_column(transaction, "messages", "link_embeds", "JSON").await?;
+    run_once(
+        transaction,
+        "messages_link_embeds",
+        &["UPDATE messages m JOIN users u ON u.id = m.author_id
+           SET m.link_embeds = m.embeds, m.embeds = NULL
+           WHERE m.embeds IS NOT NULL AND NOT u.is_bot"],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Whether the table has an index by that name
+async fn has_index(transaction: &mut Transaction<'_, MySql>, table: &str, index: &str) -> Result<bool, sqlx::Error> {
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM information_schema.STATISTICS
+         WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND INDEX_NAME = ?"
+    )
+    .bind(table)
+    .bind(index)
+    .fetch_one(&mut **transaction)
+    .await?;
+    Ok(count > 0)
+}
+
+/// Name and lowercased ON DELETE rule of the foreign key on the column
+async fn foreign_key(
+    transaction: &mut Transaction<'_, MySql>,
+    table: &str,
+    column: &str,
+) -> Result<Option<(String, String)>, sqlx::Error> {
+    let row: Option<(String, String)> = sqlx::query_as(
+        "SELECT CAST(rc.CONSTRAINT_NAME AS CHAR), CAST(rc.DELETE_RULE AS CHAR)
+         FROM information_schema.KEY_COLUMN_USAGE k
+         JOIN information_schema.REFERENTIAL_CONSTRAINTS rc
+            ON rc.CONSTRAINT_SCHEMA = k.CONSTRAINT_SCHEMA AND rc.CONSTRAINT_NAME = k.CONSTRAINT_NAME
+         WHERE k.TABLE_SCHEMA = DATABASE() AND k.TABLE_NAME = ? AND k.COLUMN_NAME = ?
+         LIMIT 1"
+    )
+    .bind(table)
+    .bind(column)
+    .fetch_optional(&mut **transaction)
+    .await?;
+    Ok(row.map(|(name, rule)| (name, rule.to_lowercase())))
+}
+
+/// The column's type as MySQL reports it, like `varchar(128)`, and whether
+/// it's nullable. None if the column doesn't exist.
+async fn column_type(
+    transaction: &mut Transaction<'_, MySql>,
+    table: &str,
+    column: &str,
+) -> Result<Option<(String, bool)>, sqlx::Error> {
+    // information_schema columns come back as binary strings on some MySQL versions
+    let row: Option<(String, i64)> = sqlx::query_as(
+        "SELECT CAST(COLUMN_TYPE AS CHAR), CAST(IS_NULLABLE = 'YES' AS SIGNED)
+         FROM information_schema.COLUMNS
+         WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ? AND COLUMN_NAME = ?"
+    )
+    .bind(table)
+    .bind(column)
+    .fetch_optional(&mut **transaction)
+    .await?;
+    Ok(row.map(|(ty, nullable)| (ty.to_lowercase(), nullable != 0)))
+}
+
+/// Adds a column unless it's already there. MySQL has no ADD COLUMN IF NOT EXISTS.
+/// `definition` may go on to add the column's keys, e.g. `BINARY(16), ADD INDEX (x)`.
+async fn add_column(
+    transaction: &mut Transaction<'_, MySql>,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    if column_type(transaction, table, column).await?.is_none() {
+        alter(transaction, &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}")).await?;
+    }
+    Ok(())
+}
+
+/// Runs data migrations that must not repeat, recording them in schema_migrations
+async fn run_once(
+    transaction: &mut Transaction<'_, MySql>,
+    name: &str,
+    statements: &[&str],
+) -> Result<(), sqlx::Error> {
+    let applied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM schema_migrations WHERE name = ?")
+        .bind(name)
+        .fetch_one(&mut **transaction)
+        .await?;
+    if applied > 0 {
+        return Ok(());
+    }
+    for statement in statements {
+        alter(transaction, statement).await?;
+    }
+    sqlx::query("INSERT INTO schema_migrations (name) VALUES (?)")
+        .bind(name)
+        .execute(&mut **transaction)
+        .await?;
+    Ok(())
+}
+
+async fn alter(transaction: &mut Transaction<'_, MySql>, statement: &str) -> Result<(), sqlx::Error> {
+    log::info!("Migrating: {statement}");
+    sqlx::query(statement).execute(&mut **transaction).await?;
     Ok(())
-}
\ No newline at end of file
+}
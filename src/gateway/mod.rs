@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::api::notifications::ServerNotificationSettings;
+use crate::api::{EphemeralMessage, Interaction, Message, ReadState};
+use presence::PresenceUpdate;
+
+pub mod presence;
+pub mod typing;
+
+/// How many undelivered dispatches a slow client may fall behind by before
+/// it starts skipping events
+const DISPATCH_BUFFER: usize = 1024;
+
+/// Events pushed to connected clients over `/gateway`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "t", content = "d", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GatewayEvent {
+    /// First event on every connection
+    Ready {
+        user_id: Uuid,
+        read_states: Vec<ReadState>,
+    },
+    TypingStart {
+        channel_id: Uuid,
+        user_id: Uuid,
+        timestamp: DateTime<Utc>,
+        /// Seconds until the indicator should be hidden unless refreshed
+        expires_in: u64,
+    },
+    PresenceUpdate(PresenceUpdate),
+    MessageCreate(Message),
+    /// The whole message as it is now, after an edit or once link previews
+    /// have been attached
+    MessageUpdate(Message),
+    MessageDelete {
+        id: Uuid,
+        channel_id: Uuid,
+    },
+    /// `emoji` is written the way the reaction routes take it, `name:id`
+    /// for server emoji
+    ReactionAdd {
+        channel_id: Uuid,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: String,
+    },
+    ReactionRemove {
+        channel_id: Uuid,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: String,
+    },
+    /// A moderator removed every reaction, or with `emoji` set, every
+    /// reaction with that emoji
+    ReactionClear {
+        channel_id: Uuid,
+        message_id: Uuid,
+        emoji: Option<String>,
+    },
+    /// The user read a channel up to `message_id`, possibly on another device
+    MessageAck {
+        channel_id: Uuid,
+        message_id: Uuid,
+    },
+    /// The user changed their settings for a server or one of its channels
+    NotificationSettingsUpdate(ServerNotificationSettings),
+    /// Sent to a bot when one of its commands is invoked
+    InteractionCreate(Interaction),
+    /// A bot's reply to a command, only sent to the user who invoked it
+    EphemeralMessageCreate(EphemeralMessage),
+    /// The bot didn't respond to the user's command in time
+    InteractionFailed {
+        interaction_id: Uuid,
+        channel_id: Uuid,
+        reason: String,
+    },
+}
+
+impl GatewayEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GatewayEvent::Ready { .. } => "READY",
+            GatewayEvent::TypingStart { .. } => "TYPING_START",
+            GatewayEvent::PresenceUpdate(_) => "PRESENCE_UPDATE",
+            GatewayEvent::MessageCreate(_) => "MESSAGE_CREATE",
+            GatewayEvent::MessageUpdate(_) => "MESSAGE_UPDATE",
+            GatewayEvent::MessageDelete { .. } => "MESSAGE_DELETE",
+            GatewayEvent::ReactionAdd { .. } => "REACTION_ADD",
+            GatewayEvent::ReactionRemove { .. } => "REACTION_REMOVE",
+            GatewayEvent::ReactionClear { .. } => "REACTION_CLEAR",
+            GatewayEvent::MessageAck { .. } => "MESSAGE_ACK",
+            GatewayEvent::NotificationSettingsUpdate(_) => "NOTIFICATION_SETTINGS_UPDATE",
+            GatewayEvent::InteractionCreate(_) => "INTERACTION_CREATE",
+            GatewayEvent::EphemeralMessageCreate(_) => "EPHEMERAL_MESSAGE_CREATE",
+            GatewayEvent::InteractionFailed { .. } => "INTERACTION_FAILED",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Dispatch {
+    pub recipients: Vec<Uuid>,
+    pub event: GatewayEvent,
+}
+
+/// Fan-out hub for real-time events. Every open connection holds a receiver
+/// and picks out the dispatches addressed to its user.
+#[derive(Clone)]
+pub struct Gateway {
+    sender: broadcast::Sender<Arc<Dispatch>>,
+    connections: Arc<Mutex<HashMap<Uuid, usize>>>,
+}
+
+impl Gateway {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(DISPATCH_BUFFER);
+        Self {
+            sender,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn dispatch(&self, recipients: Vec<Uuid>, event: GatewayEvent) {
+        if recipients.is_empty() {
+            return;
+        }
+        debug!("Dispatching {} to {} users", event.name(), recipients.len());
+        // Only fails when nobody is connected, which is fine
+        let _ = self.sender.send(Arc::new(Dispatch { recipients, event }));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Dispatch>> {
+        self.sender.subscribe()
+    }
+
+    /// Registers a live connection for the user. The connection counts until
+    /// the returned guard is dropped.
+    pub fn connect(&self, user_id: Uuid) -> ConnectionGuard {
+        *self
+            .connections
+            .lock()
+            .expect("gateway connections poisoned")
+            .entry(user_id)
+            .or_default() += 1;
+        ConnectionGuard {
+            user_id,
+            connections: self.connections.clone(),
+        }
+    }
+
+    pub fn is_connected(&self, user_id: Uuid) -> bool {
+        self.connections
+            .lock()
+            .expect("gateway connections poisoned")
+            .contains_key(&user_id)
+    }
+}
+
+impl Default for Gateway {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct ConnectionGuard {
+    user_id: Uuid,
+    connections: Arc<Mutex<HashMap<Uuid, usize>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().expect("gateway connections poisoned");
+        if let Some(count) = connections.get_mut(&self.user_id) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.user_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::error;
+use serde::Serialize;
+use sqlx::{MySql, Pool};
+use uuid::Uuid;
+
+use super::{Gateway, GatewayEvent};
+use crate::api::UserStatus;
+use crate::db::queries;
+
+/// Connected users with no activity for this long show as idle
+pub const IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+/// Users stay online this long after their last connection drops, so a
+/// reconnect doesn't flicker them offline
+pub const OFFLINE_GRACE: Duration = Duration::from_secs(30);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PresenceUpdate {
+    pub user_id: Uuid,
+    pub status: UserStatus,
+    pub custom_status: Option<String>,
+}
+
+struct PresenceState {
+    /// What the user picked, `Online` meaning "work it out for me"
+    preference: UserStatus,
+    custom_status: Option<String>,
+    last_active: Instant,
+    disconnected_at: Option<Instant>,
+    published: Option<PresenceUpdate>,
+}
+
+impl PresenceState {
+    fn visible(&self, user_id: Uuid, connected: bool, now: Instant) -> PresenceUpdate {
+        let in_grace = self
+            .disconnected_at
+            .map_or(false, |at| now.duration_since(at) < OFFLINE_GRACE);
+        let status = if !connected && !in_grace {
+            UserStatus::Offline
+        } else {
+            match self.preference {
+                UserStatus::Invisible => UserStatus::Offline,
+                UserStatus::Dnd => UserStatus::Dnd,
+                UserStatus::Idle => UserStatus::Idle,
+                // Users can't pick offline, a stored one is the old column
+                // default and means the same as online
+                UserStatus::Online | UserStatus::Offline if now.duration_since(self.last_active) >= IDLE_AFTER => {
+                    UserStatus::Idle
+                }
+                UserStatus::Online | UserStatus::Offline => UserStatus::Online,
+            }
+        };
+        // Invisible users shouldn't leak that they're around through their status text
+        let custom_status = match status {
+            UserStatus::Offline => None,
+            _ => self.custom_status.clone(),
+        };
+        PresenceUpdate {
+            user_id,
+            status,
+            custom_status,
+        }
+    }
+}
+
+/// Presence computed from live gateway connections and recent activity,
+/// layered over the status the user chose for themselves
+#[derive(Clone, Default)]
+pub struct PresenceTracker {
+    states: Arc<Mutex<HashMap<Uuid, PresenceState>>>,
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called when a gateway connection opens
+    pub fn connected(&self, user_id: Uuid, preference: UserStatus, custom_status: Option<String>) {
+        let now = Instant::now();
+        let mut states = self.states.lock().expect("presence states poisoned");
+        let state = states.entry(user_id).or_insert_with(|| PresenceState {
+            preference,
+            custom_status: None,
+            last_active: now,
+            disconnected_at: None,
+            published: None,
+        });
+        state.preference = preference;
+        state.custom_status = custom_status;
+        state.last_active = now;
+        state.disconnected_at = None;
+    }
+
+    /// Marks the user as active, bringing them back from automatic idle
+    pub fn touch(&self, user_id: Uuid) {
+        if let Some(state) = self.states.lock().expect("presence states poisoned").get_mut(&user_id) {
+            state.last_active = Instant::now();
+        }
+    }
+
+    pub fn set_preference(&self, user_id: Uuid, preference: UserStatus, custom_status: Option<String>) {
+        if let Some(state) = self.states.lock().expect("presence states poisoned").get_mut(&user_id) {
+            state.preference = preference;
+            state.custom_status = custom_status;
+        }
+    }
+
+    pub fn clear_custom_status(&self, user_id: Uuid) {
+        if let Some(state) = self.states.lock().expect("presence states poisoned").get_mut(&user_id) {
+            state.custom_status = None;
+        }
+    }
+
+    /// What other users currently see for this user
+    pub fn presence_of(&self, user_id: Uuid, gateway: &Gateway) -> PresenceUpdate {
+        let connected = gateway.is_connected(user_id);
+        match self.states.lock().expect("presence states poisoned").get(&user_id) {
+            Some(state) => state.visible(user_id, connected, Instant::now()),
+            None => PresenceUpdate {
+                user_id,
+                status: UserStatus::Offline,
+                custom_status: None,
+            },
+        }
+    }
+
+    /// Recomputes one user's presence, returning it if it changed since it
+    /// was last published
+    pub fn refresh(&self, user_id: Uuid, gateway: &Gateway) -> Option<PresenceUpdate> {
+        let connected = gateway.is_connected(user_id);
+        let mut states = self.states.lock().expect("presence states poisoned");
+        let state = states.get_mut(&user_id)?;
+        Self::refresh_state(user_id, state, connected, Instant::now())
+    }
+
+    fn refresh_state(user_id: Uuid, state: &mut PresenceState, connected: bool, now: Instant) -> Option<PresenceUpdate> {
+        if !connected && state.disconnected_at.is_none() {
+            state.disconnected_at = Some(now);
+        }
+        let visible = state.visible(user_id, connected, now);
+        if state.published.as_ref() == Some(&visible) {
+            return None;
+        }
+        state.published = Some(visible.clone());
+        Some(visible)
+    }
+
+    /// Recomputes everyone, dropping users who have gone fully offline
+    fn sweep(&self, gateway: &Gateway) -> Vec<PresenceUpdate> {
+        let now = Instant::now();
+        let mut states = self.states.lock().expect("presence states poisoned");
+        let updates = states
+            .iter_mut()
+            .filter_map(|(user_id, state)| {
+                Self::refresh_state(*user_id, state, gateway.is_connected(*user_id), now)
+            })
+            .collect();
+        // Invisible users publish offline too, so only drop the disconnected ones
+        states.retain(|user_id, state| {
+            gateway.is_connected(*user_id)
+                || state.published.as_ref().map(|p| &p.status) != Some(&UserStatus::Offline)
+        });
+        updates
+    }
+}
+
+/// Sends a presence change to connected users who share a server with the
+/// user or are their friends
+pub async fn publish_presence(pool: &Pool<MySql>, gateway: &Gateway, update: PresenceUpdate) {
+    match queries::get_presence_audience(pool, update.user_id).await {
+        Ok(mut audience) => {
+            audience.retain(|id| gateway.is_connected(*id));
+            gateway.dispatch(audience, GatewayEvent::PresenceUpdate(update));
+        }
+        Err(e) => error!("Failed to fetch presence audience for {}: {e}", update.user_id),
+    }
+}
+
+/// Periodically applies idle/offline transitions and expires custom statuses
+pub async fn run_presence_sweeper(pool: Pool<MySql>, gateway: Gateway, presence: PresenceTracker) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match queries::clear_expired_custom_statuses(&pool).await {
+            Ok(users) => users
+                .into_iter()
+                .for_each(|user_id| presence.clear_custom_status(user_id)),
+            Err(e) => error!("Failed to expire custom statuses: {e}"),
+        }
+        for update in presence.sweep(&gateway) {
+            publish_presence(&pool, &gateway, update).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(preference: UserStatus) -> PresenceState {
+        PresenceState {
+            preference,
+            custom_status: Some("around".to_string()),
+            last_active: Instant::now(),
+            disconnected_at: None,
+            published: None,
+        }
+    }
+
+    #[test]
+    fn stored_offline_shows_online_while_connected() {
+        let user_id = Uuid::new_v4();
+        let update = state(UserStatus::Offline).visible(user_id, true, Instant::now());
+        assert_eq!(update.status, UserStatus::Online);
+        assert_eq!(update.custom_status.as_deref(), Some("around"));
+    }
+
+    #[test]
+    fn invisible_shows_offline_without_custom_status() {
+        let update = state(UserStatus::Invisible).visible(Uuid::new_v4(), true, Instant::now());
+        assert_eq!(update.status, UserStatus::Offline);
+        assert_eq!(update.custom_status, None);
+    }
+
+    #[test]
+    fn disconnected_users_are_offline_after_the_grace_period() {
+        let mut state = state(UserStatus::Online);
+        let now = Instant::now();
+        state.disconnected_at = Some(now);
+        assert_eq!(state.visible(Uuid::new_v4(), false, now).status, UserStatus::Online);
+        let later = now + OFFLINE_GRACE;
+        assert_eq!(state.visible(Uuid::new_v4(), false, later).status, UserStatus::Offline);
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+/// How long a typing indicator lives without being refreshed
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Refreshes inside this interval update the expiry without re-broadcasting
+const REBROADCAST_INTERVAL: Duration = Duration::from_secs(5);
+/// Calls allowed per (channel, user) within RATE_LIMIT_WINDOW
+const RATE_LIMIT_CALLS: u32 = 5;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TypingOutcome {
+    /// New or stale indicator, other viewers should be told
+    Broadcast,
+    /// Expiry was extended, nothing to send
+    Refreshed,
+    RateLimited { retry_after: Duration },
+}
+
+struct TypingState {
+    expires_at: Instant,
+    last_broadcast: Instant,
+    window_start: Instant,
+    calls_in_window: u32,
+}
+
+/// Tracks who is typing where, keyed by (channel, user)
+#[derive(Default)]
+pub struct TypingTracker {
+    states: Mutex<HashMap<(Uuid, Uuid), TypingState>>,
+}
+
+impl TypingTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, channel_id: Uuid, user_id: Uuid) -> TypingOutcome {
+        self.record_at(channel_id, user_id, Instant::now())
+    }
+
+    fn record_at(&self, channel_id: Uuid, user_id: Uuid, now: Instant) -> TypingOutcome {
+        let mut states = self.states.lock().expect("typing states poisoned");
+        states.retain(|_, state| state.expires_at > now);
+
+        let Some(state) = states.get_mut(&(channel_id, user_id)) else {
+            states.insert(
+                (channel_id, user_id),
+                TypingState {
+                    expires_at: now + TYPING_TIMEOUT,
+                    last_broadcast: now,
+                    window_start: now,
+                    calls_in_window: 1,
+                },
+            );
+            return TypingOutcome::Broadcast;
+        };
+
+        if now.duration_since(state.window_start) >= RATE_LIMIT_WINDOW {
+            state.window_start = now;
+            state.calls_in_window = 0;
+        }
+        if state.calls_in_window >= RATE_LIMIT_CALLS {
+            return TypingOutcome::RateLimited {
+                retry_after: RATE_LIMIT_WINDOW - now.duration_since(state.window_start),
+            };
+        }
+        state.calls_in_window += 1;
+        state.expires_at = now + TYPING_TIMEOUT;
+
+        if now.duration_since(state.last_broadcast) >= REBROADCAST_INTERVAL {
+            state.last_broadcast = now;
+            TypingOutcome::Broadcast
+        } else {
+            TypingOutcome::Refreshed
+        }
+    }
+
+    /// Drops the indicator, e.g. once the user's message has been sent
+    pub fn clear(&self, channel_id: Uuid, user_id: Uuid) {
+        self.states
+            .lock()
+            .expect("typing states poisoned")
+            .remove(&(channel_id, user_id));
+    }
+
+    #[cfg(test)]
+    fn typing_in(&self, channel_id: Uuid) -> Vec<Uuid> {
+        let now = Instant::now();
+        self.states
+            .lock()
+            .expect("typing states poisoned")
+            .iter()
+            .filter(|((channel, _), state)| *channel == channel_id && state.expires_at > now)
+            .map(|((_, user), _)| *user)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refreshes_without_rebroadcasting() {
+        let tracker = TypingTracker::new();
+        let (channel, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        assert_eq!(tracker.record_at(channel, user, start), TypingOutcome::Broadcast);
+        assert_eq!(tracker.record_at(channel, user, start + Duration::from_secs(1)), TypingOutcome::Refreshed);
+        assert_eq!(
+            tracker.record_at(channel, user, start + REBROADCAST_INTERVAL),
+            TypingOutcome::Broadcast
+        );
+    }
+
+    #[test]
+    fn expired_indicators_start_over() {
+        let tracker = TypingTracker::new();
+        let (channel, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        assert_eq!(tracker.record_at(channel, user, start), TypingOutcome::Broadcast);
+        // Kept alive by the refresh at 1s, so still live just before 11s
+        assert_eq!(tracker.record_at(channel, user, start + Duration::from_secs(1)), TypingOutcome::Refreshed);
+        let later = start + Duration::from_secs(1) + TYPING_TIMEOUT;
+        assert_eq!(tracker.record_at(channel, user, later), TypingOutcome::Broadcast);
+    }
+
+    #[test]
+    fn throttles_per_channel_and_user() {
+        let tracker = TypingTracker::new();
+        let (channel, user) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        for i in 0..RATE_LIMIT_CALLS {
+            let outcome = tracker.record_at(channel, user, start + Duration::from_millis(100 * i as u64));
+            assert!(!matches!(outcome, TypingOutcome::RateLimited { .. }));
+        }
+        let now = start + Duration::from_secs(1);
+        assert_eq!(
+            tracker.record_at(channel, user, now),
+            TypingOutcome::RateLimited {
+                retry_after: RATE_LIMIT_WINDOW - Duration::from_secs(1)
+            }
+        );
+        // Someone else in the same channel isn't affected
+        assert_eq!(tracker.record_at(channel, Uuid::new_v4(), now), TypingOutcome::Broadcast);
+        // And the window resets
+        assert!(!matches!(
+            tracker.record_at(channel, user, start + RATE_LIMIT_WINDOW),
+            TypingOutcome::RateLimited { .. }
+        ));
+    }
+
+    #[test]
+    fn clear_drops_the_indicator() {
+        let tracker = TypingTracker::new();
+        let (channel, user) = (Uuid::new_v4(), Uuid::new_v4());
+
+        tracker.record(channel, user);
+        assert_eq!(tracker.typing_in(channel), vec![user]);
+        tracker.clear(channel, user);
+        assert!(tracker.typing_in(channel).is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::{error, info};
+use reqwest::header::{CONTENT_TYPE, USER_AGENT};
+use serde::{Deserialize, Serialize};
+use sqlx::{MySql, Pool};
+use url::Url;
+use uuid::Uuid;
+
+use crate::api::incoming_webhooks::WebhookEmbed;
+use crate::db::queries;
+use crate::unfurl::ssrf::{check_url, PublicResolver};
+use crate::webhooks::{sign, WebhookConfig};
+
+/// How long a bot has to respond to an interaction. Http endpoints must
+/// answer within it too, or acknowledge and use the callback.
+pub const RESPONSE_DEADLINE: Duration = Duration::from_secs(10);
+pub const MAX_COMMAND_NAME_LENGTH: usize = 32;
+pub const MAX_COMMAND_DESCRIPTION_LENGTH: usize = 100;
+pub const MAX_COMMAND_OPTIONS: usize = 25;
+const MAX_OPTION_CHOICES: usize = 25;
+const MAX_STRING_OPTION_LENGTH: usize = 4000;
+const INTERACTION_RETENTION_DAYS: i64 = 1;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Most a response from an http endpoint may be. Ten full embeds and a
+/// message fit in a fraction of it.
+const MAX_RESPONSE_SIZE: usize = 256 * 1024;
+const USER_AGENT_VALUE: &str = concat!("occult-server/", env!("CARGO_PKG_VERSION"), " (interactions)");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandOptionType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    /// The id of a server member
+    User,
+    /// The id of a channel in the server
+    Channel,
+    /// The id of a role in the server
+    Role,
+}
+
+impl CommandOptionType {
+    fn accepts(&self, value: &serde_json::Value) -> bool {
+        match self {
+            CommandOptionType::String => value
+                .as_str()
+                .is_some_and(|value| !value.is_empty() && value.chars().count() <= MAX_STRING_OPTION_LENGTH),
+            CommandOptionType::Integer => value.is_i64(),
+            CommandOptionType::Number => value.is_number(),
+            CommandOptionType::Boolean => value.is_boolean(),
+            CommandOptionType::User | CommandOptionType::Channel | CommandOptionType::Role => {
+                value.as_str().is_some_and(|id| Uuid::parse_str(id).is_ok())
+            }
+        }
+    }
+
+    fn has_choices(&self) -> bool {
+        matches!(
+            self,
+            CommandOptionType::String | CommandOptionType::Integer | CommandOptionType::Number
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandOptionChoice {
+    pub name: String,
+    pub value: serde_json::Value,
+}
+
+/// A typed argument of an application command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandOption {
+    pub name: String,
+    pub description: String,
+    #[serde(rename = "type")]
+    pub option_type: CommandOptionType,
+    #[serde(default)]
+    pub required: bool,
+    /// When set, the value must be one of these
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<CommandOptionChoice>,
+}
+
+/// An option value as the invoking user gave it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InteractionOption {
+    pub name: String,
+    pub value: serde_json::Value,
+}
+
+/// Lowercase letters, digits, dashes and underscores, like `/remind-me`
+pub fn is_valid_command_name(name: &str) -> bool {
+    (1..=MAX_COMMAND_NAME_LENGTH).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+fn check_description(description: &str, what: &str) -> Result<(), String> {
+    let length = description.trim().chars().count();
+    if !(1..=MAX_COMMAND_DESCRIPTION_LENGTH).contains(&length) {
+        return Err(format!("{what} descriptions must be 1 to {MAX_COMMAND_DESCRIPTION_LENGTH} characters"));
+    }
+    Ok(())
+}
+
+/// Checks a command definition before it's registered
+pub fn validate_command(name: &str, description: &str, options: &[CommandOption]) -> Result<(), String> {
+    if !is_valid_command_name(name) {
+        return Err(format!(
+            "Command names must be 1 to {MAX_COMMAND_NAME_LENGTH} lowercase letters, digits, dashes or underscores"
+        ));
+    }
+    check_description(description, "Command")?;
+    if options.len() > MAX_COMMAND_OPTIONS {
+        return Err(format!("A command can have at most {MAX_COMMAND_OPTIONS} options"));
+    }
+
+    let mut names = HashSet::new();
+    let mut seen_optional = false;
+    for option in options {
+        if !is_valid_command_name(&option.name) {
+            return Err(format!("Invalid option name: {}", option.name));
+        }
+        if !names.insert(option.name.as_str()) {
+            return Err(format!("Duplicate option: {}", option.name));
+        }
+        check_description(&option.description, "Option")?;
+        // Positional input in clients relies on this order
+        if option.required && seen_optional {
+            return Err(format!("Required option {} must come before optional ones", option.name));
+        }
+        seen_optional |= !option.required;
+
+        if option.choices.is_empty() {
+            continue;
+        }
+        if !option.option_type.has_choices() {
+            return Err(format!("Option {} can't have choices", option.name));
+        }
+        if option.choices.len() > MAX_OPTION_CHOICES {
+            return Err(format!("Option {} can have at most {MAX_OPTION_CHOICES} choices", option.name));
+        }
+        for choice in &option.choices {
+            let length = choice.name.trim().chars().count();
+            if !(1..=MAX_COMMAND_DESCRIPTION_LENGTH).contains(&length) {
+                return Err(format!("Choice names of option {} must be 1 to {MAX_COMMAND_DESCRIPTION_LENGTH} characters", option.name));
+            }
+            if !option.option_type.accepts(&choice.value) {
+                return Err(format!("Choice {} doesn't match the type of option {}", choice.name, option.name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Matches the given values against the command's options, in the order the
+/// command declares them
+pub fn resolve_options(
+    definitions: &[CommandOption],
+    mut given: Vec<InteractionOption>,
+) -> Result<Vec<InteractionOption>, String> {
+    if let Some(unknown) = given
+        .iter()
+        .find(|option| !definitions.iter().any(|definition| definition.name == option.name))
+    {
+        return Err(format!("Unknown option: {}", unknown.name));
+    }
+
+    let mut resolved = Vec::with_capacity(given.len());
+    for definition in definitions {
+        let Some(index) = given.iter().position(|option| option.name == definition.name) else {
+            if definition.required {
+                return Err(format!("Missing required option: {}", definition.name));
+            }
+            continue;
+        };
+        let option = given.swap_remove(index);
+        if given.iter().any(|other| other.name == definition.name) {
+            return Err(format!("Option {} was given more than once", definition.name));
+        }
+        if !definition.option_type.accepts(&option.value) {
+            return Err(format!("Invalid value for option {}", definition.name));
+        }
+        if !definition.choices.is_empty() && !definition.choices.iter().any(|choice| choice.value == option.value) {
+            return Err(format!("Option {} must be one of its choices", definition.name));
+        }
+        resolved.push(option);
+    }
+    Ok(resolved)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InteractionResponseType {
+    /// Posted in the channel for everyone
+    Message,
+    /// Only shown to the user who invoked the command, and never stored
+    Ephemeral,
+}
+
+/// How a bot answers an interaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InteractionResponse {
+    #[serde(rename = "type")]
+    pub response_type: InteractionResponseType,
+    pub content: Option<String>,
+    #[serde(default)]
+    pub embeds: Vec<WebhookEmbed>,
+}
+
+/// Posts signed interactions to applications that receive them over http
+#[derive(Clone)]
+pub struct InteractionSender {
+    client: reqwest::Client,
+    allow_private: bool,
+}
+
+impl InteractionSender {
+    pub fn new(config: &WebhookConfig) -> Result<Self> {
+        let mut client = reqwest::Client::builder()
+            .no_proxy()
+            .timeout(RESPONSE_DEADLINE)
+            .connect_timeout(RESPONSE_DEADLINE)
+            .redirect(reqwest::redirect::Policy::none());
+        if !config.allow_private_networks {
+            client = client.dns_resolver(Arc::new(PublicResolver));
+        }
+        Ok(Self {
+            client: client.build().context("Failed to build the interactions client")?,
+            allow_private: config.allow_private_networks,
+        })
+    }
+
+    /// Refuses urls we'd never deliver to, before they're stored
+    pub fn check_url(&self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|_| "Invalid interactions url".to_string())?;
+        if url.scheme() != "https" && !self.allow_private {
+            return Err("Interactions urls must use https".to_string());
+        }
+        check_url(&url, self.allow_private)
+    }
+
+    /// Sends the interaction, signed like outgoing webhooks are. The endpoint
+    /// either answers with a response right away or acknowledges with 202 or
+    /// 204 and responds through the callback before the deadline.
+    pub async fn deliver(
+        &self,
+        url: &str,
+        secret: &str,
+        interaction_id: Uuid,
+        interaction: &impl Serialize,
+    ) -> Result<Option<InteractionResponse>, String> {
+        let body = serde_json::to_vec(interaction).map_err(|e| e.to_string())?;
+        let timestamp = Utc::now().timestamp();
+        let mut response = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(USER_AGENT, USER_AGENT_VALUE)
+            .header("X-Occult-Interaction-Id", interaction_id.to_string())
+            .header("X-Occult-Timestamp", timestamp.to_string())
+            .header("X-Occult-Signature", sign(secret, timestamp, &body))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let status = response.status();
+        match status.as_u16() {
+            202 | 204 => Ok(None),
+            200 => {
+                let mut body = Vec::new();
+                while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+                    if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+                        return Err(format!("Response is larger than {MAX_RESPONSE_SIZE} bytes"));
+                    }
+                    body.extend_from_slice(&chunk);
+                }
+                serde_json::from_slice(&body)
+                    .map(Some)
+                    .map_err(|e| format!("Invalid interaction response: {e}"))
+            }
+            _ => Err(format!("Endpoint answered {status}")),
+        }
+    }
+}
+
+/// Interactions are only kept for as long as they might be looked at
+pub async fn run_interaction_pruner(pool: Pool<MySql>) {
+    loop {
+        let cutoff = Utc::now() - chrono::Duration::days(INTERACTION_RETENTION_DAYS);
+        match queries::prune_interactions(&pool, cutoff).await {
+            Ok(0) => {}
+            Ok(pruned) => info!("Pruned {pruned} old interactions"),
+            Err(e) => error!("Failed to prune interactions: {e}"),
+        }
+        tokio::time::sleep(PRUNE_INTERVAL).await;
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message as Email, Tokio1Executor};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    pub enabled: bool,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    // STARTTLS on the submission port. Off means TLS from the first byte,
+    // as on port 465
+    pub starttls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // e.g. "Occult <noreply@example.com>"
+    pub from: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            smtp_host: "localhost".to_string(),
+            smtp_port: 587,
+            starttls: true,
+            username: None,
+            password: None,
+            from: "Occult <noreply@localhost>".to_string(),
+        }
+    }
+}
+
+/// Sends account emails over SMTP in the background
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Option<Mailbox>,
+}
+
+impl Mailer {
+    pub fn new(config: &MailConfig) -> Result<Self> {
+        if !config.enabled {
+            return Ok(Self::disabled());
+        }
+        let builder = if config.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host)
+        }
+        .context("Failed to set up the SMTP transport")?
+        .port(config.smtp_port);
+        let builder = match (&config.username, &config.password) {
+            (Some(username), Some(password)) => builder.credentials(Credentials::new(username.clone(), password.clone())),
+            _ => builder,
+        };
+        Ok(Self {
+            transport: Some(builder.build()),
+            from: Some(config.from.parse().context("Invalid mail.from address")?),
+        })
+    }
+
+    /// A mailer that drops everything, for when mail is off
+    pub fn disabled() -> Self {
+        Self {
+            transport: None,
+            from: None,
+        }
+    }
+
+    /// Sends a plain text email without waiting for it to go out
+    pub fn send(&self, to: &str, subject: &str, body: String) {
+        let (Some(transport), Some(from)) = (self.transport.clone(), self.from.clone()) else {
+            warn!("Not sending \"{subject}\" because mail is not configured");
+            return;
+        };
+        let to: Mailbox = match to.parse() {
+            Ok(to) => to,
+            Err(e) => {
+                warn!("Not sending \"{subject}\" to invalid address {to}: {e}");
+                return;
+            }
+        };
+        let email = match Email::builder()
+            .from(from)
+            .to(to.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+        {
+            Ok(email) => email,
+            Err(e) => {
+                error!("Failed to build email \"{subject}\": {e}");
+                return;
+            }
+        };
+        let subject = subject.to_string();
+        tokio::spawn(async move {
+            match transport.send(email).await {
+                Ok(_) => info!("Sent \"{subject}\" to {to}"),
+                Err(e) => error!("Failed to send \"{subject}\" to {to}: {e}"),
+            }
+        });
+    }
+}
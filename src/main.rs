@@ -8,6 +8,15 @@ pub mod workspace;
 pub mod logger;
 pub mod user;
 pub mod db;
+pub mod gateway;
+pub mod media;
+pub mod storage;
+pub mod unfurl;
+pub mod search;
+pub mod push;
+pub mod webhooks;
+pub mod interactions;
+pub mod mail;
 
 #[rocket::main]
 async fn main() -> Result<()> {
@@ -0,0 +1,203 @@
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, GenericImageView, ImageDecoder, ImageFormat, ImageReader, Limits};
+use log::{debug, error, warn};
+use sqlx::{MySql, Pool};
+use tokio::io::AsyncReadExt;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::api::AttachmentType;
+use crate::db::queries::{self, NewThumbnail};
+use crate::storage::{store_bytes, Storage};
+
+/// Longest edge of each generated thumbnail
+pub const THUMBNAIL_SIZES: [u32; 3] = [128, 512, 1024];
+const JPEG_QUALITY: u8 = 80;
+/// Blurhash works on a tiny copy, the detail is thrown away anyway
+const BLURHASH_SOURCE_SIZE: u32 = 32;
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+/// Sources bigger than this are refused from their header, before decoding
+const MAX_SOURCE_DIMENSION: u32 = 16384;
+/// Most the decoder may allocate for one image
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+const JOB_BATCH_SIZE: i64 = 8;
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Jobs are dropped after this many failed attempts
+pub const MAX_JOB_ATTEMPTS: i32 = 5;
+
+/// Wakes the thumbnail worker when a new job is queued, so it doesn't have
+/// to wait for the next poll
+#[derive(Clone, Default)]
+pub struct ThumbnailQueue {
+    notify: Arc<Notify>,
+}
+
+impl ThumbnailQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+}
+
+/// Seconds to wait before retrying a job that has failed `attempts` times
+pub fn retry_backoff(attempts: i32) -> i64 {
+    30 * 2i64.pow(attempts.clamp(0, 10) as u32)
+}
+
+pub async fn run_thumbnail_worker(pool: Pool<MySql>, storage: Storage, queue: ThumbnailQueue) {
+    loop {
+        match queries::claim_thumbnail_jobs(&pool, JOB_BATCH_SIZE).await {
+            Ok(jobs) => {
+                for job in jobs {
+                    match generate_thumbnails(&pool, &storage, job.attachment_id).await {
+                        Ok(()) => {
+                            if let Err(e) = queries::complete_thumbnail_job(&pool, job.attachment_id).await {
+                                error!("Failed to complete thumbnail job {}: {e}", job.attachment_id);
+                            }
+                        }
+                        Err(e) if job.attempts >= MAX_JOB_ATTEMPTS => {
+                            error!("Giving up on thumbnails for {} after {} attempts: {e:#}", job.attachment_id, job.attempts);
+                            if let Err(e) = queries::complete_thumbnail_job(&pool, job.attachment_id).await {
+                                error!("Failed to drop thumbnail job {}: {e}", job.attachment_id);
+                            }
+                        }
+                        Err(e) => {
+                            warn!("Thumbnail job {} failed, will retry: {e:#}", job.attachment_id);
+                            let retry_in = retry_backoff(job.attempts);
+                            if let Err(e) = queries::fail_thumbnail_job(&pool, job.attachment_id, &format!("{e:#}"), retry_in).await {
+                                error!("Failed to reschedule thumbnail job {}: {e}", job.attachment_id);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => error!("Failed to claim thumbnail jobs: {e}"),
+        }
+
+        tokio::select! {
+            _ = queue.notify.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+struct RenderedThumbnail {
+    width: u32,
+    height: u32,
+    mime_type: &'static str,
+    bytes: Vec<u8>,
+}
+
+async fn generate_thumbnails(pool: &Pool<MySql>, storage: &Storage, attachment_id: Uuid) -> Result<()> {
+    let Some(stored) = queries::get_attachment(pool, attachment_id).await? else {
+        debug!("Attachment {attachment_id} was deleted before its thumbnails were made");
+        return Ok(());
+    };
+    if !matches!(stored.attachment.attachment_type, AttachmentType::Image) {
+        return Ok(());
+    }
+
+    let mut source = Vec::new();
+    storage
+        .get(&stored.storage_key)
+        .await?
+        .read_to_end(&mut source)
+        .await
+        .context("Failed to read attachment")?;
+
+    let (blurhash, rendered) = tokio::task::spawn_blocking(move || render(&source)).await??;
+
+    let mut thumbnails = Vec::with_capacity(rendered.len());
+    for thumbnail in rendered {
+        let object = store_bytes(storage, &thumbnail.bytes, thumbnail.mime_type, "thumbnails").await?;
+        thumbnails.push(NewThumbnail {
+            width: thumbnail.width as i32,
+            height: thumbnail.height as i32,
+            mime_type: thumbnail.mime_type.to_string(),
+            storage_key: object.key,
+        });
+    }
+    queries::save_thumbnails(pool, attachment_id, &blurhash, &thumbnails).await?;
+    Ok(())
+}
+
+fn render(source: &[u8]) -> Result<(String, Vec<RenderedThumbnail>)> {
+    let open = || {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+        limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+        limits.max_alloc = Some(MAX_DECODE_ALLOC);
+        let mut reader = ImageReader::new(Cursor::new(source))
+            .with_guessed_format()
+            .context("Failed to read image")?;
+        reader.limits(limits);
+        anyhow::Ok(reader)
+    };
+    // A decompression bomb is caught from its header, before any pixels are allocated
+    let (width, height) = open()?.into_dimensions().context("Failed to read image size")?;
+    if width > MAX_SOURCE_DIMENSION || height > MAX_SOURCE_DIMENSION {
+        bail!("Image is {width}x{height}, too big to thumbnail");
+    }
+
+    let mut decoder = open()?.into_decoder().context("Failed to decode image")?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).context("Failed to decode image")?;
+    image.apply_orientation(orientation);
+    let (width, height) = image.dimensions();
+
+    let tiny = image.thumbnail(BLURHASH_SOURCE_SIZE, BLURHASH_SOURCE_SIZE).to_rgba8();
+    let blurhash = blurhash::encode(
+        BLURHASH_COMPONENTS.0,
+        BLURHASH_COMPONENTS.1,
+        tiny.width(),
+        tiny.height(),
+        tiny.as_raw(),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to compute blurhash: {e:?}"))?;
+
+    let mut thumbnails = Vec::new();
+    for size in THUMBNAIL_SIZES {
+        // Never upscale, the original already covers anything bigger
+        if size >= width.max(height) {
+            break;
+        }
+        let resized = image.resize(size, size, FilterType::Lanczos3);
+        thumbnails.push(encode(&resized)?);
+    }
+    Ok((blurhash, thumbnails))
+}
+
+/// JPEG for opaque images; images with transparency keep it as WebP
+fn encode(image: &DynamicImage) -> Result<RenderedThumbnail> {
+    let mut bytes = Vec::new();
+    let mime_type = if image.color().has_alpha() {
+        image
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::WebP)
+            .context("Failed to encode WebP thumbnail")?;
+        "image/webp"
+    } else {
+        let encoder = JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY);
+        image
+            .to_rgb8()
+            .write_with_encoder(encoder)
+            .context("Failed to encode JPEG thumbnail")?;
+        "image/jpeg"
+    };
+    Ok(RenderedThumbnail {
+        width: image.width(),
+        height: image.height(),
+        mime_type,
+        bytes,
+    })
+}
@@ -0,0 +1,517 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{debug, error, warn};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::SecretKey;
+use reqwest::header::RETRY_AFTER;
+use serde::{Deserialize, Serialize};
+use sqlx::{MySql, Pool};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use url::Url;
+use uuid::Uuid;
+use web_push::{
+    request_builder, ContentEncoding, PartialVapidSignatureBuilder, SubscriptionInfo, VapidSignatureBuilder,
+    WebPushMessageBuilder,
+};
+
+use crate::api::notifications::{MentionKind, ServerNotificationSettings};
+use crate::api::permissions::Permissions;
+use crate::api::{Message, PushSubscription, UserStatus};
+use crate::db::queries;
+use crate::gateway::Gateway;
+use crate::unfurl::ssrf::{check_url, PublicResolver};
+
+/// How long a push service should hold a notification for an offline device
+const PUSH_TTL_SECS: u32 = 24 * 60 * 60;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Waits before each retry of a delivery that failed on the push service's end.
+/// Retries are only kept in memory, so a restart drops them. Unlike webhook
+/// deliveries that's fine: a notification minutes late is barely worth having.
+const RETRY_DELAYS: [Duration; 3] = [Duration::from_secs(5), Duration::from_secs(30), Duration::from_secs(120)];
+/// Subscriptions that failed this many deliveries in a row are dropped
+const MAX_CONSECUTIVE_FAILURES: i32 = 5;
+const CONCURRENT_DELIVERIES: usize = 8;
+/// Recipients whose settings and subscriptions are loaded in one go
+const RECIPIENT_BATCH: usize = 500;
+/// Notification bodies are cut here, the client fetches the full message
+const MAX_BODY_LENGTH: usize = 200;
+/// Sent messages waiting for the worker. Past this, new ones get no push
+/// notifications rather than piling up in memory.
+const QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PushConfig {
+    pub enabled: bool,
+    // Generated by init. Push stays off without them, since regenerating
+    // keys would silently break every existing subscription
+    pub vapid: Option<VapidKeys>,
+    // Contact the push services can reach the operator at
+    pub subject: String,
+    // Lets subscriptions point at loopback and private networks, for testing
+    // against a local push service stub. Never turn it on in production
+    pub allow_private_endpoints: bool,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            vapid: None,
+            subject: "mailto:admin@localhost".to_string(),
+            allow_private_endpoints: false,
+        }
+    }
+}
+
+impl PushConfig {
+    /// Defaults with a fresh VAPID key pair, for new configs
+    pub fn generate() -> Self {
+        Self {
+            vapid: Some(VapidKeys::generate()),
+            ..Default::default()
+        }
+    }
+}
+
+/// P-256 key pair, base64url encoded without padding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VapidKeys {
+    pub private_key: String,
+    /// Handed to browsers as the `applicationServerKey`
+    pub public_key: String,
+}
+
+impl VapidKeys {
+    pub fn generate() -> Self {
+        let secret = SecretKey::random(&mut rand::rngs::OsRng);
+        Self {
+            private_key: URL_SAFE_NO_PAD.encode(secret.to_bytes()),
+            public_key: URL_SAFE_NO_PAD.encode(secret.public_key().to_encoded_point(false).as_bytes()),
+        }
+    }
+}
+
+/// What the service worker receives, encrypted end to end
+#[derive(Debug, Serialize)]
+struct PushPayload<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    message_id: Uuid,
+    channel_id: Uuid,
+    server_id: Uuid,
+    author: &'a str,
+    body: String,
+}
+
+enum Delivery {
+    Delivered,
+    /// The subscription expired or was revoked, it will never work again
+    Gone,
+    Retry(Option<Duration>),
+    Failed(String),
+}
+
+/// Sends notifications to push services
+#[derive(Clone)]
+pub struct Pusher {
+    client: reqwest::Client,
+    vapid: PartialVapidSignatureBuilder,
+    subject: String,
+    allow_private: bool,
+}
+
+impl Pusher {
+    pub fn new(config: &PushConfig) -> Result<Self> {
+        let keys = config.vapid.as_ref().ok_or_else(|| anyhow!("No VAPID keys configured"))?;
+        let vapid = VapidSignatureBuilder::from_base64_no_sub(&keys.private_key)
+            .map_err(|e| anyhow!("Invalid VAPID private key: {e}"))?;
+        let mut client = reqwest::Client::builder()
+            .no_proxy()
+            .timeout(SEND_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none());
+        if !config.allow_private_endpoints {
+            client = client.dns_resolver(Arc::new(PublicResolver));
+        }
+        Ok(Self {
+            client: client.build().context("Failed to build the push client")?,
+            vapid,
+            subject: config.subject.clone(),
+            allow_private: config.allow_private_endpoints,
+        })
+    }
+
+    /// Refuses endpoints we'd never deliver to, before they're stored
+    pub fn check_endpoint(&self, endpoint: &str) -> Result<(), String> {
+        let url = Url::parse(endpoint).map_err(|_| "Invalid endpoint url".to_string())?;
+        if url.scheme() != "https" && !self.allow_private {
+            return Err("Push endpoints must use https".to_string());
+        }
+        check_url(&url, self.allow_private)
+    }
+
+    async fn send(&self, subscription: &PushSubscription, payload: &[u8]) -> Result<Delivery> {
+        let info = SubscriptionInfo::new(&subscription.endpoint, &subscription.p256dh, &subscription.auth);
+        let mut signature = self.vapid.clone().add_sub_info(&info);
+        signature.add_claim("sub", self.subject.as_str());
+        let signature = signature.build().map_err(|e| anyhow!("Failed to sign push: {e}"))?;
+
+        let mut message = WebPushMessageBuilder::new(&info);
+        message.set_payload(ContentEncoding::Aes128Gcm, payload);
+        message.set_vapid_signature(signature);
+        message.set_ttl(PUSH_TTL_SECS);
+        let message = message.build().map_err(|e| anyhow!("Failed to encrypt push: {e}"))?;
+
+        // web-push builds the request, reqwest sends it so the SSRF resolver applies
+        let request = request_builder::build_request::<Vec<u8>>(message);
+        let mut outgoing = self.client.post(request.uri().to_string());
+        for (name, value) in request.headers() {
+            outgoing = outgoing.header(name.as_str(), value.as_bytes());
+        }
+        let response = match outgoing.body(request.body().clone()).send().await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() || e.is_connect() => return Ok(Delivery::Retry(None)),
+            Err(e) => return Ok(Delivery::Failed(e.to_string())),
+        };
+
+        let status = response.status();
+        Ok(match status.as_u16() {
+            200..=299 => Delivery::Delivered,
+            404 | 410 => Delivery::Gone,
+            429 | 500..=599 => Delivery::Retry(
+                response
+                    .headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+                    .map(Duration::from_secs),
+            ),
+            _ => Delivery::Failed(format!("Push service answered {status}")),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct PushJob {
+    message_id: Uuid,
+    server_id: Uuid,
+}
+
+/// Hands sent messages to the background push worker
+#[derive(Clone)]
+pub struct PushQueue {
+    sender: Option<mpsc::Sender<PushJob>>,
+    pusher: Option<Pusher>,
+}
+
+pub struct PushReceiver(mpsc::Receiver<PushJob>);
+
+impl PushQueue {
+    pub fn new(pusher: Pusher) -> (Self, PushReceiver) {
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        (
+            Self {
+                sender: Some(sender),
+                pusher: Some(pusher),
+            },
+            PushReceiver(receiver),
+        )
+    }
+
+    /// A queue that drops everything, for when push is off
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            pusher: None,
+        }
+    }
+
+    pub fn pusher(&self) -> Option<&Pusher> {
+        self.pusher.as_ref()
+    }
+
+    pub fn enqueue(&self, message_id: Uuid, server_id: Uuid) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Err(TrySendError::Full(job)) = sender.try_send(PushJob { message_id, server_id }) {
+            warn!("Push queue is full, dropping notifications for message {}", job.message_id);
+        }
+    }
+}
+
+pub async fn run_push_worker(pool: Pool<MySql>, gateway: Gateway, pusher: Pusher, mut receiver: PushReceiver) {
+    // Messages are worked through one at a time and wait for delivery
+    // permits, so a big fan out holds the queue back rather than piling up
+    let permits = Arc::new(Semaphore::new(CONCURRENT_DELIVERIES));
+    while let Some(job) = receiver.0.recv().await {
+        if let Err(e) = push_message(&pool, &gateway, &pusher, &permits, job).await {
+            error!("Failed to send push notifications: {e:#}");
+        }
+    }
+}
+
+/// Notifies offline users the message mentions, as far as their settings,
+/// presence and channel access allow
+async fn push_message(
+    pool: &Pool<MySql>,
+    gateway: &Gateway,
+    pusher: &Pusher,
+    permits: &Arc<Semaphore>,
+    job: PushJob,
+) -> Result<()> {
+    let Some(message) = queries::get_message(pool, job.message_id, None).await? else {
+        return Ok(());
+    };
+    let Some(channel) = queries::get_channel(pool, message.channel_id).await? else {
+        return Ok(());
+    };
+    let recipients = queries::get_mention_recipients(pool, message.id, job.server_id).await?;
+    if recipients.is_empty() {
+        return Ok(());
+    }
+    let author = match &message.webhook {
+        Some(webhook) => webhook.name.clone(),
+        None => queries::get_public_user(pool, message.author_id)
+            .await?
+            .map(|user| user.display_name.unwrap_or(user.username))
+            .unwrap_or_default(),
+    };
+    let payload = serde_json::to_vec(&PushPayload {
+        kind: "message",
+        message_id: message.id,
+        channel_id: channel.id,
+        server_id: job.server_id,
+        author: &author,
+        body: preview(&message),
+    })?;
+
+    // Mentions of people who can't read the channel go nowhere
+    let permissions: HashMap<Uuid, Permissions> =
+        queries::get_server_member_permissions(pool, job.server_id).await?.into_iter().collect();
+    let recipients: Vec<(Uuid, MentionKind)> = recipients
+        .into_iter()
+        .filter(|(user_id, _)| *user_id != message.author_id && !gateway.is_connected(*user_id))
+        .filter(|(user_id, _)| {
+            permissions.get(user_id).is_some_and(|permissions| {
+                !channel.is_private || permissions.contains(Permissions::VIEW_PRIVATE_CHANNELS)
+            })
+        })
+        .collect();
+
+    let now = chrono::Utc::now();
+    for batch in recipients.chunks(RECIPIENT_BATCH) {
+        let user_ids: Vec<Uuid> = batch.iter().map(|(user_id, _)| *user_id).collect();
+        let dnd = queries::get_users_with_status(pool, &user_ids, UserStatus::Dnd).await?;
+        let settings = queries::get_users_notification_settings(pool, job.server_id, &user_ids).await?;
+        let mut subscriptions: HashMap<Uuid, Vec<PushSubscription>> = HashMap::new();
+        for subscription in queries::get_users_push_subscriptions(pool, &user_ids).await? {
+            subscriptions.entry(subscription.user_id).or_default().push(subscription);
+        }
+
+        for (user_id, mention) in batch {
+            if dnd.contains(user_id) {
+                continue;
+            }
+            let wants_push = match settings.get(user_id) {
+                Some(settings) => settings.effective(channel.id, now).should_notify(Some(*mention)),
+                None => ServerNotificationSettings::new(job.server_id)
+                    .effective(channel.id, now)
+                    .should_notify(Some(*mention)),
+            };
+            if !wants_push {
+                continue;
+            }
+            for subscription in subscriptions.remove(user_id).unwrap_or_default() {
+                let Ok(permit) = permits.clone().acquire_owned().await else {
+                    return Ok(());
+                };
+                let (pool, pusher, permits, payload) = (pool.clone(), pusher.clone(), permits.clone(), payload.clone());
+                tokio::spawn(async move { deliver(&pool, &pusher, &permits, permit, subscription, &payload).await });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends to one subscription, retrying while the push service asks us to.
+/// The permit is only held while a request is in flight, not between tries.
+async fn deliver(
+    pool: &Pool<MySql>,
+    pusher: &Pusher,
+    permits: &Arc<Semaphore>,
+    permit: OwnedSemaphorePermit,
+    subscription: PushSubscription,
+    payload: &[u8],
+) {
+    let mut permit = Some(permit);
+    for attempt in 0..=RETRY_DELAYS.len() {
+        if permit.is_none() {
+            match permits.clone().acquire_owned().await {
+                Ok(acquired) => permit = Some(acquired),
+                Err(_) => return,
+            }
+        }
+        let outcome = match pusher.send(&subscription, payload).await {
+            Ok(outcome) => outcome,
+            Err(e) => Delivery::Failed(format!("{e:#}")),
+        };
+        let result = match outcome {
+            Delivery::Delivered => queries::record_push_success(pool, subscription.id).await,
+            Delivery::Gone => {
+                debug!("Push subscription {} is gone, removing it", subscription.id);
+                queries::delete_push_subscription(pool, subscription.id).await.map(|_| ())
+            }
+            Delivery::Retry(after) if attempt < RETRY_DELAYS.len() => {
+                // Honour Retry-After, within reason
+                let delay = after.unwrap_or(RETRY_DELAYS[attempt]).min(RETRY_DELAYS[RETRY_DELAYS.len() - 1]);
+                permit = None;
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            Delivery::Retry(_) => record_failure(pool, &subscription, "push service kept failing").await,
+            Delivery::Failed(reason) => record_failure(pool, &subscription, &reason).await,
+        };
+        if let Err(e) = result {
+            error!("Failed to record push delivery for {}: {e}", subscription.id);
+        }
+        return;
+    }
+}
+
+async fn record_failure(pool: &Pool<MySql>, subscription: &PushSubscription, reason: &str) -> Result<(), sqlx::Error> {
+    warn!("Push to subscription {} failed: {reason}", subscription.id);
+    let failures = queries::record_push_failure(pool, subscription.id).await?;
+    if failures >= MAX_CONSECUTIVE_FAILURES {
+        queries::delete_push_subscription(pool, subscription.id).await?;
+    }
+    Ok(())
+}
+
+fn preview(message: &Message) -> String {
+    let content = message.content.trim();
+    if content.is_empty() {
+        return match message.attachments.len() {
+            0 => String::new(),
+            1 => "Sent an attachment".to_string(),
+            n => format!("Sent {n} attachments"),
+        };
+    }
+    match content.char_indices().nth(MAX_BODY_LENGTH) {
+        Some((end, _)) => format!("{}…", &content[..end]),
+        None => content.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A push service on loopback that answers every request with `response`
+    /// and hands back the request heads it saw
+    async fn push_service_stub(response: &'static str) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (seen, requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    // Read the whole request so the client isn't cut off mid body
+                    let mut request = Vec::new();
+                    let mut chunk = [0; 4096];
+                    loop {
+                        let Ok(read) = socket.read(&mut chunk).await else { return };
+                        if read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&chunk[..read]);
+                        let text = String::from_utf8_lossy(&request).to_string();
+                        let Some(head_end) = text.find("\r\n\r\n") else { continue };
+                        let length = text[..head_end]
+                            .lines()
+                            .find_map(|line| {
+                                let (name, value) = line.split_once(':')?;
+                                name.eq_ignore_ascii_case("content-length").then(|| value.trim().parse().ok())?
+                            })
+                            .unwrap_or(0);
+                        if request.len() >= head_end + 4 + length {
+                            let _ = seen.send(text[..head_end].to_string());
+                            break;
+                        }
+                    }
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (format!("http://{address}/push/device"), requests)
+    }
+
+    fn pusher() -> Pusher {
+        Pusher::new(&PushConfig {
+            allow_private_endpoints: true,
+            ..PushConfig::generate()
+        })
+        .unwrap()
+    }
+
+    /// A subscription with real browser-side keys, so the payload can be encrypted
+    fn subscription(endpoint: String) -> PushSubscription {
+        let browser_key = SecretKey::random(&mut rand::rngs::OsRng);
+        PushSubscription {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            endpoint,
+            p256dh: URL_SAFE_NO_PAD.encode(browser_key.public_key().to_encoded_point(false).as_bytes()),
+            auth: URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>()),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_encrypted_and_signed() {
+        let (endpoint, mut requests) = push_service_stub("HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n").await;
+        let outcome = pusher().send(&subscription(endpoint), b"{\"kind\":\"message\"}").await.unwrap();
+        assert!(matches!(outcome, Delivery::Delivered));
+
+        let head = requests.recv().await.unwrap().to_ascii_lowercase();
+        assert!(head.starts_with("post /push/device"));
+        assert!(head.contains("content-encoding: aes128gcm"));
+        assert!(head.contains(&format!("ttl: {PUSH_TTL_SECS}")));
+        assert!(head.contains("authorization: vapid t="));
+    }
+
+    #[tokio::test]
+    async fn classifies_push_service_answers() {
+        let payload = b"{}";
+        let (endpoint, _requests) = push_service_stub("HTTP/1.1 410 Gone\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(matches!(pusher().send(&subscription(endpoint), payload).await.unwrap(), Delivery::Gone));
+
+        let (endpoint, _requests) =
+            push_service_stub("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 7\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(matches!(
+            pusher().send(&subscription(endpoint), payload).await.unwrap(),
+            Delivery::Retry(Some(delay)) if delay == Duration::from_secs(7)
+        ));
+
+        let (endpoint, _requests) = push_service_stub("HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(matches!(pusher().send(&subscription(endpoint), payload).await.unwrap(), Delivery::Failed(_)));
+    }
+
+    #[test]
+    fn refuses_private_endpoints_by_default() {
+        let pusher = Pusher::new(&PushConfig::generate()).unwrap();
+        assert!(pusher.check_endpoint("https://127.0.0.1/push").is_err());
+        assert!(pusher.check_endpoint("http://push.example.com/push").is_err());
+        assert!(pusher.check_endpoint("https://push.example.com/push").is_ok());
+    }
+}
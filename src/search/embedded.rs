@@ -0,0 +1,268 @@
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::{error, info};
+use rocket::async_trait;
+use sqlx::{MySql, Pool};
+use tantivy::collector::{Count, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery, TermSetQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value, FAST, INDEXED, STORED, STRING, TEXT};
+use tantivy::{doc, Index, IndexReader, IndexWriter, Order, ReloadPolicy, TantivyDocument, Term};
+use uuid::Uuid;
+
+use super::{HasFilter, IndexedMessage, SearchFilters, SearchIndex, SearchResults};
+use crate::api::Message;
+use crate::db::queries;
+
+/// Memory the writer may buffer before it flushes a segment
+const WRITER_MEMORY: usize = 64 * 1024 * 1024;
+/// Writes become searchable within this long. Committing on every message
+/// would leave a segment per message.
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+const REINDEX_BATCH: i64 = 1000;
+
+#[derive(Clone, Copy)]
+struct Fields {
+    id: Field,
+    channel_id: Field,
+    author_id: Field,
+    content: Field,
+    mentions: Field,
+    has: Field,
+    pinned: Field,
+    created_at: Field,
+}
+
+impl Fields {
+    fn schema() -> (Schema, Fields) {
+        let mut builder = Schema::builder();
+        let fields = Fields {
+            id: builder.add_text_field("id", STRING | STORED),
+            channel_id: builder.add_text_field("channel_id", STRING),
+            author_id: builder.add_text_field("author_id", STRING),
+            content: builder.add_text_field("content", TEXT),
+            mentions: builder.add_text_field("mentions", STRING),
+            has: builder.add_text_field("has", STRING),
+            pinned: builder.add_u64_field("pinned", INDEXED),
+            created_at: builder.add_i64_field("created_at", INDEXED | FAST),
+        };
+        (builder.build(), fields)
+    }
+}
+
+/// An inverted index on local disk, for deployments that outgrow FULLTEXT.
+/// It holds only what filters need; results are loaded from the database.
+#[derive(Clone)]
+pub struct EmbeddedSearchIndex {
+    pool: Pool<MySql>,
+    index: Index,
+    reader: IndexReader,
+    writer: Arc<Mutex<IndexWriter>>,
+    fields: Fields,
+    dirty: Arc<AtomicBool>,
+}
+
+impl EmbeddedSearchIndex {
+    /// Only one process can hold the index's writer lock, so the server has
+    /// to be stopped before `occult-server reindex` can open it
+    pub fn open(path: &Path, pool: Pool<MySql>) -> Result<Self> {
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("Failed to create search index dir {}", path.display()))?;
+        let (schema, fields) = Fields::schema();
+        let directory = MmapDirectory::open(path).context("Failed to open search index dir")?;
+        let index = Index::open_or_create(directory, schema).context("Failed to open search index")?;
+        let writer = index.writer(WRITER_MEMORY).context("Failed to lock search index, is another server using it?")?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()
+            .context("Failed to open search index reader")?;
+
+        let search_index = Self {
+            pool,
+            index,
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
+            fields,
+            dirty: Arc::new(AtomicBool::new(false)),
+        };
+        tokio::spawn(search_index.clone().run_committer());
+        Ok(search_index)
+    }
+
+    async fn run_committer(self) {
+        loop {
+            tokio::time::sleep(COMMIT_INTERVAL).await;
+            if !self.dirty.swap(false, Ordering::AcqRel) {
+                continue;
+            }
+            let writer = self.writer.clone();
+            let result = tokio::task::spawn_blocking(move || lock(&writer)?.commit().map_err(anyhow::Error::from)).await;
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => error!("Failed to commit search index: {e:#}"),
+                Err(e) => error!("Search index commit panicked: {e}"),
+            }
+        }
+    }
+
+    fn document(&self, message: &IndexedMessage) -> TantivyDocument {
+        let f = self.fields;
+        let mut document = doc!(
+            f.id => message.id.to_string(),
+            f.channel_id => message.channel_id.to_string(),
+            f.author_id => message.author_id.to_string(),
+            f.content => message.content.as_str(),
+            f.pinned => message.is_pinned as u64,
+            f.created_at => message.created_at.timestamp(),
+        );
+        for user_id in &message.mention_ids {
+            document.add_text(f.mentions, user_id.to_string());
+        }
+        for has in &message.has {
+            document.add_text(f.has, has.as_str());
+        }
+        document
+    }
+
+    /// Replaces whatever is indexed for the message. Not searchable until
+    /// the next commit.
+    fn write(&self, message: &IndexedMessage) -> Result<()> {
+        let writer = lock(&self.writer)?;
+        writer.delete_term(Term::from_field_text(self.fields.id, &message.id.to_string()));
+        writer.add_document(self.document(message))?;
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn any_of(field: Field, ids: &[Uuid]) -> Box<dyn Query> {
+        Box::new(TermSetQuery::new(
+            ids.iter().map(|id| Term::from_field_text(field, &id.to_string())),
+        ))
+    }
+
+    fn build_query(&self, filters: &SearchFilters) -> Result<Box<dyn Query>> {
+        let f = self.fields;
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, Self::any_of(f.channel_id, &filters.channel_ids))];
+
+        if !filters.text.trim().is_empty() {
+            let mut parser = QueryParser::for_index(&self.index, vec![f.content]);
+            parser.set_conjunction_by_default();
+            // Typos in query syntax shouldn't fail the search, the valid parts are kept
+            let (query, _) = parser.parse_query_lenient(&filters.text);
+            clauses.push((Occur::Must, query));
+        }
+        if !filters.author_ids.is_empty() {
+            clauses.push((Occur::Must, Self::any_of(f.author_id, &filters.author_ids)));
+        }
+        if !filters.mention_ids.is_empty() {
+            clauses.push((Occur::Must, Self::any_of(f.mentions, &filters.mention_ids)));
+        }
+        for has in &filters.has {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(Term::from_field_text(f.has, has.as_str()), IndexRecordOption::Basic)),
+            ));
+        }
+        if filters.before.is_some() || filters.after.is_some() {
+            let lower = filters.after.map_or(Bound::Unbounded, |after| Bound::Included(after.timestamp()));
+            let upper = filters.before.map_or(Bound::Unbounded, |before| Bound::Excluded(before.timestamp()));
+            clauses.push((Occur::Must, Box::new(RangeQuery::new_i64_bounds("created_at".to_string(), lower, upper))));
+        }
+        if let Some(pinned) = filters.pinned {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(Term::from_field_u64(f.pinned, pinned as u64), IndexRecordOption::Basic)),
+            ));
+        }
+        Ok(Box::new(BooleanQuery::new(clauses)))
+    }
+}
+
+#[async_trait]
+impl SearchIndex for EmbeddedSearchIndex {
+    async fn search(&self, filters: &SearchFilters) -> Result<SearchResults> {
+        if filters.channel_ids.is_empty() {
+            return Ok(SearchResults::default());
+        }
+        let query = self.build_query(filters)?;
+        let searcher = self.reader.searcher();
+        let id_field = self.fields.id;
+        let top = TopDocs::with_limit(filters.limit.max(1) as usize)
+            .and_offset(filters.offset.max(0) as usize)
+            .order_by_fast_field::<i64>("created_at", Order::Desc);
+
+        // Searching and loading stored fields read segments from disk
+        tokio::task::spawn_blocking(move || {
+            let (total, hits) = searcher.search(&query, &(Count, top))?;
+            let mut message_ids = Vec::with_capacity(hits.len());
+            for (_, address) in hits {
+                let document: TantivyDocument = searcher.doc(address)?;
+                if let Some(id) = document
+                    .get_first(id_field)
+                    .and_then(|value| value.as_str())
+                    .and_then(|id| Uuid::parse_str(id).ok())
+                {
+                    message_ids.push(id);
+                }
+            }
+            Ok(SearchResults {
+                total: total as i64,
+                message_ids,
+            })
+        })
+        .await?
+    }
+
+    async fn index_message(&self, message: &Message) -> Result<()> {
+        // The same mentions the database backend filters on, so users that
+        // don't exist aren't indexed
+        let mention_ids = queries::get_message_mention_ids(&self.pool, message.id)
+            .await
+            .context("Failed to load mentions")?;
+        self.write(&IndexedMessage::new(message, mention_ids))
+    }
+
+    async fn remove_message(&self, message_id: Uuid) -> Result<()> {
+        lock(&self.writer)?.delete_term(Term::from_field_text(self.fields.id, &message_id.to_string()));
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    async fn rebuild(&self, pool: &Pool<MySql>) -> Result<u64> {
+        lock(&self.writer)?.delete_all_documents()?;
+        let mut indexed = 0u64;
+        let mut after = None;
+        loop {
+            let batch = queries::get_messages_for_index(pool, after, REINDEX_BATCH)
+                .await
+                .context("Failed to load messages")?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            after = Some(last.id);
+            {
+                let writer = lock(&self.writer)?;
+                for message in &batch {
+                    writer.add_document(self.document(message))?;
+                }
+            }
+            indexed += batch.len() as u64;
+            info!("Indexed {indexed} messages");
+        }
+
+        let writer = self.writer.clone();
+        tokio::task::spawn_blocking(move || lock(&writer)?.commit().map_err(anyhow::Error::from)).await??;
+        self.dirty.store(false, Ordering::Release);
+        Ok(indexed)
+    }
+}
+
+fn lock(writer: &Mutex<IndexWriter>) -> Result<std::sync::MutexGuard<'_, IndexWriter>> {
+    writer.lock().map_err(|_| anyhow!("Search index writer was poisoned"))
+}
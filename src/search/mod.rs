@@ -0,0 +1,257 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use rocket::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::{MySql, Pool};
+use uuid::Uuid;
+
+use crate::api::{AttachmentType, Message};
+use crate::workspace::get_data_dir;
+use embedded::EmbeddedSearchIndex;
+use mysql::MySqlSearchIndex;
+
+pub mod embedded;
+pub mod mysql;
+
+/// Shared handle to whichever index the server searches with
+pub type Search = Arc<dyn SearchIndex>;
+
+/// Swappable so a dedicated engine can replace the database's FULLTEXT index
+#[async_trait]
+pub trait SearchIndex: Send + Sync {
+    /// Message ids matching the filters, newest first
+    async fn search(&self, filters: &SearchFilters) -> Result<SearchResults>;
+
+    /// Called after a message is sent or edited. Indexes that live in the
+    /// database are always up to date and can ignore it.
+    async fn index_message(&self, _message: &Message) -> Result<()> {
+        Ok(())
+    }
+
+    async fn remove_message(&self, _message_id: Uuid) -> Result<()> {
+        Ok(())
+    }
+
+    /// Throws the index away and rebuilds it from the messages table.
+    /// Returns how many messages were indexed.
+    async fn rebuild(&self, _pool: &Pool<MySql>) -> Result<u64> {
+        Ok(0)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum SearchConfig {
+    /// FULLTEXT on `messages.content`, nothing to set up
+    #[default]
+    Mysql,
+    /// Inverted index on local disk, kept up to date as messages change.
+    /// Run `occult-server reindex` after switching to it, with the server
+    /// stopped since only one process can write the index.
+    Embedded {
+        // Defaults to <data dir>/search
+        path: Option<PathBuf>,
+    },
+}
+
+pub fn build_search(config: &SearchConfig, pool: Pool<MySql>) -> Result<Search> {
+    Ok(match config {
+        SearchConfig::Mysql => Arc::new(MySqlSearchIndex::new(pool)),
+        SearchConfig::Embedded { path } => {
+            let path = match path {
+                Some(path) => path.clone(),
+                None => {
+                    let mut dir = get_data_dir().context("Failed to obtain data dir")?;
+                    dir.push("search");
+                    dir
+                }
+            };
+            Arc::new(EmbeddedSearchIndex::open(&path, pool)?)
+        }
+    })
+}
+
+/// What an index needs to know about a message to apply every filter
+#[derive(Debug)]
+pub struct IndexedMessage {
+    pub id: Uuid,
+    pub channel_id: Uuid,
+    pub author_id: Uuid,
+    pub content: String,
+    pub mention_ids: Vec<Uuid>,
+    pub has: Vec<HasFilter>,
+    pub is_pinned: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl IndexedMessage {
+    /// `mention_ids` come from the mentions table rather than the content
+    pub fn new(message: &Message, mention_ids: Vec<Uuid>) -> Self {
+        let mut has = Vec::new();
+        for attachment in &message.attachments {
+            let filter = match attachment.attachment_type {
+                AttachmentType::Image => HasFilter::Image,
+                AttachmentType::Video => HasFilter::Video,
+                AttachmentType::File => HasFilter::File,
+            };
+            if !has.contains(&filter) {
+                has.push(filter);
+            }
+        }
+        IndexedMessage {
+            id: message.id,
+            channel_id: message.channel_id,
+            author_id: message.author_id,
+            content: message.content.clone(),
+            mention_ids,
+            has: HasFilter::derived(has, &message.content, !message.embeds.is_empty()),
+            is_pinned: message.is_pinned,
+            created_at: message.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HasFilter {
+    Attachment,
+    Image,
+    Video,
+    File,
+    Link,
+    Embed,
+}
+
+impl HasFilter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HasFilter::Attachment => "attachment",
+            HasFilter::Image => "image",
+            HasFilter::Video => "video",
+            HasFilter::File => "file",
+            HasFilter::Link => "link",
+            HasFilter::Embed => "embed",
+        }
+    }
+
+    /// Completes the attachment types a message has with the filters that
+    /// follow from them and from its content
+    pub fn derived(mut has: Vec<HasFilter>, content: &str, has_embeds: bool) -> Vec<HasFilter> {
+        if !has.is_empty() {
+            has.push(HasFilter::Attachment);
+        }
+        if content.contains("http://") || content.contains("https://") {
+            has.push(HasFilter::Link);
+        }
+        if has_embeds {
+            has.push(HasFilter::Embed);
+        }
+        has
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        Some(match value {
+            "attachment" => HasFilter::Attachment,
+            "image" => HasFilter::Image,
+            "video" => HasFilter::Video,
+            "file" => HasFilter::File,
+            "link" => HasFilter::Link,
+            "embed" => HasFilter::Embed,
+            _ => return None,
+        })
+    }
+}
+
+/// A search as the user typed it, e.g. `release notes from:alice has:link`.
+/// Names in `from:`, `in:` and `mentions:` are resolved by the caller.
+#[derive(Debug, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub from: Vec<String>,
+    pub channels: Vec<String>,
+    pub mentions: Vec<String>,
+    pub has: Vec<HasFilter>,
+    pub before: Option<DateTime<Utc>>,
+    pub after: Option<DateTime<Utc>>,
+    pub pinned: Option<bool>,
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut parsed = SearchQuery::default();
+        let mut text = Vec::new();
+        for word in query.split_whitespace() {
+            let Some((key, value)) = word.split_once(':').filter(|(_, value)| !value.is_empty()) else {
+                text.push(word);
+                continue;
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "from" => parsed.from.push(value.to_string()),
+                "in" => parsed.channels.push(value.trim_start_matches('#').to_string()),
+                "mentions" => parsed.mentions.push(value.to_string()),
+                "has" => parsed
+                    .has
+                    .push(HasFilter::parse(&value.to_ascii_lowercase()).ok_or_else(|| format!("Unknown has: filter {value}"))?),
+                "before" => parsed.before = Some(parse_date(value)?),
+                "after" => parsed.after = Some(parse_date(value)?),
+                "pinned" => {
+                    parsed.pinned = Some(match value.to_ascii_lowercase().as_str() {
+                        "true" | "yes" => true,
+                        "false" | "no" => false,
+                        _ => return Err(format!("pinned: takes true or false, not {value}")),
+                    })
+                }
+                // Not a filter, e.g. a url or a time of day
+                _ => text.push(word),
+            }
+        }
+        parsed.text = text.join(" ");
+        Ok(parsed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+            && self.from.is_empty()
+            && self.channels.is_empty()
+            && self.mentions.is_empty()
+            && self.has.is_empty()
+            && self.before.is_none()
+            && self.after.is_none()
+            && self.pinned.is_none()
+    }
+}
+
+/// `before:2024-05-01` is midnight UTC at the start of that day. Full
+/// RFC 3339 timestamps are accepted too.
+fn parse_date(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| format!("Invalid date {value}, expected YYYY-MM-DD"))
+}
+
+/// A query with every name resolved. `channel_ids` is the full scope of the
+/// search and must only hold channels the searching user can read.
+#[derive(Debug, Default)]
+pub struct SearchFilters {
+    pub text: String,
+    pub channel_ids: Vec<Uuid>,
+    pub author_ids: Vec<Uuid>,
+    pub mention_ids: Vec<Uuid>,
+    pub has: Vec<HasFilter>,
+    pub before: Option<DateTime<Utc>>,
+    pub after: Option<DateTime<Utc>>,
+    pub pinned: Option<bool>,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct SearchResults {
+    pub total: i64,
+    pub message_ids: Vec<Uuid>,
+}
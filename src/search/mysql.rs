@@ -0,0 +1,126 @@
+use anyhow::Result;
+use rocket::async_trait;
+use sqlx::{MySql, Pool, QueryBuilder, Row};
+use uuid::Uuid;
+
+use super::{HasFilter, SearchFilters, SearchIndex, SearchResults};
+
+/// Searches with the FULLTEXT index on `messages.content`
+pub struct MySqlSearchIndex {
+    pool: Pool<MySql>,
+}
+
+impl MySqlSearchIndex {
+    pub fn new(pool: Pool<MySql>) -> Self {
+        Self { pool }
+    }
+
+    /// Everything after `FROM`, shared by the count and the page query
+    fn push_conditions<'a>(builder: &mut QueryBuilder<'a, MySql>, filters: &'a SearchFilters, terms: &'a Option<String>) {
+        builder.push(" FROM messages m WHERE 1 = 1");
+        push_in(builder, "m.channel_id", &filters.channel_ids);
+        if let Some(terms) = terms {
+            builder.push(" AND MATCH(m.content) AGAINST (");
+            builder.push_bind(terms.as_str());
+            builder.push(" IN BOOLEAN MODE)");
+        }
+        if !filters.author_ids.is_empty() {
+            push_in(builder, "m.author_id", &filters.author_ids);
+        }
+        if !filters.mention_ids.is_empty() {
+            builder.push(" AND EXISTS (SELECT 1 FROM mentions mn WHERE mn.message_id = m.id");
+            push_in(builder, "mn.user_id", &filters.mention_ids);
+            builder.push(")");
+        }
+        for has in &filters.has {
+            builder.push(match has {
+                HasFilter::Attachment => " AND EXISTS (SELECT 1 FROM attachments a WHERE a.message_id = m.id)",
+                HasFilter::Image => {
+                    " AND EXISTS (SELECT 1 FROM attachments a WHERE a.message_id = m.id AND a.attachment_type = 'image')"
+                }
+                HasFilter::Video => {
+                    " AND EXISTS (SELECT 1 FROM attachments a WHERE a.message_id = m.id AND a.attachment_type = 'video')"
+                }
+                HasFilter::File => {
+                    " AND EXISTS (SELECT 1 FROM attachments a WHERE a.message_id = m.id AND a.attachment_type = 'file')"
+                }
+                HasFilter::Link => " AND (m.content LIKE '%http://%' OR m.content LIKE '%https://%')",
+                HasFilter::Embed => " AND (JSON_LENGTH(m.embeds) > 0 OR JSON_LENGTH(m.link_embeds) > 0)",
+            });
+        }
+        if let Some(before) = filters.before {
+            builder.push(" AND m.created_at < ");
+            builder.push_bind(before);
+        }
+        if let Some(after) = filters.after {
+            builder.push(" AND m.created_at >= ");
+            builder.push_bind(after);
+        }
+        if let Some(pinned) = filters.pinned {
+            builder.push(" AND m.is_pinned = ");
+            builder.push_bind(pinned);
+        }
+    }
+}
+
+// The database keeps FULLTEXT up to date itself, so the write hooks and
+// rebuild keep their no-op defaults
+#[async_trait]
+impl SearchIndex for MySqlSearchIndex {
+    async fn search(&self, filters: &SearchFilters) -> Result<SearchResults> {
+        if filters.channel_ids.is_empty() {
+            return Ok(SearchResults::default());
+        }
+        let terms = boolean_terms(&filters.text);
+        if !filters.text.trim().is_empty() && terms.is_none() {
+            // Nothing searchable was left after stripping operators
+            return Ok(SearchResults::default());
+        }
+
+        let mut count = QueryBuilder::new("SELECT COUNT(*)");
+        Self::push_conditions(&mut count, filters, &terms);
+        let total: i64 = count.build().fetch_one(&self.pool).await?.try_get(0)?;
+        if total == 0 {
+            return Ok(SearchResults::default());
+        }
+
+        let mut page = QueryBuilder::new("SELECT m.id");
+        Self::push_conditions(&mut page, filters, &terms);
+        page.push(" ORDER BY m.created_at DESC, m.id DESC LIMIT ");
+        page.push_bind(filters.limit);
+        page.push(" OFFSET ");
+        page.push_bind(filters.offset);
+        let rows = page.build().fetch_all(&self.pool).await?;
+
+        Ok(SearchResults {
+            total,
+            message_ids: rows
+                .iter()
+                .filter_map(|row| row.try_get::<Vec<u8>, _>(0).ok())
+                .filter_map(|id| Uuid::from_slice(&id).ok())
+                .collect(),
+        })
+    }
+}
+
+fn push_in(builder: &mut QueryBuilder<'_, MySql>, column: &str, ids: &[Uuid]) {
+    builder.push(format!(" AND {column} IN ("));
+    let mut separated = builder.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+    builder.push(")");
+}
+
+/// Turns free text into a boolean mode query where every word must match,
+/// as a prefix. Users' own operators are stripped so they can't break the
+/// query; None when no words are left.
+fn boolean_terms(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric() && c != '_' && c != '\'')
+        .map(|word| word.trim_matches('\''))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("+{word}*"))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
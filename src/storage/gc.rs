@@ -0,0 +1,131 @@
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use log::{error, info, warn};
+use sqlx::{MySql, Pool};
+
+use super::Storage;
+use crate::api::resumable::{partial_upload_path, remove_partial_upload};
+use crate::api::upload::format_size;
+use crate::db::queries;
+
+/// How often the background sweep runs
+pub const GC_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Uploads and objects younger than this are never collected, so an upload
+/// that is about to be attached (or whose row is about to be written) is safe
+pub const GC_GRACE_HOURS: i64 = 24;
+/// Key prefixes whose objects belong to rows in the database. Anything else
+/// in the bucket isn't ours to delete.
+const MANAGED_CATEGORIES: [&str; 5] = ["attachments", "thumbnails", "avatars", "banners", "emojis"];
+
+/// What a collection removed, or would have in a dry run
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub orphaned_attachments: u64,
+    pub expired_uploads: u64,
+    pub deleted_objects: u64,
+    pub reclaimed_bytes: u64,
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run { "Would remove" } else { "Removed" };
+        write!(
+            f,
+            "{verb} {} orphaned attachments, {} expired uploads and {} unreferenced objects, reclaiming {}",
+            self.orphaned_attachments,
+            self.expired_uploads,
+            self.deleted_objects,
+            format_size(self.reclaimed_bytes)
+        )
+    }
+}
+
+/// Removes attachments that never made it onto a message, abandoned
+/// resumable uploads, and stored objects nothing points at any more (e.g.
+/// the files of deleted messages). With `dry_run` nothing is touched.
+pub async fn collect_garbage(pool: &Pool<MySql>, storage: &Storage, dry_run: bool) -> Result<GcReport> {
+    let cutoff = Utc::now() - chrono::Duration::hours(GC_GRACE_HOURS);
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    let orphans = queries::get_orphaned_attachments(pool, cutoff)
+        .await
+        .context("Failed to find orphaned attachments")?;
+    report.orphaned_attachments = if dry_run {
+        orphans.len() as u64
+    } else {
+        queries::delete_orphaned_attachments(pool, &orphans)
+            .await
+            .context("Failed to delete orphaned attachments")?
+    };
+
+    let expired = queries::get_expired_upload_sessions(pool)
+        .await
+        .context("Failed to find expired uploads")?;
+    for upload_id in expired {
+        let path = partial_upload_path(upload_id)?;
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            report.reclaimed_bytes += metadata.len();
+        }
+        if !dry_run {
+            remove_partial_upload(upload_id).await?;
+            queries::delete_upload_session(pool, upload_id).await?;
+        }
+        report.expired_uploads += 1;
+    }
+
+    // Objects are shared between rows with the same content, so only the
+    // full set of references says whether one can go. Orphans are already
+    // left out of it, so their files are picked up here.
+    let referenced = queries::get_referenced_storage_keys(pool, cutoff)
+        .await
+        .context("Failed to load referenced storage keys")?;
+    for category in MANAGED_CATEGORIES {
+        let objects = storage
+            .list(&format!("{category}/"))
+            .await
+            .with_context(|| format!("Failed to list {category}"))?;
+        for object in objects {
+            if referenced.contains(&object.key) || object.last_modified > cutoff {
+                continue;
+            }
+            if !dry_run {
+                // Uploads of content we already have don't touch the object,
+                // so an old one may have been picked up again since the
+                // references were loaded
+                match queries::is_storage_key_referenced(pool, &object.key).await {
+                    Ok(false) => {}
+                    Ok(true) => continue,
+                    Err(e) => {
+                        warn!("Failed to check references to {}: {e}", object.key);
+                        continue;
+                    }
+                }
+                if let Err(e) = storage.delete(&object.key).await {
+                    warn!("Failed to delete unreferenced object {}: {e:#}", object.key);
+                    continue;
+                }
+            }
+            report.deleted_objects += 1;
+            report.reclaimed_bytes += object.size;
+        }
+    }
+
+    Ok(report)
+}
+
+pub async fn run_storage_gc(pool: Pool<MySql>, storage: Storage) {
+    loop {
+        tokio::time::sleep(GC_INTERVAL).await;
+        match collect_garbage(&pool, &storage, false).await {
+            Ok(report) => info!("Storage garbage collection finished. {report}"),
+            Err(e) => error!("Storage garbage collection failed: {e:#}"),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use rocket::async_trait;
+use tokio::io::AsyncRead;
+use uuid::Uuid;
+
+use super::{is_valid_key, ObjectInfo, ObjectLocation, StorageBackend};
+
+/// Half written objects, never listed or served
+const STAGING_DIR: &str = ".staging";
+
+/// Stores objects as plain files under `root`
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path_for(&self, key: &str) -> Result<PathBuf> {
+        if !is_valid_key(key) {
+            return Err(anyhow!("Invalid storage key: {key}"));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(
+        &self,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        _content_type: &str,
+    ) -> Result<()> {
+        let path = self.path_for(key)?;
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(());
+        }
+
+        // Write somewhere else first so a half written upload never shows up under its key
+        let staging_dir = self.root.join(STAGING_DIR);
+        tokio::fs::create_dir_all(&staging_dir)
+            .await
+            .with_context(|| format!("Failed to create {staging_dir:#?}"))?;
+        let staging = staging_dir.join(Uuid::new_v4().to_string());
+        let mut file = tokio::fs::File::create(&staging)
+            .await
+            .with_context(|| format!("Failed to create {staging:#?}"))?;
+        if let Err(e) = tokio::io::copy(reader, &mut file).await {
+            let _ = tokio::fs::remove_file(&staging).await;
+            return Err(e).context("Failed to write object");
+        }
+        file.sync_all().await?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(&staging, &path)
+            .await
+            .with_context(|| format!("Failed to move object into {path:#?}"))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let path = self.path_for(key)?;
+        let file = tokio::fs::File::open(&path)
+            .await
+            .with_context(|| format!("Failed to open {path:#?}"))?;
+        Ok(Box::new(file))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.path_for(key)?).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path_for(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                // Nothing has been stored yet
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to read {dir:#?}")),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    if entry.file_name() != STAGING_DIR {
+                        pending.push(path);
+                    }
+                    continue;
+                }
+
+                let Ok(relative) = path.strip_prefix(&self.root) else {
+                    continue;
+                };
+                let key = relative
+                    .components()
+                    .map(|part| part.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if key.starts_with(prefix) {
+                    objects.push(ObjectInfo {
+                        key,
+                        size: metadata.len(),
+                        last_modified: metadata.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now()),
+                    });
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn locate(&self, key: &str) -> Result<ObjectLocation> {
+        Ok(ObjectLocation::File(self.path_for(key)?))
+    }
+}
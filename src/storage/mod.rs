@@ -0,0 +1,247 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rocket::async_trait;
+use rocket::fs::TempFile;
+use rocket::http::ContentType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use url::Url;
+
+use crate::workspace::get_data_dir;
+use local::LocalStorage;
+use s3_compat::S3Storage;
+
+pub mod gc;
+pub mod local;
+pub mod s3_compat;
+
+/// Shared handle to whichever backend the server was configured with
+pub type Storage = Arc<dyn StorageBackend>;
+
+/// Where a stored object can be read from
+pub enum ObjectLocation {
+    /// Served by us straight off disk
+    File(PathBuf),
+    /// The client should be sent elsewhere, e.g. a presigned S3 url
+    Url(String),
+}
+
+/// An object as reported by `StorageBackend::list`
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Streams `reader` into the object at `key`. Keys are content addressed,
+    /// so writing a key that already exists is a no-op.
+    async fn put(
+        &self,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        content_type: &str,
+    ) -> Result<()>;
+
+    /// Opens an object for reading by background jobs. Clients are served
+    /// through `locate` instead.
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Unpin + Send>>;
+
+    async fn exists(&self, key: &str) -> Result<bool>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Every object whose key starts with `prefix`, for garbage collection
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>>;
+
+    async fn locate(&self, key: &str) -> Result<ObjectLocation>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    Local {
+        // Defaults to <data dir>/storage
+        root: Option<PathBuf>,
+    },
+    /// Any S3 compatible service (AWS, MinIO, R2, ...)
+    S3 {
+        endpoint: Url,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+        // MinIO and most self hosted services need path style urls
+        #[serde(default)]
+        path_style: bool,
+    },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Local { root: None }
+    }
+}
+
+pub fn build_storage(config: &StorageConfig) -> Result<Storage> {
+    Ok(match config {
+        StorageConfig::Local { root } => {
+            let root = match root {
+                Some(root) => root.clone(),
+                None => {
+                    let mut dir = get_data_dir().context("Failed to obtain data dir")?;
+                    dir.push("storage");
+                    dir
+                }
+            };
+            Arc::new(LocalStorage::new(root))
+        }
+        StorageConfig::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            path_style,
+        } => Arc::new(
+            S3Storage::new(endpoint, bucket, region, access_key, secret_key, *path_style)
+                .context("Failed to configure S3 storage")?,
+        ),
+    })
+}
+
+/// Bytes waiting to be inspected and stored: a multipart upload, or a file
+/// assembled from the chunks of a resumable upload
+#[derive(Clone, Copy)]
+pub enum UploadSource<'a> {
+    TempFile(&'a TempFile<'a>),
+    Path { path: &'a Path, len: u64 },
+}
+
+impl<'a> UploadSource<'a> {
+    pub fn len(&self) -> u64 {
+        match self {
+            UploadSource::TempFile(file) => file.len(),
+            UploadSource::Path { len, .. } => *len,
+        }
+    }
+
+    /// Set when the bytes are already on disk
+    pub fn path(&self) -> Option<&'a Path> {
+        match self {
+            UploadSource::TempFile(file) => file.path(),
+            UploadSource::Path { path, .. } => Some(path),
+        }
+    }
+
+    pub async fn open(&self) -> io::Result<Pin<Box<dyn AsyncRead + Send + 'a>>> {
+        Ok(match *self {
+            UploadSource::TempFile(file) => Box::pin(file.open().await?),
+            UploadSource::Path { path, .. } => Box::pin(tokio::fs::File::open(path).await?),
+        })
+    }
+}
+
+impl<'a> From<&'a TempFile<'a>> for UploadSource<'a> {
+    fn from(file: &'a TempFile<'a>) -> Self {
+        UploadSource::TempFile(file)
+    }
+}
+
+/// An upload after it has been written to the backend
+#[derive(Debug)]
+pub struct StoredObject {
+    pub key: String,
+    pub size: u64,
+    pub content_type: String,
+}
+
+/// Streams an upload into storage under a key derived from its sha256.
+///
+/// The file is read twice, once to hash it and once to store it, so it is
+/// never held in memory as a whole. `content_type` should be the sniffed type,
+/// not whatever the client claimed.
+pub async fn store_upload(
+    storage: &Storage,
+    file: UploadSource<'_>,
+    content_type: &str,
+    category: &str,
+) -> Result<StoredObject> {
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    {
+        let mut stream = file.open().await.context("Failed to open upload")?;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let read = stream.read(&mut buf).await.context("Failed to read upload")?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            size += read as u64;
+        }
+    }
+    let key = object_key(category, &format!("{:x}", hasher.finalize()), content_type);
+
+    let mut stream = file.open().await.context("Failed to open upload")?;
+    storage
+        .put(&key, &mut stream, content_type)
+        .await
+        .with_context(|| format!("Failed to store object {key}"))?;
+
+    Ok(StoredObject {
+        key,
+        size,
+        content_type: content_type.to_string(),
+    })
+}
+
+/// Stores content we already hold in memory, e.g. an image after its
+/// metadata was stripped
+pub async fn store_bytes(
+    storage: &Storage,
+    bytes: &[u8],
+    content_type: &str,
+    category: &str,
+) -> Result<StoredObject> {
+    let key = object_key(category, &format!("{:x}", Sha256::digest(bytes)), content_type);
+    let mut reader = bytes;
+    storage
+        .put(&key, &mut reader, content_type)
+        .await
+        .with_context(|| format!("Failed to store object {key}"))?;
+
+    Ok(StoredObject {
+        key,
+        size: bytes.len() as u64,
+        content_type: content_type.to_string(),
+    })
+}
+
+/// `category/ab/abcdef...[.ext]`, fanned out so no directory gets huge. The
+/// extension lets public files be served with the right Content-Type.
+pub fn object_key(category: &str, hash: &str, content_type: &str) -> String {
+    let extension = ContentType::parse_flexible(content_type)
+        .and_then(|content_type| content_type.extension().map(|ext| ext.to_string()));
+    match extension {
+        Some(ext) => format!("{category}/{}/{hash}.{ext}", &hash[..2]),
+        None => format!("{category}/{}/{hash}", &hash[..2]),
+    }
+}
+
+/// Rejects keys that could escape the storage root
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with('/')
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
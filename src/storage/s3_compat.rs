@@ -0,0 +1,183 @@
+use std::io::Cursor;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rocket::async_trait;
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::{Bucket, Region};
+use tokio::io::AsyncRead;
+use url::Url;
+
+use super::{is_valid_key, ObjectInfo, ObjectLocation, StorageBackend};
+
+/// How long presigned download urls handed to clients stay valid
+const PRESIGN_EXPIRY_SECS: u32 = 60 * 60;
+
+/// Stores objects in an S3 compatible bucket
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: &Url,
+        bucket: &str,
+        region: &str,
+        access_key: &str,
+        secret_key: &str,
+        path_style: bool,
+    ) -> Result<Self> {
+        let region = Region::Custom {
+            region: region.to_string(),
+            endpoint: endpoint.as_str().trim_end_matches('/').to_string(),
+        };
+        let credentials = Credentials::new(Some(access_key), Some(secret_key), None, None, None)?;
+        let mut bucket = Bucket::new(bucket, region, credentials)?;
+        if path_style {
+            bucket = bucket.with_path_style();
+        }
+        Ok(Self { bucket })
+    }
+
+    fn check_key(key: &str) -> Result<()> {
+        if is_valid_key(key) {
+            Ok(())
+        } else {
+            Err(anyhow!("Invalid storage key: {key}"))
+        }
+    }
+
+    /// rust-s3 is built without `fail-on-err`, so error responses come back
+    /// as Ok and have to be caught here
+    fn check_status(status: u16, action: &str, key: &str) -> Result<()> {
+        if (200..300).contains(&status) {
+            Ok(())
+        } else {
+            Err(anyhow!("Failed to {action} {key}: the bucket answered {status}"))
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn put(
+        &self,
+        key: &str,
+        mut reader: &mut (dyn AsyncRead + Unpin + Send),
+        content_type: &str,
+    ) -> Result<()> {
+        Self::check_key(key)?;
+        if self.exists(key).await? {
+            return Ok(());
+        }
+        // Multipart upload, the body is streamed in chunks rather than buffered
+        let response = self
+            .bucket
+            .put_object_stream_with_content_type(&mut reader, key, content_type)
+            .await?;
+        Self::check_status(response.status_code(), "upload", key)
+    }
+
+    async fn get(&self, key: &str) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+        Self::check_key(key)?;
+        // Only background jobs read objects back and they work on whole files anyway
+        let response = self.bucket.get_object(key).await?;
+        Self::check_status(response.status_code(), "download", key)?;
+        Ok(Box::new(Cursor::new(response.to_vec())))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Self::check_key(key)?;
+        match self.bucket.head_object(key).await {
+            Ok((_, 404)) | Err(S3Error::HttpFailWithBody(404, _)) => Ok(false),
+            Ok((_, status)) => Self::check_status(status, "look up", key).map(|_| true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        Self::check_key(key)?;
+        let response = self.bucket.delete_object(key).await?;
+        // Already gone is as good as deleted
+        match response.status_code() {
+            404 => Ok(()),
+            status => Self::check_status(status, "delete", key),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        // rust-s3 follows continuation tokens, each result is one page
+        let pages = self.bucket.list(prefix.to_string(), None).await?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| ObjectInfo {
+                // An unparseable date reads as brand new, so the object is kept
+                last_modified: DateTime::parse_from_rfc3339(&object.last_modified)
+                    .map(|date| date.with_timezone(&Utc))
+                    .unwrap_or_else(|_| Utc::now()),
+                key: object.key,
+                size: object.size,
+            })
+            .collect())
+    }
+
+    async fn locate(&self, key: &str) -> Result<ObjectLocation> {
+        Self::check_key(key)?;
+        let url = self.bucket.presign_get(key, PRESIGN_EXPIRY_SECS, None).await?;
+        Ok(ObjectLocation::Url(url))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against a real bucket, e.g. a local MinIO:
+    /// `S3_TEST_ENDPOINT=http://localhost:9000 S3_TEST_BUCKET=occult-test
+    /// S3_TEST_ACCESS_KEY=minioadmin S3_TEST_SECRET_KEY=minioadmin cargo test -- --ignored`
+    fn test_storage() -> Option<S3Storage> {
+        let var = |name: &str| std::env::var(name).ok();
+        let endpoint = Url::parse(&var("S3_TEST_ENDPOINT")?).expect("S3_TEST_ENDPOINT is not a url");
+        Some(
+            S3Storage::new(
+                &endpoint,
+                &var("S3_TEST_BUCKET")?,
+                &var("S3_TEST_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+                &var("S3_TEST_ACCESS_KEY")?,
+                &var("S3_TEST_SECRET_KEY")?,
+                true,
+            )
+            .expect("Failed to set up the test bucket"),
+        )
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 compatible server, see test_storage"]
+    async fn round_trip() {
+        let Some(storage) = test_storage() else {
+            eprintln!("S3_TEST_* not set, skipping");
+            return;
+        };
+        let key = format!("test/{}", uuid::Uuid::new_v4().simple());
+        let body = b"round trip".to_vec();
+
+        assert!(!storage.exists(&key).await.unwrap());
+        storage.put(&key, &mut Cursor::new(body.clone()), "text/plain").await.unwrap();
+        assert!(storage.exists(&key).await.unwrap());
+
+        let mut read = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut storage.get(&key).await.unwrap(), &mut read)
+            .await
+            .unwrap();
+        assert_eq!(read, body);
+        assert!(storage.list("test/").await.unwrap().iter().any(|object| object.key == key));
+
+        storage.delete(&key).await.unwrap();
+        assert!(!storage.exists(&key).await.unwrap());
+        // Missing objects are errors, not empty bodies
+        assert!(storage.get(&key).await.is_err());
+        storage.delete(&key).await.unwrap();
+    }
+}
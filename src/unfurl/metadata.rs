@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use scraper::{Html, Selector};
+use serde::Deserialize;
+use url::Url;
+
+use crate::api::{Embed, EmbedMedia, EmbedType};
+
+/// Longest title and description we keep, pages stuff all sorts in there
+const MAX_TITLE_LENGTH: usize = 256;
+const MAX_DESCRIPTION_LENGTH: usize = 1024;
+
+/// What we could read out of a page's `<head>`
+#[derive(Debug, Default)]
+pub struct PageMetadata {
+    /// `og:*`, `twitter:*` and named `<meta>` tags, first value wins
+    meta: HashMap<String, String>,
+    title: Option<String>,
+    /// Where the page advertises its oEmbed JSON, if it does
+    pub oembed_url: Option<Url>,
+}
+
+impl PageMetadata {
+    pub fn parse(html: &str, page_url: &Url) -> Self {
+        let document = Html::parse_document(html);
+        let mut metadata = PageMetadata::default();
+
+        let meta = Selector::parse("meta").unwrap();
+        for element in document.select(&meta) {
+            let element = element.value();
+            let Some(key) = element.attr("property").or_else(|| element.attr("name")) else {
+                continue;
+            };
+            let Some(content) = element.attr("content").map(str::trim).filter(|c| !c.is_empty()) else {
+                continue;
+            };
+            metadata
+                .meta
+                .entry(key.to_ascii_lowercase())
+                .or_insert_with(|| content.to_string());
+        }
+
+        let title = Selector::parse("title").unwrap();
+        metadata.title = document
+            .select(&title)
+            .next()
+            .map(|title| title.text().collect::<String>().trim().to_string())
+            .filter(|title| !title.is_empty());
+
+        let link = Selector::parse(r#"link[rel="alternate"][type="application/json+oembed"]"#).unwrap();
+        metadata.oembed_url = document
+            .select(&link)
+            .next()
+            .and_then(|link| link.value().attr("href"))
+            .and_then(|href| page_url.join(href).ok());
+
+        metadata
+    }
+
+    fn get(&self, keys: &[&str]) -> Option<&str> {
+        keys.iter().find_map(|key| self.meta.get(*key)).map(String::as_str)
+    }
+
+    fn dimension(&self, key: &str) -> Option<u32> {
+        self.get(&[key]).and_then(|value| value.parse().ok())
+    }
+
+    /// None when the page has nothing worth previewing
+    pub fn into_embed(self, page_url: &Url, oembed: Option<OEmbed>) -> Option<Embed> {
+        let oembed = oembed.unwrap_or_default();
+        let absolute = |value: &str| page_url.join(value).ok().map(String::from);
+
+        let title = self
+            .get(&["og:title", "twitter:title"])
+            .map(str::to_string)
+            .or(oembed.title)
+            .or_else(|| self.title.clone());
+        let description = self
+            .get(&["og:description", "twitter:description", "description"])
+            .map(str::to_string);
+        let site_name = self
+            .get(&["og:site_name"])
+            .map(str::to_string)
+            .or(oembed.provider_name);
+
+        let image = match self.get(&["og:image", "og:image:url", "twitter:image"]).and_then(absolute) {
+            Some(url) => Some(EmbedMedia {
+                url,
+                width: self.dimension("og:image:width"),
+                height: self.dimension("og:image:height"),
+            }),
+            None => oembed.thumbnail_url.and_then(|url| absolute(&url)).map(|url| EmbedMedia {
+                url,
+                width: oembed.thumbnail_width,
+                height: oembed.thumbnail_height,
+            }),
+        };
+        let video = self
+            .get(&["og:video:secure_url", "og:video", "og:video:url"])
+            .and_then(absolute)
+            .map(|url| EmbedMedia {
+                url,
+                width: self.dimension("og:video:width"),
+                height: self.dimension("og:video:height"),
+            });
+
+        if title.is_none() && description.is_none() && image.is_none() {
+            return None;
+        }
+        let embed_type = match (&video, oembed.kind.as_deref()) {
+            (Some(_), _) | (None, Some("video")) => EmbedType::Video,
+            (None, Some("rich")) => EmbedType::Rich,
+            _ => EmbedType::Link,
+        };
+        Some(Embed {
+            embed_type,
+            url: page_url.to_string(),
+            title: title.map(|title| truncate(&title, MAX_TITLE_LENGTH)),
+            description: description.map(|description| truncate(&description, MAX_DESCRIPTION_LENGTH)),
+            site_name,
+            color: self.get(&["theme-color"]).map(str::to_string),
+            image,
+            video,
+        })
+    }
+}
+
+/// The parts of an oEmbed response we use. Its `html` is deliberately
+/// ignored, we never hand third party markup to clients.
+#[derive(Debug, Default, Deserialize)]
+pub struct OEmbed {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub title: Option<String>,
+    pub provider_name: Option<String>,
+    pub thumbnail_url: Option<String>,
+    pub thumbnail_width: Option<u32>,
+    pub thumbnail_height: Option<u32>,
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
@@ -0,0 +1,412 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, warn};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, USER_AGENT};
+use reqwest::redirect::Policy;
+use serde::{Deserialize, Serialize};
+use sqlx::{MySql, Pool};
+use tokio::sync::{mpsc, Semaphore};
+use url::Url;
+use uuid::Uuid;
+
+use crate::api::signing::UrlSigner;
+use crate::api::{channel_audience, Embed, EmbedMedia, EmbedType};
+use crate::db::queries;
+use crate::gateway::{Gateway, GatewayEvent};
+use crate::search::Search;
+use metadata::{OEmbed, PageMetadata};
+use ssrf::{check_url, PublicResolver};
+
+pub mod metadata;
+pub mod ssrf;
+
+/// Links past this many in one message are left alone
+pub const MAX_EMBEDS_PER_MESSAGE: usize = 5;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Pages are cut off here. OpenGraph tags live in `<head>`, so this is plenty.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+const CONCURRENT_UNFURLS: usize = 4;
+const USER_AGENT_VALUE: &str = concat!("occult-server/", env!("CARGO_PKG_VERSION"), " (link preview)");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UnfurlConfig {
+    pub enabled: bool,
+    // Lets previews reach loopback and private networks. Only meant for
+    // testing against a local fixture server, never turn it on in production
+    pub allow_private_networks: bool,
+}
+
+impl Default for UnfurlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allow_private_networks: false,
+        }
+    }
+}
+
+/// Fetches pages and turns their metadata into embeds
+#[derive(Clone)]
+pub struct Unfurler {
+    client: reqwest::Client,
+    allow_private: bool,
+}
+
+impl Unfurler {
+    pub fn new(config: &UnfurlConfig) -> Result<Self> {
+        let allow_private = config.allow_private_networks;
+        let redirects = Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error("Too many redirects");
+            }
+            // Hostnames are filtered by the resolver, literal addresses have to be caught here
+            match check_url(attempt.url(), allow_private) {
+                Ok(()) => attempt.follow(),
+                Err(e) => attempt.error(e),
+            }
+        });
+
+        let mut client = reqwest::Client::builder()
+            // A proxy from the environment would connect for us, past the resolver
+            .no_proxy()
+            .timeout(FETCH_TIMEOUT)
+            .connect_timeout(FETCH_TIMEOUT)
+            .redirect(redirects);
+        if !allow_private {
+            client = client.dns_resolver(Arc::new(PublicResolver));
+        }
+        Ok(Self {
+            client: client.build().context("Failed to build the link preview client")?,
+            allow_private,
+        })
+    }
+
+    /// Embeds for whichever of the urls produced one, in order
+    pub async fn unfurl_all(&self, urls: &[Url]) -> Vec<Embed> {
+        let mut embeds = Vec::new();
+        for url in urls {
+            match self.unfurl(url).await {
+                Ok(Some(embed)) => embeds.push(embed),
+                Ok(None) => debug!("Nothing to preview at {url}"),
+                Err(e) => debug!("Failed to unfurl {url}: {e:#}"),
+            }
+        }
+        embeds
+    }
+
+    pub async fn unfurl(&self, url: &Url) -> Result<Option<Embed>> {
+        let (content_type, body) = self.fetch(url, "text/html,application/xhtml+xml;q=0.9,image/*;q=0.8").await?;
+
+        if content_type.starts_with("image/") {
+            return Ok(Some(media_embed(EmbedType::Image, url, true)));
+        }
+        if content_type.starts_with("video/") {
+            return Ok(Some(media_embed(EmbedType::Video, url, false)));
+        }
+        if !content_type.starts_with("text/html") && !content_type.starts_with("application/xhtml") {
+            return Ok(None);
+        }
+
+        let html = String::from_utf8_lossy(&body);
+        let metadata = PageMetadata::parse(&html, url);
+        let oembed = match &metadata.oembed_url {
+            Some(oembed_url) => match self.fetch_oembed(oembed_url).await {
+                Ok(oembed) => Some(oembed),
+                Err(e) => {
+                    debug!("Failed to fetch oEmbed for {url}: {e:#}");
+                    None
+                }
+            },
+            None => None,
+        };
+        Ok(metadata.into_embed(url, oembed))
+    }
+
+    async fn fetch_oembed(&self, url: &Url) -> Result<OEmbed> {
+        let (_, body) = self.fetch(url, "application/json").await?;
+        serde_json::from_slice(&body).context("Invalid oEmbed response")
+    }
+
+    /// GETs a url with the SSRF, time and size limits applied. Returns the
+    /// response's content type and up to `MAX_BODY_SIZE` bytes of its body.
+    async fn fetch(&self, url: &Url, accept: &str) -> Result<(String, Vec<u8>)> {
+        check_url(url, self.allow_private).map_err(|e| anyhow!(e))?;
+        let mut response = self
+            .client
+            .get(url.clone())
+            .header(USER_AGENT, USER_AGENT_VALUE)
+            .header(ACCEPT, accept)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        // Media is previewed from its url alone, there's no need to download it
+        if content_type.starts_with("image/") || content_type.starts_with("video/") {
+            return Ok((content_type, Vec::new()));
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            let room = MAX_BODY_SIZE - body.len();
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if body.len() >= MAX_BODY_SIZE {
+                break;
+            }
+        }
+        Ok((content_type, body))
+    }
+}
+
+fn media_embed(embed_type: EmbedType, url: &Url, is_image: bool) -> Embed {
+    let media = EmbedMedia {
+        url: url.to_string(),
+        width: None,
+        height: None,
+    };
+    Embed {
+        embed_type,
+        url: url.to_string(),
+        title: None,
+        description: None,
+        site_name: None,
+        color: None,
+        image: is_image.then(|| media.clone()),
+        video: (!is_image).then_some(media),
+    }
+}
+
+/// The http(s) links in a message, in order and without duplicates. Links
+/// wrapped in `<>` are skipped, that's how users opt out of a preview.
+pub fn extract_urls(content: &str) -> Vec<Url> {
+    let mut urls: Vec<Url> = Vec::new();
+    for word in content.split_whitespace() {
+        if word.starts_with('<') && word.ends_with('>') {
+            continue;
+        }
+        // Punctuation that usually ends the sentence rather than the link
+        let word = word.trim_end_matches(['.', ',', '!', '?', ';', ':', ')', '"', '\'']);
+        if !(word.starts_with("http://") || word.starts_with("https://")) {
+            continue;
+        }
+        let Ok(url) = Url::parse(word) else {
+            continue;
+        };
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+        if urls.len() == MAX_EMBEDS_PER_MESSAGE {
+            break;
+        }
+    }
+    urls
+}
+
+#[derive(Debug)]
+struct UnfurlJob {
+    message_id: Uuid,
+    /// The content the links were taken from. If the message has been
+    /// edited since, the result is thrown away.
+    content: String,
+    urls: Vec<Url>,
+}
+
+/// Hands messages with links to the background unfurler
+#[derive(Clone)]
+pub struct UnfurlQueue {
+    sender: Option<mpsc::UnboundedSender<UnfurlJob>>,
+}
+
+pub struct UnfurlReceiver(mpsc::UnboundedReceiver<UnfurlJob>);
+
+impl UnfurlQueue {
+    pub fn new() -> (Self, UnfurlReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { sender: Some(sender) }, UnfurlReceiver(receiver))
+    }
+
+    /// A queue that drops everything, for when previews are turned off
+    pub fn disabled() -> Self {
+        Self { sender: None }
+    }
+
+    pub fn enqueue(&self, message_id: Uuid, content: &str) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let urls = extract_urls(content);
+        if urls.is_empty() {
+            return;
+        }
+        let _ = sender.send(UnfurlJob {
+            message_id,
+            content: content.to_string(),
+            urls,
+        });
+    }
+}
+
+pub async fn run_unfurler(
+    pool: Pool<MySql>,
+    gateway: Gateway,
+    signer: UrlSigner,
+    search: Search,
+    unfurler: Unfurler,
+    mut receiver: UnfurlReceiver,
+) {
+    let permits = Arc::new(Semaphore::new(CONCURRENT_UNFURLS));
+    while let Some(job) = receiver.0.recv().await {
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            break;
+        };
+        let (pool, gateway, signer, search, unfurler) =
+            (pool.clone(), gateway.clone(), signer.clone(), search.clone(), unfurler.clone());
+        tokio::spawn(async move {
+            if let Err(e) = unfurl_message(&pool, &gateway, &signer, &search, &unfurler, job).await {
+                error!("Failed to attach link previews: {e:#}");
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn unfurl_message(
+    pool: &Pool<MySql>,
+    gateway: &Gateway,
+    signer: &UrlSigner,
+    search: &Search,
+    unfurler: &Unfurler,
+    job: UnfurlJob,
+) -> Result<()> {
+    let embeds = unfurler.unfurl_all(&job.urls).await;
+    if embeds.is_empty() {
+        return Ok(());
+    }
+    if !queries::set_link_embeds(pool, job.message_id, &job.content, &embeds).await? {
+        debug!("Message {} was edited or deleted while unfurling", job.message_id);
+        return Ok(());
+    }
+
+    let Some(mut message) = queries::get_message(pool, job.message_id, None).await? else {
+        return Ok(());
+    };
+    let Some(channel) = queries::get_channel(pool, message.channel_id).await? else {
+        return Ok(());
+    };
+    // has:embed only matches once the index knows about the previews
+    if let Err(e) = search.index_message(&message).await {
+        warn!("Failed to reindex message {}: {e:#}", message.id);
+    }
+    signer.sign_message(&mut message);
+    match channel_audience(pool, gateway, &channel).await {
+        Ok(recipients) => gateway.dispatch(recipients, GatewayEvent::MessageUpdate(message)),
+        Err((status, _)) => warn!("Failed to work out who can see message {}: {status}", job.message_id),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const ARTICLE: &str = r#"<html><head>
+        <title>Fallback title</title>
+        <meta property="og:title" content="An article">
+        <meta property="og:description" content="What it's about">
+        <meta property="og:image" content="/cover.png">
+        <link rel="alternate" type="application/json+oembed" href="/oembed.json">
+    </head><body></body></html>"#;
+    const OEMBED: &str = r#"{"type": "rich", "provider_name": "Fixtures"}"#;
+
+    /// Serves each path's content type and body over plain HTTP on loopback,
+    /// anything else is a 404
+    async fn fixture_server(pages: &'static [(&'static str, &'static str, &'static str)]) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = vec![0; 4096];
+                    let read = socket.read(&mut request).await.unwrap_or(0);
+                    let request = String::from_utf8_lossy(&request[..read]);
+                    let path = request.split_whitespace().nth(1).unwrap_or("/");
+                    let response = match pages.iter().find(|(page, _, _)| *page == path) {
+                        Some((_, content_type, body)) => format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                            body.len()
+                        ),
+                        None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                    };
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        Url::parse(&format!("http://{address}/")).unwrap()
+    }
+
+    fn unfurler(allow_private_networks: bool) -> Unfurler {
+        Unfurler::new(&UnfurlConfig {
+            enabled: true,
+            allow_private_networks,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unfurls_opengraph_and_oembed() {
+        let base = fixture_server(&[
+            ("/article", "text/html; charset=utf-8", ARTICLE),
+            ("/oembed.json", "application/json", OEMBED),
+        ])
+        .await;
+
+        let embed = unfurler(true)
+            .unfurl(&base.join("article").unwrap())
+            .await
+            .unwrap()
+            .expect("the article has metadata");
+        assert!(matches!(embed.embed_type, EmbedType::Rich));
+        assert_eq!(embed.title.as_deref(), Some("An article"));
+        assert_eq!(embed.description.as_deref(), Some("What it's about"));
+        assert_eq!(embed.site_name.as_deref(), Some("Fixtures"));
+        assert_eq!(embed.image.unwrap().url, base.join("cover.png").unwrap().to_string());
+    }
+
+    #[tokio::test]
+    async fn media_and_missing_pages() {
+        let base = fixture_server(&[("/cat.png", "image/png", "not really a png"), ("/plain", "text/plain", "hi")]).await;
+        let unfurler = unfurler(true);
+
+        let embed = unfurler.unfurl(&base.join("cat.png").unwrap()).await.unwrap().unwrap();
+        assert!(matches!(embed.embed_type, EmbedType::Image));
+        assert!(unfurler.unfurl(&base.join("plain").unwrap()).await.unwrap().is_none());
+        assert!(unfurler.unfurl(&base.join("missing").unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses_by_default() {
+        let base = fixture_server(&[("/article", "text/html", ARTICLE)]).await;
+        assert!(unfurler(false).unfurl(&base.join("article").unwrap()).await.is_err());
+
+        let localhost = Url::parse(&format!("http://localhost:{}/article", base.port().unwrap())).unwrap();
+        assert!(unfurler(false).unfurl(&localhost).await.is_err());
+    }
+
+    #[test]
+    fn extracts_links() {
+        let urls = extract_urls("see https://example.com/a, <https://example.com/b> and https://example.com/a.");
+        assert_eq!(urls, vec![Url::parse("https://example.com/a").unwrap()]);
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{MySql, Pool};
+use tokio::sync::OwnedMutexGuard;
+use uuid::Uuid;
+
+use crate::db::queries;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginProtectionConfig {
+    // Failed attempts older than this are forgotten
+    pub window_minutes: i64,
+    // Failures per account before each further attempt has to wait, the
+    // wait doubling every time up to max_delay_seconds
+    pub free_attempts: i64,
+    // Same, for failures from one ip across all accounts
+    pub ip_free_attempts: i64,
+    pub base_delay_seconds: u64,
+    pub max_delay_seconds: u64,
+    // Failures that lock an account, and for how long
+    pub account_lockout_attempts: i64,
+    pub account_lockout_minutes: i64,
+    // Failures from one ip that lock it out of every account
+    pub ip_lockout_attempts: i64,
+    pub ip_lockout_minutes: i64,
+}
+
+impl Default for LoginProtectionConfig {
+    fn default() -> Self {
+        Self {
+            window_minutes: 15,
+            free_attempts: 3,
+            ip_free_attempts: 20,
+            base_delay_seconds: 1,
+            max_delay_seconds: 60,
+            account_lockout_attempts: 10,
+            account_lockout_minutes: 15,
+            ip_lockout_attempts: 50,
+            ip_lockout_minutes: 60,
+        }
+    }
+}
+
+impl LoginProtectionConfig {
+    fn window_start(&self) -> DateTime<Utc> {
+        Utc::now() - chrono::Duration::minutes(self.window_minutes)
+    }
+
+    /// How long after the last failure the next attempt may be made
+    fn delay(&self, failures: i64, free_attempts: i64) -> Duration {
+        if failures < free_attempts {
+            return Duration::ZERO;
+        }
+        let doublings = (failures - free_attempts).min(16) as u32;
+        Duration::from_secs(self.base_delay_seconds.saturating_mul(1 << doublings).min(self.max_delay_seconds))
+    }
+}
+
+/// How a sign-in attempt ended, as the audit log records it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginOutcome {
+    Success,
+    InvalidCredentials,
+    /// Refused without checking the password, for coming too soon after a failure
+    Throttled,
+    /// Refused without checking the password, the account or ip is locked out
+    Locked,
+    /// An admin lifted a lockout, which also clears the failures before it
+    Unlocked,
+}
+
+impl LoginOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginOutcome::Success => "success",
+            LoginOutcome::InvalidCredentials => "invalid_credentials",
+            LoginOutcome::Throttled => "throttled",
+            LoginOutcome::Locked => "locked",
+            LoginOutcome::Unlocked => "unlocked",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockoutKind {
+    Account,
+    Ip,
+}
+
+impl LockoutKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockoutKind::Account => "account",
+            LockoutKind::Ip => "ip",
+        }
+    }
+}
+
+/// Why a sign-in attempt was turned away before its password was checked
+#[derive(Debug, Clone, Copy)]
+pub enum LoginRefusal {
+    Throttled { retry_after: Duration },
+    Locked { kind: LockoutKind, until: DateTime<Utc> },
+}
+
+/// Runs sign-in attempts on the same account or from the same ip one at a
+/// time. Otherwise a burst of guesses could all pass `check_login` before
+/// the first of them is recorded as a failure.
+#[derive(Default)]
+pub struct LoginLocks {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// Held from `check_login` until the attempt is recorded
+pub struct LoginLock {
+    _guards: Vec<OwnedMutexGuard<()>>,
+}
+
+impl LoginLocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits for earlier attempts on the account or ip to finish. The ip is
+    /// always locked first, so two attempts can't wait on each other.
+    pub async fn acquire(&self, user_id: Option<Uuid>, ip: IpAddr) -> LoginLock {
+        let keys = [Some(format!("ip:{ip}")), user_id.map(|id| format!("account:{id}"))];
+        let locks: Vec<_> = {
+            let mut locks = self.locks.lock().expect("login locks poisoned");
+            // Nobody holds or waits on these any more
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            keys.into_iter()
+                .flatten()
+                .map(|key| locks.entry(key).or_default().clone())
+                .collect()
+        };
+        let mut guards = Vec::with_capacity(locks.len());
+        for lock in locks {
+            guards.push(lock.lock_owned().await);
+        }
+        LoginLock { _guards: guards }
+    }
+}
+
+/// Hashes a new password with argon2 and a fresh salt, in the PHC string
+/// format `verify_password` reads
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    Argon2::default()
+        .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+        .map(|hash| hash.to_string())
+}
+
+/// Checks the password against an argon2 hash. Accounts that don't exist or
+/// have no password are checked against a throwaway hash, so they take as
+/// long to refuse as a wrong password does.
+pub fn verify_password(hash: Option<&str>, password: &str) -> bool {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let (hash, known) = match hash {
+        Some(hash) => (hash, true),
+        None => (
+            DUMMY_HASH
+                .get_or_init(|| hash_password(&Uuid::new_v4().to_string()).unwrap_or_default())
+                .as_str(),
+            false,
+        ),
+    };
+    let verified = PasswordHash::new(hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok());
+    known && verified
+}
+
+/// Whether the account (if the identifier named one) may attempt a sign-in from this ip right now
+pub async fn check_login(
+    pool: &Pool<MySql>,
+    config: &LoginProtectionConfig,
+    user_id: Option<Uuid>,
+    ip: IpAddr,
+) -> Result<Option<LoginRefusal>, sqlx::Error> {
+    let ip = ip.to_string();
+    if let Some(until) = queries::get_login_lockout(pool, LockoutKind::Ip, &ip).await? {
+        return Ok(Some(LoginRefusal::Locked { kind: LockoutKind::Ip, until }));
+    }
+    if let Some(user_id) = user_id {
+        if let Some(until) = queries::get_login_lockout(pool, LockoutKind::Account, &user_id.to_string()).await? {
+            return Ok(Some(LoginRefusal::Locked { kind: LockoutKind::Account, until }));
+        }
+    }
+
+    let since = config.window_start();
+    let mut wait = Duration::ZERO;
+    let (failures, last_failure) = queries::count_failed_logins_from_ip(pool, &ip, since).await?;
+    if let Some(last_failure) = last_failure {
+        wait = wait.max(remaining(config.delay(failures, config.ip_free_attempts), last_failure));
+    }
+    if let Some(user_id) = user_id {
+        let (failures, last_failure) = queries::count_failed_logins_for_user(pool, user_id, since).await?;
+        if let Some(last_failure) = last_failure {
+            wait = wait.max(remaining(config.delay(failures, config.free_attempts), last_failure));
+        }
+    }
+    Ok((!wait.is_zero()).then_some(LoginRefusal::Throttled { retry_after: wait }))
+}
+
+/// Records a wrong password and locks the account or ip out once they've
+/// failed too often. Returns when the account's lockout ends if this
+/// failure is the one that locked it.
+pub async fn record_failed_login(
+    pool: &Pool<MySql>,
+    config: &LoginProtectionConfig,
+    user_id: Option<Uuid>,
+    identifier: &str,
+    ip: IpAddr,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    let ip = ip.to_string();
+    queries::record_login_attempt(pool, user_id, Some(identifier), Some(&ip), LoginOutcome::InvalidCredentials).await?;
+
+    let since = config.window_start();
+    let (failures, _) = queries::count_failed_logins_from_ip(pool, &ip, since).await?;
+    if failures >= config.ip_lockout_attempts {
+        let until = Utc::now() + chrono::Duration::minutes(config.ip_lockout_minutes);
+        queries::lock_login(pool, LockoutKind::Ip, &ip, until).await?;
+    }
+
+    let Some(user_id) = user_id else {
+        return Ok(None);
+    };
+    let (failures, _) = queries::count_failed_logins_for_user(pool, user_id, since).await?;
+    if failures < config.account_lockout_attempts {
+        return Ok(None);
+    }
+    let until = Utc::now() + chrono::Duration::minutes(config.account_lockout_minutes);
+    queries::lock_login(pool, LockoutKind::Account, &user_id.to_string(), until).await?;
+    Ok(Some(until))
+}
+
+/// Lifts the lockout on an ip address, or on the account with this username
+/// or email, and clears the failures that led to it. False if there was
+/// nothing by that name to unlock.
+pub async fn unlock(pool: &Pool<MySql>, target: &str) -> Result<bool, sqlx::Error> {
+    if let Ok(ip) = target.parse::<IpAddr>() {
+        let ip = ip.to_string();
+        queries::unlock_login(pool, LockoutKind::Ip, &ip).await?;
+        queries::record_login_attempt(pool, None, None, Some(&ip), LoginOutcome::Unlocked).await?;
+        return Ok(true);
+    }
+    let Some(account) = queries::get_login_account(pool, target).await? else {
+        return Ok(false);
+    };
+    queries::unlock_login(pool, LockoutKind::Account, &account.id.to_string()).await?;
+    queries::record_login_attempt(pool, Some(account.id), None, None, LoginOutcome::Unlocked).await?;
+    Ok(true)
+}
+
+fn remaining(delay: Duration, since: DateTime<Utc>) -> Duration {
+    let elapsed = (Utc::now() - since).to_std().unwrap_or_default();
+    delay.saturating_sub(elapsed)
+}
@@ -0,0 +1,459 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use rand::RngCore;
+use reqwest::header::{CONTENT_TYPE, USER_AGENT};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{MySql, Pool};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use url::Url;
+use uuid::Uuid;
+
+use crate::api::permissions::Permissions;
+use crate::api::OutgoingWebhook;
+use crate::db::queries;
+use crate::unfurl::ssrf::{check_url, PublicResolver};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts per delivery, the first one included
+const MAX_ATTEMPTS: u32 = 6;
+/// Doubles after every failed attempt: 5s, 10s, 20s, 40s, 80s
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Webhooks whose deliveries failed this many times in a row are disabled
+const MAX_CONSECUTIVE_FAILURES: i32 = 10;
+/// Requests in flight at once, retries included
+const CONCURRENT_DELIVERIES: usize = 8;
+const RETRY_BATCH_SIZE: i64 = 32;
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const DELIVERY_LOG_RETENTION_DAYS: i64 = 30;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Errors are cut to fit the delivery log
+const MAX_ERROR_LENGTH: usize = 500;
+const USER_AGENT_VALUE: &str = concat!("occult-server/", env!("CARGO_PKG_VERSION"), " (webhooks)");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub enabled: bool,
+    // Lets webhooks target loopback and private networks. Only meant for
+    // testing against a local receiver, never turn it on in production
+    pub allow_private_networks: bool,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allow_private_networks: false,
+        }
+    }
+}
+
+/// Server events a webhook can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+    #[serde(rename = "message.create")]
+    MessageCreate,
+    #[serde(rename = "message.update")]
+    MessageUpdate,
+    #[serde(rename = "message.delete")]
+    MessageDelete,
+    #[serde(rename = "member.join")]
+    MemberJoin,
+    #[serde(rename = "member.ban")]
+    MemberBan,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::MessageCreate => "message.create",
+            WebhookEvent::MessageUpdate => "message.update",
+            WebhookEvent::MessageDelete => "message.delete",
+            WebhookEvent::MemberJoin => "member.join",
+            WebhookEvent::MemberBan => "member.ban",
+        }
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "message.create" => Ok(WebhookEvent::MessageCreate),
+            "message.update" => Ok(WebhookEvent::MessageUpdate),
+            "message.delete" => Ok(WebhookEvent::MessageDelete),
+            "member.join" => Ok(WebhookEvent::MemberJoin),
+            "member.ban" => Ok(WebhookEvent::MemberBan),
+            _ => Err(format!("Unknown webhook event: {s}")),
+        }
+    }
+}
+
+/// Random signing secret for webhooks created without one, hex encoded
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    hex::encode(secret)
+}
+
+/// `sha256=<hex>` over `<timestamp>.<body>`. Receivers recompute it with
+/// the webhook's secret and reject stale timestamps to stop replays.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// What every delivery's body looks like
+#[derive(Debug, Serialize)]
+struct Envelope<'a> {
+    id: Uuid,
+    #[serde(rename = "type")]
+    event: WebhookEvent,
+    server_id: Uuid,
+    timestamp: chrono::DateTime<Utc>,
+    data: &'a serde_json::Value,
+}
+
+/// One event on its way to one webhook, the same across every attempt
+struct Delivery {
+    id: Uuid,
+    event: WebhookEvent,
+    body: Vec<u8>,
+}
+
+enum Attempt {
+    Delivered(u16),
+    Retry(Option<u16>, String),
+    Failed(Option<u16>, String),
+}
+
+/// Posts signed events to webhook urls
+#[derive(Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+    allow_private: bool,
+}
+
+impl WebhookSender {
+    pub fn new(config: &WebhookConfig) -> Result<Self> {
+        let mut client = reqwest::Client::builder()
+            .no_proxy()
+            .timeout(SEND_TIMEOUT)
+            .connect_timeout(SEND_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none());
+        if !config.allow_private_networks {
+            client = client.dns_resolver(Arc::new(PublicResolver));
+        }
+        Ok(Self {
+            client: client.build().context("Failed to build the webhook client")?,
+            allow_private: config.allow_private_networks,
+        })
+    }
+
+    /// Refuses urls we'd never deliver to, before they're stored
+    pub fn check_url(&self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|_| "Invalid webhook url".to_string())?;
+        if url.scheme() != "https" && !self.allow_private {
+            return Err("Webhook urls must use https".to_string());
+        }
+        check_url(&url, self.allow_private)
+    }
+
+    async fn post(&self, webhook: &OutgoingWebhook, delivery_id: Uuid, event: WebhookEvent, body: &[u8]) -> Attempt {
+        // Signed per attempt, so a retry isn't rejected as stale
+        let timestamp = Utc::now().timestamp();
+        let request = self
+            .client
+            .post(&webhook.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(USER_AGENT, USER_AGENT_VALUE)
+            .header("X-Occult-Webhook-Id", webhook.id.to_string())
+            .header("X-Occult-Delivery", delivery_id.to_string())
+            .header("X-Occult-Event", event.as_str())
+            .header("X-Occult-Timestamp", timestamp.to_string())
+            .header("X-Occult-Signature", sign(&webhook.secret, timestamp, body))
+            .body(body.to_vec());
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() || e.is_connect() => return Attempt::Retry(None, e.to_string()),
+            Err(e) => return Attempt::Failed(None, e.to_string()),
+        };
+        let status = response.status();
+        match status.as_u16() {
+            code @ 200..=299 => Attempt::Delivered(code),
+            code @ (408 | 429 | 500..=599) => Attempt::Retry(Some(code), format!("Receiver answered {status}")),
+            code => Attempt::Failed(Some(code), format!("Receiver answered {status}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct WebhookJob {
+    server_id: Uuid,
+    event: WebhookEvent,
+    /// Events from private channels only reach webhooks whose creator can read them
+    private: bool,
+    data: serde_json::Value,
+}
+
+/// Hands server events to the background webhook worker
+#[derive(Clone)]
+pub struct WebhookQueue {
+    sender: Option<mpsc::UnboundedSender<WebhookJob>>,
+    webhooks: Option<WebhookSender>,
+}
+
+pub struct WebhookReceiver(mpsc::UnboundedReceiver<WebhookJob>);
+
+impl WebhookQueue {
+    pub fn new(webhooks: WebhookSender) -> (Self, WebhookReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            Self {
+                sender: Some(sender),
+                webhooks: Some(webhooks),
+            },
+            WebhookReceiver(receiver),
+        )
+    }
+
+    /// A queue that drops everything, for when webhooks are off
+    pub fn disabled() -> Self {
+        Self {
+            sender: None,
+            webhooks: None,
+        }
+    }
+
+    pub fn sender(&self) -> Option<&WebhookSender> {
+        self.webhooks.as_ref()
+    }
+
+    pub fn emit(&self, server_id: Uuid, event: WebhookEvent, private: bool, data: &impl Serialize) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match serde_json::to_value(data) {
+            Ok(data) => {
+                let _ = sender.send(WebhookJob {
+                    server_id,
+                    event,
+                    private,
+                    data,
+                });
+            }
+            Err(e) => error!("Failed to serialize {} webhook payload: {e}", event.as_str()),
+        }
+    }
+}
+
+pub async fn run_webhook_worker(pool: Pool<MySql>, sender: WebhookSender, mut receiver: WebhookReceiver) {
+    tokio::spawn(prune_delivery_log(pool.clone()));
+    let permits = Arc::new(Semaphore::new(CONCURRENT_DELIVERIES));
+    tokio::spawn(run_retries(pool.clone(), sender.clone(), permits.clone()));
+    while let Some(job) = receiver.0.recv().await {
+        let webhooks = match subscribed_webhooks(&pool, &job).await {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                error!("Failed to load webhooks for server {}: {e:#}", job.server_id);
+                continue;
+            }
+        };
+        for webhook in webhooks {
+            let delivery_id = Uuid::new_v4();
+            let body = match serde_json::to_vec(&Envelope {
+                id: delivery_id,
+                event: job.event,
+                server_id: job.server_id,
+                timestamp: Utc::now(),
+                data: &job.data,
+            }) {
+                Ok(body) => body,
+                Err(e) => {
+                    error!("Failed to serialize {} webhook envelope: {e}", job.event.as_str());
+                    continue;
+                }
+            };
+            let Ok(permit) = permits.clone().acquire_owned().await else {
+                return;
+            };
+            let (pool, sender) = (pool.clone(), sender.clone());
+            let delivery = Delivery {
+                id: delivery_id,
+                event: job.event,
+                body,
+            };
+            tokio::spawn(async move {
+                if let Err(e) = attempt(&pool, &sender, permit, &webhook, &delivery, 1).await {
+                    error!("Failed to deliver to webhook {}: {e:#}", webhook.id);
+                }
+            });
+        }
+    }
+}
+
+/// Picks up deliveries whose next attempt is due, including ones left over
+/// from before a restart
+async fn run_retries(pool: Pool<MySql>, sender: WebhookSender, permits: Arc<Semaphore>) {
+    loop {
+        match queries::claim_webhook_retries(&pool, RETRY_BATCH_SIZE).await {
+            Ok(retries) => {
+                for retry in retries {
+                    let webhook = match queries::get_outgoing_webhook(&pool, retry.webhook_id).await {
+                        Ok(Some(webhook)) if webhook.enabled => webhook,
+                        // Disabled since the last attempt, a deleted one cascades
+                        Ok(_) => {
+                            if let Err(e) = queries::delete_webhook_retry(&pool, retry.delivery_id).await {
+                                error!("Failed to drop webhook retry {}: {e}", retry.delivery_id);
+                            }
+                            continue;
+                        }
+                        // Left claimed, it's tried again once the claim runs out
+                        Err(e) => {
+                            error!("Failed to load webhook {}: {e}", retry.webhook_id);
+                            continue;
+                        }
+                    };
+                    let Ok(permit) = permits.clone().acquire_owned().await else {
+                        return;
+                    };
+                    let (pool, sender) = (pool.clone(), sender.clone());
+                    let delivery = Delivery {
+                        id: retry.delivery_id,
+                        event: retry.event,
+                        body: retry.body,
+                    };
+                    let number = retry.attempts.max(0) as u32 + 1;
+                    tokio::spawn(async move {
+                        if let Err(e) = attempt(&pool, &sender, permit, &webhook, &delivery, number).await {
+                            error!("Failed to deliver to webhook {}: {e:#}", webhook.id);
+                        }
+                    });
+                }
+            }
+            Err(e) => error!("Failed to claim webhook retries: {e}"),
+        }
+        tokio::time::sleep(RETRY_POLL_INTERVAL).await;
+    }
+}
+
+async fn subscribed_webhooks(pool: &Pool<MySql>, job: &WebhookJob) -> Result<Vec<OutgoingWebhook>> {
+    let mut subscribed = Vec::new();
+    for webhook in queries::get_enabled_outgoing_webhooks(pool, job.server_id).await? {
+        if !webhook.events.contains(&job.event) {
+            continue;
+        }
+        if job.private && !creator_sees_private(pool, &webhook).await? {
+            continue;
+        }
+        subscribed.push(webhook);
+    }
+    Ok(subscribed)
+}
+
+/// A webhook can't be used to read channels its creator can't
+async fn creator_sees_private(pool: &Pool<MySql>, webhook: &OutgoingWebhook) -> Result<bool> {
+    let Some(creator) = webhook.created_by else {
+        return Ok(false);
+    };
+    Ok(queries::get_member_permissions(pool, webhook.server_id, creator)
+        .await?
+        .is_some_and(|permissions| permissions.contains(Permissions::VIEW_PRIVATE_CHANNELS)))
+}
+
+/// Makes one attempt at a delivery and logs it. The permit is only held for
+/// the request; a retryable failure is stored with its next attempt time
+/// rather than waited out here.
+async fn attempt(
+    pool: &Pool<MySql>,
+    sender: &WebhookSender,
+    permit: OwnedSemaphorePermit,
+    webhook: &OutgoingWebhook,
+    delivery: &Delivery,
+    number: u32,
+) -> Result<()> {
+    let started = Instant::now();
+    let outcome = sender.post(webhook, delivery.id, delivery.event, &delivery.body).await;
+    let duration_ms = started.elapsed().as_millis().min(i32::MAX as u128) as i32;
+    drop(permit);
+
+    let (status_code, error, retry) = match &outcome {
+        Attempt::Delivered(code) => (Some(*code), None, false),
+        Attempt::Retry(code, error) => (*code, Some(truncate(error)), true),
+        Attempt::Failed(code, error) => (*code, Some(truncate(error)), false),
+    };
+    queries::record_webhook_delivery(
+        pool,
+        webhook.id,
+        delivery.id,
+        delivery.event,
+        number as i32,
+        status_code,
+        error.as_deref(),
+        duration_ms,
+    )
+    .await?;
+
+    if retry && number < MAX_ATTEMPTS {
+        let delay = BASE_RETRY_DELAY * 2u32.pow(number - 1);
+        queries::schedule_webhook_retry(
+            pool,
+            delivery.id,
+            webhook.id,
+            delivery.event,
+            &delivery.body,
+            number as i32,
+            delay.as_secs() as i64,
+        )
+        .await?;
+        return Ok(());
+    }
+    // A no-op for deliveries settled on their first attempt
+    queries::delete_webhook_retry(pool, delivery.id).await?;
+
+    if let Attempt::Delivered(_) = outcome {
+        queries::record_outgoing_webhook_success(pool, webhook.id).await?;
+        return Ok(());
+    }
+    let failures = queries::record_outgoing_webhook_failure(pool, webhook.id).await?;
+    warn!("Delivery {} to webhook {} failed, {failures} in a row", delivery.id, webhook.id);
+    if failures >= MAX_CONSECUTIVE_FAILURES {
+        let reason = format!("Disabled after {failures} failed deliveries in a row");
+        queries::disable_outgoing_webhook(pool, webhook.id, &reason).await?;
+        info!("Disabled webhook {}: {reason}", webhook.id);
+    }
+    Ok(())
+}
+
+async fn prune_delivery_log(pool: Pool<MySql>) {
+    loop {
+        let cutoff = Utc::now() - chrono::Duration::days(DELIVERY_LOG_RETENTION_DAYS);
+        match queries::prune_webhook_deliveries(&pool, cutoff).await {
+            Ok(0) => {}
+            Ok(pruned) => info!("Pruned {pruned} old webhook delivery log entries"),
+            Err(e) => error!("Failed to prune webhook delivery log: {e}"),
+        }
+        tokio::time::sleep(PRUNE_INTERVAL).await;
+    }
+}
+
+fn truncate(error: &str) -> String {
+    match error.char_indices().nth(MAX_ERROR_LENGTH) {
+        Some((end, _)) => error[..end].to_string(),
+        None => error.to_string(),
+    }
+}
@@ -16,6 +16,16 @@ use serde::Serialize;
 use thiserror::Error;
 use url::Url;
 
+use crate::api::ratelimit::RateLimitConfig;
+use crate::api::signing::generate_signing_key;
+use crate::storage::StorageConfig;
+use crate::mail::MailConfig;
+use crate::push::PushConfig;
+use crate::search::SearchConfig;
+use crate::user::auth::LoginProtectionConfig;
+use crate::webhooks::WebhookConfig;
+use crate::unfurl::UnfurlConfig;
+
 #[derive(Debug, Error)]
 pub enum ConfigError {
     #[error("Invalid port number. expected value in range 1..65535 got {received}")]
@@ -77,6 +87,18 @@ impl std::fmt::Display for Port {
     }
 }
 
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 25 * 1024 * 1024;
+
+fn default_max_upload_size() -> u64 {
+    DEFAULT_MAX_UPLOAD_SIZE
+}
+
+pub const DEFAULT_ATTACHMENT_URL_TTL: u64 = 60 * 60;
+
+fn default_attachment_url_ttl() -> u64 {
+    DEFAULT_ATTACHMENT_URL_TTL
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     // Base url the repository is hosted at
@@ -96,6 +118,49 @@ pub struct ServerConfig {
     pub db_user: String,
     pub db_pass: String,
     pub db_name: String,
+
+    // Where attachments and other uploads are kept
+    #[serde(default)]
+    pub storage: StorageConfig,
+    // Largest single upload in bytes. Servers can set a lower limit of their own
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+
+    // Secret attachment download urls are signed with. Configs written before
+    // it existed have one generated and saved on the next start
+    #[serde(default)]
+    pub url_signing_key: String,
+    // Seconds a signed attachment url stays valid for
+    #[serde(default = "default_attachment_url_ttl")]
+    pub attachment_url_ttl: u64,
+
+    // Link previews for urls in messages
+    #[serde(default)]
+    pub unfurl: UnfurlConfig,
+
+    // Which index message search runs against
+    #[serde(default)]
+    pub search: SearchConfig,
+
+    // Web Push for users who aren't connected
+    #[serde(default)]
+    pub push: PushConfig,
+
+    // Signed event deliveries to external urls
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
+
+    // Request limits per user, or per ip for anonymous clients
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+
+    // Delays and lockouts after failed sign-ins
+    #[serde(default)]
+    pub login_protection: LoginProtectionConfig,
+
+    // SMTP for account emails such as lockout alerts
+    #[serde(default)]
+    pub mail: MailConfig,
 }
 
 impl Default for ServerConfig {
@@ -110,7 +175,18 @@ impl Default for ServerConfig {
             db_port: Port(3306),
             db_user: "occult".to_string(),
             db_pass: "occult".to_string(),
-            db_name: "occult_db".to_string()
+            db_name: "occult_db".to_string(),
+            storage: StorageConfig::default(),
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            url_signing_key: generate_signing_key(),
+            attachment_url_ttl: DEFAULT_ATTACHMENT_URL_TTL,
+            unfurl: UnfurlConfig::default(),
+            search: SearchConfig::default(),
+            push: PushConfig::generate(),
+            webhooks: WebhookConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            login_protection: LoginProtectionConfig::default(),
+            mail: MailConfig::default(),
         }
     }
 }
@@ -130,9 +206,17 @@ pub fn get_config() -> Result<ServerConfig> {
     }
     let config =
         fs::read_to_string(config_path).context("Failed to read configuration file to string")?;
-    match serde_yml::from_str(&config) {
-        Ok(config) => {
+    match serde_yml::from_str::<ServerConfig>(&config) {
+        Ok(mut config) => {
             debug!("server config has been serialized: Config: {config:#?}");
+            if config.url_signing_key.is_empty() {
+                // Saved straight away, a key that changed every start would
+                // break every signed link on restart
+                config.url_signing_key = generate_signing_key();
+                let content = serde_yml::to_string(&config).context("Failed to serialize config content")?;
+                write_to_path(&get_server_dir()?, content, "config.server.yml")
+                    .context("Failed to save the generated url signing key")?;
+            }
             return Ok(config);
         }
         Err(e) => {